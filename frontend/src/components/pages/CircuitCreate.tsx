@@ -1,5 +1,6 @@
 import { useNavigate, useNavigation } from "react-router-dom";
 import { CircuitDTO, RequestResponse } from "@/lib/types";
+import { authFetch } from "@/lib/auth";
 import { LoaderCircle } from "lucide-react";
 import {
   Card,
@@ -25,11 +26,10 @@ async function saveCircuit(value: CircuitDTO) {
     throw new Error("JWT is undefined. Please log in.");
   }
 
-  const response = await fetch("/api/circuits/create", {
+  const response = await authFetch("/api/circuits/create", {
     method: "POST",
     headers: {
       "Content-Type": "application/json",
-      Authorization: `Bearer ${jwt}`,
     },
     body: JSON.stringify(value),
   });
@@ -47,10 +47,12 @@ import { Link, useNavigate, useNavigation } from "react-router-dom";
 import {
   Circuit,
   FailedRequestResponse,
+  Page,
   RequestResponse,
   SuccessfulRequestResponse,
 } from "@/lib/types";
 import { toast } from "sonner";
+import { authFetch } from "@/lib/auth";
 
 const circuitColumns: Array<keyof Circuit> = [
   "state",
@@ -118,18 +120,14 @@ export const columns: ColumnDef<Circuit>[] = [
   },
 ];
 
-async function getCircuits(): Promise<RequestResponse<Array<Circuit>>> {
+async function getCircuits(): Promise<RequestResponse<Page<Circuit>>> {
   const jwt = sessionStorage.getItem("jwt");
 
   if (!jwt) {
     throw new Error("JWT is undefined. Please log in.");
   }
 
-  const res = await fetch("/api/circuits/all", {
-    headers: {
-      Authorization: `Bearer ${jwt}`,
-    },
-  });
+  const res = await authFetch("/api/circuits/all");
 
   debugger;
 
@@ -143,11 +141,7 @@ async function exportCircuits() {
     throw new Error("JWT is undefined. Please log in.");
   }
 
-  const res = await fetch("/api/circuits/export", {
-    headers: {
-      Authorization: `Bearer ${jwt}`,
-    },
-  });
+  const res = await authFetch("/api/circuits/export");
 
   if (!res.ok) {
     throw new Error(`Failed to fetch CSV: ${res.statusText}`);
@@ -172,16 +166,12 @@ function downloadBlob(data: Blob) {
 }
 
 async function importCsvFile(file: File) {
-  const jwt = sessionStorage.getItem("jwt");
   const formData = new FormData();
   formData.append("file", file);
 
-  const response = await fetch("/api/circuits/import", {
+  const response = await authFetch("/api/circuits/import", {
     method: "POST",
     body: formData,
-    headers: {
-      Authorization: `Bearer ${jwt}`,
-    },
   });
 
   if (!response.ok) {
@@ -248,7 +238,7 @@ export function CircuitDashboard() {
           toast.error(response.message);
           break;
         case "success":
-          toast.success(response.data);
+          toast.success("Import started");
           break;
       }
     },
@@ -272,13 +262,13 @@ export function CircuitDashboard() {
   const handleButtonClick = () => {
     const input = document.createElement("input");
     input.type = "file";
-    input.accept = ".csv";
+    input.accept = ".csv,.gz,.xlsx";
     input.onchange = handleFileUpload;
     input.click();
   };
 
   const { data, isLoading, isError, error } = useQuery<
-    RequestResponse<Array<Circuit>>
+    RequestResponse<Page<Circuit>>
   >({
     queryKey: ["CircuitsAll"],
     queryFn: async () => await getCircuits(),
@@ -290,7 +280,7 @@ export function CircuitDashboard() {
   const table = useReactTable({
     data:
       data?.status === "success"
-        ? (data as SuccessfulRequestResponse<Circuit[]>).data
+        ? (data as SuccessfulRequestResponse<Page<Circuit>>).data.items
         : [],
     columns,
     onSortingChange: setSorting,
@@ -7,6 +7,7 @@ import {
 } from "react-router-dom";
 import { queryClient } from "@/main";
 import { Circuit, RequestResponse } from "@/lib/types";
+import { authFetch } from "@/lib/auth";
 import { LoaderCircle, ServerCrash } from "lucide-react";
 import {
   Card,
@@ -37,11 +38,7 @@ export function singleCircuitLoader({ params }: { params: Params }) {
   return queryClient.fetchQuery({
     queryKey: ["Circuit", id],
     queryFn: async () => {
-      const response = await fetch(`/api/circuits/${id}`, {
-        headers: {
-          Authorization: `Bearer ${jwt}`,
-        },
-      });
+      const response = await authFetch(`/api/circuits/${id}`);
 
       if (!response.ok) {
         throw new Error("Network response was not ok");
@@ -60,11 +57,10 @@ export async function updateCircuit(value: Circuit) {
     throw new Error("JWT is undefined. Please log in.");
   }
 
-  const response = await fetch("/api/circuits/update", {
+  const response = await authFetch("/api/circuits/update", {
     method: "PUT",
     headers: {
       "Content-Type": "application/json",
-      Authorization: `Bearer ${jwt}`,
     },
     body: JSON.stringify(value),
   });
@@ -1,4 +1,4 @@
-import { Button, buttonVariants } from "@/components/ui/button";
+import { Button } from "@/components/ui/button";
 import {
   Card,
   CardContent,
@@ -7,30 +7,29 @@ import {
   CardHeader,
   CardTitle,
 } from "@/components/ui/card";
+import { Checkbox } from "@/components/ui/checkbox";
 import { Input } from "@/components/ui/input";
 import { Label } from "@/components/ui/label";
 import { toast } from "sonner";
 
 import React from "react";
-import { Link, useNavigate } from "react-router-dom";
+import { useNavigate } from "react-router-dom";
 import { useMutation, UseMutationResult } from "@tanstack/react-query";
 import { LoaderCircle } from "lucide-react";
 import { RequestResponse } from "@/lib/types";
+import { LoginResponse, storeSession } from "@/lib/auth";
 
 interface LoginRequest {
   username: string;
   password: string;
-  requested_role: string;
+  reduced_privileges: boolean;
 }
 
-interface LoginResponse {
-  token: string;
-}
-
-function Login({ role }: { role: string }) {
+function Login() {
   const navigate = useNavigate();
   const [username, setUsername] = React.useState("");
   const [password, setPassword] = React.useState("");
+  const [readOnly, setReadOnly] = React.useState(false);
 
   React.useEffect(() => {
     const jwt = sessionStorage.getItem("jwt");
@@ -70,7 +69,7 @@ function Login({ role }: { role: string }) {
         return;
       }
 
-      sessionStorage.setItem("jwt", response.data.token);
+      storeSession(response.data);
       toast.success("Login Successful");
       navigate("/circuits/dashboard");
     },
@@ -81,16 +80,17 @@ function Login({ role }: { role: string }) {
   });
 
   const handleSubmit = () => {
-    loginMutation.mutate({ username, password, requested_role: role });
+    loginMutation.mutate({
+      username,
+      password,
+      reduced_privileges: readOnly,
+    });
   };
 
-  const redirectRole = role === "admin" ? "user" : "admin";
-  const capitalizedRole = role.charAt(0).toUpperCase() + role.slice(1);
-
   return (
     <Card className="w-[350px] mt-24">
       <CardHeader>
-        <CardTitle>{capitalizedRole} Login</CardTitle>
+        <CardTitle>Login</CardTitle>
         <CardDescription>Enter your provided password</CardDescription>
       </CardHeader>
       <CardContent>
@@ -116,6 +116,14 @@ function Login({ role }: { role: string }) {
               onChange={(e) => setPassword(e.target.value)}
             />
           </div>
+          <div className="flex items-center space-x-2">
+            <Checkbox
+              id="read-only"
+              checked={readOnly}
+              onCheckedChange={(checked) => setReadOnly(checked === true)}
+            />
+            <Label htmlFor="read-only">Read-only session</Label>
+          </div>
         </div>
       </CardContent>
       <CardFooter className="flex justify-center flex-col gap-4 place-items-center">
@@ -135,12 +143,6 @@ function Login({ role }: { role: string }) {
             "Submit"
           )}
         </Button>
-        <Link
-          to={`/${redirectRole}`}
-          className={buttonVariants({ variant: "outline" })}
-        >
-          Go to {redirectRole} login
-        </Link>
       </CardFooter>
     </Card>
   );
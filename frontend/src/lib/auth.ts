@@ -0,0 +1,71 @@
+import { RequestResponse } from "@/lib/types";
+
+export interface LoginResponse {
+  token: string;
+  refresh_token: string;
+  roles: string[];
+}
+
+export function storeSession(session: LoginResponse) {
+  sessionStorage.setItem("jwt", session.token);
+  sessionStorage.setItem("refresh_token", session.refresh_token);
+}
+
+export function clearSession() {
+  sessionStorage.removeItem("jwt");
+  sessionStorage.removeItem("refresh_token");
+}
+
+// Refresh tokens can only be used once, concurrent requests share the refresh
+let refreshing: Promise<boolean> | null = null;
+
+async function refreshSession(): Promise<boolean> {
+  const refreshToken = sessionStorage.getItem("refresh_token");
+
+  if (!refreshToken) {
+    return false;
+  }
+
+  const response = await fetch("/auth/refresh", {
+    method: "POST",
+    headers: {
+      "Content-Type": "application/json",
+    },
+    body: JSON.stringify({ refresh_token: refreshToken }),
+  });
+  const json: RequestResponse<LoginResponse> = await response.json();
+
+  if (json.status === "error") {
+    clearSession();
+    return false;
+  }
+
+  storeSession(json.data);
+  return true;
+}
+
+// fetch with the access token, refreshed and retried once when it expired
+export async function authFetch(
+  url: string,
+  init: RequestInit = {},
+): Promise<Response> {
+  const send = () => {
+    const headers = new Headers(init.headers);
+    headers.set("Authorization", `Bearer ${sessionStorage.getItem("jwt")}`);
+    return fetch(url, { ...init, headers });
+  };
+
+  const response = await send();
+
+  if (response.status !== 401) {
+    return response;
+  }
+
+  if (!refreshing) {
+    refreshing = refreshSession().finally(() => {
+      refreshing = null;
+    });
+  }
+
+  return (await refreshing) ? await send() : response;
+}
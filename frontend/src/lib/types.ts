@@ -56,3 +56,9 @@ export interface FailedRequestResponse {
 export type RequestResponse<T> =
   | SuccessfulRequestResponse<T>
   | FailedRequestResponse;
+
+export interface Page<T> {
+  items: T[];
+  total: number;
+  next_cursor: string | null;
+}
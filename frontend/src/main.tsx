@@ -18,16 +18,16 @@ export const queryClient = new QueryClient();
 const router = createBrowserRouter([
   {
     path: "/",
-    element: <Login role="admin" />,
+    element: <Login />,
     index: true,
   },
   {
     path: "/admin",
-    element: <Login role="admin" />,
+    element: <Login />,
   },
   {
     path: "/user",
-    element: <Login role="user" />,
+    element: <Login />,
   },
   {
     path: "circuits",
@@ -0,0 +1,40 @@
+-- Schema as it existed before migrations were tracked. Everything is
+-- IF NOT EXISTS so this is a no-op against databases restored from
+-- um_device_tracker_db.sql.
+
+CREATE TABLE IF NOT EXISTS circuits (
+    id character varying(32) PRIMARY KEY,
+    state text,
+    site_name text,
+    ckt_id text,
+    parent text,
+    link_type text,
+    provider text,
+    z_loc text,
+    rtr_name_z_loc text,
+    to_description text,
+    rtr_port_z_loc text,
+    interf_ip_z_loc text,
+    a_loc text,
+    rtr_name_a_loc text,
+    rtr_port text,
+    interf_ip_a_loc text,
+    bw_mbps text,
+    single_isp text,
+    ups_closet text,
+    router_ip text
+);
+
+CREATE TABLE IF NOT EXISTS users (
+    username character varying(255) NOT NULL,
+    password character varying(255) NOT NULL,
+    role character varying(24)
+);
+
+CREATE TABLE IF NOT EXISTS import_report (
+    type text NOT NULL,
+    id character varying(32) PRIMARY KEY,
+    message text NOT NULL,
+    file_name text,
+    seen boolean NOT NULL DEFAULT FALSE
+);
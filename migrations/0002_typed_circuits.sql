@@ -0,0 +1,120 @@
+-- Converts the free text network columns on circuits to real types.
+--
+-- Values that cannot be converted are cleared and recorded in
+-- circuit_migration_issues, which is logged on startup so they can be fixed
+-- by hand or through a CSV import.
+
+CREATE TABLE circuit_migration_issues (
+    circuit_id character varying(32) NOT NULL,
+    field text NOT NULL,
+    raw_value text NOT NULL,
+    message text NOT NULL
+);
+
+CREATE FUNCTION pg_temp.parse_ip(raw text) RETURNS inet
+LANGUAGE plpgsql IMMUTABLE AS $$
+DECLARE
+    parsed inet;
+BEGIN
+    parsed := NULLIF(btrim(raw), '')::inet;
+    -- Only host addresses are accepted, a bare network like 10.0.0.0/24 is not
+    IF parsed IS NOT NULL AND host(parsed)::inet <> parsed THEN
+        RETURN NULL;
+    END IF;
+    RETURN parsed;
+EXCEPTION WHEN others THEN
+    RETURN NULL;
+END;
+$$;
+
+CREATE FUNCTION pg_temp.parse_bandwidth(raw text) RETURNS bigint
+LANGUAGE plpgsql IMMUTABLE AS $$
+DECLARE
+    parts text[];
+BEGIN
+    parts := regexp_match(
+        lower(replace(btrim(raw), ',', '')),
+        '^([0-9]+(?:\.[0-9]+)?)\s*(m|mb|mbps|mbit/s|g|gb|gbps|gbit/s)?$'
+    );
+    IF parts IS NULL THEN
+        RETURN NULL;
+    END IF;
+    RETURN round(parts[1]::numeric * CASE WHEN left(coalesce(parts[2], 'm'), 1) = 'g' THEN 1000 ELSE 1 END);
+END;
+$$;
+
+CREATE FUNCTION pg_temp.parse_state(raw text) RETURNS text
+LANGUAGE sql IMMUTABLE AS $$
+    SELECT CASE
+        WHEN lower(btrim(raw)) = 'active' THEN 'Active'
+        WHEN lower(btrim(raw)) = 'new' THEN 'New'
+        WHEN lower(btrim(raw)) = 'tba' THEN 'TBA'
+        WHEN lower(btrim(raw)) = 'no um' THEN 'No UM'
+        WHEN upper(btrim(raw)) ~ '^R[0-9]+(S[0-9]+|-PP)?$' THEN upper(btrim(raw))
+    END
+$$;
+
+CREATE FUNCTION pg_temp.parse_flag(raw text) RETURNS boolean
+LANGUAGE sql IMMUTABLE AS $$
+    SELECT CASE
+        WHEN lower(btrim(coalesce(raw, ''))) IN ('yes', 'y', 'true', '1') THEN TRUE
+        WHEN lower(btrim(coalesce(raw, ''))) IN ('no', 'n', 'false', '0', '') THEN FALSE
+    END
+$$;
+
+INSERT INTO circuit_migration_issues (circuit_id, field, raw_value, message)
+SELECT id, field, raw_value, message
+FROM circuits,
+LATERAL (VALUES
+    ('state', state, pg_temp.parse_state(state) IS NULL, 'not a known circuit state'),
+    ('interf_ip_z_loc', interf_ip_z_loc, pg_temp.parse_ip(interf_ip_z_loc) IS NULL, 'not a valid IP address'),
+    ('interf_ip_a_loc', interf_ip_a_loc, pg_temp.parse_ip(interf_ip_a_loc) IS NULL, 'not a valid IP address'),
+    ('router_ip', router_ip, pg_temp.parse_ip(router_ip) IS NULL, 'not a valid IP address'),
+    ('bw_mbps', bw_mbps, pg_temp.parse_bandwidth(bw_mbps) IS NULL, 'not a valid bandwidth'),
+    ('single_isp', single_isp, pg_temp.parse_flag(single_isp) IS NULL, 'not a yes/no value')
+) AS checks (field, raw_value, failed, message)
+WHERE failed AND btrim(coalesce(raw_value, '')) <> '';
+
+ALTER TABLE circuits
+    ALTER COLUMN state TYPE text USING pg_temp.parse_state(state),
+    ALTER COLUMN interf_ip_z_loc TYPE inet USING pg_temp.parse_ip(interf_ip_z_loc),
+    ALTER COLUMN interf_ip_a_loc TYPE inet USING pg_temp.parse_ip(interf_ip_a_loc),
+    ALTER COLUMN router_ip TYPE inet USING pg_temp.parse_ip(router_ip),
+    ALTER COLUMN bw_mbps TYPE bigint USING pg_temp.parse_bandwidth(bw_mbps),
+    ALTER COLUMN single_isp TYPE boolean USING coalesce(pg_temp.parse_flag(single_isp), FALSE),
+    ALTER COLUMN single_isp SET DEFAULT FALSE,
+    ALTER COLUMN single_isp SET NOT NULL,
+    ADD CONSTRAINT circuits_bw_mbps_check CHECK (bw_mbps >= 0),
+    ADD CONSTRAINT circuits_state_check
+        CHECK (state ~ '^(Active|New|TBA|No UM|R[0-9]+(S[0-9]+|-PP)?)$');
+
+-- The remaining text columns are never NULL from the application side
+UPDATE circuits SET
+    site_name = coalesce(site_name, ''),
+    ckt_id = coalesce(ckt_id, ''),
+    parent = coalesce(parent, ''),
+    link_type = coalesce(link_type, ''),
+    provider = coalesce(provider, ''),
+    z_loc = coalesce(z_loc, ''),
+    rtr_name_z_loc = coalesce(rtr_name_z_loc, ''),
+    to_description = coalesce(to_description, ''),
+    rtr_port_z_loc = coalesce(rtr_port_z_loc, ''),
+    a_loc = coalesce(a_loc, ''),
+    rtr_name_a_loc = coalesce(rtr_name_a_loc, ''),
+    rtr_port = coalesce(rtr_port, ''),
+    ups_closet = coalesce(ups_closet, '');
+
+ALTER TABLE circuits
+    ALTER COLUMN site_name SET NOT NULL,
+    ALTER COLUMN ckt_id SET NOT NULL,
+    ALTER COLUMN parent SET NOT NULL,
+    ALTER COLUMN link_type SET NOT NULL,
+    ALTER COLUMN provider SET NOT NULL,
+    ALTER COLUMN z_loc SET NOT NULL,
+    ALTER COLUMN rtr_name_z_loc SET NOT NULL,
+    ALTER COLUMN to_description SET NOT NULL,
+    ALTER COLUMN rtr_port_z_loc SET NOT NULL,
+    ALTER COLUMN a_loc SET NOT NULL,
+    ALTER COLUMN rtr_name_a_loc SET NOT NULL,
+    ALTER COLUMN rtr_port SET NOT NULL,
+    ALTER COLUMN ups_closet SET NOT NULL;
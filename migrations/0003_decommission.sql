@@ -0,0 +1,4 @@
+-- Decommissioned circuits keep their row but are hidden from default listings
+ALTER TABLE circuits
+    ADD COLUMN decommissioned_at timestamptz,
+    ADD COLUMN decommission_reason text;
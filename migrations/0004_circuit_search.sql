@@ -0,0 +1,24 @@
+-- Search support for /api/circuits/search, exact matching is done on a
+-- normalized form of each field so that 38.KRGS.047826 and 38KRGS047826
+-- compare equal, anything else falls back to trigram word similarity
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+CREATE FUNCTION normalize_search(value text) RETURNS text
+LANGUAGE sql IMMUTABLE PARALLEL SAFE AS $$
+    SELECT regexp_replace(lower(coalesce(value, '')), '[^a-z0-9]+', '', 'g')
+$$;
+
+-- How well a field matches a search term, from 0 for no match to 1 for an
+-- exact one. Fuzzy matches never rank above substring matches.
+CREATE FUNCTION search_score(field text, term text) RETURNS real
+LANGUAGE sql IMMUTABLE PARALLEL SAFE AS $$
+    SELECT CASE
+        WHEN normalize_search(term) = '' THEN 0
+        WHEN normalize_search(field) = normalize_search(term) THEN 1
+        WHEN starts_with(normalize_search(field), normalize_search(term)) THEN 0.9
+        WHEN strpos(normalize_search(field), normalize_search(term)) > 0 THEN 0.75
+        WHEN word_similarity(term, coalesce(field, '')) >= 0.45
+            THEN word_similarity(term, coalesce(field, '')) * 0.6
+        ELSE 0
+    END
+$$;
@@ -0,0 +1,28 @@
+-- Append only log of every change made to a circuit. Each row is a revision
+-- holding the field level diff and the full circuit as it was after the change
+CREATE TABLE circuit_history (
+    revision bigserial PRIMARY KEY,
+    circuit_id character varying(32) NOT NULL,
+    action text NOT NULL CHECK (action IN ('create', 'update', 'decommission', 'delete')),
+    actor text NOT NULL,
+    source text NOT NULL CHECK (source IN ('ui', 'api', 'import')),
+    file_name text,
+    changed_at timestamptz NOT NULL DEFAULT now(),
+    changes jsonb NOT NULL,
+    snapshot jsonb
+);
+
+CREATE INDEX circuit_history_circuit_id_idx ON circuit_history (circuit_id, revision);
+CREATE INDEX circuit_history_changed_at_idx ON circuit_history (changed_at);
+CREATE INDEX circuit_history_actor_idx ON circuit_history (actor, changed_at);
+
+CREATE FUNCTION reject_history_rewrite() RETURNS trigger
+LANGUAGE plpgsql AS $$
+BEGIN
+    RAISE EXCEPTION 'circuit_history is append only';
+END;
+$$;
+
+CREATE TRIGGER circuit_history_append_only
+    BEFORE UPDATE OR DELETE ON circuit_history
+    FOR EACH ROW EXECUTE FUNCTION reject_history_rewrite();
@@ -0,0 +1,33 @@
+-- Reverting writes its own kind of revision, and revisions made by an import
+-- remember which import job made them so the whole job can be undone
+ALTER TABLE circuit_history
+    ADD COLUMN import_id character varying(32),
+    DROP CONSTRAINT circuit_history_action_check,
+    ADD CONSTRAINT circuit_history_action_check
+        CHECK (action IN ('create', 'update', 'decommission', 'delete', 'revert')),
+    DROP CONSTRAINT circuit_history_source_check,
+    ADD CONSTRAINT circuit_history_source_check
+        CHECK (source IN ('ui', 'api', 'import', 'migration'));
+
+CREATE INDEX circuit_history_import_id_idx ON circuit_history (import_id)
+    WHERE import_id IS NOT NULL;
+
+-- Circuits that haven't changed since history was introduced get a baseline
+-- revision, so there is always an earlier version to go back to
+INSERT INTO circuit_history (circuit_id, action, actor, source, changes, snapshot)
+SELECT
+    circuits.id,
+    'create',
+    'system',
+    'migration',
+    coalesce((
+        SELECT jsonb_agg(jsonb_build_object('field', key, 'old', NULL, 'new', value))
+        FROM jsonb_each_text(to_jsonb(circuits) - 'id')
+        WHERE value <> ''
+    ), '[]'::jsonb),
+    to_jsonb(circuits)
+FROM circuits
+WHERE NOT EXISTS (
+    SELECT 1 FROM circuit_history WHERE circuit_history.circuit_id = circuits.id
+)
+ORDER BY circuits.id;
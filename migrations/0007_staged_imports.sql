@@ -0,0 +1,8 @@
+-- Uploads that were previewed but not yet committed
+CREATE TABLE staged_imports (
+    id character varying(32) PRIMARY KEY,
+    file_name text,
+    data text NOT NULL,
+    staged_by text NOT NULL,
+    staged_at timestamptz NOT NULL DEFAULT now()
+);
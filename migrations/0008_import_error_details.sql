@@ -0,0 +1,14 @@
+-- Errors reported by an import point back at the import and at the CSV row,
+-- column and value that caused them. The failed row itself is kept so the
+-- failures can be downloaded again for fixing.
+ALTER TABLE import_report
+    ADD COLUMN import_id character varying(32),
+    ADD COLUMN line bigint,
+    ADD COLUMN column_name text,
+    ADD COLUMN raw_value text,
+    ADD COLUMN category text,
+    ADD COLUMN headers text[],
+    ADD COLUMN record text[];
+
+CREATE INDEX import_report_import_id_idx ON import_report (import_id)
+    WHERE import_id IS NOT NULL;
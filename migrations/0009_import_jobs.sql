@@ -0,0 +1,23 @@
+-- Background CSV imports, from the moment they are queued until they stop.
+-- The id is shared with the import's reports and history entries.
+CREATE TABLE import_jobs (
+    id character varying(32) PRIMARY KEY,
+    file_name text,
+    mode text NOT NULL CHECK (mode IN ('atomic', 'best_effort')),
+    status text NOT NULL DEFAULT 'queued'
+        CHECK (status IN ('queued', 'running', 'succeeded', 'failed', 'cancelled')),
+    started_by text NOT NULL,
+    message text,
+    total_rows bigint,
+    processed bigint NOT NULL DEFAULT 0,
+    created bigint NOT NULL DEFAULT 0,
+    updated bigint NOT NULL DEFAULT 0,
+    decommissioned bigint NOT NULL DEFAULT 0,
+    deleted bigint NOT NULL DEFAULT 0,
+    failed bigint NOT NULL DEFAULT 0,
+    queued_at timestamptz NOT NULL DEFAULT now(),
+    started_at timestamptz,
+    finished_at timestamptz
+);
+
+CREATE INDEX import_jobs_queued_at_idx ON import_jobs (queued_at DESC);
@@ -0,0 +1,15 @@
+-- Saved translations of spreadsheet headers onto circuit fields, see
+-- `ColumnMapping`. `columns` holds the mapped columns in order.
+CREATE TABLE column_mappings (
+    id character varying(32) PRIMARY KEY,
+    name text NOT NULL UNIQUE,
+    columns jsonb NOT NULL,
+    updated_at timestamptz NOT NULL DEFAULT now()
+);
+
+-- A preview worked out with a mapping that is gone can't be committed anymore
+ALTER TABLE staged_imports
+    ADD COLUMN mapping_id character varying(32) REFERENCES column_mappings (id) ON DELETE CASCADE;
+
+ALTER TABLE import_jobs
+    ADD COLUMN mapping_id character varying(32) REFERENCES column_mappings (id) ON DELETE SET NULL;
@@ -0,0 +1,8 @@
+-- The columns import rows without an id are matched to circuits by, see
+-- `NaturalKey`. There is at most one key, the unique index that goes with it
+-- (`circuits_natural_key`) is created and dropped along with its row.
+CREATE TABLE circuit_natural_key (
+    singleton boolean PRIMARY KEY DEFAULT TRUE CHECK (singleton),
+    columns text[] NOT NULL CHECK (cardinality(columns) > 0),
+    updated_at timestamptz NOT NULL DEFAULT now()
+);
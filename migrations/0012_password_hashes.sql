@@ -0,0 +1,7 @@
+-- Passwords are kept as argon2id hashes in PHC string format. Users created
+-- before keep their plaintext `password` until their next successful login,
+-- which hashes it and clears the plaintext.
+ALTER TABLE users
+    ADD COLUMN password_hash text,
+    ALTER COLUMN password DROP NOT NULL,
+    ADD CONSTRAINT users_password_check CHECK (password IS NOT NULL OR password_hash IS NOT NULL);
@@ -0,0 +1,30 @@
+-- Users become accounts managed through the API: one row per username with
+-- every role it had, and a flag to lock it out without deleting it. When a
+-- person had a separate row for each role, the password of their admin row
+-- is the one kept.
+CREATE TEMPORARY TABLE merged_users ON COMMIT DROP AS
+SELECT DISTINCT ON (u.username)
+    u.username,
+    u.password,
+    u.password_hash,
+    ARRAY(
+        SELECT DISTINCT r.role
+        FROM users r
+        WHERE r.username = u.username AND r.role IS NOT NULL
+        ORDER BY r.role
+    ) AS roles
+FROM users u
+ORDER BY u.username, u.role = 'admin' DESC NULLS LAST;
+
+DELETE FROM users;
+
+ALTER TABLE users
+    DROP COLUMN role,
+    ADD COLUMN roles text[] NOT NULL DEFAULT '{}',
+    ADD COLUMN disabled boolean NOT NULL DEFAULT FALSE,
+    ADD COLUMN created_at timestamptz NOT NULL DEFAULT now(),
+    ADD PRIMARY KEY (username),
+    ADD CONSTRAINT users_roles_check CHECK (roles <@ ARRAY['admin', 'user']);
+
+INSERT INTO users (username, password, password_hash, roles)
+SELECT username, password, password_hash, roles FROM merged_users;
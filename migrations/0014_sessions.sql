@@ -0,0 +1,16 @@
+-- A session per login, holding the SHA-256 hash of its current refresh token
+-- and of the one it accepted before that. Only a copy of the previous token can
+-- still present it, so seeing it again revokes the session. Access tokens name
+-- their session, revoking it stops them before they expire.
+CREATE TABLE sessions (
+    id text PRIMARY KEY,
+    username text NOT NULL REFERENCES users (username) ON DELETE CASCADE,
+    reduced_privileges boolean NOT NULL DEFAULT FALSE,
+    refresh_hash text NOT NULL,
+    previous_refresh_hash text,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    expires_at timestamptz NOT NULL,
+    revoked_at timestamptz
+);
+
+CREATE INDEX sessions_username ON sessions (username);
@@ -0,0 +1,13 @@
+-- Keys for scripts and other service accounts, sent in place of an access
+-- token. Only a hash of each key is kept, the key itself is shown once.
+CREATE TABLE api_keys (
+    id text PRIMARY KEY,
+    name text NOT NULL UNIQUE,
+    key_hash text NOT NULL,
+    roles text[] NOT NULL,
+    scopes text[] NOT NULL DEFAULT '{}',
+    created_by text NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    expires_at timestamptz,
+    last_used_at timestamptz
+);
@@ -0,0 +1,21 @@
+-- Roles become data: each is a set of the permissions routes ask for. Users
+-- and API keys keep the names of their roles, `admin` and `user` are created
+-- with what they allowed until now.
+CREATE TABLE roles (
+    name text PRIMARY KEY,
+    description text NOT NULL DEFAULT '',
+    permissions text[] NOT NULL
+);
+
+INSERT INTO roles (name, description, permissions) VALUES
+    (
+        'admin',
+        'Everything, including managing users',
+        ARRAY[
+            'circuits:read', 'circuits:write', 'circuits:delete', 'circuits:import',
+            'reports:read', 'users:admin'
+        ]
+    ),
+    ('user', 'Looking up circuits', ARRAY['circuits:read']);
+
+ALTER TABLE users DROP CONSTRAINT users_roles_check;
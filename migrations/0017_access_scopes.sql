@@ -0,0 +1,15 @@
+-- Regions group sites under a name scopes can refer to
+CREATE TABLE regions (
+    name text PRIMARY KEY,
+    description text NOT NULL DEFAULT '',
+    sites text[] NOT NULL DEFAULT '{}'
+);
+
+-- Users with scopes only reach the circuits matching one of them, users
+-- without any reach every circuit
+CREATE TABLE user_scopes (
+    username text NOT NULL REFERENCES users (username) ON DELETE CASCADE,
+    kind text NOT NULL CHECK (kind IN ('site', 'state', 'provider', 'region')),
+    value text NOT NULL,
+    PRIMARY KEY (username, kind, value)
+);
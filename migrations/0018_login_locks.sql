@@ -0,0 +1,21 @@
+-- Recent failed logins per username and per client address. Keys whose
+-- failures run over the limits wait until `locked_until` before trying again.
+CREATE TABLE login_locks (
+    kind text NOT NULL CHECK (kind IN ('username', 'ip')),
+    key text NOT NULL,
+    failures integer NOT NULL,
+    last_failed_at timestamptz NOT NULL DEFAULT now(),
+    locked_until timestamptz,
+    PRIMARY KEY (kind, key)
+);
+
+-- Every failed login for the audit trail, kept after the lock is cleared
+CREATE TABLE login_failures (
+    id bigint GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    username text NOT NULL,
+    ip text NOT NULL,
+    reason text NOT NULL CHECK (reason IN ('invalid_credentials', 'locked')),
+    failed_at timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE INDEX login_failures_username ON login_failures (username);
@@ -0,0 +1,89 @@
+//! Commands the binary runs instead of the server, for setting up what can't
+//! be done through the API yet, like the first admin.
+
+use std::io::{BufRead, IsTerminal};
+
+use chrono::Utc;
+
+use crate::{
+    model::{Role, User, Users},
+    password,
+};
+
+const USAGE: &str = "Usage: um-device-tracker [create-admin <username>]
+
+Without a command the server is started.
+
+Commands:
+  create-admin <username>  Creates an admin account. The password is read from
+                           ADMIN_PASSWORD or else from standard input.";
+
+pub enum Command {
+    CreateAdmin { username: String },
+}
+
+impl Command {
+    /// The command given in `args`, `None` to start the server. Fails with the
+    /// usage for anything else.
+    pub fn from_args(mut args: impl Iterator<Item = String>) -> Result<Option<Command>, String> {
+        let command = match args.next().as_deref() {
+            None => return Ok(None),
+            Some("create-admin") => match args.next() {
+                Some(username) => Command::CreateAdmin { username },
+                None => return Err(USAGE.to_string()),
+            },
+            Some(_) => return Err(USAGE.to_string()),
+        };
+
+        if args.next().is_some() {
+            return Err(USAGE.to_string());
+        }
+
+        Ok(Some(command))
+    }
+}
+
+pub async fn run<S>(data_source: &S, command: Command) -> eyre::Result<()>
+where
+    S: Users<User>,
+{
+    match command {
+        Command::CreateAdmin { username } => {
+            User::validate_username(&username)?;
+
+            let password = read_password()?;
+            password::validate(&password)?;
+
+            let admin = User {
+                username,
+                roles: vec![Role::ADMIN.to_string()],
+                disabled: false,
+                created_at: Utc::now(),
+            };
+
+            let admin = data_source
+                .create_user(admin, password::hash(&password)?)
+                .await?;
+            println!("Created admin {}", admin.username);
+        }
+    }
+
+    Ok(())
+}
+
+fn read_password() -> eyre::Result<String> {
+    if let Ok(password) = std::env::var("ADMIN_PASSWORD") {
+        return Ok(password);
+    }
+
+    let stdin = std::io::stdin();
+    // Typed passwords aren't echoed back
+    if stdin.is_terminal() {
+        return Ok(rpassword::prompt_password("Password: ")?);
+    }
+
+    let mut password = String::new();
+    stdin.lock().read_line(&mut password)?;
+
+    Ok(password.trim_end_matches(['\r', '\n']).to_string())
+}
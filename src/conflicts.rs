@@ -0,0 +1,435 @@
+//! Circuits claiming what only one of them can have: the same circuit ID, the
+//! same interface address or the same port on a router. Only circuits that
+//! aren't decommissioned hold on to what they claim.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use serde::Serialize;
+
+use crate::model::{Circuit, CircuitScope};
+
+/// What two circuits can't both claim
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictKind {
+    /// The same `ckt_id`
+    DuplicateCktId,
+    /// The same address in `interf_ip_z_loc` or `interf_ip_a_loc`. `router_ip`
+    /// is left out, every circuit on a router shares it.
+    IpCollision,
+    /// The same port on the same router, on either end of the circuits
+    PortDoubleBooking,
+}
+
+impl ConflictKind {
+    fn describe(&self) -> &'static str {
+        match self {
+            ConflictKind::DuplicateCktId => "circuit ID",
+            ConflictKind::IpCollision => "interface IP",
+            ConflictKind::PortDoubleBooking => "router port",
+        }
+    }
+}
+
+/// Whether a conflict is let through with a warning or refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// The severity of each kind of conflict, set with
+/// `CONFLICTS_DUPLICATE_CKT_ID`, `CONFLICTS_IP_COLLISION` and
+/// `CONFLICTS_PORT_DOUBLE_BOOKING` to `warn` or `error`. All of them are
+/// warnings by default.
+#[derive(Debug, Clone, Copy)]
+pub struct ConflictPolicy {
+    pub duplicate_ckt_id: Severity,
+    pub ip_collision: Severity,
+    pub port_double_booking: Severity,
+}
+
+impl ConflictPolicy {
+    pub fn from_env() -> ConflictPolicy {
+        let read = |name: &str| match std::env::var(name).as_deref() {
+            Ok("error") => Severity::Error,
+            Ok("warn") | Err(_) => Severity::Warning,
+            Ok(_) => panic!("{name} MUST BE `warn` OR `error`"),
+        };
+
+        ConflictPolicy {
+            duplicate_ckt_id: read("CONFLICTS_DUPLICATE_CKT_ID"),
+            ip_collision: read("CONFLICTS_IP_COLLISION"),
+            port_double_booking: read("CONFLICTS_PORT_DOUBLE_BOOKING"),
+        }
+    }
+
+    pub fn severity(&self, kind: ConflictKind) -> Severity {
+        match kind {
+            ConflictKind::DuplicateCktId => self.duplicate_ckt_id,
+            ConflictKind::IpCollision => self.ip_collision,
+            ConflictKind::PortDoubleBooking => self.port_double_booking,
+        }
+    }
+
+    /// `conflicts` split into the ones that are refused and the warnings
+    pub fn split(&self, conflicts: Vec<Conflict>) -> (Vec<Conflict>, Vec<Conflict>) {
+        conflicts
+            .into_iter()
+            .partition(|conflict| self.severity(conflict.kind) == Severity::Error)
+    }
+}
+
+/// Circuits that claim the same value, normalized the way they are compared
+#[derive(Debug, Clone, Serialize)]
+pub struct Conflict {
+    pub kind: ConflictKind,
+    pub value: String,
+    pub circuit_ids: Vec<String>,
+    /// Circuits outside the caller's scope, counted but not named
+    pub hidden: usize,
+}
+
+impl Conflict {
+    /// The conflict as told to a caller that can't see the circuits in
+    /// `out_of_scope`
+    pub fn scoped(self, out_of_scope: &HashSet<String>) -> Conflict {
+        let (circuit_ids, hidden): (Vec<String>, Vec<String>) = self
+            .circuit_ids
+            .into_iter()
+            .partition(|id| !out_of_scope.contains(id));
+
+        Conflict {
+            circuit_ids,
+            hidden: self.hidden + hidden.len(),
+            ..self
+        }
+    }
+}
+
+impl std::fmt::Display for Conflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut others = self.circuit_ids.clone();
+        match self.hidden {
+            0 => {}
+            1 => others.push("a circuit out of scope".to_string()),
+            hidden => others.push(format!("{hidden} circuits out of scope")),
+        }
+
+        write!(
+            f,
+            "The {} `{}` is also used by {}",
+            self.kind.describe(),
+            self.value,
+            others.join(", ")
+        )
+    }
+}
+
+/// Ids of the circuits `scope` doesn't reach, none without a scope
+pub fn out_of_scope(scope: Option<&CircuitScope>, circuits: &[Circuit]) -> HashSet<String> {
+    match scope {
+        Some(scope) => circuits
+            .iter()
+            .filter(|circuit| !scope.contains(circuit))
+            .map(|circuit| circuit.id.clone())
+            .collect(),
+        None => HashSet::new(),
+    }
+}
+
+/// Conflicts found in the inventory, grouped by kind
+#[derive(Debug, Default, Serialize)]
+pub struct ConflictReport {
+    pub duplicate_ckt_ids: Vec<Conflict>,
+    pub ip_collisions: Vec<Conflict>,
+    pub port_double_bookings: Vec<Conflict>,
+}
+
+impl ConflictReport {
+    /// The report as told to a caller that can't see the circuits in
+    /// `out_of_scope`, leaving out the conflicts among those circuits only
+    pub fn scoped(self, out_of_scope: &HashSet<String>) -> ConflictReport {
+        let scoped = |conflicts: Vec<Conflict>| {
+            conflicts
+                .into_iter()
+                .map(|conflict| conflict.scoped(out_of_scope))
+                .filter(|conflict| !conflict.circuit_ids.is_empty())
+                .collect()
+        };
+
+        ConflictReport {
+            duplicate_ckt_ids: scoped(self.duplicate_ckt_ids),
+            ip_collisions: scoped(self.ip_collisions),
+            port_double_bookings: scoped(self.port_double_bookings),
+        }
+    }
+}
+
+/// A change refused for the conflicts it would cause
+#[derive(Debug)]
+pub struct Conflicting(pub Vec<Conflict>);
+
+impl std::fmt::Display for Conflicting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let messages: Vec<String> = self.0.iter().map(ToString::to_string).collect();
+        f.write_str(&messages.join("; "))
+    }
+}
+
+impl std::error::Error for Conflicting {}
+
+type Claim = (ConflictKind, String);
+
+/// What every circuit claims, kept up to date as changes are checked so a
+/// batch of them is checked against each other as well
+#[derive(Debug, Default)]
+pub struct ConflictIndex {
+    claims: HashMap<Claim, BTreeSet<String>>,
+    by_circuit: HashMap<String, Vec<Claim>>,
+}
+
+impl ConflictIndex {
+    pub fn new(circuits: &[Circuit]) -> ConflictIndex {
+        let mut index = ConflictIndex::default();
+        circuits.iter().for_each(|circuit| index.insert(circuit));
+        index
+    }
+
+    /// Every value claimed by more than one circuit
+    pub fn report(&self) -> ConflictReport {
+        let mut groups: BTreeMap<&Claim, &BTreeSet<String>> = BTreeMap::new();
+        groups.extend(
+            self.claims
+                .iter()
+                .filter(|(_, circuit_ids)| circuit_ids.len() > 1),
+        );
+
+        let mut report = ConflictReport::default();
+        for ((kind, value), circuit_ids) in groups {
+            let conflict = Conflict {
+                kind: *kind,
+                value: value.clone(),
+                circuit_ids: circuit_ids.iter().cloned().collect(),
+                hidden: 0,
+            };
+
+            match kind {
+                ConflictKind::DuplicateCktId => report.duplicate_ckt_ids.push(conflict),
+                ConflictKind::IpCollision => report.ip_collisions.push(conflict),
+                ConflictKind::PortDoubleBooking => report.port_double_bookings.push(conflict),
+            }
+        }
+
+        report
+    }
+
+    /// What `circuit` claims that other circuits already do
+    pub fn check(&self, circuit: &Circuit) -> Vec<Conflict> {
+        claims(circuit)
+            .into_iter()
+            .filter_map(|claim| {
+                let others: Vec<String> = self
+                    .claims
+                    .get(&claim)?
+                    .iter()
+                    .filter(|id| **id != circuit.id)
+                    .cloned()
+                    .collect();
+
+                (!others.is_empty()).then_some(Conflict {
+                    kind: claim.0,
+                    value: claim.1,
+                    circuit_ids: others,
+                    hidden: 0,
+                })
+            })
+            .collect()
+    }
+
+    /// Replaces what the circuit with the same id claimed
+    pub fn insert(&mut self, circuit: &Circuit) {
+        self.remove(&circuit.id);
+
+        if circuit.decommissioned_at.is_some() {
+            return;
+        }
+
+        let claims = claims(circuit);
+        for claim in &claims {
+            self.claims
+                .entry(claim.clone())
+                .or_default()
+                .insert(circuit.id.clone());
+        }
+        self.by_circuit.insert(circuit.id.clone(), claims);
+    }
+
+    pub fn remove(&mut self, circuit_id: &str) {
+        for claim in self.by_circuit.remove(circuit_id).unwrap_or_default() {
+            if let Some(circuit_ids) = self.claims.get_mut(&claim) {
+                circuit_ids.remove(circuit_id);
+                if circuit_ids.is_empty() {
+                    self.claims.remove(&claim);
+                }
+            }
+        }
+    }
+}
+
+/// The values of `kind` claimed by `circuit`, normalized the way they are
+/// compared
+pub fn claimed(circuit: &Circuit, kind: ConflictKind) -> Vec<String> {
+    claims(circuit)
+        .into_iter()
+        .filter(|claim| claim.0 == kind)
+        .map(|claim| claim.1)
+        .collect()
+}
+
+fn claims(circuit: &Circuit) -> Vec<Claim> {
+    let text = |value: &str| Some(value.trim().to_lowercase()).filter(|value| !value.is_empty());
+
+    let mut claims = vec![];
+
+    if let Some(ckt_id) = text(&circuit.ckt_id) {
+        claims.push((ConflictKind::DuplicateCktId, ckt_id));
+    }
+
+    for address in [circuit.interf_ip_z_loc, circuit.interf_ip_a_loc]
+        .into_iter()
+        .flatten()
+    {
+        claims.push((ConflictKind::IpCollision, address.to_string()));
+    }
+
+    for (router, port) in [
+        (&circuit.rtr_name_z_loc, &circuit.rtr_port_z_loc),
+        (&circuit.rtr_name_a_loc, &circuit.rtr_port),
+    ] {
+        if let (Some(router), Some(port)) = (text(router), text(port)) {
+            claims.push((ConflictKind::PortDoubleBooking, format!("{router} {port}")));
+        }
+    }
+
+    // Both ends of a circuit on the same address or port is no conflict
+    claims.sort();
+    claims.dedup();
+    claims
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{CircuitDTO, RawCircuit};
+
+    fn circuit(raw: RawCircuit) -> Circuit {
+        Circuit::from(CircuitDTO::try_from(raw).unwrap())
+    }
+
+    #[test]
+    fn checks_find_what_other_circuits_claim() {
+        let existing = circuit(RawCircuit {
+            ckt_id: Some("CR-01".to_string()),
+            interf_ip_z_loc: Some("10.0.0.1".to_string()),
+            rtr_name_z_loc: Some("edge-1".to_string()),
+            rtr_port_z_loc: Some("ge-0/0/1".to_string()),
+            ..RawCircuit::default()
+        });
+        let index = ConflictIndex::new(std::slice::from_ref(&existing));
+
+        let new = circuit(RawCircuit {
+            ckt_id: Some(" cr-01 ".to_string()),
+            interf_ip_a_loc: Some("10.0.0.1".to_string()),
+            rtr_name_a_loc: Some("EDGE-1".to_string()),
+            rtr_port: Some("GE-0/0/1".to_string()),
+            ..RawCircuit::default()
+        });
+        let conflicts = index.check(&new);
+
+        let kinds: Vec<ConflictKind> = conflicts.iter().map(|conflict| conflict.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                ConflictKind::DuplicateCktId,
+                ConflictKind::IpCollision,
+                ConflictKind::PortDoubleBooking
+            ]
+        );
+        assert!(conflicts
+            .iter()
+            .all(|conflict| conflict.circuit_ids == [existing.id.clone()]));
+
+        // A circuit doesn't conflict with itself
+        assert!(index.check(&existing).is_empty());
+    }
+
+    #[test]
+    fn decommissioned_circuits_claim_nothing() {
+        let mut existing = circuit(RawCircuit {
+            ckt_id: Some("CR-01".to_string()),
+            ..RawCircuit::default()
+        });
+        let new = circuit(RawCircuit {
+            ckt_id: Some("CR-01".to_string()),
+            ..RawCircuit::default()
+        });
+
+        let mut index = ConflictIndex::new(std::slice::from_ref(&existing));
+        assert_eq!(index.check(&new).len(), 1);
+
+        existing.decommissioned_at = Some(chrono::Utc::now());
+        index.insert(&existing);
+        assert!(index.check(&new).is_empty());
+    }
+
+    #[test]
+    fn policies_refuse_only_the_kinds_set_to_error() {
+        let conflict = |kind| Conflict {
+            kind,
+            value: String::new(),
+            circuit_ids: vec![],
+            hidden: 0,
+        };
+        let policy = ConflictPolicy {
+            duplicate_ckt_id: Severity::Error,
+            ip_collision: Severity::Warning,
+            port_double_booking: Severity::Warning,
+        };
+
+        let (errors, warnings) = policy.split(vec![
+            conflict(ConflictKind::DuplicateCktId),
+            conflict(ConflictKind::IpCollision),
+        ]);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, ConflictKind::DuplicateCktId);
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[test]
+    fn scoped_conflicts_count_circuits_out_of_scope_without_naming_them() {
+        let conflict = Conflict {
+            kind: ConflictKind::DuplicateCktId,
+            value: "cr-01".to_string(),
+            circuit_ids: vec!["A".to_string(), "B".to_string(), "C".to_string()],
+            hidden: 0,
+        };
+        let out_of_scope = HashSet::from(["B".to_string(), "C".to_string()]);
+
+        assert_eq!(
+            conflict.scoped(&out_of_scope).to_string(),
+            "The circuit ID `cr-01` is also used by A, 2 circuits out of scope"
+        );
+
+        let report = ConflictReport {
+            duplicate_ckt_ids: vec![Conflict {
+                kind: ConflictKind::DuplicateCktId,
+                value: "cr-02".to_string(),
+                circuit_ids: vec!["B".to_string(), "C".to_string()],
+                hidden: 0,
+            }],
+            ..ConflictReport::default()
+        };
+        assert!(report.scoped(&out_of_scope).duplicate_ckt_ids.is_empty());
+    }
+}
@@ -1,125 +1,1163 @@
-use crate::model::{Circuit, CircuitImportReport, DataSource, NotificationRepository, Reporter};
-use sqlx::{query, query_as, PgPool};
+use std::net::IpAddr;
+
+use crate::model::{
+    normalize_search, AccessScope, AccessScopes, AlreadyExists, ApiKey, ApiKeys, AuditFilter,
+    Change, ChangeAction, ChangeContext, ChangeHistory, ChangedSince, Circuit, CircuitColumn,
+    CircuitImportReport, CircuitMigrationIssue, CircuitQuery, CircuitScope, CircuitState,
+    ColumnMapping, ConflictChecks, DataSource, DuplicateKey, FieldChange, Highlight, ImportJob,
+    ImportJobs, ImportProgress, ImportStatus, LockKind, LoginAudit, LoginFailure, LoginLock,
+    LoginLocks, MappedColumn, MappingProfiles, NaturalKey, NaturalKeys, NotFound,
+    NotificationRepository, Page, Permission, RefreshHashes, Region, Regions, Reporter, Revision,
+    Role, Roles, SearchQuery, SearchResult, Session, Sessions, StagedImport, Staging, StillInUse,
+    UnknownCursor, User, Users,
+};
+use crate::{
+    conflicts::{
+        claimed, out_of_scope, Conflict, ConflictIndex, ConflictKind, ConflictPolicy, Conflicting,
+    },
+    password::StoredPassword,
+};
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
+use sqlx::{
+    encode::IsNull,
+    error::BoxDynError,
+    postgres::{PgArgumentBuffer, PgTypeInfo, PgValueRef},
+    query, query_as, query_scalar,
+    types::Json,
+    Acquire, Decode, Encode, FromRow, PgConnection, PgPool, Postgres, QueryBuilder, Type,
+};
 
 #[derive(Clone)]
 pub struct CircuitDB {
     pub pool: PgPool,
 }
 
+impl CircuitDB {
+    /// Values that the typed circuits migration had to clear because they did not parse
+    pub async fn get_migration_issues(&self) -> Result<Vec<CircuitMigrationIssue>> {
+        let issues = query_as!(
+            CircuitMigrationIssue,
+            "SELECT circuit_id, field, raw_value, message FROM circuit_migration_issues"
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(issues)
+    }
+}
+
 use eyre::Result;
 
+// Circuit states are kept as text so new rollout phases don't need a migration,
+// the circuits_state_check constraint mirrors `CircuitState::from_str`
+impl Type<Postgres> for CircuitState {
+    fn type_info() -> PgTypeInfo {
+        <String as Type<Postgres>>::type_info()
+    }
+
+    fn compatible(ty: &PgTypeInfo) -> bool {
+        <String as Type<Postgres>>::compatible(ty)
+    }
+}
+
+impl Encode<'_, Postgres> for CircuitState {
+    fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> IsNull {
+        <String as Encode<Postgres>>::encode(self.to_string(), buf)
+    }
+}
+
+impl<'r> Decode<'r, Postgres> for CircuitState {
+    fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+        Ok(<&str as Decode<Postgres>>::decode(value)?.parse()?)
+    }
+}
+
 impl DataSource<Circuit> for CircuitDB {
     type Id = ulid::Ulid;
+    type Query = CircuitQuery;
+
+    async fn get_all(&self, query: CircuitQuery) -> eyre::Result<Page<Circuit>> {
+        check_cursor(&self.pool, &query).await?;
+
+        let mut count_builder = QueryBuilder::new("SELECT COUNT(*) FROM ");
+        push_circuit_source(&mut count_builder, query.as_of);
+        count_builder.push(" WHERE TRUE");
+        push_circuit_filters(&mut count_builder, &query);
+
+        let total: i64 = count_builder
+            .build_query_scalar()
+            .fetch_one(&self.pool)
+            .await
+            .map_err(|e| eyre::Report::msg(e.to_string()))?;
 
-    async fn get_all(&self) -> eyre::Result<Vec<Circuit>> {
-        sqlx::query_as("SELECT * FROM circuits")
+        // One extra row tells us whether there is a next page
+        let mut builder = circuit_list_query(&query, query.limit.map(|limit| limit + 1));
+
+        let mut items: Vec<Circuit> = builder
+            .build_query_as()
             .fetch_all(&self.pool)
             .await
-            .map_err(|e| eyre::Report::msg(e.to_string()))
-    }
-
-    async fn update(&self, value: Circuit) -> Result<Circuit> {
-        let result = sqlx::query!(
-            r#"
-            UPDATE circuits SET
-                state = $1,
-                site_name = $2,
-                ckt_id = $3,
-                parent = $4,
-                link_type = $5,
-                provider = $6,
-                z_loc = $7,
-                rtr_name_z_loc = $8,
-                to_description = $9,
-                rtr_port_z_loc = $10,
-                interf_ip_z_loc = $11,
-                a_loc = $12,
-                rtr_name_a_loc = $13,
-                rtr_port = $14,
-                interf_ip_a_loc = $15,
-                bw_mbps = $16,
-                single_isp = $17,
-                ups_closet = $18,
-                router_ip = $19
-            WHERE id = $20
-            "#,
-            value.state,
-            value.site_name,
-            value.ckt_id,
-            value.parent,
-            value.link_type,
-            value.provider,
-            value.z_loc,
-            value.rtr_name_z_loc,
-            value.to_description,
-            value.rtr_port_z_loc,
-            value.interf_ip_z_loc,
-            value.a_loc,
-            value.rtr_name_a_loc,
-            value.rtr_port,
-            value.interf_ip_a_loc,
-            value.bw_mbps,
-            value.single_isp,
-            value.ups_closet,
-            value.router_ip,
-            value.id
-        )
-        .execute(&self.pool)
-        .await
-        .map_err(|e| eyre::Report::msg(e.to_string()))?;
+            .map_err(|e| eyre::Report::msg(e.to_string()))?;
 
-        tracing::debug!("Got result from update query {:?}", result);
+        let next_cursor = match query.limit {
+            Some(limit) if items.len() as i64 > limit => {
+                items.truncate(limit as usize);
+                items.last().map(|circuit| circuit.id.clone())
+            }
+            _ => None,
+        };
 
-        Ok(value)
+        Ok(Page {
+            items,
+            total,
+            next_cursor,
+        })
+    }
+
+    fn stream_all(
+        &self,
+        query: CircuitQuery,
+    ) -> impl futures_util::Stream<Item = Result<Circuit>> + Send + 'static {
+        // Rows read ahead of the consumer, the cursor waits once this many are buffered
+        const STREAM_BUFFER: usize = 256;
+        // Longest the cursor waits for the consumer to make room, a stalled
+        // download would otherwise hold on to its connection for good
+        const SEND_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+        let pool = self.pool.clone();
+        let (sender, receiver) = tokio::sync::mpsc::channel(STREAM_BUFFER);
+
+        tokio::spawn(async move {
+            if let Err(e) = check_cursor(&pool, &query).await {
+                let _ = sender.send(Err(e)).await;
+                return;
+            }
+
+            let mut builder = circuit_list_query(&query, query.limit);
+            let mut rows = builder.build_query_as::<Circuit>().fetch(&pool);
+
+            while let Some(row) = rows.next().await {
+                let row = row.map_err(|e| eyre::Report::msg(e.to_string()));
+                match tokio::time::timeout(SEND_TIMEOUT, sender.send(row)).await {
+                    Ok(Ok(())) => {}
+                    // Nobody is reading anymore, e.g. the download was aborted
+                    Ok(Err(_)) => break,
+                    Err(_) => {
+                        tracing::warn!("Gave up on a circuit stream its consumer stopped reading");
+                        break;
+                    }
+                }
+            }
+        });
+
+        futures_util::stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|row| (row, receiver))
+        })
+    }
+
+    async fn update(&self, value: Circuit, context: ChangeContext) -> Result<Circuit> {
+        let mut tx = self.pool.begin().await?;
+        let updated = update_circuit(&mut tx, value, &context).await?;
+        tx.commit().await?;
+
+        Ok(updated)
     }
 
     async fn get(&self, id: Self::Id) -> Result<Circuit> {
         sqlx::query_as("SELECT * FROM circuits WHERE id = $1")
             .bind(id.to_string())
-            .fetch_one(&self.pool)
+            .fetch_optional(&self.pool)
             .await
-            .map_err(|e| eyre::Report::msg(e.to_string()))
+            .map_err(|e| eyre::Report::msg(e.to_string()))?
+            .ok_or_else(|| eyre::Report::new(NotFound::circuit(id)))
     }
 
-    async fn create(&self, value: Circuit) -> Result<Circuit> {
-        sqlx::query!(
-            r#"
-            INSERT INTO circuits (
-                id, state, site_name, ckt_id, parent, link_type, provider, z_loc, 
-                rtr_name_z_loc, to_description, rtr_port_z_loc, interf_ip_z_loc, 
-                a_loc, rtr_name_a_loc, rtr_port, interf_ip_a_loc, bw_mbps, 
-                single_isp, ups_closet, router_ip
-            ) VALUES (
-                $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, 
-                $13, $14, $15, $16, $17, $18, $19, $20
+    async fn create(&self, value: Circuit, context: ChangeContext) -> Result<Circuit> {
+        let mut tx = self.pool.begin().await?;
+        let created = create_circuit(&mut tx, value, &context).await?;
+        tx.commit().await?;
+
+        Ok(created)
+    }
+
+    async fn decommission(
+        &self,
+        id: Self::Id,
+        reason: String,
+        context: ChangeContext,
+    ) -> Result<Circuit> {
+        let mut tx = self.pool.begin().await?;
+        let decommissioned =
+            decommission_circuit(&mut tx, &id.to_string(), reason, &context).await?;
+        tx.commit().await?;
+
+        Ok(decommissioned)
+    }
+
+    async fn delete(&self, id: Self::Id, context: ChangeContext) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        delete_circuit(&mut tx, &id.to_string(), &context).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn apply_all(
+        &self,
+        changes: Vec<Change<Circuit, Self::Id>>,
+        context: ChangeContext,
+        processed: tokio::sync::watch::Sender<usize>,
+    ) -> Result<Vec<(usize, eyre::Report)>> {
+        let mut tx = self.pool.begin().await?;
+        let mut failures = vec![];
+
+        for (position, change) in changes.into_iter().enumerate() {
+            // A savepoint per change keeps one failure from aborting the rest of
+            // the transaction, so every failing change gets reported
+            let mut savepoint = tx.begin().await?;
+
+            let result = match change {
+                Change::Create(value) => create_circuit(&mut savepoint, value, &context)
+                    .await
+                    .map(|_| ()),
+                Change::Update(value) => update_circuit(&mut savepoint, value, &context)
+                    .await
+                    .map(|_| ()),
+                Change::Decommission { id, reason } => {
+                    decommission_circuit(&mut savepoint, &id.to_string(), reason, &context)
+                        .await
+                        .map(|_| ())
+                }
+                Change::Delete(id) => {
+                    delete_circuit(&mut savepoint, &id.to_string(), &context).await
+                }
+            };
+
+            match result {
+                Ok(()) => savepoint.commit().await?,
+                Err(e) => {
+                    savepoint.rollback().await?;
+                    failures.push((position, e));
+                }
+            }
+
+            processed.send_replace(position + 1);
+        }
+
+        if failures.is_empty() {
+            tx.commit().await?;
+        } else {
+            tx.rollback().await?;
+        }
+
+        Ok(failures)
+    }
+
+    async fn search(&self, query: SearchQuery) -> Result<Vec<SearchResult<Circuit>>> {
+        let term = normalize_search(&query.text);
+
+        if term.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut builder = QueryBuilder::new("SELECT * FROM (SELECT circuits.*, GREATEST(");
+        for (i, (_, expression, weight)) in SEARCH_FIELDS.iter().enumerate() {
+            if i > 0 {
+                builder.push(", ");
+            }
+            builder.push(format!("search_score({expression}, "));
+            builder.push_bind(query.text.clone());
+            builder.push(format!(") * {weight}"));
+        }
+        builder.push(")::real AS rank FROM circuits WHERE (");
+        builder.push_bind(query.include_decommissioned);
+        builder.push(" OR decommissioned_at IS NULL)");
+        if let Some(scope) = &query.scope {
+            push_scope_filter(&mut builder, scope);
+        }
+        builder.push(") AS ranked");
+        builder.push(" WHERE rank > 0 ORDER BY rank DESC, id LIMIT ");
+        builder.push_bind(query.limit);
+
+        let ranked: Vec<RankedCircuit> = builder
+            .build_query_as()
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| eyre::Report::msg(e.to_string()))?;
+
+        Ok(ranked
+            .into_iter()
+            .map(|RankedCircuit { circuit, rank }| {
+                let highlights = SEARCH_FIELDS
+                    .iter()
+                    .filter_map(|(column, _, _)| {
+                        Highlight::find(column.as_str(), &circuit.field(*column), &term)
+                    })
+                    .collect();
+
+                SearchResult {
+                    item: circuit,
+                    rank,
+                    highlights,
+                }
+            })
+            .collect())
+    }
+}
+
+impl ConflictChecks<Circuit> for CircuitDB {
+    async fn create_checked(
+        &self,
+        value: Circuit,
+        context: ChangeContext,
+        policy: ConflictPolicy,
+    ) -> Result<(Circuit, Vec<Conflict>)> {
+        let mut tx = self.pool.begin().await?;
+        let created = create_circuit(&mut tx, value, &context).await?;
+        let warnings = check_conflicts(&mut tx, &created, policy, context.scope.as_ref()).await?;
+        tx.commit().await?;
+
+        Ok((created, warnings))
+    }
+
+    async fn update_checked(
+        &self,
+        value: Circuit,
+        context: ChangeContext,
+        policy: ConflictPolicy,
+    ) -> Result<(Circuit, Vec<Conflict>)> {
+        let mut tx = self.pool.begin().await?;
+        let updated = update_circuit(&mut tx, value, &context).await?;
+        let warnings = check_conflicts(&mut tx, &updated, policy, context.scope.as_ref()).await?;
+        tx.commit().await?;
+
+        Ok((updated, warnings))
+    }
+}
+
+/// The conflicts `circuit`, already written, causes with the circuits that
+/// aren't decommissioned. Only the circuits sharing its circuit ID, an
+/// interface address or a router port are read. Checks are taken one at a time
+/// until the transaction ends so two writes can't both miss each other.
+/// Circuits out of `scope` are counted without naming them.
+async fn check_conflicts(
+    conn: &mut PgConnection,
+    circuit: &Circuit,
+    policy: ConflictPolicy,
+    scope: Option<&CircuitScope>,
+) -> Result<Vec<Conflict>> {
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtext('circuit_conflicts'))")
+        .execute(&mut *conn)
+        .await?;
+
+    let addresses: Vec<IpAddr> = [circuit.interf_ip_z_loc, circuit.interf_ip_a_loc]
+        .into_iter()
+        .flatten()
+        .collect();
+
+    let colliding: Vec<Circuit> = sqlx::query_as(
+        r#"
+        SELECT * FROM circuits
+        WHERE decommissioned_at IS NULL AND id <> $1 AND (
+            lower(trim(ckt_id)) = ANY($2)
+            OR interf_ip_z_loc = ANY($3)
+            OR interf_ip_a_loc = ANY($3)
+            OR lower(trim(rtr_name_z_loc)) || ' ' || lower(trim(rtr_port_z_loc)) = ANY($4)
+            OR lower(trim(rtr_name_a_loc)) || ' ' || lower(trim(rtr_port)) = ANY($4)
+        )
+        "#,
+    )
+    .bind(&circuit.id)
+    .bind(claimed(circuit, ConflictKind::DuplicateCktId))
+    .bind(addresses)
+    .bind(claimed(circuit, ConflictKind::PortDoubleBooking))
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let hidden = out_of_scope(scope, &colliding);
+    let conflicts = ConflictIndex::new(&colliding)
+        .check(circuit)
+        .into_iter()
+        .map(|conflict| conflict.scoped(&hidden))
+        .collect();
+    let (errors, warnings) = policy.split(conflicts);
+
+    if !errors.is_empty() {
+        return Err(Conflicting(errors).into());
+    }
+
+    Ok(warnings)
+}
+
+/// Columns looked at by `search` with the SQL that reads them and how much a
+/// match in that column counts towards the rank
+const SEARCH_FIELDS: [(CircuitColumn, &str, f32); 8] = [
+    (CircuitColumn::CktId, "ckt_id", 1.0),
+    (CircuitColumn::RtrNameZLoc, "rtr_name_z_loc", 0.9),
+    (CircuitColumn::RtrNameALoc, "rtr_name_a_loc", 0.9),
+    (CircuitColumn::InterfIpZLoc, "host(interf_ip_z_loc)", 0.9),
+    (CircuitColumn::InterfIpALoc, "host(interf_ip_a_loc)", 0.9),
+    (CircuitColumn::SiteName, "site_name", 0.8),
+    (CircuitColumn::ZLoc, "z_loc", 0.6),
+    (CircuitColumn::ALoc, "a_loc", 0.6),
+];
+
+/// Writes that break the natural key constraint come out as `DuplicateKey`
+fn circuit_write_error(error: sqlx::Error) -> eyre::Report {
+    let breaks_key = error
+        .as_database_error()
+        .is_some_and(|e| e.is_unique_violation() && e.constraint() == Some(NATURAL_KEY_INDEX));
+
+    if breaks_key {
+        return DuplicateKey(
+            "Another circuit that isn't decommissioned has the same natural key".to_string(),
+        )
+        .into();
+    }
+
+    eyre::Report::msg(error.to_string())
+}
+
+async fn update_circuit(
+    conn: &mut PgConnection,
+    value: Circuit,
+    context: &ChangeContext,
+) -> Result<Circuit> {
+    let old = lock_circuit(&mut *conn, &value.id).await?;
+    // Circuits can't be moved into the scope or out of it either
+    CircuitScope::check(context.scope.as_ref(), &old)?;
+    CircuitScope::check(context.scope.as_ref(), &value)?;
+
+    sqlx::query!(
+        r#"
+        UPDATE circuits SET
+            state = $1,
+            site_name = $2,
+            ckt_id = $3,
+            parent = $4,
+            link_type = $5,
+            provider = $6,
+            z_loc = $7,
+            rtr_name_z_loc = $8,
+            to_description = $9,
+            rtr_port_z_loc = $10,
+            interf_ip_z_loc = $11,
+            a_loc = $12,
+            rtr_name_a_loc = $13,
+            rtr_port = $14,
+            interf_ip_a_loc = $15,
+            bw_mbps = $16,
+            single_isp = $17,
+            ups_closet = $18,
+            router_ip = $19
+        WHERE id = $20
+        "#,
+        value.state as _,
+        value.site_name,
+        value.ckt_id,
+        value.parent,
+        value.link_type,
+        value.provider,
+        value.z_loc,
+        value.rtr_name_z_loc,
+        value.to_description,
+        value.rtr_port_z_loc,
+        value.interf_ip_z_loc as _,
+        value.a_loc,
+        value.rtr_name_a_loc,
+        value.rtr_port,
+        value.interf_ip_a_loc as _,
+        value.bw_mbps as _,
+        value.single_isp,
+        value.ups_closet,
+        value.router_ip as _,
+        value.id
+    )
+    .execute(&mut *conn)
+    .await
+    .map_err(circuit_write_error)?;
+
+    let updated = lock_circuit(&mut *conn, &value.id).await?;
+    record_revision(
+        &mut *conn,
+        ChangeAction::Update,
+        context,
+        Some(&old),
+        Some(&updated),
+    )
+    .await?;
+
+    tracing::debug!("Updated circuit {}", updated.id);
+
+    Ok(updated)
+}
+
+async fn create_circuit(
+    conn: &mut PgConnection,
+    value: Circuit,
+    context: &ChangeContext,
+) -> Result<Circuit> {
+    CircuitScope::check(context.scope.as_ref(), &value)?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO circuits (
+            id, state, site_name, ckt_id, parent, link_type, provider, z_loc, 
+            rtr_name_z_loc, to_description, rtr_port_z_loc, interf_ip_z_loc, 
+            a_loc, rtr_name_a_loc, rtr_port, interf_ip_a_loc, bw_mbps, 
+            single_isp, ups_closet, router_ip
+        ) VALUES (
+            $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, 
+            $13, $14, $15, $16, $17, $18, $19, $20
+        )
+        "#,
+        value.id,
+        value.state as _,
+        value.site_name,
+        value.ckt_id,
+        value.parent,
+        value.link_type,
+        value.provider,
+        value.z_loc,
+        value.rtr_name_z_loc,
+        value.to_description,
+        value.rtr_port_z_loc,
+        value.interf_ip_z_loc as _,
+        value.a_loc,
+        value.rtr_name_a_loc,
+        value.rtr_port,
+        value.interf_ip_a_loc as _,
+        value.bw_mbps as _,
+        value.single_isp,
+        value.ups_closet,
+        value.router_ip as _
+    )
+    .execute(&mut *conn)
+    .await
+    .map_err(circuit_write_error)?;
+
+    record_revision(
+        &mut *conn,
+        ChangeAction::Create,
+        context,
+        None,
+        Some(&value),
+    )
+    .await?;
+
+    Ok(value)
+}
+
+async fn decommission_circuit(
+    conn: &mut PgConnection,
+    id: &str,
+    reason: String,
+    context: &ChangeContext,
+) -> Result<Circuit> {
+    let old = lock_circuit(&mut *conn, id).await?;
+    CircuitScope::check(context.scope.as_ref(), &old)?;
+
+    // Decommissioning twice only updates the reason, the original date is kept
+    let decommissioned: Circuit = sqlx::query_as(
+        r#"
+        UPDATE circuits SET
+            decommissioned_at = COALESCE(decommissioned_at, now()),
+            decommission_reason = $2
+        WHERE id = $1
+        RETURNING *
+        "#,
+    )
+    .bind(id)
+    .bind(reason)
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(|e| eyre::Report::msg(e.to_string()))?;
+
+    record_revision(
+        &mut *conn,
+        ChangeAction::Decommission,
+        context,
+        Some(&old),
+        Some(&decommissioned),
+    )
+    .await?;
+
+    Ok(decommissioned)
+}
+
+async fn delete_circuit(conn: &mut PgConnection, id: &str, context: &ChangeContext) -> Result<()> {
+    let old = lock_circuit(&mut *conn, id).await?;
+    CircuitScope::check(context.scope.as_ref(), &old)?;
+
+    query!("DELETE FROM circuits WHERE id = $1", id)
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| eyre::Report::msg(e.to_string()))?;
+
+    record_revision(&mut *conn, ChangeAction::Delete, context, Some(&old), None).await?;
+
+    tracing::debug!("Deleted circuit {}", id);
+
+    Ok(())
+}
+
+/// Current row of a circuit, locked until the transaction ends so nothing can
+/// change it between reading it and recording the revision
+async fn lock_circuit(conn: &mut PgConnection, id: &str) -> Result<Circuit> {
+    find_circuit_for_update(conn, id)
+        .await?
+        .ok_or_else(|| eyre::Report::new(NotFound::circuit(id)))
+}
+
+async fn find_circuit_for_update(conn: &mut PgConnection, id: &str) -> Result<Option<Circuit>> {
+    sqlx::query_as("SELECT * FROM circuits WHERE id = $1 FOR UPDATE")
+        .bind(id)
+        .fetch_optional(conn)
+        .await
+        .map_err(|e| eyre::Report::msg(e.to_string()))
+}
+
+/// Makes the stored circuit match `target` exactly, decommission details
+/// included, deleting it when `target` is `None` and recreating it if it was deleted
+async fn restore_circuit(
+    conn: &mut PgConnection,
+    id: &str,
+    target: Option<&Circuit>,
+    context: &ChangeContext,
+) -> Result<Option<Circuit>> {
+    let old = find_circuit_for_update(conn, id).await?;
+    for circuit in old.iter().chain(target) {
+        CircuitScope::check(context.scope.as_ref(), circuit)?;
+    }
+
+    match target {
+        Some(value) => {
+            sqlx::query!(
+                r#"
+                INSERT INTO circuits (
+                    id, state, site_name, ckt_id, parent, link_type, provider, z_loc,
+                    rtr_name_z_loc, to_description, rtr_port_z_loc, interf_ip_z_loc,
+                    a_loc, rtr_name_a_loc, rtr_port, interf_ip_a_loc, bw_mbps,
+                    single_isp, ups_closet, router_ip, decommissioned_at, decommission_reason
+                ) VALUES (
+                    $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12,
+                    $13, $14, $15, $16, $17, $18, $19, $20, $21, $22
+                )
+                ON CONFLICT (id) DO UPDATE SET
+                    state = EXCLUDED.state,
+                    site_name = EXCLUDED.site_name,
+                    ckt_id = EXCLUDED.ckt_id,
+                    parent = EXCLUDED.parent,
+                    link_type = EXCLUDED.link_type,
+                    provider = EXCLUDED.provider,
+                    z_loc = EXCLUDED.z_loc,
+                    rtr_name_z_loc = EXCLUDED.rtr_name_z_loc,
+                    to_description = EXCLUDED.to_description,
+                    rtr_port_z_loc = EXCLUDED.rtr_port_z_loc,
+                    interf_ip_z_loc = EXCLUDED.interf_ip_z_loc,
+                    a_loc = EXCLUDED.a_loc,
+                    rtr_name_a_loc = EXCLUDED.rtr_name_a_loc,
+                    rtr_port = EXCLUDED.rtr_port,
+                    interf_ip_a_loc = EXCLUDED.interf_ip_a_loc,
+                    bw_mbps = EXCLUDED.bw_mbps,
+                    single_isp = EXCLUDED.single_isp,
+                    ups_closet = EXCLUDED.ups_closet,
+                    router_ip = EXCLUDED.router_ip,
+                    decommissioned_at = EXCLUDED.decommissioned_at,
+                    decommission_reason = EXCLUDED.decommission_reason
+                "#,
+                id,
+                value.state as _,
+                value.site_name,
+                value.ckt_id,
+                value.parent,
+                value.link_type,
+                value.provider,
+                value.z_loc,
+                value.rtr_name_z_loc,
+                value.to_description,
+                value.rtr_port_z_loc,
+                value.interf_ip_z_loc as _,
+                value.a_loc,
+                value.rtr_name_a_loc,
+                value.rtr_port,
+                value.interf_ip_a_loc as _,
+                value.bw_mbps as _,
+                value.single_isp,
+                value.ups_closet,
+                value.router_ip as _,
+                value.decommissioned_at,
+                value.decommission_reason
             )
+            .execute(&mut *conn)
+            .await
+            .map_err(|e| eyre::Report::msg(e.to_string()))?;
+        }
+        None => {
+            query!("DELETE FROM circuits WHERE id = $1", id)
+                .execute(&mut *conn)
+                .await
+                .map_err(|e| eyre::Report::msg(e.to_string()))?;
+        }
+    }
+
+    record_revision(conn, ChangeAction::Revert, context, old.as_ref(), target).await?;
+
+    Ok(target.cloned())
+}
+
+/// Appends to circuit_history, meant to run in the same transaction as the change
+async fn record_revision(
+    conn: &mut PgConnection,
+    action: ChangeAction,
+    context: &ChangeContext,
+    old: Option<&Circuit>,
+    new: Option<&Circuit>,
+) -> Result<()> {
+    let changes = FieldChange::diff(old, new);
+
+    // Saving a circuit without touching anything isn't worth a revision
+    if changes.is_empty() {
+        return Ok(());
+    }
+
+    let circuit_id = new.or(old).map(|circuit| circuit.id.clone());
+
+    query!(
+        r#"
+        INSERT INTO circuit_history (
+            circuit_id, action, actor, source, file_name, import_id, changes, snapshot
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        "#,
+        circuit_id,
+        action.as_str(),
+        context.actor,
+        context.source.as_str(),
+        context.source.file_name(),
+        context.source.import_id(),
+        Json(changes) as _,
+        new.map(Json) as _
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+struct RevisionRow {
+    revision: i64,
+    circuit_id: String,
+    action: String,
+    actor: String,
+    source: String,
+    file_name: Option<String>,
+    import_id: Option<String>,
+    changed_at: DateTime<Utc>,
+    changes: Json<Vec<FieldChange>>,
+    snapshot: Option<Json<Circuit>>,
+}
+
+impl TryFrom<RevisionRow> for Revision<Circuit> {
+    type Error = eyre::Report;
+
+    fn try_from(row: RevisionRow) -> Result<Self> {
+        Ok(Revision {
+            revision: row.revision,
+            item_id: row.circuit_id,
+            action: row.action.parse()?,
+            actor: row.actor,
+            source: row.source,
+            file_name: row.file_name,
+            import_id: row.import_id,
+            changed_at: row.changed_at,
+            changes: row.changes.0,
+            snapshot: row.snapshot.map(|snapshot| snapshot.0),
+        })
+    }
+}
+
+impl ChangeHistory<Circuit> for CircuitDB {
+    type Id = ulid::Ulid;
+
+    async fn get_history(&self, id: Self::Id) -> Result<Vec<Revision<Circuit>>> {
+        query_as!(
+            RevisionRow,
+            r#"
+            SELECT
+                revision, circuit_id, action, actor, source, file_name, import_id, changed_at,
+                changes AS "changes: Json<Vec<FieldChange>>",
+                snapshot AS "snapshot: Json<Circuit>"
+            FROM circuit_history
+            WHERE circuit_id = $1
+            ORDER BY revision
             "#,
-            value.id,
-            value.state,
-            value.site_name,
-            value.ckt_id,
-            value.parent,
-            value.link_type,
-            value.provider,
-            value.z_loc,
-            value.rtr_name_z_loc,
-            value.to_description,
-            value.rtr_port_z_loc,
-            value.interf_ip_z_loc,
-            value.a_loc,
-            value.rtr_name_a_loc,
-            value.rtr_port,
-            value.interf_ip_a_loc,
-            value.bw_mbps,
-            value.single_isp,
-            value.ups_closet,
-            value.router_ip
+            id.to_string()
         )
-        .execute(&self.pool)
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(Revision::try_from)
+        .collect::<Result<Vec<_>>>()
+        .and_then(|revisions| {
+            // Every circuit has at least the revision that created it
+            if revisions.is_empty() {
+                Err(NotFound::circuit(id).into())
+            } else {
+                Ok(revisions)
+            }
+        })
+    }
+
+    async fn get_audit_log(&self, filter: AuditFilter) -> Result<Vec<Revision<Circuit>>> {
+        query_as!(
+            RevisionRow,
+            r#"
+            SELECT
+                revision, circuit_id, action, actor, source, file_name, import_id, changed_at,
+                changes AS "changes: Json<Vec<FieldChange>>",
+                snapshot AS "snapshot: Json<Circuit>"
+            FROM circuit_history
+            WHERE ($1::text IS NULL OR actor = $1)
+                AND ($2::timestamptz IS NULL OR changed_at >= $2)
+                AND ($3::timestamptz IS NULL OR changed_at < $3)
+                AND ($4::bigint IS NULL OR revision < $4)
+            ORDER BY revision DESC
+            LIMIT $5
+            "#,
+            filter.actor,
+            filter.from,
+            filter.to,
+            filter.before,
+            filter.limit
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(Revision::try_from)
+        .collect()
+    }
+
+    async fn get_as_of(&self, id: Self::Id, as_of: DateTime<Utc>) -> Result<Circuit> {
+        let snapshot = query_scalar!(
+            r#"
+            SELECT snapshot AS "snapshot: Json<Circuit>"
+            FROM circuit_history
+            WHERE circuit_id = $1 AND changed_at <= $2
+            ORDER BY revision DESC
+            LIMIT 1
+            "#,
+            id.to_string(),
+            as_of
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .flatten();
+
+        snapshot
+            .map(|snapshot| snapshot.0)
+            .ok_or_else(|| eyre::Report::new(NotFound::circuit(format!("{id} at {as_of}"))))
+    }
+
+    async fn revert(&self, id: Self::Id, revision: i64, context: ChangeContext) -> Result<Circuit> {
+        let mut tx = self.pool.begin().await?;
+
+        let snapshot = query_scalar!(
+            r#"
+            SELECT snapshot AS "snapshot: Json<Circuit>"
+            FROM circuit_history
+            WHERE circuit_id = $1 AND revision = $2
+            "#,
+            id.to_string(),
+            revision
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or_else(|| {
+            eyre::Report::new(NotFound::new(
+                "revision",
+                format!("{revision} of circuit {id}"),
+            ))
+        })?
+        .ok_or_else(|| {
+            eyre::Report::msg(format!(
+                "Revision {revision} deleted circuit {id}, there is nothing to revert to"
+            ))
+        })?;
+
+        restore_circuit(&mut tx, &id.to_string(), Some(&snapshot.0), &context).await?;
+        tx.commit().await?;
+
+        Ok(snapshot.0)
+    }
+
+    async fn undo_import(
+        &self,
+        import_id: String,
+        force: bool,
+        context: ChangeContext,
+    ) -> Result<Vec<String>> {
+        let mut tx = self.pool.begin().await?;
+
+        // For every circuit the import touched, the revision right before the
+        // import first changed it. Circuits the import created have none.
+        let targets = query!(
+            r#"
+            SELECT
+                touched.circuit_id AS "circuit_id!",
+                (
+                    SELECT snapshot FROM circuit_history AS previous
+                    WHERE previous.circuit_id = touched.circuit_id
+                        AND previous.revision < touched.first_revision
+                    ORDER BY previous.revision DESC
+                    LIMIT 1
+                ) AS "snapshot: Json<Circuit>",
+                EXISTS (
+                    SELECT 1 FROM circuit_history AS later
+                    WHERE later.circuit_id = touched.circuit_id
+                        AND later.revision > touched.last_revision
+                ) AS "changed_since!"
+            FROM (
+                SELECT circuit_id, min(revision) AS first_revision, max(revision) AS last_revision
+                FROM circuit_history
+                WHERE import_id = $1
+                GROUP BY circuit_id
+            ) AS touched
+            ORDER BY touched.circuit_id
+            "#,
+            import_id
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        if targets.is_empty() {
+            return Err(eyre::Report::msg(format!(
+                "Import {import_id} did not change any circuits"
+            )));
+        }
+
+        let changed_since: Vec<String> = targets
+            .iter()
+            .filter(|target| target.changed_since)
+            .map(|target| target.circuit_id.clone())
+            .collect();
+        if !force && !changed_since.is_empty() {
+            return Err(eyre::Report::new(ChangedSince(changed_since)));
+        }
+
+        let mut reverted = Vec::with_capacity(targets.len());
+        for target in targets {
+            let snapshot = target.snapshot.map(|snapshot| snapshot.0);
+            restore_circuit(&mut tx, &target.circuit_id, snapshot.as_ref(), &context).await?;
+            reverted.push(target.circuit_id);
+        }
+
+        tx.commit().await?;
+
+        Ok(reverted)
+    }
+}
+
+/// The circuits matching `query` in its sort order, from its cursor on and at
+/// most `limit` of them
+fn circuit_list_query(query: &CircuitQuery, limit: Option<i64>) -> QueryBuilder<'static, Postgres> {
+    let sort_expressions: Vec<(&str, bool)> = query
+        .sort
+        .iter()
+        .map(|key| (sort_expression(key.column), key.descending))
+        .chain(std::iter::once(("id", false)))
+        .collect();
+
+    let mut builder = QueryBuilder::new("SELECT circuits.* FROM ");
+    push_circuit_source(&mut builder, query.as_of);
+
+    // Keyset pagination, the cursor row is looked up again so that its sort
+    // values don't have to be round tripped through the client
+    if let Some(cursor) = query.cursor {
+        builder.push(" CROSS JOIN (SELECT ");
+        for (i, (expression, _)) in sort_expressions.iter().enumerate() {
+            if i > 0 {
+                builder.push(", ");
+            }
+            builder.push(format!("{expression} AS cursor_{i}"));
+        }
+        builder.push(" FROM ");
+        push_circuit_source(&mut builder, query.as_of);
+        builder.push(" WHERE id = ");
+        builder.push_bind(cursor.to_string());
+        builder.push(") AS cursor_row");
+    }
+
+    builder.push(" WHERE TRUE");
+    push_circuit_filters(&mut builder, query);
+
+    if query.cursor.is_some() {
+        builder.push(" AND (");
+        for (i, (expression, descending)) in sort_expressions.iter().enumerate() {
+            if i > 0 {
+                builder.push(" OR ");
+            }
+            builder.push("(");
+            for (j, (previous, _)) in sort_expressions[..i].iter().enumerate() {
+                builder.push(format!("{previous} = cursor_{j} AND "));
+            }
+            let comparison = if *descending { "<" } else { ">" };
+            builder.push(format!("{expression} {comparison} cursor_{i})"));
+        }
+        builder.push(")");
+    }
+
+    builder.push(" ORDER BY ");
+    for (i, (expression, descending)) in sort_expressions.iter().enumerate() {
+        if i > 0 {
+            builder.push(", ");
+        }
+        builder.push(format!(
+            "{expression} {}",
+            if *descending { "DESC" } else { "ASC" }
+        ));
+    }
+
+    if let Some(limit) = limit {
+        builder.push(" LIMIT ");
+        builder.push_bind(limit);
+    }
+
+    builder
+}
+
+/// Pushes what `get_all` reads circuits from, either the table itself or the
+/// latest snapshot of every circuit at `as_of` shaped like the table
+fn push_circuit_source(builder: &mut QueryBuilder<'_, Postgres>, as_of: Option<DateTime<Utc>>) {
+    match as_of {
+        None => {
+            builder.push("circuits");
+        }
+        Some(as_of) => {
+            builder.push(
+                r#"(
+                SELECT (jsonb_populate_record(NULL::circuits, snapshot)).*
+                FROM (
+                    SELECT DISTINCT ON (circuit_id) snapshot
+                    FROM circuit_history
+                    WHERE changed_at <= "#,
+            );
+            builder.push_bind(as_of);
+            builder.push(
+                r#"
+                    ORDER BY circuit_id, revision DESC
+                ) AS latest
+                WHERE snapshot IS NOT NULL
+            ) AS circuits"#,
+            );
+        }
+    }
+}
+
+#[derive(FromRow)]
+struct RankedCircuit {
+    #[sqlx(flatten)]
+    circuit: Circuit,
+    rank: f32,
+}
+
+/// Fails with `UnknownCursor` when the cursor of `query` isn't a circuit, the
+/// page after it would otherwise just come back empty
+async fn check_cursor(pool: &PgPool, query: &CircuitQuery) -> Result<()> {
+    let Some(cursor) = query.cursor else {
+        return Ok(());
+    };
+
+    let mut builder = QueryBuilder::new("SELECT EXISTS (SELECT 1 FROM ");
+    push_circuit_source(&mut builder, query.as_of);
+    builder.push(" WHERE id = ");
+    builder.push_bind(cursor.to_string());
+    builder.push(")");
+
+    let exists: bool = builder
+        .build_query_scalar()
+        .fetch_one(pool)
         .await
         .map_err(|e| eyre::Report::msg(e.to_string()))?;
 
-        Ok(value)
+    if exists {
+        Ok(())
+    } else {
+        Err(eyre::Report::new(UnknownCursor(cursor.to_string())))
+    }
+}
+
+/// Appends the `AND ...` conditions of `query` that narrow down which circuits match
+fn push_circuit_filters(builder: &mut QueryBuilder<'_, Postgres>, query: &CircuitQuery) {
+    if !query.include_decommissioned {
+        builder.push(" AND circuits.decommissioned_at IS NULL");
+    }
+
+    let exact_filters = [
+        ("state", &query.state),
+        ("provider", &query.provider),
+        ("link_type", &query.link_type),
+        ("site_name", &query.site_name),
+    ];
+
+    for (column, value) in exact_filters {
+        if let Some(value) = value {
+            builder.push(format!(" AND lower(circuits.{column}) = lower("));
+            builder.push_bind(value.clone());
+            builder.push(")");
+        }
+    }
+
+    if let Some(search) = &query.search {
+        let pattern = format!(
+            "%{}%",
+            search
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_")
+        );
+
+        builder.push(
+            r#" AND concat_ws(' ',
+                circuits.state, circuits.site_name, circuits.ckt_id, circuits.parent,
+                circuits.link_type, circuits.provider, circuits.z_loc, circuits.rtr_name_z_loc,
+                circuits.to_description, circuits.rtr_port_z_loc, host(circuits.interf_ip_z_loc),
+                circuits.a_loc, circuits.rtr_name_a_loc, circuits.rtr_port,
+                host(circuits.interf_ip_a_loc), circuits.ups_closet, host(circuits.router_ip)
+            ) ILIKE "#,
+        );
+        builder.push_bind(pattern);
+    }
+
+    if let Some(scope) = &query.scope {
+        push_scope_filter(builder, scope);
+    }
+}
+
+/// Appends the `AND ...` condition that keeps only the circuits in `scope`
+fn push_scope_filter(builder: &mut QueryBuilder<'_, Postgres>, scope: &CircuitScope) {
+    builder.push(" AND (lower(circuits.site_name) = ANY(");
+    builder.push_bind(scope.sites.clone());
+    builder.push(") OR lower(circuits.provider) = ANY(");
+    builder.push_bind(scope.providers.clone());
+    builder.push(") OR lower(circuits.state) = ANY(");
+    builder.push_bind(scope.states.clone());
+    builder.push("))");
+}
+
+/// Sort keys can't be NULL or keyset pagination would skip rows, so nullable
+/// columns sort as if empty values came first
+fn sort_expression(column: CircuitColumn) -> &'static str {
+    match column {
+        CircuitColumn::State => "coalesce(state, '')",
+        CircuitColumn::InterfIpZLoc => "coalesce(interf_ip_z_loc, '0.0.0.0'::inet)",
+        CircuitColumn::InterfIpALoc => "coalesce(interf_ip_a_loc, '0.0.0.0'::inet)",
+        CircuitColumn::RouterIp => "coalesce(router_ip, '0.0.0.0'::inet)",
+        CircuitColumn::BwMbps => "coalesce(bw_mbps, -1)",
+        CircuitColumn::DecommissionedAt => "coalesce(decommissioned_at, '-infinity')",
+        CircuitColumn::DecommissionReason => "coalesce(decommission_reason, '')",
+        column => column.as_str(),
     }
 }
 
@@ -129,13 +1167,23 @@ impl Reporter<CircuitImportReport> for CircuitDB {
     async fn report(&self, value: CircuitImportReport) -> Result<CircuitImportReport> {
         query!(
             r#"
-            INSERT INTO import_report(type, id, message,file_name)
-            VALUES ($1,$2,$3, $4)
+            INSERT INTO import_report(
+                type, id, message, file_name, import_id, line, column_name, raw_value,
+                category, headers, record
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
             "#,
             value.r#type,
             value.id,
             value.message,
-            value.file_name
+            value.file_name,
+            value.import_id,
+            value.line,
+            value.column,
+            value.raw_value,
+            value.category,
+            value.headers.as_deref(),
+            value.record.as_deref()
         )
         .execute(&self.pool)
         .await?;
@@ -179,7 +1227,12 @@ impl NotificationRepository<CircuitImportReport> for CircuitDB {
     async fn get_all(&self) -> Result<Vec<CircuitImportReport>> {
         let all_notifications = query_as!(
             CircuitImportReport,
-            "SELECT type, id, message, file_name FROM import_report"
+            r#"
+            SELECT
+                type, id, message, file_name, import_id, line, column_name AS "column",
+                raw_value, category, headers, record
+            FROM import_report
+            "#
         )
         .fetch_all(&self.pool)
         .await?;
@@ -190,11 +1243,1180 @@ impl NotificationRepository<CircuitImportReport> for CircuitDB {
     async fn get_new(&self) -> Result<Vec<CircuitImportReport>> {
         let new_notifications = query_as!(
             CircuitImportReport,
-            "SELECT type, id, message, file_name FROM import_report WHERE type = 'finish' AND seen = FALSE"
+            r#"
+            SELECT
+                type, id, message, file_name, import_id, line, column_name AS "column",
+                raw_value, category, headers, record
+            FROM import_report
+            WHERE type = 'finished' AND seen = FALSE
+            "#
         )
         .fetch_all(&self.pool)
         .await?;
 
         Ok(new_notifications)
     }
+
+    async fn get_for_import(&self, import_id: String) -> Result<Vec<CircuitImportReport>> {
+        let notifications = query_as!(
+            CircuitImportReport,
+            r#"
+            SELECT
+                type, id, message, file_name, import_id, line, column_name AS "column",
+                raw_value, category, headers, record
+            FROM import_report
+            WHERE import_id = $1
+            ORDER BY line NULLS LAST, id
+            "#,
+            import_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(notifications)
+    }
+}
+
+impl Staging<StagedImport> for CircuitDB {
+    type Id = String;
+
+    async fn stage(&self, value: StagedImport) -> Result<()> {
+        // Previews nobody committed within a day aren't coming back, `take`
+        // ignores them until they're cleaned up here
+        query!("DELETE FROM staged_imports WHERE staged_at < now() - interval '1 day'")
+            .execute(&self.pool)
+            .await?;
+
+        query!(
+            r#"
+            INSERT INTO staged_imports (id, file_name, data, staged_by, mapping_id)
+            VALUES ($1, $2, $3, $4, $5)
+            "#,
+            value.id,
+            value.file_name,
+            value.data,
+            value.staged_by,
+            value.mapping_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn take(&self, id: Self::Id, staged_by: String) -> Result<StagedImport> {
+        query_as!(
+            StagedImport,
+            r#"
+            DELETE FROM staged_imports
+            WHERE id = $1 AND staged_by = $2 AND staged_at > now() - interval '1 day'
+            RETURNING id, file_name, data, staged_by, mapping_id
+            "#,
+            id,
+            staged_by
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or_else(|| eyre::Report::new(NotFound::new("staged import", id)))
+    }
+}
+
+struct ImportJobRow {
+    id: String,
+    file_name: Option<String>,
+    mode: String,
+    status: String,
+    started_by: String,
+    message: Option<String>,
+    total_rows: Option<i64>,
+    processed: i64,
+    created: i64,
+    updated: i64,
+    decommissioned: i64,
+    deleted: i64,
+    failed: i64,
+    queued_at: DateTime<Utc>,
+    started_at: Option<DateTime<Utc>>,
+    finished_at: Option<DateTime<Utc>>,
+    mapping_id: Option<String>,
+}
+
+impl TryFrom<ImportJobRow> for ImportJob {
+    type Error = eyre::Report;
+
+    fn try_from(row: ImportJobRow) -> Result<Self> {
+        Ok(ImportJob {
+            id: row.id,
+            file_name: row.file_name,
+            mode: row.mode.parse()?,
+            status: row.status.parse()?,
+            started_by: row.started_by,
+            message: row.message,
+            total_rows: row.total_rows.map(|total| total as u64),
+            progress: ImportProgress {
+                processed: row.processed as u64,
+                created: row.created as u64,
+                updated: row.updated as u64,
+                decommissioned: row.decommissioned as u64,
+                deleted: row.deleted as u64,
+                failed: row.failed as u64,
+            },
+            queued_at: row.queued_at,
+            started_at: row.started_at,
+            finished_at: row.finished_at,
+            mapping_id: row.mapping_id,
+        })
+    }
+}
+
+impl ImportJobs<ImportJob> for CircuitDB {
+    type Id = String;
+
+    async fn queue_job(&self, value: ImportJob) -> Result<ImportJob> {
+        query_as!(
+            ImportJobRow,
+            r#"
+            INSERT INTO import_jobs (id, file_name, mode, status, started_by, mapping_id)
+            VALUES ($1, $2, $3, 'queued', $4, $5)
+            RETURNING *
+            "#,
+            value.id,
+            value.file_name,
+            value.mode.as_str(),
+            value.started_by,
+            value.mapping_id
+        )
+        .fetch_one(&self.pool)
+        .await?
+        .try_into()
+    }
+
+    async fn get_job(&self, id: Self::Id) -> Result<ImportJob> {
+        query_as!(ImportJobRow, "SELECT * FROM import_jobs WHERE id = $1", id)
+            .fetch_optional(&self.pool)
+            .await?
+            .ok_or_else(|| eyre::Report::new(NotFound::new("import", id)))?
+            .try_into()
+    }
+
+    async fn start_job(&self, id: Self::Id, total_rows: u64) -> Result<()> {
+        query!(
+            r#"
+            UPDATE import_jobs
+            SET status = 'running', started_at = now(), total_rows = $2
+            WHERE id = $1 AND status = 'queued'
+            "#,
+            id,
+            total_rows as i64
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn record_progress(&self, id: Self::Id, progress: ImportProgress) -> Result<()> {
+        query!(
+            r#"
+            UPDATE import_jobs
+            SET processed = $2, created = $3, updated = $4, decommissioned = $5,
+                deleted = $6, failed = $7
+            WHERE id = $1 AND status = 'running'
+            "#,
+            id,
+            progress.processed as i64,
+            progress.created as i64,
+            progress.updated as i64,
+            progress.decommissioned as i64,
+            progress.deleted as i64,
+            progress.failed as i64
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn finish_job(
+        &self,
+        id: Self::Id,
+        status: ImportStatus,
+        progress: ImportProgress,
+        message: String,
+    ) -> Result<()> {
+        query!(
+            r#"
+            UPDATE import_jobs
+            SET status = $2, message = $3, finished_at = now(),
+                processed = $4, created = $5, updated = $6, decommissioned = $7,
+                deleted = $8, failed = $9
+            WHERE id = $1 AND status IN ('queued', 'running')
+            "#,
+            id,
+            status.as_str(),
+            message,
+            progress.processed as i64,
+            progress.created as i64,
+            progress.updated as i64,
+            progress.decommissioned as i64,
+            progress.deleted as i64,
+            progress.failed as i64
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn fail_interrupted_jobs(&self) -> Result<u64> {
+        // The import's report still says it is in progress
+        let interrupted = query_scalar!(
+            r#"
+            WITH interrupted AS (
+                UPDATE import_jobs
+                SET status = 'failed', finished_at = now(),
+                    message = 'Interrupted by a server restart'
+                WHERE status IN ('queued', 'running')
+                RETURNING id, message
+            ), reports AS (
+                UPDATE import_report
+                SET message = interrupted.message
+                FROM interrupted
+                WHERE import_report.id = interrupted.id
+            )
+            SELECT count(*) AS "count!" FROM interrupted
+            "#
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(interrupted as u64)
+    }
+}
+
+impl MappingProfiles<ColumnMapping> for CircuitDB {
+    type Id = String;
+
+    async fn get_profiles(&self) -> Result<Vec<ColumnMapping>> {
+        let profiles = query!(
+            r#"
+            SELECT id, name, columns AS "columns: Json<Vec<MappedColumn>>"
+            FROM column_mappings
+            ORDER BY name
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|row| ColumnMapping {
+            id: row.id,
+            name: row.name,
+            columns: row.columns.0,
+        })
+        .collect();
+
+        Ok(profiles)
+    }
+
+    async fn get_profile(&self, id: Self::Id) -> Result<ColumnMapping> {
+        query!(
+            r#"
+            SELECT id, name, columns AS "columns: Json<Vec<MappedColumn>>"
+            FROM column_mappings
+            WHERE id = $1
+            "#,
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|row| ColumnMapping {
+            id: row.id,
+            name: row.name,
+            columns: row.columns.0,
+        })
+        .ok_or_else(|| eyre::Report::new(NotFound::new("column mapping", id)))
+    }
+
+    async fn save_profile(&self, value: ColumnMapping) -> Result<ColumnMapping> {
+        query!(
+            r#"
+            INSERT INTO column_mappings (id, name, columns)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (id) DO UPDATE
+            SET name = EXCLUDED.name, columns = EXCLUDED.columns, updated_at = now()
+            "#,
+            value.id,
+            value.name,
+            Json(&value.columns) as _
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(value)
+    }
+
+    async fn delete_profile(&self, id: Self::Id) -> Result<()> {
+        let result = query!("DELETE FROM column_mappings WHERE id = $1", id)
+            .execute(&self.pool)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(eyre::Report::new(NotFound::new("column mapping", id)));
+        }
+
+        Ok(())
+    }
+}
+
+/// Unique index on the natural key, see `NaturalKeys::set_natural_key`
+const NATURAL_KEY_INDEX: &str = "circuits_natural_key";
+
+/// SQL for the value of a natural key column as `NaturalKey` compares them,
+/// with the condition for the column to hold one
+fn natural_key_expression(column: CircuitColumn) -> (String, String) {
+    let name = column.as_str();
+
+    if NaturalKey::is_address_column(column) {
+        (name.to_string(), format!("{name} IS NOT NULL"))
+    } else {
+        (
+            format!(r"lower(btrim({name}, E' \t\r\n'))"),
+            format!(r"btrim({name}, E' \t\r\n') <> ''"),
+        )
+    }
+}
+
+impl NaturalKeys<NaturalKey> for CircuitDB {
+    async fn get_natural_key(&self) -> Result<Option<NaturalKey>> {
+        let columns = query_scalar!("SELECT columns FROM circuit_natural_key")
+            .fetch_optional(&self.pool)
+            .await?;
+
+        columns
+            .map(|columns| {
+                Ok(NaturalKey {
+                    columns: columns
+                        .iter()
+                        .map(|column| column.parse())
+                        .collect::<Result<_>>()?,
+                })
+            })
+            .transpose()
+    }
+
+    async fn set_natural_key(&self, key: Option<NaturalKey>) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query(&format!("DROP INDEX IF EXISTS {NATURAL_KEY_INDEX}"))
+            .execute(&mut *tx)
+            .await?;
+        query!("DELETE FROM circuit_natural_key")
+            .execute(&mut *tx)
+            .await?;
+
+        if let Some(key) = key {
+            let (expressions, conditions): (Vec<_>, Vec<_>) = key
+                .columns
+                .iter()
+                .map(|column| natural_key_expression(*column))
+                .unzip();
+
+            // Decommissioned circuits and ones missing part of the key are left out
+            sqlx::query(&format!(
+                "CREATE UNIQUE INDEX {NATURAL_KEY_INDEX} ON circuits ({}) \
+                 WHERE decommissioned_at IS NULL AND {}",
+                expressions.join(", "),
+                conditions.join(" AND ")
+            ))
+            .execute(&mut *tx)
+            .await
+            .map_err(circuit_write_error)?;
+
+            let columns: Vec<String> = key
+                .columns
+                .iter()
+                .map(|column| column.as_str().to_string())
+                .collect();
+            query!(
+                "INSERT INTO circuit_natural_key (columns) VALUES ($1)",
+                &columns
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
+
+impl Users<User> for CircuitDB {
+    type Id = String;
+
+    async fn get_users(&self) -> Result<Vec<User>> {
+        let users = query_as!(
+            User,
+            "SELECT username, roles, disabled, created_at FROM users ORDER BY username"
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(users)
+    }
+
+    async fn get_user(&self, id: Self::Id) -> Result<User> {
+        query_as!(
+            User,
+            "SELECT username, roles, disabled, created_at FROM users WHERE username = $1",
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or_else(|| eyre::Report::new(NotFound::new("user", id)))
+    }
+
+    async fn create_user(&self, value: User, password_hash: String) -> Result<User> {
+        let user = query_as!(
+            User,
+            r#"
+            INSERT INTO users (username, password_hash, roles, disabled)
+            VALUES ($1, $2, $3, $4)
+            RETURNING username, roles, disabled, created_at
+            "#,
+            value.username,
+            password_hash,
+            &value.roles,
+            value.disabled
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| match e.as_database_error() {
+            Some(db_error) if db_error.is_unique_violation() => eyre::Report::new(AlreadyExists(
+                format!("There already is a user named {}", value.username),
+            )),
+            _ => e.into(),
+        })?;
+
+        Ok(user)
+    }
+
+    async fn update_user(&self, value: User) -> Result<User> {
+        query_as!(
+            User,
+            r#"
+            UPDATE users SET roles = $1, disabled = $2
+            WHERE username = $3
+            RETURNING username, roles, disabled, created_at
+            "#,
+            &value.roles,
+            value.disabled,
+            value.username
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or_else(|| eyre::Report::new(NotFound::new("user", value.username)))
+    }
+
+    async fn delete_user(&self, id: Self::Id) -> Result<()> {
+        let result = query!("DELETE FROM users WHERE username = $1", id)
+            .execute(&self.pool)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(NotFound::new("user", id).into());
+        }
+
+        Ok(())
+    }
+
+    async fn get_password(&self, id: Self::Id) -> Result<StoredPassword> {
+        query_as!(
+            StoredPassword,
+            "SELECT password AS plaintext, password_hash AS hash FROM users WHERE username = $1",
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or_else(|| eyre::Report::new(NotFound::new("user", id)))
+    }
+
+    async fn set_password(&self, id: Self::Id, password_hash: String) -> Result<()> {
+        let result = query!(
+            "UPDATE users SET password_hash = $1, password = NULL WHERE username = $2",
+            password_hash,
+            id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(NotFound::new("user", id).into());
+        }
+
+        Ok(())
+    }
+}
+
+impl Sessions<Session> for CircuitDB {
+    type Id = String;
+
+    async fn get_session(&self, id: Self::Id) -> Result<Session> {
+        query_as!(
+            Session,
+            r#"
+            SELECT id, username, reduced_privileges, created_at, expires_at, revoked_at
+            FROM sessions WHERE id = $1
+            "#,
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or_else(|| eyre::Report::msg(format!("No session with id {id}")))
+    }
+
+    async fn get_active_sessions(&self, username: String) -> Result<Vec<Session>> {
+        let sessions = query_as!(
+            Session,
+            r#"
+            SELECT id, username, reduced_privileges, created_at, expires_at, revoked_at
+            FROM sessions
+            WHERE username = $1 AND revoked_at IS NULL AND expires_at > now()
+            ORDER BY created_at DESC
+            "#,
+            username
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(sessions)
+    }
+
+    async fn create_session(&self, value: Session, refresh_hash: String) -> Result<Session> {
+        let session = query_as!(
+            Session,
+            r#"
+            INSERT INTO sessions (id, username, reduced_privileges, refresh_hash, expires_at)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING id, username, reduced_privileges, created_at, expires_at, revoked_at
+            "#,
+            value.id,
+            value.username,
+            value.reduced_privileges,
+            refresh_hash,
+            value.expires_at
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(session)
+    }
+
+    async fn get_refresh_hashes(&self, id: Self::Id) -> Result<RefreshHashes> {
+        query_as!(
+            RefreshHashes,
+            r#"
+            SELECT refresh_hash AS current, previous_refresh_hash AS previous
+            FROM sessions WHERE id = $1
+            "#,
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or_else(|| eyre::Report::msg(format!("No session with id {id}")))
+    }
+
+    async fn rotate_session(
+        &self,
+        id: Self::Id,
+        current_hash: String,
+        refresh_hash: String,
+        expires_at: DateTime<Utc>,
+    ) -> Result<bool> {
+        let result = query!(
+            r#"
+            UPDATE sessions SET
+                previous_refresh_hash = refresh_hash,
+                refresh_hash = $1,
+                expires_at = $2
+            WHERE id = $3 AND refresh_hash = $4 AND revoked_at IS NULL
+            "#,
+            refresh_hash,
+            expires_at,
+            id,
+            current_hash
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn revoke_session(&self, id: Self::Id) -> Result<()> {
+        let result = query!(
+            "UPDATE sessions SET revoked_at = COALESCE(revoked_at, now()) WHERE id = $1",
+            id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(eyre::Report::msg(format!("No session with id {id}")));
+        }
+
+        Ok(())
+    }
+
+    async fn revoke_user_sessions(&self, username: String) -> Result<u64> {
+        let result = query!(
+            r#"
+            UPDATE sessions SET revoked_at = now()
+            WHERE username = $1 AND revoked_at IS NULL AND expires_at > now()
+            "#,
+            username
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn revoke_other_sessions(&self, username: String, keep: Self::Id) -> Result<u64> {
+        let result = query!(
+            r#"
+            UPDATE sessions SET revoked_at = now()
+            WHERE username = $1 AND id <> $2 AND revoked_at IS NULL AND expires_at > now()
+            "#,
+            username,
+            keep
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+}
+
+impl ApiKeys<ApiKey> for CircuitDB {
+    type Id = String;
+
+    async fn get_api_keys(&self) -> Result<Vec<ApiKey>> {
+        let keys = query_as!(
+            ApiKey,
+            r#"
+            SELECT id, name, roles, scopes, created_by, created_at, expires_at, last_used_at
+            FROM api_keys ORDER BY name
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(keys)
+    }
+
+    async fn get_api_key(&self, id: Self::Id) -> Result<ApiKey> {
+        query_as!(
+            ApiKey,
+            r#"
+            SELECT id, name, roles, scopes, created_by, created_at, expires_at, last_used_at
+            FROM api_keys WHERE id = $1
+            "#,
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or_else(|| eyre::Report::new(NotFound::new("API key", id)))
+    }
+
+    async fn create_api_key(&self, value: ApiKey, key_hash: String) -> Result<ApiKey> {
+        let key = query_as!(
+            ApiKey,
+            r#"
+            INSERT INTO api_keys (id, name, key_hash, roles, scopes, created_by, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            RETURNING id, name, roles, scopes, created_by, created_at, expires_at, last_used_at
+            "#,
+            value.id,
+            value.name,
+            key_hash,
+            &value.roles,
+            &value.scopes,
+            value.created_by,
+            value.expires_at
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| match e.as_database_error() {
+            Some(db_error) if db_error.is_unique_violation() => eyre::Report::new(AlreadyExists(
+                format!("There already is an API key named {}", value.name),
+            )),
+            _ => e.into(),
+        })?;
+
+        Ok(key)
+    }
+
+    async fn delete_api_key(&self, id: Self::Id) -> Result<()> {
+        let result = query!("DELETE FROM api_keys WHERE id = $1", id)
+            .execute(&self.pool)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(NotFound::new("API key", id).into());
+        }
+
+        Ok(())
+    }
+
+    async fn get_key_hash(&self, id: Self::Id) -> Result<String> {
+        query_scalar!("SELECT key_hash FROM api_keys WHERE id = $1", id)
+            .fetch_optional(&self.pool)
+            .await?
+            .ok_or_else(|| eyre::Report::new(NotFound::new("API key", id)))
+    }
+
+    async fn touch_api_key(&self, id: Self::Id) -> Result<()> {
+        // Busy keys would otherwise write on every request
+        query!(
+            r#"
+            UPDATE api_keys SET last_used_at = now()
+            WHERE id = $1 AND (last_used_at IS NULL OR last_used_at < now() - interval '1 minute')
+            "#,
+            id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+/// Permissions as stored, anything no longer known is left out
+fn permissions(stored: Vec<String>) -> Vec<Permission> {
+    stored
+        .iter()
+        .filter_map(|permission| Permission::try_from(permission.as_str()).ok())
+        .collect()
+}
+
+fn permission_names(permissions: &[Permission]) -> Vec<String> {
+    permissions
+        .iter()
+        .map(|permission| permission.as_str().to_string())
+        .collect()
+}
+
+impl Roles<Role> for CircuitDB {
+    type Id = String;
+
+    async fn get_roles(&self) -> Result<Vec<Role>> {
+        let roles = query!("SELECT name, description, permissions FROM roles ORDER BY name")
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(|row| Role {
+                name: row.name,
+                description: row.description,
+                permissions: permissions(row.permissions),
+            })
+            .collect();
+
+        Ok(roles)
+    }
+
+    async fn get_role(&self, id: Self::Id) -> Result<Role> {
+        query!(
+            "SELECT name, description, permissions FROM roles WHERE name = $1",
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|row| Role {
+            name: row.name,
+            description: row.description,
+            permissions: permissions(row.permissions),
+        })
+        .ok_or_else(|| eyre::Report::new(NotFound::new("role", id)))
+    }
+
+    async fn create_role(&self, value: Role) -> Result<Role> {
+        query!(
+            "INSERT INTO roles (name, description, permissions) VALUES ($1, $2, $3)",
+            value.name,
+            value.description,
+            &permission_names(&value.permissions)
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| match e.as_database_error() {
+            Some(db_error) if db_error.is_unique_violation() => eyre::Report::new(AlreadyExists(
+                format!("There already is a role named {}", value.name),
+            )),
+            _ => e.into(),
+        })?;
+
+        Ok(value)
+    }
+
+    async fn update_role(&self, value: Role) -> Result<Role> {
+        let result = query!(
+            "UPDATE roles SET description = $1, permissions = $2 WHERE name = $3",
+            value.description,
+            &permission_names(&value.permissions),
+            value.name
+        )
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(NotFound::new("role", value.name).into());
+        }
+
+        Ok(value)
+    }
+
+    async fn delete_role(&self, id: Self::Id) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        // Nothing stops a role named in `users.roles` from being deleted but this
+        let in_use = query_scalar!(
+            r#"
+            SELECT EXISTS (SELECT 1 FROM users WHERE $1 = ANY(roles))
+                OR EXISTS (SELECT 1 FROM api_keys WHERE $1 = ANY(roles)) AS "in_use!"
+            "#,
+            id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        if in_use {
+            return Err(
+                StillInUse(format!("The role {id} is still given to users or API keys")).into(),
+            );
+        }
+
+        let result = query!("DELETE FROM roles WHERE name = $1", id)
+            .execute(&mut *tx)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(NotFound::new("role", id).into());
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn get_permissions(&self, roles: Vec<String>) -> Result<Vec<Permission>> {
+        let stored = query_scalar!(
+            r#"
+            SELECT DISTINCT permission AS "permission!"
+            FROM roles, unnest(permissions) AS permission
+            WHERE name = ANY($1)
+            ORDER BY 1
+            "#,
+            &roles
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(permissions(stored))
+    }
+}
+
+impl AccessScopes<AccessScope> for CircuitDB {
+    async fn get_scopes(&self, username: String) -> Result<Vec<AccessScope>> {
+        query!(
+            "SELECT kind, value FROM user_scopes WHERE username = $1 ORDER BY kind, value",
+            username
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|row| {
+            Ok(AccessScope {
+                kind: row.kind.try_into()?,
+                value: row.value,
+            })
+        })
+        .collect()
+    }
+
+    async fn set_scopes(
+        &self,
+        username: String,
+        scopes: Vec<AccessScope>,
+    ) -> Result<Vec<AccessScope>> {
+        let mut tx = self.pool.begin().await?;
+
+        query!("DELETE FROM user_scopes WHERE username = $1", username)
+            .execute(&mut *tx)
+            .await?;
+
+        let (kinds, values): (Vec<String>, Vec<String>) = scopes
+            .iter()
+            .map(|scope| (scope.kind.as_str().to_string(), scope.value.clone()))
+            .unzip();
+
+        query!(
+            r#"
+            INSERT INTO user_scopes (username, kind, value)
+            SELECT $1, kind, value FROM unnest($2::text[], $3::text[]) AS scopes(kind, value)
+            "#,
+            username,
+            &kinds,
+            &values
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(scopes)
+    }
+}
+
+impl Regions<Region> for CircuitDB {
+    type Id = String;
+
+    async fn get_regions(&self) -> Result<Vec<Region>> {
+        let regions = query_as!(
+            Region,
+            "SELECT name, description, sites FROM regions ORDER BY name"
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(regions)
+    }
+
+    async fn get_region(&self, id: Self::Id) -> Result<Region> {
+        query_as!(
+            Region,
+            "SELECT name, description, sites FROM regions WHERE name = $1",
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or_else(|| eyre::Report::new(NotFound::new("region", id)))
+    }
+
+    async fn save_region(&self, value: Region) -> Result<Region> {
+        query!(
+            r#"
+            INSERT INTO regions (name, description, sites) VALUES ($1, $2, $3)
+            ON CONFLICT (name) DO UPDATE SET
+                description = EXCLUDED.description,
+                sites = EXCLUDED.sites
+            "#,
+            value.name,
+            value.description,
+            &value.sites
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(value)
+    }
+
+    async fn delete_region(&self, id: Self::Id) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        // Scopes name regions as plain values, there is no foreign key to do this
+        let in_use = query_scalar!(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM user_scopes WHERE kind = 'region' AND value = $1
+            ) AS "in_use!"
+            "#,
+            id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        if in_use {
+            return Err(eyre::Report::new(StillInUse(format!(
+                "Users are still scoped to the region {id}"
+            ))));
+        }
+
+        let result = query!("DELETE FROM regions WHERE name = $1", id)
+            .execute(&mut *tx)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(NotFound::new("region", id).into());
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
+
+struct LoginLockRow {
+    kind: String,
+    key: String,
+    failures: i32,
+    last_failed_at: DateTime<Utc>,
+    locked_until: Option<DateTime<Utc>>,
+}
+
+impl TryFrom<LoginLockRow> for LoginLock {
+    type Error = eyre::Report;
+
+    fn try_from(row: LoginLockRow) -> Result<Self> {
+        Ok(LoginLock {
+            kind: row.kind.try_into()?,
+            key: row.key,
+            failures: row.failures,
+            last_failed_at: row.last_failed_at,
+            locked_until: row.locked_until,
+        })
+    }
+}
+
+impl LoginLocks<LoginLock> for CircuitDB {
+    async fn get_locks(&self) -> Result<Vec<LoginLock>> {
+        query_as!(
+            LoginLockRow,
+            r#"
+            SELECT kind, key, failures, last_failed_at, locked_until
+            FROM login_locks
+            WHERE locked_until > now()
+            ORDER BY locked_until DESC
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(LoginLock::try_from)
+        .collect()
+    }
+
+    async fn get_lock(&self, kind: LockKind, key: String) -> Result<Option<LoginLock>> {
+        query_as!(
+            LoginLockRow,
+            r#"
+            SELECT kind, key, failures, last_failed_at, locked_until
+            FROM login_locks
+            WHERE kind = $1 AND key = $2
+            "#,
+            kind.as_str(),
+            key
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .map(LoginLock::try_from)
+        .transpose()
+    }
+
+    async fn record_failure(
+        &self,
+        kind: LockKind,
+        key: String,
+        forget_before: DateTime<Utc>,
+    ) -> Result<LoginLock> {
+        query_as!(
+            LoginLockRow,
+            r#"
+            INSERT INTO login_locks (kind, key, failures) VALUES ($1, $2, 1)
+            ON CONFLICT (kind, key) DO UPDATE SET
+                failures = CASE
+                    WHEN login_locks.last_failed_at < $3 THEN 1
+                    ELSE login_locks.failures + 1
+                END,
+                last_failed_at = now()
+            RETURNING kind, key, failures, last_failed_at, locked_until
+            "#,
+            kind.as_str(),
+            key,
+            forget_before
+        )
+        .fetch_one(&self.pool)
+        .await?
+        .try_into()
+    }
+
+    async fn lock(&self, kind: LockKind, key: String, until: DateTime<Utc>) -> Result<()> {
+        query!(
+            "UPDATE login_locks SET locked_until = $3 WHERE kind = $1 AND key = $2",
+            kind.as_str(),
+            key,
+            until
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn clear_lock(&self, kind: LockKind, key: String) -> Result<bool> {
+        let result = query!(
+            "DELETE FROM login_locks WHERE kind = $1 AND key = $2",
+            kind.as_str(),
+            key
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+impl LoginAudit<LoginFailure> for CircuitDB {
+    async fn record_login_failure(&self, value: LoginFailure) -> Result<LoginFailure> {
+        let (id, failed_at) = query!(
+            r#"
+            INSERT INTO login_failures (username, ip, reason) VALUES ($1, $2, $3)
+            RETURNING id, failed_at
+            "#,
+            value.username,
+            value.ip,
+            value.reason.as_str()
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map(|row| (row.id, row.failed_at))?;
+
+        Ok(LoginFailure {
+            id,
+            failed_at,
+            ..value
+        })
+    }
+
+    async fn get_login_failures(&self, filter: AuditFilter) -> Result<Vec<LoginFailure>> {
+        query!(
+            r#"
+            SELECT id, username, ip, reason, failed_at
+            FROM login_failures
+            WHERE ($1::text IS NULL OR username = $1)
+                AND ($2::timestamptz IS NULL OR failed_at >= $2)
+                AND ($3::timestamptz IS NULL OR failed_at < $3)
+                AND ($4::bigint IS NULL OR id < $4)
+            ORDER BY id DESC
+            LIMIT $5
+            "#,
+            filter.actor,
+            filter.from,
+            filter.to,
+            filter.before,
+            filter.limit
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|row| {
+            Ok(LoginFailure {
+                id: row.id,
+                username: row.username,
+                ip: row.ip,
+                reason: row.reason.try_into()?,
+                failed_at: row.failed_at,
+            })
+        })
+        .collect()
+    }
 }
@@ -0,0 +1,164 @@
+//! JSON bodies of circuits in and out. An uploaded body is turned into CSV text
+//! so it goes through the same import pipeline as an uploaded CSV file.
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// How circuits are laid out in a JSON body
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JsonLayout {
+    /// A single array of objects
+    Array,
+    /// One object per line
+    Lines,
+}
+
+impl JsonLayout {
+    /// An array when the body starts like one, one object per line otherwise
+    pub fn detect(body: &str) -> JsonLayout {
+        if body.trim_start().starts_with('[') {
+            JsonLayout::Array
+        } else {
+            JsonLayout::Lines
+        }
+    }
+}
+
+/// The objects of a JSON array or NDJSON body as CSV, with a column for every
+/// key found in any of them. Records follow the header one per line, blank
+/// lines of an NDJSON body are kept, so until a value with line breaks in it
+/// pushes the records under it down the line of a record in the CSV is its
+/// position in an array or its line in an NDJSON body plus one.
+pub fn records_to_csv(body: &str) -> eyre::Result<String> {
+    let records: Vec<Option<Map<String, Value>>> = match JsonLayout::detect(body) {
+        JsonLayout::Array => serde_json::from_str::<Vec<Map<String, Value>>>(body)
+            .map_err(|e| eyre::Report::msg(format!("The body is not an array of objects : {e}")))?
+            .into_iter()
+            .map(Some)
+            .collect(),
+        JsonLayout::Lines => body
+            .lines()
+            .enumerate()
+            .map(|(index, line)| {
+                if line.trim().is_empty() {
+                    return Ok(None);
+                }
+                serde_json::from_str(line).map(Some).map_err(|e| {
+                    eyre::Report::msg(format!("Line {} is not a JSON object : {e}", index + 1))
+                })
+            })
+            .collect::<eyre::Result<_>>()?,
+    };
+
+    if records.iter().all(Option::is_none) {
+        return Err(eyre::Report::msg("The body has no circuits in it"));
+    }
+
+    let mut headers: Vec<&str> = vec![];
+    for key in records.iter().flatten().flat_map(Map::keys) {
+        if !headers.contains(&key.as_str()) {
+            headers.push(key);
+        }
+    }
+
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer.write_record(&headers)?;
+    writer.flush()?;
+    let mut csv_data = writer.into_inner()?;
+
+    for (index, record) in records.iter().enumerate() {
+        let Some(record) = record else {
+            csv_data.push(b'\n');
+            continue;
+        };
+
+        let values = headers
+            .iter()
+            .map(|header| match record.get(*header) {
+                None | Some(Value::Null) => Ok(String::new()),
+                Some(Value::String(text)) => Ok(text.clone()),
+                Some(Value::Bool(flag)) => Ok(flag.to_string()),
+                Some(Value::Number(number)) => Ok(number.to_string()),
+                Some(Value::Array(_) | Value::Object(_)) => Err(eyre::Report::msg(format!(
+                    "Record {} has a nested value in `{header}`, only text, numbers and booleans can be imported",
+                    index + 1
+                ))),
+            })
+            .collect::<eyre::Result<Vec<_>>>()?;
+
+        let mut writer = csv::Writer::from_writer(&mut csv_data);
+        writer.write_record(&values)?;
+        writer.flush()?;
+    }
+
+    Ok(String::from_utf8(csv_data)?)
+}
+
+/// `items` as the part of a JSON array or NDJSON body they make up. `first`
+/// says whether they open the array, so the ones after it get a comma.
+pub fn encode<T: Serialize>(items: &[T], layout: JsonLayout, first: bool) -> eyre::Result<Vec<u8>> {
+    let mut data = vec![];
+
+    for (index, item) in items.iter().enumerate() {
+        if layout == JsonLayout::Array && !(first && index == 0) {
+            data.push(b',');
+        }
+        serde_json::to_writer(&mut data, item)?;
+        data.push(b'\n');
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arrays_become_csv_with_a_column_per_key() {
+        let body = r#"[
+            {"ckt_id": "CR-01", "bw_mbps": 100},
+            {"ckt_id": "CR-02", "single_isp": true, "router_ip": null}
+        ]"#;
+
+        assert_eq!(JsonLayout::detect(body), JsonLayout::Array);
+        assert_eq!(
+            records_to_csv(body).unwrap(),
+            "bw_mbps,ckt_id,router_ip,single_isp\n100,CR-01,,\n,CR-02,,true\n"
+        );
+    }
+
+    #[test]
+    fn ndjson_keeps_blank_lines_so_lines_match() {
+        let body = "{\"ckt_id\": \"CR-01\"}\n\n{\"ckt_id\": \"CR-02\"}\n";
+
+        assert_eq!(JsonLayout::detect(body), JsonLayout::Lines);
+        assert_eq!(records_to_csv(body).unwrap(), "ckt_id\nCR-01\n\nCR-02\n");
+
+        let error = records_to_csv("{\"ckt_id\": \"CR-01\"}\nnope\n").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Line 2 is not a JSON object : expected ident at line 1 column 2"
+        );
+    }
+
+    #[test]
+    fn nested_and_empty_bodies_are_refused() {
+        assert!(records_to_csv(r#"[{"ckt_id": {"id": 1}}]"#).is_err());
+        assert!(records_to_csv("[]").is_err());
+        assert!(records_to_csv("\n\n").is_err());
+    }
+
+    #[test]
+    fn encoded_chunks_join_into_one_array() {
+        let first = encode(&[1, 2], JsonLayout::Array, true).unwrap();
+        let rest = encode(&[3], JsonLayout::Array, false).unwrap();
+        let array = [b"[".as_slice(), &first, &rest, b"]"].concat();
+
+        assert_eq!(
+            serde_json::from_slice::<Vec<i32>>(&array).unwrap(),
+            [1, 2, 3]
+        );
+        assert_eq!(encode(&[1, 2], JsonLayout::Lines, true).unwrap(), b"1\n2\n");
+    }
+}
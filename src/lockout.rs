@@ -0,0 +1,185 @@
+//! Failed logins are counted per username and per client address. After a few
+//! free attempts every further failure doubles how long the next attempt has
+//! to wait, and enough of them lock the key out for a while. Failures older
+//! than the lockout are forgotten.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use axum::http::HeaderMap;
+use chrono::{DateTime, Utc};
+
+use crate::model::LockKind;
+
+const DEFAULT_LOCKOUT_ATTEMPTS: u32 = 10;
+const DEFAULT_IP_LOCKOUT_ATTEMPTS: u32 = 50;
+const DEFAULT_LOCKOUT_MINUTES: u64 = 15;
+
+// Addresses are shared by everyone behind the same NAT, they get more room
+const USERNAME_FREE_ATTEMPTS: u32 = 3;
+const IP_FREE_ATTEMPTS: u32 = 10;
+
+/// How many failures a key has before it's held back and locked out
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub free_attempts: u32,
+    pub lockout_attempts: u32,
+}
+
+/// Set with `LOGIN_LOCKOUT_ATTEMPTS`, `LOGIN_IP_LOCKOUT_ATTEMPTS`,
+/// `LOGIN_LOCKOUT_MINUTES` and `TRUST_FORWARDED_FOR`
+#[derive(Debug, Clone, Copy)]
+pub struct LockoutPolicy {
+    pub username: Limits,
+    pub ip: Limits,
+    /// How long a locked out key waits, also the longest a backoff gets
+    pub lockout: Duration,
+    /// Takes the client address from `X-Forwarded-For`, for running behind a
+    /// reverse proxy that sets it
+    pub trust_forwarded_for: bool,
+}
+
+impl LockoutPolicy {
+    pub fn from_env() -> LockoutPolicy {
+        let read = |name: &str, default: u64| match std::env::var(name) {
+            Ok(value) => value
+                .parse()
+                .unwrap_or_else(|_| panic!("{name} MUST BE A WHOLE NUMBER")),
+            Err(_) => default,
+        };
+
+        let trust_forwarded_for = match std::env::var("TRUST_FORWARDED_FOR").as_deref() {
+            Ok("true") => true,
+            Ok("false") | Err(_) => false,
+            Ok(_) => panic!("TRUST_FORWARDED_FOR MUST BE `true` OR `false`"),
+        };
+
+        LockoutPolicy {
+            username: Limits {
+                free_attempts: USERNAME_FREE_ATTEMPTS,
+                lockout_attempts: read("LOGIN_LOCKOUT_ATTEMPTS", DEFAULT_LOCKOUT_ATTEMPTS as u64)
+                    as u32,
+            },
+            ip: Limits {
+                free_attempts: IP_FREE_ATTEMPTS,
+                lockout_attempts: read(
+                    "LOGIN_IP_LOCKOUT_ATTEMPTS",
+                    DEFAULT_IP_LOCKOUT_ATTEMPTS as u64,
+                ) as u32,
+            },
+            lockout: Duration::from_secs(
+                read("LOGIN_LOCKOUT_MINUTES", DEFAULT_LOCKOUT_MINUTES) * 60,
+            ),
+            trust_forwarded_for,
+        }
+    }
+
+    pub fn limits(&self, kind: LockKind) -> Limits {
+        match kind {
+            LockKind::Username => self.username,
+            LockKind::Ip => self.ip,
+        }
+    }
+
+    /// Until when a key with `failures` recent failures has to wait, `None`
+    /// while it still has free attempts
+    pub fn locked_until(&self, kind: LockKind, failures: u32) -> Option<DateTime<Utc>> {
+        let limits = self.limits(kind);
+
+        let wait = if failures >= limits.lockout_attempts {
+            self.lockout
+        } else if failures > limits.free_attempts {
+            let doublings = (failures - limits.free_attempts - 1).min(31);
+            Duration::from_secs(1 << doublings).min(self.lockout)
+        } else {
+            return None;
+        };
+
+        Some(Utc::now() + chrono::Duration::from_std(wait).expect("Lockout fits a duration"))
+    }
+
+    /// Failures from before this don't count anymore
+    pub fn forget_before(&self) -> DateTime<Utc> {
+        Utc::now() - chrono::Duration::from_std(self.lockout).expect("Lockout fits a duration")
+    }
+
+    /// The address a request came from, the first one in `X-Forwarded-For`
+    /// when the proxy is trusted and set it
+    pub fn client_ip(&self, peer: SocketAddr, headers: &HeaderMap) -> IpAddr {
+        let forwarded = self
+            .trust_forwarded_for
+            .then(|| headers.get("x-forwarded-for")?.to_str().ok())
+            .flatten()
+            .and_then(|value| value.split(',').next()?.trim().parse().ok());
+
+        forwarded.unwrap_or(peer.ip())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> LockoutPolicy {
+        LockoutPolicy {
+            username: Limits {
+                free_attempts: 3,
+                lockout_attempts: 10,
+            },
+            ip: Limits {
+                free_attempts: 10,
+                lockout_attempts: 50,
+            },
+            lockout: Duration::from_secs(15 * 60),
+            trust_forwarded_for: false,
+        }
+    }
+
+    /// Roughly how many seconds a key with `failures` waits
+    fn wait(kind: LockKind, failures: u32) -> Option<i64> {
+        policy()
+            .locked_until(kind, failures)
+            .map(|until| (until - Utc::now()).num_seconds() + 1)
+    }
+
+    #[test]
+    fn waits_double_after_the_free_attempts() {
+        assert_eq!(wait(LockKind::Username, 0), None);
+        assert_eq!(wait(LockKind::Username, 3), None);
+        assert_eq!(wait(LockKind::Username, 4), Some(1));
+        assert_eq!(wait(LockKind::Username, 5), Some(2));
+        assert_eq!(wait(LockKind::Username, 8), Some(16));
+
+        // Addresses get more free attempts than usernames
+        assert_eq!(wait(LockKind::Ip, 10), None);
+        assert_eq!(wait(LockKind::Ip, 11), Some(1));
+    }
+
+    #[test]
+    fn waits_never_run_past_the_lockout() {
+        assert_eq!(wait(LockKind::Username, 10), Some(15 * 60));
+        assert_eq!(wait(LockKind::Username, 1000), Some(15 * 60));
+        assert_eq!(wait(LockKind::Ip, 49), Some(15 * 60));
+    }
+
+    #[test]
+    fn forwarded_addresses_are_only_trusted_when_configured() {
+        let peer: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "203.0.113.7, 10.0.0.1".parse().unwrap());
+
+        assert_eq!(policy().client_ip(peer, &headers), peer.ip());
+
+        let trusting = LockoutPolicy {
+            trust_forwarded_for: true,
+            ..policy()
+        };
+        assert_eq!(
+            trusting.client_ip(peer, &headers),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(trusting.client_ip(peer, &HeaderMap::new()), peer.ip());
+    }
+}
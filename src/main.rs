@@ -1,4 +1,4 @@
-use std::time::Duration;
+use std::{net::SocketAddr, time::Duration};
 
 use axum::{
     error_handling::HandleErrorLayer,
@@ -7,9 +7,9 @@ use axum::{
     BoxError, Router,
 };
 use data::CircuitDB;
-use model::AppState;
+use model::{AppState, ImportJobs};
 use tokio::net::TcpListener;
-use tower::{buffer::BufferLayer, limit::RateLimitLayer, timeout::TimeoutLayer, ServiceBuilder};
+use tower::{timeout::TimeoutLayer, ServiceBuilder};
 use tower_http::{
     services::{ServeDir, ServeFile},
     trace::TraceLayer,
@@ -20,14 +20,31 @@ use web::{
     responses::RequestResponse,
 };
 
+mod api_key;
+mod cli;
+mod conflicts;
 mod data;
+mod json;
+mod lockout;
 mod model;
+mod password;
+mod session;
+mod upload;
 mod web;
+mod xlsx;
 
 #[tokio::main]
 async fn main() {
     dotenvy::dotenv().expect(".env file must exist");
 
+    let command = match cli::Command::from_args(std::env::args().skip(1)) {
+        Ok(command) => command,
+        Err(usage) => {
+            eprintln!("{usage}");
+            std::process::exit(2);
+        }
+    };
+
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
@@ -44,18 +61,60 @@ async fn main() {
             .await
             .expect("Failed to connect to db");
 
+    sqlx::migrate!()
+        .run(&pool)
+        .await
+        .expect("Failed to run migrations");
+
     let data_source = CircuitDB { pool };
 
+    // Commands run next to a server that may be running, nothing below is theirs to do
+    if let Some(command) = command {
+        if let Err(e) = cli::run(&data_source, command).await {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    match data_source.get_migration_issues().await {
+        Ok(issues) => {
+            for issue in issues {
+                tracing::warn!(
+                    "Circuit {} has an unconvertible {} value `{}` : {}",
+                    issue.circuit_id,
+                    issue.field,
+                    issue.raw_value,
+                    issue.message
+                );
+            }
+        }
+        Err(e) => tracing::error!("Failed to read circuit migration issues : {}", e),
+    }
+
+    // Imports run in this process, any left unfinished died with the last one
+    match data_source.fail_interrupted_jobs().await {
+        Ok(0) => {}
+        Ok(interrupted) => tracing::warn!("Marked {} interrupted imports as failed", interrupted),
+        Err(e) => tracing::error!("Failed to fail interrupted imports : {}", e),
+    }
+
     let app_state = AppState::new(data_source);
 
     let api_routes = web::handlers::get_api_router()
-        .with_state(app_state)
-        .layer(axum::middleware::from_fn(web::middleware::validate_jwt_mw));
+        .with_state(app_state.clone())
+        .layer(axum::middleware::from_fn_with_state(
+            app_state.clone(),
+            web::middleware::validate_jwt_mw,
+        ));
 
     let app = Router::new()
         .route("/favicon.ico", axum::routing::get(favicon_ico_handler))
         .nest("/api", api_routes)
-        .nest("/auth", web::handlers::get_auth_router())
+        .nest(
+            "/auth",
+            web::handlers::get_auth_router().with_state(app_state),
+        )
         .route_service("/", ServeFile::new("static/index.html"))
         .nest_service("/assets", ServeDir::new("static/assets"))
         .layer(from_fn(log_responses))
@@ -70,8 +129,6 @@ async fn main() {
                         code: StatusCode::BAD_REQUEST,
                     }
                 }))
-                .layer(BufferLayer::new(1024))
-                .layer(RateLimitLayer::new(5, Duration::from_secs(1)))
                 .layer(TimeoutLayer::new(Duration::from_secs(60))),
         );
 
@@ -84,10 +141,14 @@ async fn main() {
 
     tracing::debug!("listening on {}", listener.local_addr().unwrap());
 
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-        .unwrap();
+    // Logins are throttled by the address they come from
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await
+    .unwrap();
 }
 
 async fn shutdown_signal() {
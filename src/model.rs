@@ -1,16 +1,73 @@
+use std::net::IpAddr;
+
+use chrono::{DateTime, Utc};
 use eyre::Result;
 use serde::{Deserialize, Serialize};
 use sqlx::prelude::FromRow;
 
+use crate::{
+    conflicts::{Conflict, ConflictPolicy},
+    lockout::LockoutPolicy,
+    password::StoredPassword,
+    session::TokenLifetimes,
+    upload::UploadLimits,
+};
+
 pub trait DataSource<T>: Clone + Send + Sync + 'static
 where
     T: Sized + Send + Sync,
 {
     type Id;
-    fn get_all(&self) -> impl std::future::Future<Output = Result<Vec<T>>> + Send;
-    fn update(&self, value: T) -> impl std::future::Future<Output = Result<T>> + Send;
+    /// Filters, sort order and page position understood by `get_all`
+    type Query: Default + Send;
+    fn get_all(
+        &self,
+        query: Self::Query,
+    ) -> impl std::future::Future<Output = Result<Page<T>>> + Send;
+    /// Like `get_all` but yields the values one at a time as they are read,
+    /// without loading the whole page first
+    fn stream_all(
+        &self,
+        query: Self::Query,
+    ) -> impl futures_util::Stream<Item = Result<T>> + Send + 'static;
+    /// Every change is recorded in the value's history together with `context`
+    fn update(
+        &self,
+        value: T,
+        context: ChangeContext,
+    ) -> impl std::future::Future<Output = Result<T>> + Send;
     fn get(&self, id: Self::Id) -> impl std::future::Future<Output = Result<T>> + Send;
-    fn create(&self, value: T) -> impl std::future::Future<Output = Result<T>> + Send;
+    fn create(
+        &self,
+        value: T,
+        context: ChangeContext,
+    ) -> impl std::future::Future<Output = Result<T>> + Send;
+    /// Keeps the value around but hides it from `get_all` unless asked for
+    fn decommission(
+        &self,
+        id: Self::Id,
+        reason: String,
+        context: ChangeContext,
+    ) -> impl std::future::Future<Output = Result<T>> + Send;
+    fn delete(
+        &self,
+        id: Self::Id,
+        context: ChangeContext,
+    ) -> impl std::future::Future<Output = Result<()>> + Send;
+    /// Applies every change in a single transaction, nothing is written unless
+    /// all of them succeed. Returns the position and error of each change that
+    /// failed. `processed` is kept at the number of changes gone through so far.
+    fn apply_all(
+        &self,
+        changes: Vec<Change<T, Self::Id>>,
+        context: ChangeContext,
+        processed: tokio::sync::watch::Sender<usize>,
+    ) -> impl std::future::Future<Output = Result<Vec<(usize, eyre::Report)>>> + Send;
+    /// Best matches for a free text search, most relevant first
+    fn search(
+        &self,
+        query: SearchQuery,
+    ) -> impl std::future::Future<Output = Result<Vec<SearchResult<T>>>> + Send;
 }
 
 pub trait Reporter<T>: Clone + Send + Sync + 'static
@@ -34,20 +91,821 @@ where
 {
     fn get_all(&self) -> impl std::future::Future<Output = Result<Vec<T>>> + Send;
     fn get_new(&self) -> impl std::future::Future<Output = Result<Vec<T>>> + Send;
+    /// Everything reported by a single import, in the order of the file
+    fn get_for_import(
+        &self,
+        import_id: String,
+    ) -> impl std::future::Future<Output = Result<Vec<T>>> + Send;
+}
+
+/// Uploads held on to between a preview and the moment they are committed
+pub trait Staging<T>: Clone + Send + Sync + 'static
+where
+    T: Sized + Send + Sync,
+{
+    type Id;
+
+    fn stage(&self, value: T) -> impl std::future::Future<Output = Result<()>> + Send;
+    /// Removes the staged value so it can only be committed once. Values staged
+    /// by someone else or too long ago fail it with `NotFound`.
+    fn take(
+        &self,
+        id: Self::Id,
+        staged_by: String,
+    ) -> impl std::future::Future<Output = Result<T>> + Send;
+}
+
+/// Background imports and how far along they are
+pub trait ImportJobs<T>: Clone + Send + Sync + 'static
+where
+    T: Sized + Send + Sync,
+{
+    type Id;
+
+    fn queue_job(&self, value: T) -> impl std::future::Future<Output = Result<T>> + Send;
+    fn get_job(&self, id: Self::Id) -> impl std::future::Future<Output = Result<T>> + Send;
+    /// Moves a queued job to running, `total_rows` is what it is about to process
+    fn start_job(
+        &self,
+        id: Self::Id,
+        total_rows: u64,
+    ) -> impl std::future::Future<Output = Result<()>> + Send;
+    fn record_progress(
+        &self,
+        id: Self::Id,
+        progress: ImportProgress,
+    ) -> impl std::future::Future<Output = Result<()>> + Send;
+    /// Final status of the job, only jobs that are still queued or running are changed
+    fn finish_job(
+        &self,
+        id: Self::Id,
+        status: ImportStatus,
+        progress: ImportProgress,
+        message: String,
+    ) -> impl std::future::Future<Output = Result<()>> + Send;
+    /// Fails every job left queued or running by a previous run of the server,
+    /// returns how many there were
+    fn fail_interrupted_jobs(&self) -> impl std::future::Future<Output = Result<u64>> + Send;
+}
+
+/// Column mappings saved for imports to use
+pub trait MappingProfiles<T>: Clone + Send + Sync + 'static
+where
+    T: Sized + Send + Sync,
+{
+    type Id;
+
+    fn get_profiles(&self) -> impl std::future::Future<Output = Result<Vec<T>>> + Send;
+    fn get_profile(&self, id: Self::Id) -> impl std::future::Future<Output = Result<T>> + Send;
+    /// Creates the profile or replaces the one with the same id
+    fn save_profile(&self, value: T) -> impl std::future::Future<Output = Result<T>> + Send;
+    fn delete_profile(&self, id: Self::Id) -> impl std::future::Future<Output = Result<()>> + Send;
+}
+
+/// Creates and updates refused or warned about for claiming what other values
+/// already do, see `conflicts`
+pub trait ConflictChecks<T>: Clone + Send + Sync + 'static
+where
+    T: Sized + Send + Sync,
+{
+    /// Like `DataSource::create`, checked against the values it could collide
+    /// with in the same transaction it is written in. Conflicts `policy`
+    /// refuses fail it with `Conflicting`, the rest are returned with it.
+    fn create_checked(
+        &self,
+        value: T,
+        context: ChangeContext,
+        policy: ConflictPolicy,
+    ) -> impl std::future::Future<Output = Result<(T, Vec<Conflict>)>> + Send;
+    /// Like `create_checked` for `DataSource::update`
+    fn update_checked(
+        &self,
+        value: T,
+        context: ChangeContext,
+        policy: ConflictPolicy,
+    ) -> impl std::future::Future<Output = Result<(T, Vec<Conflict>)>> + Send;
+}
+
+/// Accounts that can log in. Passwords only go in already hashed and only come
+/// out as what's stored, to be checked with `StoredPassword::credentials`.
+pub trait Users<T>: Clone + Send + Sync + 'static
+where
+    T: Sized + Send + Sync,
+{
+    type Id;
+
+    fn get_users(&self) -> impl std::future::Future<Output = Result<Vec<T>>> + Send;
+    fn get_user(&self, id: Self::Id) -> impl std::future::Future<Output = Result<T>> + Send;
+    fn create_user(
+        &self,
+        value: T,
+        password_hash: String,
+    ) -> impl std::future::Future<Output = Result<T>> + Send;
+    /// Saves everything but the password
+    fn update_user(&self, value: T) -> impl std::future::Future<Output = Result<T>> + Send;
+    fn delete_user(&self, id: Self::Id) -> impl std::future::Future<Output = Result<()>> + Send;
+    fn get_password(
+        &self,
+        id: Self::Id,
+    ) -> impl std::future::Future<Output = Result<StoredPassword>> + Send;
+    fn set_password(
+        &self,
+        id: Self::Id,
+        password_hash: String,
+    ) -> impl std::future::Future<Output = Result<()>> + Send;
+}
+
+/// Logins, each accepting the refresh token whose hash it holds. Tokens only
+/// go in already hashed, like passwords.
+pub trait Sessions<T>: Clone + Send + Sync + 'static
+where
+    T: Sized + Send + Sync,
+{
+    type Id;
+
+    fn get_session(&self, id: Self::Id) -> impl std::future::Future<Output = Result<T>> + Send;
+    /// Sessions of a user that weren't revoked and haven't expired, newest first
+    fn get_active_sessions(
+        &self,
+        username: String,
+    ) -> impl std::future::Future<Output = Result<Vec<T>>> + Send;
+    fn create_session(
+        &self,
+        value: T,
+        refresh_hash: String,
+    ) -> impl std::future::Future<Output = Result<T>> + Send;
+    fn get_refresh_hashes(
+        &self,
+        id: Self::Id,
+    ) -> impl std::future::Future<Output = Result<RefreshHashes>> + Send;
+    /// Swaps the refresh token hash, keeping the one it replaces as the
+    /// previous one, and moves the expiry, as long as `current_hash` is still
+    /// the stored one. False when another refresh got there first.
+    fn rotate_session(
+        &self,
+        id: Self::Id,
+        current_hash: String,
+        refresh_hash: String,
+        expires_at: DateTime<Utc>,
+    ) -> impl std::future::Future<Output = Result<bool>> + Send;
+    fn revoke_session(&self, id: Self::Id) -> impl std::future::Future<Output = Result<()>> + Send;
+    /// Revokes every active session of a user, returning how many there were
+    fn revoke_user_sessions(
+        &self,
+        username: String,
+    ) -> impl std::future::Future<Output = Result<u64>> + Send;
+    /// Like `revoke_user_sessions` but leaves the session `keep` logged in
+    fn revoke_other_sessions(
+        &self,
+        username: String,
+        keep: Self::Id,
+    ) -> impl std::future::Future<Output = Result<u64>> + Send;
+}
+
+/// Named sets of permissions that users and API keys are given
+pub trait Roles<T>: Clone + Send + Sync + 'static
+where
+    T: Sized + Send + Sync,
+{
+    type Id;
+
+    fn get_roles(&self) -> impl std::future::Future<Output = Result<Vec<T>>> + Send;
+    fn get_role(&self, id: Self::Id) -> impl std::future::Future<Output = Result<T>> + Send;
+    fn create_role(&self, value: T) -> impl std::future::Future<Output = Result<T>> + Send;
+    fn update_role(&self, value: T) -> impl std::future::Future<Output = Result<T>> + Send;
+    /// Fails while a user or API key still has the role
+    fn delete_role(&self, id: Self::Id) -> impl std::future::Future<Output = Result<()>> + Send;
+    /// Everything the roles named in `roles` allow together, unknown ones allow nothing
+    fn get_permissions(
+        &self,
+        roles: Vec<String>,
+    ) -> impl std::future::Future<Output = Result<Vec<Permission>>> + Send;
+}
+
+/// Keys that stand in for a login, stored as a hash like passwords
+pub trait ApiKeys<T>: Clone + Send + Sync + 'static
+where
+    T: Sized + Send + Sync,
+{
+    type Id;
+
+    fn get_api_keys(&self) -> impl std::future::Future<Output = Result<Vec<T>>> + Send;
+    fn get_api_key(&self, id: Self::Id) -> impl std::future::Future<Output = Result<T>> + Send;
+    fn create_api_key(
+        &self,
+        value: T,
+        key_hash: String,
+    ) -> impl std::future::Future<Output = Result<T>> + Send;
+    fn delete_api_key(&self, id: Self::Id) -> impl std::future::Future<Output = Result<()>> + Send;
+    fn get_key_hash(
+        &self,
+        id: Self::Id,
+    ) -> impl std::future::Future<Output = Result<String>> + Send;
+    /// Records that the key was just used
+    fn touch_api_key(&self, id: Self::Id) -> impl std::future::Future<Output = Result<()>> + Send;
+}
+
+/// What part of the inventory each user is limited to, users without any
+/// scopes reach every circuit
+pub trait AccessScopes<T>: Clone + Send + Sync + 'static
+where
+    T: Sized + Send + Sync,
+{
+    fn get_scopes(
+        &self,
+        username: String,
+    ) -> impl std::future::Future<Output = Result<Vec<T>>> + Send;
+    /// Replaces every scope of the user
+    fn set_scopes(
+        &self,
+        username: String,
+        scopes: Vec<T>,
+    ) -> impl std::future::Future<Output = Result<Vec<T>>> + Send;
+}
+
+/// Named groups of sites that scopes can refer to
+pub trait Regions<T>: Clone + Send + Sync + 'static
+where
+    T: Sized + Send + Sync,
+{
+    type Id;
+
+    fn get_regions(&self) -> impl std::future::Future<Output = Result<Vec<T>>> + Send;
+    fn get_region(&self, id: Self::Id) -> impl std::future::Future<Output = Result<T>> + Send;
+    /// Creates the region or replaces the one with the same name
+    fn save_region(&self, value: T) -> impl std::future::Future<Output = Result<T>> + Send;
+    /// Fails while a user is still scoped to the region
+    fn delete_region(&self, id: Self::Id) -> impl std::future::Future<Output = Result<()>> + Send;
+}
+
+/// Recent failed logins, counted against usernames and client addresses
+pub trait LoginLocks<T>: Clone + Send + Sync + 'static
+where
+    T: Sized + Send + Sync,
+{
+    /// Keys that have to wait before logging in again
+    fn get_locks(&self) -> impl std::future::Future<Output = Result<Vec<T>>> + Send;
+    fn get_lock(
+        &self,
+        kind: LockKind,
+        key: String,
+    ) -> impl std::future::Future<Output = Result<Option<T>>> + Send;
+    /// Counts a failure against the key, starting over when its last one was
+    /// before `forget_before`
+    fn record_failure(
+        &self,
+        kind: LockKind,
+        key: String,
+        forget_before: DateTime<Utc>,
+    ) -> impl std::future::Future<Output = Result<T>> + Send;
+    fn lock(
+        &self,
+        kind: LockKind,
+        key: String,
+        until: DateTime<Utc>,
+    ) -> impl std::future::Future<Output = Result<()>> + Send;
+    /// Forgets the failures of the key, false when it had none
+    fn clear_lock(
+        &self,
+        kind: LockKind,
+        key: String,
+    ) -> impl std::future::Future<Output = Result<bool>> + Send;
+}
+
+/// Failed logins as kept in the audit trail
+pub trait LoginAudit<T>: Clone + Send + Sync + 'static
+where
+    T: Sized + Send + Sync,
+{
+    fn record_login_failure(&self, value: T)
+        -> impl std::future::Future<Output = Result<T>> + Send;
+    /// Newest first, `AuditFilter::actor` matches the username that was tried
+    fn get_login_failures(
+        &self,
+        filter: AuditFilter,
+    ) -> impl std::future::Future<Output = Result<Vec<T>>> + Send;
+}
+
+/// The key that tells values apart without their id, kept unique among the
+/// values that aren't decommissioned
+pub trait NaturalKeys<K>: Clone + Send + Sync + 'static
+where
+    K: Sized + Send + Sync,
+{
+    fn get_natural_key(&self) -> impl std::future::Future<Output = Result<Option<K>>> + Send;
+    /// Replaces the key and the constraint that keeps it unique, `None` removes both
+    fn set_natural_key(
+        &self,
+        key: Option<K>,
+    ) -> impl std::future::Future<Output = Result<()>> + Send;
+}
+
+/// Read side of the revisions written by `DataSource` mutations
+pub trait ChangeHistory<T>: Clone + Send + Sync + 'static
+where
+    T: Sized + Send + Sync,
+{
+    type Id;
+
+    /// Every revision of a single value, oldest first
+    fn get_history(
+        &self,
+        id: Self::Id,
+    ) -> impl std::future::Future<Output = Result<Vec<Revision<T>>>> + Send;
+    /// Revisions across all values, newest first
+    fn get_audit_log(
+        &self,
+        filter: AuditFilter,
+    ) -> impl std::future::Future<Output = Result<Vec<Revision<T>>>> + Send;
+    /// The value as it was at `as_of`
+    fn get_as_of(
+        &self,
+        id: Self::Id,
+        as_of: DateTime<Utc>,
+    ) -> impl std::future::Future<Output = Result<T>> + Send;
+    /// Puts the value back the way it was right after `revision`
+    fn revert(
+        &self,
+        id: Self::Id,
+        revision: i64,
+        context: ChangeContext,
+    ) -> impl std::future::Future<Output = Result<T>> + Send;
+    /// Puts every value an import touched back the way it was before that
+    /// import, returns the ids of the values that were reverted. Values changed
+    /// again since the import fail it with `ChangedSince` unless `force` is set.
+    fn undo_import(
+        &self,
+        import_id: String,
+        force: bool,
+        context: ChangeContext,
+    ) -> impl std::future::Future<Output = Result<Vec<String>>> + Send;
+}
+
+/// One write out of a batch given to `DataSource::apply_all`
+#[derive(Debug)]
+pub enum Change<T, Id> {
+    Create(T),
+    Update(T),
+    Decommission { id: Id, reason: String },
+    Delete(Id),
+}
+
+/// One page of a listing, `total` counts every match regardless of the page size
+#[derive(Debug, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: i64,
+    pub next_cursor: Option<String>,
+}
+
+/// Filters, sort order and cursor for listing circuits. Without a `limit`
+/// every matching circuit is returned in a single page.
+#[derive(Debug, Clone, Default)]
+pub struct CircuitQuery {
+    pub include_decommissioned: bool,
+    pub state: Option<String>,
+    pub provider: Option<String>,
+    pub link_type: Option<String>,
+    pub site_name: Option<String>,
+    /// Free text matched against every text and address column
+    pub search: Option<String>,
+    /// Applied in order, `id` is always appended as the final tie breaker
+    pub sort: Vec<SortKey>,
+    /// Id of the last circuit on the previous page
+    pub cursor: Option<ulid::Ulid>,
+    pub limit: Option<i64>,
+    /// Lists the inventory as it was at this time instead of as it is now
+    pub as_of: Option<DateTime<Utc>>,
+    /// Only the circuits in scope match, all of them when `None`
+    pub scope: Option<CircuitScope>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SortKey {
+    pub column: CircuitColumn,
+    pub descending: bool,
+}
+
+/// Parses `column` or `-column` for descending order
+impl std::str::FromStr for SortKey {
+    type Err = eyre::Report;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (column, descending) = match value.trim().strip_prefix('-') {
+            Some(column) => (column, true),
+            None => (value.trim(), false),
+        };
+
+        Ok(SortKey {
+            column: column.parse()?,
+            descending,
+        })
+    }
+}
+
+/// Every column of `Circuit`, in the order they are declared and exported
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum CircuitColumn {
+    Id,
+    State,
+    SiteName,
+    CktId,
+    Parent,
+    LinkType,
+    Provider,
+    ZLoc,
+    RtrNameZLoc,
+    ToDescription,
+    RtrPortZLoc,
+    InterfIpZLoc,
+    ALoc,
+    RtrNameALoc,
+    RtrPort,
+    InterfIpALoc,
+    BwMbps,
+    SingleIsp,
+    UpsCloset,
+    RouterIp,
+    DecommissionedAt,
+    DecommissionReason,
+}
+
+impl CircuitColumn {
+    pub const ALL: [CircuitColumn; 22] = [
+        CircuitColumn::Id,
+        CircuitColumn::State,
+        CircuitColumn::SiteName,
+        CircuitColumn::CktId,
+        CircuitColumn::Parent,
+        CircuitColumn::LinkType,
+        CircuitColumn::Provider,
+        CircuitColumn::ZLoc,
+        CircuitColumn::RtrNameZLoc,
+        CircuitColumn::ToDescription,
+        CircuitColumn::RtrPortZLoc,
+        CircuitColumn::InterfIpZLoc,
+        CircuitColumn::ALoc,
+        CircuitColumn::RtrNameALoc,
+        CircuitColumn::RtrPort,
+        CircuitColumn::InterfIpALoc,
+        CircuitColumn::BwMbps,
+        CircuitColumn::SingleIsp,
+        CircuitColumn::UpsCloset,
+        CircuitColumn::RouterIp,
+        CircuitColumn::DecommissionedAt,
+        CircuitColumn::DecommissionReason,
+    ];
+
+    /// The field name, which is also the database column and CSV header
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CircuitColumn::Id => "id",
+            CircuitColumn::State => "state",
+            CircuitColumn::SiteName => "site_name",
+            CircuitColumn::CktId => "ckt_id",
+            CircuitColumn::Parent => "parent",
+            CircuitColumn::LinkType => "link_type",
+            CircuitColumn::Provider => "provider",
+            CircuitColumn::ZLoc => "z_loc",
+            CircuitColumn::RtrNameZLoc => "rtr_name_z_loc",
+            CircuitColumn::ToDescription => "to_description",
+            CircuitColumn::RtrPortZLoc => "rtr_port_z_loc",
+            CircuitColumn::InterfIpZLoc => "interf_ip_z_loc",
+            CircuitColumn::ALoc => "a_loc",
+            CircuitColumn::RtrNameALoc => "rtr_name_a_loc",
+            CircuitColumn::RtrPort => "rtr_port",
+            CircuitColumn::InterfIpALoc => "interf_ip_a_loc",
+            CircuitColumn::BwMbps => "bw_mbps",
+            CircuitColumn::SingleIsp => "single_isp",
+            CircuitColumn::UpsCloset => "ups_closet",
+            CircuitColumn::RouterIp => "router_ip",
+            CircuitColumn::DecommissionedAt => "decommissioned_at",
+            CircuitColumn::DecommissionReason => "decommission_reason",
+        }
+    }
+}
+
+impl std::str::FromStr for CircuitColumn {
+    type Err = eyre::Report;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        CircuitColumn::ALL
+            .into_iter()
+            .find(|column| column.as_str() == value)
+            .ok_or_else(|| eyre::Report::msg(format!("Unknown circuit column `{value}`")))
+    }
+}
+
+impl TryFrom<String> for CircuitColumn {
+    type Error = eyre::Report;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<CircuitColumn> for String {
+    fn from(value: CircuitColumn) -> Self {
+        value.as_str().to_string()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SearchQuery {
+    pub text: String,
+    pub limit: i64,
+    pub include_decommissioned: bool,
+    pub scope: Option<CircuitScope>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchResult<T> {
+    pub item: T,
+    pub rank: f32,
+    pub highlights: Vec<Highlight>,
+}
+
+/// Where a search term was found in a field, split so clients can render the
+/// match without having to deal with offsets
+#[derive(Debug, Serialize)]
+pub struct Highlight {
+    pub field: &'static str,
+    pub before: String,
+    pub matched: String,
+    pub after: String,
+}
+
+/// Lower cases and drops everything but ASCII letters and digits, mirrors the
+/// `normalize_search` database function
+pub fn normalize_search(value: &str) -> String {
+    value
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+const HIGHLIGHT_CONTEXT: usize = 24;
+
+impl Highlight {
+    /// Finds the normalized `term` in `value` and maps the match back onto the
+    /// original text, punctuation inside the match included
+    pub fn find(field: &'static str, value: &str, term: &str) -> Option<Highlight> {
+        if term.is_empty() {
+            return None;
+        }
+
+        let positions: Vec<(usize, char)> = value
+            .char_indices()
+            .filter(|(_, c)| c.is_ascii_alphanumeric())
+            .collect();
+        let normalized: String = positions
+            .iter()
+            .map(|(_, c)| c.to_ascii_lowercase())
+            .collect();
+
+        // Every normalized char is ASCII so byte offsets are char offsets here
+        let start = normalized.find(term)?;
+        let (match_start, _) = positions[start];
+        let (last_start, last) = positions[start + term.len() - 1];
+        let match_end = last_start + last.len_utf8();
+
+        let before: String = value[..match_start]
+            .chars()
+            .rev()
+            .take(HIGHLIGHT_CONTEXT)
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .collect();
+        let after: String = value[match_end..].chars().take(HIGHLIGHT_CONTEXT).collect();
+
+        Some(Highlight {
+            field,
+            before: if before.len() < match_start {
+                format!("…{before}")
+            } else {
+                before
+            },
+            matched: value[match_start..match_end].to_string(),
+            after: if match_end + after.len() < value.len() {
+                format!("{after}…")
+            } else {
+                after
+            },
+        })
+    }
 }
 
-#[derive(Serialize)]
+/// Progress and errors of an import. Errors point at the import they belong to
+/// and, as far as it is known, the CSV line, column and value that caused them.
+#[derive(Serialize, Default, Clone)]
 pub struct CircuitImportReport {
     pub r#type: String,
     pub id: String,
     pub message: String,
     pub file_name: Option<String>,
+    pub import_id: Option<String>,
+    pub line: Option<i64>,
+    pub column: Option<String>,
+    pub raw_value: Option<String>,
+    pub category: Option<String>,
+    /// Header and values of the failed row
+    #[serde(skip)]
+    pub headers: Option<Vec<String>>,
+    #[serde(skip)]
+    pub record: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportErrorCategory {
+    /// The row isn't valid CSV or doesn't fit the header
+    Csv,
+    /// A field holds a value that can't be used
+    Validation,
+    /// The row refers to a circuit that doesn't exist
+    NotFound,
+    /// The row's natural key is shared with other rows or circuits
+    DuplicateKey,
+    /// The row would claim a circuit ID, address or router port another circuit has
+    Conflict,
+    /// The row touches a circuit outside the scope of whoever imported it
+    OutOfScope,
+    /// The database refused the change
+    Database,
+}
+
+impl ImportErrorCategory {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImportErrorCategory::Csv => "csv",
+            ImportErrorCategory::Validation => "validation",
+            ImportErrorCategory::NotFound => "not_found",
+            ImportErrorCategory::DuplicateKey => "duplicate_key",
+            ImportErrorCategory::Conflict => "conflict",
+            ImportErrorCategory::OutOfScope => "out_of_scope",
+            ImportErrorCategory::Database => "database",
+        }
+    }
+}
+
+/// Who made a change and through what, stored with every revision
+#[derive(Debug, Clone)]
+pub struct ChangeContext {
+    pub actor: String,
+    pub source: ChangeSource,
+    /// The circuits the actor may change, any of them when `None`
+    pub scope: Option<CircuitScope>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeSource {
+    /// Made by someone logged in with a session
+    Ui,
+    /// Made with an API key
+    Api,
+    Import {
+        file_name: Option<String>,
+        import_id: String,
+    },
+}
+
+impl ChangeSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChangeSource::Ui => "ui",
+            ChangeSource::Api => "api",
+            ChangeSource::Import { .. } => "import",
+        }
+    }
+
+    pub fn file_name(&self) -> Option<&str> {
+        match self {
+            ChangeSource::Import { file_name, .. } => file_name.as_deref(),
+            _ => None,
+        }
+    }
+
+    pub fn import_id(&self) -> Option<&str> {
+        match self {
+            ChangeSource::Import { import_id, .. } => Some(import_id),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeAction {
+    Create,
+    Update,
+    Decommission,
+    Delete,
+    Revert,
+}
+
+impl ChangeAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChangeAction::Create => "create",
+            ChangeAction::Update => "update",
+            ChangeAction::Decommission => "decommission",
+            ChangeAction::Delete => "delete",
+            ChangeAction::Revert => "revert",
+        }
+    }
+}
+
+impl std::str::FromStr for ChangeAction {
+    type Err = eyre::Report;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "create" => Ok(ChangeAction::Create),
+            "update" => Ok(ChangeAction::Update),
+            "decommission" => Ok(ChangeAction::Decommission),
+            "delete" => Ok(ChangeAction::Delete),
+            "revert" => Ok(ChangeAction::Revert),
+            _ => Err(eyre::Report::msg(format!(
+                "Unknown change action `{value}`"
+            ))),
+        }
+    }
+}
+
+/// Old and new text form of one field, `None` when the field was empty
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FieldChange {
+    pub field: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+impl FieldChange {
+    /// Fields that differ between two versions of a circuit, a missing old or
+    /// new version means the circuit was created or deleted
+    pub fn diff(old: Option<&Circuit>, new: Option<&Circuit>) -> Vec<FieldChange> {
+        let value = |circuit: Option<&Circuit>, column| {
+            circuit
+                .map(|circuit| circuit.field(column))
+                .filter(|value| !value.is_empty())
+        };
+
+        CircuitColumn::ALL
+            .iter()
+            .filter(|column| **column != CircuitColumn::Id)
+            .filter_map(|column| {
+                let old = value(old, *column);
+                let new = value(new, *column);
+
+                (old != new).then(|| FieldChange {
+                    field: column.as_str().to_string(),
+                    old,
+                    new,
+                })
+            })
+            .collect()
+    }
+}
+
+/// A single recorded change, `snapshot` is the value as it was right after it
+/// and is missing for deletions
+#[derive(Debug, Serialize)]
+pub struct Revision<T> {
+    pub revision: i64,
+    pub item_id: String,
+    pub action: ChangeAction,
+    pub actor: String,
+    pub source: String,
+    pub file_name: Option<String>,
+    pub import_id: Option<String>,
+    pub changed_at: DateTime<Utc>,
+    pub changes: Vec<FieldChange>,
+    pub snapshot: Option<T>,
+}
+
+/// Narrows down the audit log, `before` is a revision number to page backwards from
+#[derive(Debug, Clone)]
+pub struct AuditFilter {
+    pub actor: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub before: Option<i64>,
+    pub limit: i64,
+}
+
+pub struct CircuitMigrationIssue {
+    pub circuit_id: String,
+    pub field: String,
+    pub raw_value: String,
+    pub message: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, FromRow)]
 pub struct Circuit {
     pub id: String,
-    pub state: String,
+    pub state: Option<CircuitState>,
     pub site_name: String,
     pub ckt_id: String,
     pub parent: String,
@@ -57,24 +915,414 @@ pub struct Circuit {
     pub rtr_name_z_loc: String,
     pub to_description: String,
     pub rtr_port_z_loc: String,
-    pub interf_ip_z_loc: String,
+    pub interf_ip_z_loc: Option<IpAddr>,
     pub a_loc: String,
     pub rtr_name_a_loc: String,
     pub rtr_port: String,
-    pub interf_ip_a_loc: String,
-    pub bw_mbps: String,
-    pub single_isp: String,
+    pub interf_ip_a_loc: Option<IpAddr>,
+    pub bw_mbps: Option<Bandwidth>,
+    pub single_isp: bool,
     pub ups_closet: String,
-    pub router_ip: String,
+    pub router_ip: Option<IpAddr>,
+    pub decommissioned_at: Option<DateTime<Utc>>,
+    pub decommission_reason: Option<String>,
 }
 
-#[derive(Clone)]
-pub struct AppState<T, S>
-where
-    S: DataSource<T>,
-    T: Send + Sync,
-{
+impl Circuit {
+    /// Text form of a single field, empty for missing values
+    pub fn field(&self, column: CircuitColumn) -> String {
+        fn optional<T: ToString>(value: &Option<T>) -> String {
+            value.as_ref().map(ToString::to_string).unwrap_or_default()
+        }
+
+        match column {
+            CircuitColumn::Id => self.id.clone(),
+            CircuitColumn::State => optional(&self.state),
+            CircuitColumn::SiteName => self.site_name.clone(),
+            CircuitColumn::CktId => self.ckt_id.clone(),
+            CircuitColumn::Parent => self.parent.clone(),
+            CircuitColumn::LinkType => self.link_type.clone(),
+            CircuitColumn::Provider => self.provider.clone(),
+            CircuitColumn::ZLoc => self.z_loc.clone(),
+            CircuitColumn::RtrNameZLoc => self.rtr_name_z_loc.clone(),
+            CircuitColumn::ToDescription => self.to_description.clone(),
+            CircuitColumn::RtrPortZLoc => self.rtr_port_z_loc.clone(),
+            CircuitColumn::InterfIpZLoc => optional(&self.interf_ip_z_loc),
+            CircuitColumn::ALoc => self.a_loc.clone(),
+            CircuitColumn::RtrNameALoc => self.rtr_name_a_loc.clone(),
+            CircuitColumn::RtrPort => self.rtr_port.clone(),
+            CircuitColumn::InterfIpALoc => optional(&self.interf_ip_a_loc),
+            CircuitColumn::BwMbps => optional(&self.bw_mbps.map(|bw| bw.0)),
+            CircuitColumn::SingleIsp => self.single_isp.to_string(),
+            CircuitColumn::UpsCloset => self.ups_closet.clone(),
+            CircuitColumn::RouterIp => optional(&self.router_ip),
+            CircuitColumn::DecommissionedAt => optional(&self.decommissioned_at),
+            CircuitColumn::DecommissionReason => optional(&self.decommission_reason),
+        }
+    }
+}
+
+/// Lifecycle of a circuit, rollout phases are written as `R<release>` optionally
+/// followed by `S<stage>` or `-PP`, e.g. `R1S2`
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum CircuitState {
+    Active,
+    New,
+    Tba,
+    NoUm,
+    Rollout(String),
+}
+
+impl std::str::FromStr for CircuitState {
+    type Err = eyre::Report;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+
+        match value.to_lowercase().as_str() {
+            "active" => return Ok(Self::Active),
+            "new" => return Ok(Self::New),
+            "tba" => return Ok(Self::Tba),
+            "no um" => return Ok(Self::NoUm),
+            _ => {}
+        }
+
+        let phase = value.to_uppercase();
+        let is_rollout = phase
+            .strip_prefix('R')
+            .and_then(|rest| {
+                let release_len = rest.chars().take_while(char::is_ascii_digit).count();
+                (release_len > 0).then_some(&rest[release_len..])
+            })
+            .is_some_and(|suffix| {
+                suffix.is_empty()
+                    || suffix == "-PP"
+                    || suffix.strip_prefix('S').is_some_and(|stage| {
+                        !stage.is_empty() && stage.chars().all(|c| c.is_ascii_digit())
+                    })
+            });
+
+        if is_rollout {
+            Ok(Self::Rollout(phase))
+        } else {
+            Err(eyre::Report::msg(
+                "not a known circuit state, expected Active, New, TBA, No UM or a rollout phase like R1S2",
+            ))
+        }
+    }
+}
+
+impl std::fmt::Display for CircuitState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CircuitState::Active => f.write_str("Active"),
+            CircuitState::New => f.write_str("New"),
+            CircuitState::Tba => f.write_str("TBA"),
+            CircuitState::NoUm => f.write_str("No UM"),
+            CircuitState::Rollout(phase) => f.write_str(phase),
+        }
+    }
+}
+
+impl TryFrom<String> for CircuitState {
+    type Error = eyre::Report;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<CircuitState> for String {
+    fn from(value: CircuitState) -> Self {
+        value.to_string()
+    }
+}
+
+/// Bandwidth of a circuit, stored in Mbps
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, sqlx::Type,
+)]
+#[serde(transparent)]
+#[sqlx(transparent)]
+pub struct Bandwidth(i64);
+
+/// Accepts plain Mbps with or without thousands separators ("1,000") as well as
+/// an explicit unit ("500 Mbps", "10G", "1.5 Gbps")
+impl std::str::FromStr for Bandwidth {
+    type Err = eyre::Report;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            eyre::Report::msg(
+                "not a valid bandwidth, expected Mbps like 1,000 or a value with a unit like 10 Gbps",
+            )
+        };
+
+        let normalized = value.trim().replace(',', "").to_lowercase();
+        let unit_start = normalized
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(normalized.len());
+        let (amount, unit) = normalized.split_at(unit_start);
+
+        let amount: f64 = amount.parse().map_err(|_| invalid())?;
+        let multiplier = match unit.trim() {
+            "" | "m" | "mb" | "mbps" | "mbit/s" => 1.0,
+            "g" | "gb" | "gbps" | "gbit/s" => 1000.0,
+            _ => return Err(invalid()),
+        };
+
+        Ok(Bandwidth((amount * multiplier).round() as i64))
+    }
+}
+
+impl Bandwidth {
+    pub fn mbps(&self) -> i64 {
+        self.0
+    }
+}
+
+impl std::fmt::Display for Bandwidth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0 >= 1000 && self.0 % 1000 == 0 {
+            write!(f, "{} Gbps", self.0 / 1000)
+        } else {
+            write!(f, "{} Mbps", self.0)
+        }
+    }
+}
+
+/// A single field that failed validation at the API or import boundary
+#[derive(Debug, Serialize)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub value: String,
+    pub message: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ValidationErrors(pub Vec<FieldError>);
+
+impl ValidationErrors {
+    fn check<T>(&mut self, field: &'static str, value: &str, result: Result<T>) -> Option<T> {
+        match result {
+            Ok(parsed) => Some(parsed),
+            Err(e) => {
+                self.0.push(FieldError {
+                    field,
+                    value: value.to_string(),
+                    message: e.to_string(),
+                });
+                None
+            }
+        }
+    }
+
+    fn into_result<T>(self, value: T) -> std::result::Result<T, ValidationErrors> {
+        if self.0.is_empty() {
+            Ok(value)
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl std::fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Invalid circuit: ")?;
+
+        for (i, error) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str("; ")?;
+            }
+            write!(
+                f,
+                "{}: {} (got `{}`)",
+                error.field, error.message, error.value
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationErrors {}
+
+/// A listing was asked to continue after a circuit that isn't there, e.g.
+/// because it was deleted since the previous page
+#[derive(Debug)]
+pub struct UnknownCursor(pub String);
+
+impl std::fmt::Display for UnknownCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unknown cursor {}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownCursor {}
+
+/// Undoing an import would throw away later changes to these circuits
+#[derive(Debug)]
+pub struct ChangedSince(pub Vec<String>);
+
+impl std::fmt::Display for ChangedSince {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Circuits changed after the import, undo with force to overwrite them: {}",
+            self.0.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for ChangedSince {}
+
+/// Something was looked up by an id that doesn't exist, `kind` says what
+#[derive(Debug)]
+pub struct NotFound {
+    pub kind: &'static str,
+    pub id: String,
+}
+
+impl NotFound {
+    pub fn new(kind: &'static str, id: impl ToString) -> NotFound {
+        NotFound {
+            kind,
+            id: id.to_string(),
+        }
+    }
+
+    /// A change referred to a circuit that doesn't exist
+    pub fn circuit(id: impl ToString) -> NotFound {
+        NotFound::new("circuit", id)
+    }
+}
+
+impl std::fmt::Display for NotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "No {} with id {}", self.kind, self.id)
+    }
+}
+
+impl std::error::Error for NotFound {}
+
+/// A circuit would share its natural key with another one, or an imported row
+/// can't tell which circuit its natural key stands for
+#[derive(Debug)]
+pub struct DuplicateKey(pub String);
+
+impl std::fmt::Display for DuplicateKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for DuplicateKey {}
+
+/// A value can't be deleted while others still refer to it
+#[derive(Debug)]
+pub struct StillInUse(pub String);
+
+impl std::fmt::Display for StillInUse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for StillInUse {}
+
+/// Something was to be created under a name that's already taken
+#[derive(Debug)]
+pub struct AlreadyExists(pub String);
+
+impl std::fmt::Display for AlreadyExists {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for AlreadyExists {}
+
+/// A change would touch a circuit outside the scope of whoever made it
+#[derive(Debug)]
+pub struct OutOfScope(pub String);
+
+impl std::fmt::Display for OutOfScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} is outside of your access scope", self.0)
+    }
+}
+
+impl std::error::Error for OutOfScope {}
+
+/// Imports started by this process. They run one at a time, the rest wait
+/// for their turn queued.
+#[derive(Clone)]
+pub struct RunningImports {
+    turn: std::sync::Arc<tokio::sync::Semaphore>,
+    cancellations: std::sync::Arc<
+        std::sync::Mutex<std::collections::HashMap<String, tokio::sync::watch::Sender<bool>>>,
+    >,
+}
+
+impl Default for RunningImports {
+    fn default() -> Self {
+        RunningImports {
+            turn: std::sync::Arc::new(tokio::sync::Semaphore::new(1)),
+            cancellations: Default::default(),
+        }
+    }
+}
+
+impl RunningImports {
+    /// Returns what the import watches to know it has been cancelled
+    pub fn register(&self, id: String) -> tokio::sync::watch::Receiver<bool> {
+        let (sender, receiver) = tokio::sync::watch::channel(false);
+        self.cancellations
+            .lock()
+            .expect("Import cancellations lock poisoned")
+            .insert(id, sender);
+        receiver
+    }
+
+    /// Waits until no other import is running
+    pub async fn wait_turn(&self) -> tokio::sync::OwnedSemaphorePermit {
+        self.turn
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Import semaphore is never closed")
+    }
+
+    /// Asks the import to stop, false if it isn't running in this process
+    pub fn cancel(&self, id: &str) -> bool {
+        self.cancellations
+            .lock()
+            .expect("Import cancellations lock poisoned")
+            .get(id)
+            .is_some_and(|sender| sender.send(true).is_ok())
+    }
+
+    pub fn remove(&self, id: &str) {
+        self.cancellations
+            .lock()
+            .expect("Import cancellations lock poisoned")
+            .remove(id);
+    }
+}
+
+#[derive(Clone)]
+pub struct AppState<T, S>
+where
+    S: DataSource<T>,
+    T: Send + Sync,
+{
     pub data_source: S,
+    pub imports: RunningImports,
+    pub upload_limits: UploadLimits,
+    pub conflict_policy: ConflictPolicy,
+    pub token_lifetimes: TokenLifetimes,
+    pub lockout: LockoutPolicy,
     _marker: std::marker::PhantomData<T>,
 }
 
@@ -86,6 +1334,11 @@ where
     pub fn new(data_source: S) -> AppState<T, S> {
         AppState {
             data_source,
+            imports: RunningImports::default(),
+            upload_limits: UploadLimits::from_env(),
+            conflict_policy: ConflictPolicy::from_env(),
+            token_lifetimes: TokenLifetimes::from_env(),
+            lockout: LockoutPolicy::from_env(),
             _marker: std::marker::PhantomData,
         }
     }
@@ -95,7 +1348,7 @@ impl From<CircuitDTO> for Circuit {
     fn from(value: CircuitDTO) -> Self {
         Circuit {
             id: ulid::Ulid::new().to_string(),
-            state: value.state.unwrap_or_default(),
+            state: value.state,
             site_name: value.site_name.unwrap_or_default(),
             ckt_id: value.ckt_id.unwrap_or_default(),
             parent: value.parent.unwrap_or_default(),
@@ -105,38 +1358,1828 @@ impl From<CircuitDTO> for Circuit {
             rtr_name_z_loc: value.rtr_name_z_loc.unwrap_or_default(),
             to_description: value.to_description.unwrap_or_default(),
             rtr_port_z_loc: value.rtr_port_z_loc.unwrap_or_default(),
-            interf_ip_z_loc: value.interf_ip_z_loc.unwrap_or_default(),
+            interf_ip_z_loc: value.interf_ip_z_loc,
             a_loc: value.a_loc.unwrap_or_default(),
             rtr_name_a_loc: value.rtr_name_a_loc.unwrap_or_default(),
             rtr_port: value.rtr_port.unwrap_or_default(),
-            interf_ip_a_loc: value.interf_ip_a_loc.unwrap_or_default(),
-            bw_mbps: value.bw_mbps.unwrap_or_default(),
+            interf_ip_a_loc: value.interf_ip_a_loc,
+            bw_mbps: value.bw_mbps,
             single_isp: value.single_isp.unwrap_or_default(),
             ups_closet: value.ups_closet.unwrap_or_default(),
-            router_ip: value.router_ip.unwrap_or_default(),
+            router_ip: value.router_ip,
+            decommissioned_at: None,
+            decommission_reason: None,
         }
     }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct CircuitDTO {
+    pub state: Option<CircuitState>,
+    pub site_name: Option<String>,
+    pub ckt_id: Option<String>,
+    pub parent: Option<String>,
+    pub link_type: Option<String>,
+    pub provider: Option<String>,
+    pub z_loc: Option<String>,
+    pub rtr_name_z_loc: Option<String>,
+    pub to_description: Option<String>,
+    pub rtr_port_z_loc: Option<String>,
+    pub interf_ip_z_loc: Option<IpAddr>,
+    pub a_loc: Option<String>,
+    pub rtr_name_a_loc: Option<String>,
+    pub rtr_port: Option<String>,
+    pub interf_ip_a_loc: Option<IpAddr>,
+    pub bw_mbps: Option<Bandwidth>,
+    pub single_isp: Option<bool>,
+    pub ups_closet: Option<String>,
+    pub router_ip: Option<IpAddr>,
+}
+
+/// A circuit as it arrives from a client or a CSV row, before any of its fields
+/// have been validated. Converting it into a `CircuitDTO` or `Circuit` reports
+/// every invalid field at once.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RawCircuit {
+    #[serde(deserialize_with = "lenient_string")]
+    pub id: Option<String>,
+    #[serde(deserialize_with = "lenient_string")]
     pub state: Option<String>,
+    #[serde(deserialize_with = "lenient_string")]
     pub site_name: Option<String>,
+    #[serde(deserialize_with = "lenient_string")]
     pub ckt_id: Option<String>,
+    #[serde(deserialize_with = "lenient_string")]
     pub parent: Option<String>,
+    #[serde(deserialize_with = "lenient_string")]
     pub link_type: Option<String>,
+    #[serde(deserialize_with = "lenient_string")]
     pub provider: Option<String>,
+    #[serde(deserialize_with = "lenient_string")]
     pub z_loc: Option<String>,
+    #[serde(deserialize_with = "lenient_string")]
     pub rtr_name_z_loc: Option<String>,
+    #[serde(deserialize_with = "lenient_string")]
     pub to_description: Option<String>,
+    #[serde(deserialize_with = "lenient_string")]
     pub rtr_port_z_loc: Option<String>,
+    #[serde(deserialize_with = "lenient_string")]
     pub interf_ip_z_loc: Option<String>,
+    #[serde(deserialize_with = "lenient_string")]
     pub a_loc: Option<String>,
+    #[serde(deserialize_with = "lenient_string")]
     pub rtr_name_a_loc: Option<String>,
+    #[serde(deserialize_with = "lenient_string")]
     pub rtr_port: Option<String>,
+    #[serde(deserialize_with = "lenient_string")]
     pub interf_ip_a_loc: Option<String>,
+    #[serde(deserialize_with = "lenient_string")]
     pub bw_mbps: Option<String>,
+    #[serde(deserialize_with = "lenient_string")]
     pub single_isp: Option<String>,
+    #[serde(deserialize_with = "lenient_string")]
     pub ups_closet: Option<String>,
+    #[serde(deserialize_with = "lenient_string")]
     pub router_ip: Option<String>,
+    /// Only read from CSV imports, see `ImportAction`
+    #[serde(deserialize_with = "lenient_string")]
+    pub action: Option<String>,
+    #[serde(deserialize_with = "lenient_string")]
+    pub decommission_reason: Option<String>,
+}
+
+impl RawCircuit {
+    /// The value given for a column of the circuit, if any
+    pub fn field(&self, column: CircuitColumn) -> Option<&str> {
+        let value = match column {
+            CircuitColumn::Id => &self.id,
+            CircuitColumn::State => &self.state,
+            CircuitColumn::SiteName => &self.site_name,
+            CircuitColumn::CktId => &self.ckt_id,
+            CircuitColumn::Parent => &self.parent,
+            CircuitColumn::LinkType => &self.link_type,
+            CircuitColumn::Provider => &self.provider,
+            CircuitColumn::ZLoc => &self.z_loc,
+            CircuitColumn::RtrNameZLoc => &self.rtr_name_z_loc,
+            CircuitColumn::ToDescription => &self.to_description,
+            CircuitColumn::RtrPortZLoc => &self.rtr_port_z_loc,
+            CircuitColumn::InterfIpZLoc => &self.interf_ip_z_loc,
+            CircuitColumn::ALoc => &self.a_loc,
+            CircuitColumn::RtrNameALoc => &self.rtr_name_a_loc,
+            CircuitColumn::RtrPort => &self.rtr_port,
+            CircuitColumn::InterfIpALoc => &self.interf_ip_a_loc,
+            CircuitColumn::BwMbps => &self.bw_mbps,
+            CircuitColumn::SingleIsp => &self.single_isp,
+            CircuitColumn::UpsCloset => &self.ups_closet,
+            CircuitColumn::RouterIp => &self.router_ip,
+            CircuitColumn::DecommissionedAt => return None,
+            CircuitColumn::DecommissionReason => &self.decommission_reason,
+        };
+
+        value.as_deref()
+    }
+
+    /// The id column if it holds anything, an empty id means the row is new
+    pub fn id(&self) -> Option<&str> {
+        self.id
+            .as_deref()
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+    }
+
+    pub fn action(&self) -> std::result::Result<ImportAction, ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+
+        let action = match self.action.as_deref().map(str::trim) {
+            None | Some("") => Some(ImportAction::Upsert),
+            Some(action) => errors.check("action", action, action.parse()),
+        };
+
+        action.ok_or(errors)
+    }
+
+    /// The id of the circuit a decommission or delete row acts on
+    pub fn required_id(&self) -> std::result::Result<ulid::Ulid, ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+
+        let id = match self.id() {
+            None => errors.check(
+                "id",
+                "",
+                Err::<ulid::Ulid, _>(eyre::Report::msg(
+                    "rows that decommission or delete need an id",
+                )),
+            ),
+            Some(id) => errors.check(
+                "id",
+                id,
+                id.parse()
+                    .map_err(|_| eyre::Report::msg("not a valid circuit id")),
+            ),
+        };
+
+        id.ok_or(errors)
+    }
+}
+
+/// How an import deals with rows that fail
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportMode {
+    /// Nothing is written unless every row succeeds
+    #[default]
+    Atomic,
+    /// Every row that can be applied is, failing rows are skipped
+    BestEffort,
+}
+
+impl ImportMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImportMode::Atomic => "atomic",
+            ImportMode::BestEffort => "best_effort",
+        }
+    }
+}
+
+impl std::str::FromStr for ImportMode {
+    type Err = eyre::Report;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "atomic" => Ok(ImportMode::Atomic),
+            "best_effort" => Ok(ImportMode::BestEffort),
+            _ => Err(eyre::Report::msg(format!("Unknown import mode `{value}`"))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl ImportStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImportStatus::Queued => "queued",
+            ImportStatus::Running => "running",
+            ImportStatus::Succeeded => "succeeded",
+            ImportStatus::Failed => "failed",
+            ImportStatus::Cancelled => "cancelled",
+        }
+    }
+
+    /// Whether the job has stopped and won't change anymore
+    pub fn is_finished(&self) -> bool {
+        !matches!(self, ImportStatus::Queued | ImportStatus::Running)
+    }
+}
+
+impl std::str::FromStr for ImportStatus {
+    type Err = eyre::Report;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "queued" => Ok(ImportStatus::Queued),
+            "running" => Ok(ImportStatus::Running),
+            "succeeded" => Ok(ImportStatus::Succeeded),
+            "failed" => Ok(ImportStatus::Failed),
+            "cancelled" => Ok(ImportStatus::Cancelled),
+            _ => Err(eyre::Report::msg(format!(
+                "Unknown import status `{value}`"
+            ))),
+        }
+    }
+}
+
+/// Rows an import has gone through so far and what came of them
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct ImportProgress {
+    pub processed: u64,
+    pub created: u64,
+    pub updated: u64,
+    pub decommissioned: u64,
+    pub deleted: u64,
+    pub failed: u64,
+}
+
+impl ImportProgress {
+    /// Counts a row that went through, `change` is what it did
+    pub fn applied<T, Id>(&mut self, change: &Change<T, Id>) {
+        let counter = match change {
+            Change::Create(_) => &mut self.created,
+            Change::Update(_) => &mut self.updated,
+            Change::Decommission { .. } => &mut self.decommissioned,
+            Change::Delete(_) => &mut self.deleted,
+        };
+        *counter += 1;
+        self.processed += 1;
+    }
+
+    pub fn failed(&mut self) {
+        self.failed += 1;
+        self.processed += 1;
+    }
+}
+
+/// A background import, `id` is also the import id of its reports and revisions
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportJob {
+    pub id: String,
+    pub file_name: Option<String>,
+    pub mode: ImportMode,
+    pub status: ImportStatus,
+    pub started_by: String,
+    pub message: Option<String>,
+    pub total_rows: Option<u64>,
+    #[serde(flatten)]
+    pub progress: ImportProgress,
+    pub queued_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub mapping_id: Option<String>,
+}
+
+/// A previewed CSV upload waiting to be committed
+pub struct StagedImport {
+    pub id: String,
+    pub file_name: Option<String>,
+    pub data: String,
+    pub staged_by: String,
+    /// Column mapping the preview was worked out with
+    pub mapping_id: Option<String>,
+}
+
+/// A saved translation of the headers of a carrier or inventory spreadsheet
+/// onto the columns imports understand
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ColumnMapping {
+    /// Assigned when the mapping is saved
+    #[serde(default)]
+    pub id: String,
+    pub name: String,
+    pub columns: Vec<MappedColumn>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MappedColumn {
+    /// Header in the source file, matched ignoring case and surrounding spaces
+    pub source: String,
+    /// Circuit field, or `action`, the values end up in
+    pub field: String,
+    /// Applied to every value in order
+    #[serde(default)]
+    pub transforms: Vec<ValueTransform>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ValueTransform {
+    Trim,
+    Uppercase,
+    Lowercase,
+    /// Drops a trailing unit, `100 Mbps` becomes `100`. Only right when the
+    /// column already says what the unit is.
+    StripUnit,
+    Replace {
+        from: String,
+        to: String,
+    },
+    /// Fills in empty values
+    Default {
+        value: String,
+    },
+}
+
+impl ValueTransform {
+    pub fn apply(&self, value: &str) -> String {
+        match self {
+            ValueTransform::Trim => value.trim().to_string(),
+            ValueTransform::Uppercase => value.to_uppercase(),
+            ValueTransform::Lowercase => value.to_lowercase(),
+            ValueTransform::StripUnit => {
+                let stripped = value
+                    .trim_end_matches(|c: char| c.is_alphabetic() || c.is_whitespace() || c == '/');
+                // Anything that isn't a number with a unit is left for validation to report
+                if stripped.ends_with(|c: char| c.is_ascii_digit()) {
+                    stripped.to_string()
+                } else {
+                    value.to_string()
+                }
+            }
+            ValueTransform::Replace { from, to } => value.replace(from.as_str(), to),
+            ValueTransform::Default { value: default } => {
+                if value.trim().is_empty() {
+                    default.clone()
+                } else {
+                    value.to_string()
+                }
+            }
+        }
+    }
+}
+
+impl ColumnMapping {
+    /// Columns of a `RawCircuit` a mapping can write to
+    fn is_import_field(field: &str) -> bool {
+        field == "action"
+            || field
+                .parse::<CircuitColumn>()
+                .is_ok_and(|column| column != CircuitColumn::DecommissionedAt)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = vec![];
+
+        if self.name.trim().is_empty() {
+            problems.push("the name can't be empty".to_string());
+        }
+
+        if self.columns.is_empty() {
+            problems.push("no columns are mapped".to_string());
+        }
+
+        for (i, column) in self.columns.iter().enumerate() {
+            if column.source.trim().is_empty() {
+                problems.push(format!("column {} has no source header", i + 1));
+            }
+
+            if !ColumnMapping::is_import_field(&column.field) {
+                problems.push(format!("`{}` is not a circuit field", column.field));
+            } else if self.columns[..i]
+                .iter()
+                .any(|earlier| earlier.field == column.field)
+            {
+                problems.push(format!("`{}` is mapped more than once", column.field));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(eyre::Report::msg(format!(
+                "Invalid column mapping: {}",
+                problems.join("; ")
+            )))
+        }
+    }
+
+    /// Works out how the rows of a file with `headers` are translated. Fails
+    /// when a mapped header isn't in the file.
+    pub fn mapper(&self, headers: &csv::StringRecord) -> Result<ColumnMapper> {
+        let normalize = |header: &str| header.trim().to_lowercase();
+        let position = |header: &str| {
+            headers
+                .iter()
+                .position(|candidate| normalize(candidate) == normalize(header))
+        };
+
+        let mut missing = vec![];
+        let mut columns = vec![];
+        for column in &self.columns {
+            match position(&column.source) {
+                Some(index) => {
+                    columns.push((column.field.clone(), index, column.transforms.clone()))
+                }
+                None => missing.push(column.source.as_str()),
+            }
+        }
+
+        if !missing.is_empty() {
+            return Err(eyre::Report::msg(format!(
+                "Column mapping `{}` expects headers that are not in the file: {}",
+                self.name,
+                missing.join(", ")
+            )));
+        }
+
+        // Headers the mapping doesn't mention pass through as they are, unless
+        // a mapped column already took their name
+        for (index, header) in headers.iter().enumerate() {
+            let used = columns.iter().any(|(field, source, _)| {
+                *source == index || normalize(field) == normalize(header)
+            });
+            if !used {
+                columns.push((header.to_string(), index, vec![]));
+            }
+        }
+
+        Ok(ColumnMapper { columns })
+    }
+}
+
+/// A `ColumnMapping` fitted to the headers of a particular file
+pub struct ColumnMapper {
+    /// Header written out, the column it's read from and what's done to the value
+    columns: Vec<(String, usize, Vec<ValueTransform>)>,
+}
+
+impl ColumnMapper {
+    pub fn headers(&self) -> csv::StringRecord {
+        self.columns.iter().map(|(field, _, _)| field).collect()
+    }
+
+    pub fn map(&self, record: &csv::StringRecord) -> csv::StringRecord {
+        self.columns
+            .iter()
+            .map(|(_, source, transforms)| {
+                let value = record.get(*source).unwrap_or_default();
+                transforms
+                    .iter()
+                    .fold(value.to_string(), |value, transform| {
+                        transform.apply(&value)
+                    })
+            })
+            .collect()
+    }
+}
+
+/// What committing an import would do, nothing in it has been written yet
+#[derive(Debug, Default, Serialize)]
+pub struct ImportPreview {
+    pub id: String,
+    pub file_name: Option<String>,
+    pub mapping_id: Option<String>,
+    pub created: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub decommissioned: usize,
+    pub deleted: usize,
+    pub failed: usize,
+    pub rows: Vec<PreviewRow>,
+}
+
+impl ImportPreview {
+    pub fn push(&mut self, row: PreviewRow) {
+        let counter = match row.action {
+            PlannedAction::Create => &mut self.created,
+            PlannedAction::Update => &mut self.updated,
+            PlannedAction::Unchanged => &mut self.unchanged,
+            PlannedAction::Decommission => &mut self.decommissioned,
+            PlannedAction::Delete => &mut self.deleted,
+            PlannedAction::Error => &mut self.failed,
+        };
+        *counter += 1;
+
+        self.rows.push(row);
+    }
+}
+
+/// Outcome of one CSV row, `line` is the line number in the uploaded file
+#[derive(Debug, Serialize)]
+pub struct PreviewRow {
+    pub line: u64,
+    pub action: PlannedAction,
+    pub circuit_id: Option<String>,
+    pub changes: Vec<FieldChange>,
+    pub error: Option<String>,
+    /// Conflicts the row would cause that don't stop it
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+}
+
+impl PreviewRow {
+    pub fn error(line: u64, circuit_id: Option<String>, error: eyre::Report) -> PreviewRow {
+        PreviewRow {
+            line,
+            action: PlannedAction::Error,
+            circuit_id,
+            changes: vec![],
+            error: Some(error.to_string()),
+            warnings: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlannedAction {
+    Create,
+    Update,
+    Unchanged,
+    Decommission,
+    Delete,
+    Error,
+}
+
+/// What an imported CSV row does to the circuit it names, set through the
+/// optional `action` column
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportAction {
+    /// Creates the circuit when the row has no id and updates it otherwise
+    Upsert,
+    /// Decommissions the circuit with the row's `decommission_reason`
+    Decommission,
+    /// Removes the circuit entirely
+    Delete,
+}
+
+impl std::str::FromStr for ImportAction {
+    type Err = eyre::Report;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "upsert" => Ok(Self::Upsert),
+            "decommission" => Ok(Self::Decommission),
+            "delete" => Ok(Self::Delete),
+            _ => Err(eyre::Report::msg(format!(
+                "Unknown import action `{value}`, expected upsert, decommission or delete"
+            ))),
+        }
+    }
+}
+
+impl TryFrom<RawCircuit> for CircuitDTO {
+    type Error = ValidationErrors;
+
+    fn try_from(value: RawCircuit) -> std::result::Result<Self, Self::Error> {
+        let mut errors = ValidationErrors::default();
+
+        let dto = CircuitDTO {
+            state: parse_field(&mut errors, "state", value.state, str::parse),
+            site_name: value.site_name,
+            ckt_id: value.ckt_id,
+            parent: value.parent,
+            link_type: value.link_type,
+            provider: value.provider,
+            z_loc: value.z_loc,
+            rtr_name_z_loc: value.rtr_name_z_loc,
+            to_description: value.to_description,
+            rtr_port_z_loc: value.rtr_port_z_loc,
+            interf_ip_z_loc: parse_field(
+                &mut errors,
+                "interf_ip_z_loc",
+                value.interf_ip_z_loc,
+                parse_ip,
+            ),
+            a_loc: value.a_loc,
+            rtr_name_a_loc: value.rtr_name_a_loc,
+            rtr_port: value.rtr_port,
+            interf_ip_a_loc: parse_field(
+                &mut errors,
+                "interf_ip_a_loc",
+                value.interf_ip_a_loc,
+                parse_ip,
+            ),
+            bw_mbps: parse_field(&mut errors, "bw_mbps", value.bw_mbps, str::parse),
+            single_isp: parse_field(&mut errors, "single_isp", value.single_isp, parse_flag),
+            ups_closet: value.ups_closet,
+            router_ip: parse_field(&mut errors, "router_ip", value.router_ip, parse_ip),
+        };
+
+        errors.into_result(dto)
+    }
+}
+
+/// Used when the circuit must already exist, the id is required and must be a ULID
+impl TryFrom<RawCircuit> for Circuit {
+    type Error = ValidationErrors;
+
+    fn try_from(mut value: RawCircuit) -> std::result::Result<Self, Self::Error> {
+        let mut errors = ValidationErrors::default();
+
+        let raw_id = value.id.take().unwrap_or_default();
+        let id = errors.check(
+            "id",
+            &raw_id,
+            raw_id
+                .trim()
+                .parse::<ulid::Ulid>()
+                .map_err(|_| eyre::Report::msg("not a valid circuit id")),
+        );
+
+        let dto = match CircuitDTO::try_from(value) {
+            Ok(dto) => Some(dto),
+            Err(ValidationErrors(field_errors)) => {
+                errors.0.extend(field_errors);
+                None
+            }
+        };
+
+        match (id, dto) {
+            (Some(id), Some(dto)) => Ok(Circuit {
+                id: id.to_string(),
+                ..Circuit::from(dto)
+            }),
+            _ => Err(errors),
+        }
+    }
+}
+
+/// Runs `parse` on a non blank value, recording a `FieldError` if it fails
+fn parse_field<T>(
+    errors: &mut ValidationErrors,
+    field: &'static str,
+    value: Option<String>,
+    parse: impl FnOnce(&str) -> Result<T>,
+) -> Option<T> {
+    let value = value?;
+    let trimmed = value.trim();
+
+    if trimmed.is_empty() {
+        return None;
+    }
+
+    errors.check(field, trimmed, parse(trimmed))
+}
+
+fn parse_ip(value: &str) -> Result<IpAddr> {
+    value
+        .parse()
+        .map_err(|_| eyre::Report::msg("not a valid IP address"))
+}
+
+fn parse_flag(value: &str) -> Result<bool> {
+    match value.to_lowercase().as_str() {
+        "yes" | "y" | "true" | "1" => Ok(true),
+        "no" | "n" | "false" | "0" => Ok(false),
+        _ => Err(eyre::Report::msg("not a yes/no value")),
+    }
+}
+
+/// JSON clients send numbers and booleans where CSV rows only ever have text,
+/// both end up as the same string before validation
+fn lenient_string<'de, D>(deserializer: D) -> std::result::Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Scalar {
+        Text(String),
+        Integer(i64),
+        Float(f64),
+        Flag(bool),
+    }
+
+    Ok(
+        Option::<Scalar>::deserialize(deserializer)?.map(|scalar| match scalar {
+            Scalar::Text(text) => text,
+            Scalar::Integer(integer) => integer.to_string(),
+            Scalar::Float(float) => float.to_string(),
+            Scalar::Flag(flag) => flag.to_string(),
+        }),
+    )
+}
+
+/// Columns that tell circuits apart when an import row comes without an id,
+/// like `provider` and `ckt_id`. Values are compared trimmed and ignoring case,
+/// a circuit or row with any of them blank has no key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NaturalKey {
+    pub columns: Vec<CircuitColumn>,
+}
+
+/// Circuits that share the same natural key
+#[derive(Debug, Serialize)]
+pub struct KeyConflict {
+    /// The key they share, normalized
+    pub values: Vec<String>,
+    pub circuit_ids: Vec<String>,
+}
+
+impl NaturalKey {
+    /// Text and address columns, the rest can't tell circuits apart
+    pub fn is_key_column(column: CircuitColumn) -> bool {
+        !matches!(
+            column,
+            CircuitColumn::Id
+                | CircuitColumn::State
+                | CircuitColumn::BwMbps
+                | CircuitColumn::SingleIsp
+                | CircuitColumn::DecommissionedAt
+                | CircuitColumn::DecommissionReason
+        )
+    }
+
+    pub fn is_address_column(column: CircuitColumn) -> bool {
+        matches!(
+            column,
+            CircuitColumn::InterfIpZLoc | CircuitColumn::InterfIpALoc | CircuitColumn::RouterIp
+        )
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.columns.is_empty() {
+            return Err(eyre::Report::msg("A natural key needs at least one column"));
+        }
+
+        for (i, column) in self.columns.iter().enumerate() {
+            if !NaturalKey::is_key_column(*column) {
+                return Err(eyre::Report::msg(format!(
+                    "`{}` can't be part of a natural key, only text and address columns can",
+                    column.as_str()
+                )));
+            }
+            if self.columns[..i].contains(column) {
+                return Err(eyre::Report::msg(format!(
+                    "`{}` is listed more than once",
+                    column.as_str()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The columns as a list for messages, e.g. `provider, ckt_id`
+    pub fn describe(&self) -> String {
+        self.columns
+            .iter()
+            .map(CircuitColumn::as_str)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    pub fn of_circuit(&self, circuit: &Circuit) -> Option<Vec<String>> {
+        self.columns
+            .iter()
+            .map(|column| NaturalKey::normalize(*column, &circuit.field(*column)))
+            .collect()
+    }
+
+    pub fn of_row(&self, raw_circuit: &RawCircuit) -> Option<Vec<String>> {
+        self.columns
+            .iter()
+            .map(|column| NaturalKey::normalize(*column, raw_circuit.field(*column)?))
+            .collect()
+    }
+
+    fn normalize(column: CircuitColumn, value: &str) -> Option<String> {
+        let value = value.trim();
+
+        if value.is_empty() {
+            return None;
+        }
+
+        if NaturalKey::is_address_column(column) {
+            if let Ok(address) = value.parse::<IpAddr>() {
+                return Some(address.to_string());
+            }
+        }
+
+        Some(value.to_lowercase())
+    }
+
+    /// Keys shared by more than one of `circuits`
+    pub fn conflicts(&self, circuits: &[Circuit]) -> Vec<KeyConflict> {
+        let mut by_key: std::collections::BTreeMap<Vec<String>, Vec<String>> = Default::default();
+
+        for circuit in circuits {
+            if let Some(key) = self.of_circuit(circuit) {
+                by_key.entry(key).or_default().push(circuit.id.clone());
+            }
+        }
+
+        by_key
+            .into_iter()
+            .filter(|(_, circuit_ids)| circuit_ids.len() > 1)
+            .map(|(values, circuit_ids)| KeyConflict {
+                values,
+                circuit_ids,
+            })
+            .collect()
+    }
+}
+
+/// Fills in the id of import rows that come without one from the circuit with
+/// the same natural key
+pub struct KeyMatcher {
+    key: NaturalKey,
+    circuits: std::collections::HashMap<Vec<String>, Vec<String>>,
+    /// Line of the first row seen with each key
+    rows: std::collections::HashMap<Vec<String>, u64>,
+}
+
+impl KeyMatcher {
+    pub fn new(key: NaturalKey, circuits: &[Circuit]) -> KeyMatcher {
+        let mut by_key: std::collections::HashMap<Vec<String>, Vec<String>> = Default::default();
+
+        for circuit in circuits {
+            if let Some(values) = key.of_circuit(circuit) {
+                by_key.entry(values).or_default().push(circuit.id.clone());
+            }
+        }
+
+        KeyMatcher {
+            key,
+            circuits: by_key,
+            rows: Default::default(),
+        }
+    }
+
+    /// `raw_circuit` with the id of the circuit it matches. Rows that match
+    /// several circuits, or share their key with an earlier row and so would
+    /// both change the same circuit, fail.
+    pub fn resolve(&mut self, mut raw_circuit: RawCircuit, line: u64) -> Result<RawCircuit> {
+        if raw_circuit.id().is_some() {
+            return Ok(raw_circuit);
+        }
+
+        let Some(values) = self.key.of_row(&raw_circuit) else {
+            return Ok(raw_circuit);
+        };
+
+        match self.rows.entry(values.clone()) {
+            std::collections::hash_map::Entry::Occupied(earlier) => {
+                return Err(DuplicateKey(format!(
+                    "Has the same {} as line {}",
+                    self.key.describe(),
+                    earlier.get()
+                ))
+                .into());
+            }
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                entry.insert(line);
+            }
+        }
+
+        match self.circuits.get(&values).map(Vec::as_slice) {
+            None | Some([]) => Ok(raw_circuit),
+            Some([circuit_id]) => {
+                raw_circuit.id = Some(circuit_id.clone());
+                Ok(raw_circuit)
+            }
+            Some(circuit_ids) => Err(DuplicateKey(format!(
+                "Matches {} circuits by {}: {}",
+                circuit_ids.len(),
+                self.key.describe(),
+                circuit_ids.join(", ")
+            ))
+            .into()),
+        }
+    }
+}
+
+/// An account that can log in
+#[derive(Debug, Clone, Serialize)]
+pub struct User {
+    pub username: String,
+    pub roles: Vec<String>,
+    /// Disabled users can't log in but keep their name in the history
+    pub disabled: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+impl User {
+    /// Usernames show up in the history of every change, they can't be blank
+    /// or have spaces in them
+    pub fn validate_username(username: &str) -> Result<()> {
+        if username.is_empty() || username.len() > 255 {
+            return Err(eyre::Report::msg(
+                "Usernames need between 1 and 255 characters",
+            ));
+        }
+
+        if username.chars().any(char::is_whitespace) {
+            return Err(eyre::Report::msg("Usernames can't have spaces in them"));
+        }
+
+        Ok(())
+    }
+
+    pub fn is_admin(&self) -> bool {
+        !self.disabled && self.roles.iter().any(|role| role == Role::ADMIN)
+    }
+}
+
+/// What a route asks of whoever calls it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "&str")]
+pub enum Permission {
+    CircuitsRead,
+    /// Creating, changing and decommissioning circuits one at a time
+    CircuitsWrite,
+    /// Deleting circuits along with their history, for cleaning up mistakes
+    CircuitsDelete,
+    /// Imports and their mapping profiles
+    CircuitsImport,
+    /// Import reports and the audit log
+    ReportsRead,
+    /// Users, roles, sessions and API keys
+    UsersAdmin,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 6] = [
+        Permission::CircuitsRead,
+        Permission::CircuitsWrite,
+        Permission::CircuitsDelete,
+        Permission::CircuitsImport,
+        Permission::ReportsRead,
+        Permission::UsersAdmin,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::CircuitsRead => "circuits:read",
+            Permission::CircuitsWrite => "circuits:write",
+            Permission::CircuitsDelete => "circuits:delete",
+            Permission::CircuitsImport => "circuits:import",
+            Permission::ReportsRead => "reports:read",
+            Permission::UsersAdmin => "users:admin",
+        }
+    }
+
+    /// Whether the permission only lets through reads, all a read-only
+    /// session keeps
+    pub fn is_read_only(&self) -> bool {
+        matches!(self, Permission::CircuitsRead | Permission::ReportsRead)
+    }
+}
+
+impl TryFrom<&str> for Permission {
+    type Error = eyre::Report;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Permission::ALL
+            .into_iter()
+            .find(|permission| permission.as_str() == value)
+            .ok_or_else(|| eyre::Report::msg(format!("`{value}` is not a permission")))
+    }
+}
+
+impl TryFrom<String> for Permission {
+    type Error = eyre::Report;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Permission::try_from(value.as_str())
+    }
+}
+
+impl From<Permission> for &'static str {
+    fn from(value: Permission) -> Self {
+        value.as_str()
+    }
+}
+
+/// A named set of permissions. `admin` always has all of them, so there is
+/// no locking everyone out by editing roles.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Role {
+    pub name: String,
+    pub description: String,
+    pub permissions: Vec<Permission>,
+}
+
+impl Role {
+    pub const ADMIN: &'static str = "admin";
+
+    pub fn validate(&self) -> Result<()> {
+        if self.name.is_empty() || self.name.len() > 64 {
+            return Err(eyre::Report::msg(
+                "Role names need between 1 and 64 characters",
+            ));
+        }
+
+        if self.name.chars().any(char::is_whitespace) {
+            return Err(eyre::Report::msg("Role names can't have spaces in them"));
+        }
+
+        if self.name == Role::ADMIN && self.permissions.len() != Permission::ALL.len() {
+            return Err(eyre::Report::msg("The admin role keeps every permission"));
+        }
+
+        Ok(())
+    }
+}
+
+/// A key a script logs in with, acting as `api-key:<name>` in the history
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub roles: Vec<String>,
+    /// The permissions of its roles the key is held to, all of them when empty
+    pub scopes: Vec<String>,
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Updated at most once a minute
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl ApiKey {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= Utc::now())
+    }
+
+    /// The name the key's changes are made under
+    pub fn actor(&self) -> String {
+        format!("api-key:{}", self.name)
+    }
+}
+
+/// A login, good for new access tokens until it expires or is revoked
+#[derive(Debug, Clone, Serialize)]
+pub struct Session {
+    pub id: String,
+    pub username: String,
+    /// Admins can log in with only the read permissions of their roles, for
+    /// a read-only session
+    pub reduced_privileges: bool,
+    pub created_at: DateTime<Utc>,
+    /// Moved forward every time the session is refreshed
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// Hashes of the refresh tokens a session knows about
+#[derive(Debug, Clone)]
+pub struct RefreshHashes {
+    /// Of the token the next refresh needs
+    pub current: String,
+    /// Of the token the last refresh used up, `None` before the first refresh
+    pub previous: Option<String>,
+}
+
+impl Session {
+    pub fn is_active(&self) -> bool {
+        self.revoked_at.is_none() && self.expires_at > Utc::now()
+    }
+
+    /// What access tokens of this session allow out of what the user's roles
+    /// do. They're read on every refresh, so role changes apply without
+    /// logging in again.
+    pub fn permissions(&self, granted: Vec<Permission>) -> Vec<Permission> {
+        if self.reduced_privileges {
+            granted
+                .into_iter()
+                .filter(|permission| permission.is_read_only())
+                .collect()
+        } else {
+            granted
+        }
+    }
+}
+
+/// What a scope limits a user to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "&str")]
+pub enum ScopeKind {
+    Site,
+    State,
+    Provider,
+    /// Every site of a region
+    Region,
+}
+
+impl ScopeKind {
+    pub const ALL: [ScopeKind; 4] = [
+        ScopeKind::Site,
+        ScopeKind::State,
+        ScopeKind::Provider,
+        ScopeKind::Region,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScopeKind::Site => "site",
+            ScopeKind::State => "state",
+            ScopeKind::Provider => "provider",
+            ScopeKind::Region => "region",
+        }
+    }
+}
+
+impl TryFrom<&str> for ScopeKind {
+    type Error = eyre::Report;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        ScopeKind::ALL
+            .into_iter()
+            .find(|kind| kind.as_str() == value)
+            .ok_or_else(|| eyre::Report::msg(format!("`{value}` is not a kind of scope")))
+    }
+}
+
+impl TryFrom<String> for ScopeKind {
+    type Error = eyre::Report;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        ScopeKind::try_from(value.as_str())
+    }
+}
+
+impl From<ScopeKind> for &'static str {
+    fn from(value: ScopeKind) -> Self {
+        value.as_str()
+    }
+}
+
+/// Lets a user reach the circuits whose site, state or provider is `value`,
+/// or whose site is in the region named `value`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct AccessScope {
+    pub kind: ScopeKind,
+    pub value: String,
+}
+
+/// A named group of sites, e.g. the ones a regional team looks after
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Region {
+    pub name: String,
+    pub description: String,
+    pub sites: Vec<String>,
+}
+
+impl Region {
+    pub fn validate(&self) -> Result<()> {
+        if self.name.is_empty() || self.name.len() > 64 {
+            return Err(eyre::Report::msg(
+                "Region names need between 1 and 64 characters",
+            ));
+        }
+
+        if self.name.chars().any(char::is_whitespace) {
+            return Err(eyre::Report::msg("Region names can't have spaces in them"));
+        }
+
+        if self.sites.iter().any(|site| site.trim().is_empty()) {
+            return Err(eyre::Report::msg("Site names can't be blank"));
+        }
+
+        Ok(())
+    }
+}
+
+/// The circuits a user with scopes can see and change, the ones at any of
+/// `sites`, in any of `states` or from any of `providers`. Values are kept
+/// lower case, circuits are matched ignoring case.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CircuitScope {
+    pub sites: Vec<String>,
+    pub states: Vec<String>,
+    pub providers: Vec<String>,
+}
+
+impl CircuitScope {
+    /// What `scopes` add up to, `None` for a user without any, who reaches
+    /// every circuit. Regions stand for their sites, one that doesn't exist
+    /// adds nothing rather than opening up the inventory.
+    pub fn resolve(scopes: &[AccessScope], regions: &[Region]) -> Option<CircuitScope> {
+        if scopes.is_empty() {
+            return None;
+        }
+
+        let mut scope = CircuitScope::default();
+        for AccessScope { kind, value } in scopes {
+            match kind {
+                ScopeKind::Site => scope.sites.push(value.to_lowercase()),
+                ScopeKind::State => scope.states.push(value.to_lowercase()),
+                ScopeKind::Provider => scope.providers.push(value.to_lowercase()),
+                ScopeKind::Region => scope.sites.extend(
+                    regions
+                        .iter()
+                        .filter(|region| region.name == *value)
+                        .flat_map(|region| region.sites.iter().map(|site| site.to_lowercase())),
+                ),
+            }
+        }
+
+        for values in [&mut scope.sites, &mut scope.states, &mut scope.providers] {
+            values.sort();
+            values.dedup();
+        }
+
+        Some(scope)
+    }
+
+    pub fn contains(&self, circuit: &Circuit) -> bool {
+        let state = circuit.field(CircuitColumn::State).to_lowercase();
+
+        self.sites.contains(&circuit.site_name.to_lowercase())
+            || self.providers.contains(&circuit.provider.to_lowercase())
+            || (!state.is_empty() && self.states.contains(&state))
+    }
+
+    /// Fails with `OutOfScope` unless the scope holds `circuit`, or there is
+    /// no scope at all
+    pub fn check(scope: Option<&CircuitScope>, circuit: &Circuit) -> Result<()> {
+        match scope {
+            Some(scope) if !scope.contains(circuit) => Err(OutOfScope(format!(
+                "Circuit {} at {}",
+                circuit.ckt_id, circuit.site_name
+            ))
+            .into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// What failed logins are counted against
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "&str")]
+pub enum LockKind {
+    Username,
+    /// The address the login came from
+    Ip,
+}
+
+impl LockKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LockKind::Username => "username",
+            LockKind::Ip => "ip",
+        }
+    }
+}
+
+impl TryFrom<&str> for LockKind {
+    type Error = eyre::Report;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "username" => Ok(LockKind::Username),
+            "ip" => Ok(LockKind::Ip),
+            _ => Err(eyre::Report::msg(format!(
+                "`{value}` is not a kind of lock"
+            ))),
+        }
+    }
+}
+
+impl TryFrom<String> for LockKind {
+    type Error = eyre::Report;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        LockKind::try_from(value.as_str())
+    }
+}
+
+impl From<LockKind> for &'static str {
+    fn from(value: LockKind) -> Self {
+        value.as_str()
+    }
+}
+
+/// Recent failed logins of a username or client address
+#[derive(Debug, Clone, Serialize)]
+pub struct LoginLock {
+    pub kind: LockKind,
+    pub key: String,
+    pub failures: i32,
+    pub last_failed_at: DateTime<Utc>,
+    /// Logins before this are refused without looking at the password
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+impl LoginLock {
+    pub fn is_locked(&self) -> bool {
+        self.locked_until
+            .is_some_and(|locked_until| locked_until > Utc::now())
+    }
+}
+
+/// A login that didn't go through, as kept in the audit trail
+#[derive(Debug, Clone, Serialize)]
+pub struct LoginFailure {
+    pub id: i64,
+    /// What was tried, whether or not such a user exists
+    pub username: String,
+    pub ip: String,
+    pub reason: LoginFailureReason,
+    pub failed_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "&str")]
+pub enum LoginFailureReason {
+    /// Unknown or disabled user, or the wrong password
+    InvalidCredentials,
+    /// Refused because the username or address had to wait
+    Locked,
+}
+
+impl LoginFailureReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LoginFailureReason::InvalidCredentials => "invalid_credentials",
+            LoginFailureReason::Locked => "locked",
+        }
+    }
+}
+
+impl TryFrom<&str> for LoginFailureReason {
+    type Error = eyre::Report;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "invalid_credentials" => Ok(LoginFailureReason::InvalidCredentials),
+            "locked" => Ok(LoginFailureReason::Locked),
+            _ => Err(eyre::Report::msg(format!(
+                "`{value}` is not a login failure reason"
+            ))),
+        }
+    }
+}
+
+impl TryFrom<String> for LoginFailureReason {
+    type Error = eyre::Report;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        LoginFailureReason::try_from(value.as_str())
+    }
+}
+
+impl From<LoginFailureReason> for &'static str {
+    fn from(value: LoginFailureReason) -> Self {
+        value.as_str()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn circuit_state_parses_known_states_and_rollout_phases() {
+        assert_eq!(
+            "active".parse::<CircuitState>().unwrap(),
+            CircuitState::Active
+        );
+        assert_eq!(
+            " No UM ".parse::<CircuitState>().unwrap(),
+            CircuitState::NoUm
+        );
+        assert_eq!("tba".parse::<CircuitState>().unwrap(), CircuitState::Tba);
+        assert_eq!(
+            "r1s2".parse::<CircuitState>().unwrap(),
+            CircuitState::Rollout("R1S2".to_string())
+        );
+        assert_eq!(
+            "R12-PP".parse::<CircuitState>().unwrap(),
+            CircuitState::Rollout("R12-PP".to_string())
+        );
+        assert_eq!(CircuitState::NoUm.to_string(), "No UM");
+
+        for invalid in ["", "R", "RS1", "R1S", "R1X", "retired"] {
+            assert!(invalid.parse::<CircuitState>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn bandwidth_accepts_separators_and_units() {
+        let mbps = |value: &str| value.parse::<Bandwidth>().unwrap().0;
+
+        assert_eq!(mbps("1,000"), 1000);
+        assert_eq!(mbps("500 Mbps"), 500);
+        assert_eq!(mbps("10G"), 10_000);
+        assert_eq!(mbps("1.5 gbps"), 1500);
+        assert_eq!(Bandwidth(10_000).to_string(), "10 Gbps");
+        assert_eq!(Bandwidth(1500).to_string(), "1500 Mbps");
+
+        for invalid in ["", "fast", "10 Tbps", "1.2.3"] {
+            assert!(invalid.parse::<Bandwidth>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn flags_and_addresses() {
+        assert!(parse_flag("Yes").unwrap());
+        assert!(!parse_flag("0").unwrap());
+        assert!(parse_flag("maybe").is_err());
+
+        assert_eq!(
+            parse_ip("10.0.0.1").unwrap(),
+            "10.0.0.1".parse::<IpAddr>().unwrap()
+        );
+        assert!(parse_ip("::1").is_ok());
+        assert!(parse_ip("10.0.0.256").is_err());
+    }
+
+    #[test]
+    fn raw_circuit_reports_every_invalid_field() {
+        let raw = RawCircuit {
+            state: Some("sideways".to_string()),
+            bw_mbps: Some("lots".to_string()),
+            router_ip: Some(" ".to_string()),
+            single_isp: Some("y".to_string()),
+            ..RawCircuit::default()
+        };
+
+        let ValidationErrors(errors) = CircuitDTO::try_from(raw).unwrap_err();
+        let fields: Vec<_> = errors.iter().map(|error| error.field).collect();
+        assert_eq!(fields, ["state", "bw_mbps"]);
+
+        let dto = CircuitDTO::try_from(RawCircuit {
+            bw_mbps: Some("1G".to_string()),
+            single_isp: Some("y".to_string()),
+            ..RawCircuit::default()
+        })
+        .unwrap();
+        assert_eq!(dto.bw_mbps, Some(Bandwidth(1000)));
+        assert_eq!(dto.single_isp, Some(true));
+        assert_eq!(dto.router_ip, None);
+    }
+
+    #[test]
+    fn sort_keys_take_a_leading_minus_for_descending() {
+        assert_eq!(
+            "provider".parse::<SortKey>().unwrap(),
+            SortKey {
+                column: CircuitColumn::Provider,
+                descending: false,
+            }
+        );
+        assert_eq!(
+            " -bw_mbps".parse::<SortKey>().unwrap(),
+            SortKey {
+                column: CircuitColumn::BwMbps,
+                descending: true,
+            }
+        );
+        assert!("--provider".parse::<SortKey>().is_err());
+        assert!("Provider".parse::<SortKey>().is_err());
+    }
+
+    #[test]
+    fn highlights_map_normalized_matches_back_onto_the_value() {
+        assert_eq!(normalize_search("CR-01/Ä b"), "cr01b");
+
+        let highlight = Highlight::find("ckt_id", "Circuit CR-01.ab", "cr01").unwrap();
+        assert_eq!(highlight.before, "Circuit ");
+        assert_eq!(highlight.matched, "CR-01");
+        assert_eq!(highlight.after, ".ab");
+
+        assert!(Highlight::find("ckt_id", "CR-01", "cr02").is_none());
+        assert!(Highlight::find("ckt_id", "CR-01", "").is_none());
+    }
+
+    #[test]
+    fn highlights_trim_long_context() {
+        let value = format!("{}needle{}", "a".repeat(40), "b".repeat(40));
+        let highlight = Highlight::find("z_loc", &value, "needle").unwrap();
+
+        assert_eq!(
+            highlight.before,
+            format!("…{}", "a".repeat(HIGHLIGHT_CONTEXT))
+        );
+        assert_eq!(highlight.matched, "needle");
+        assert_eq!(
+            highlight.after,
+            format!("{}…", "b".repeat(HIGHLIGHT_CONTEXT))
+        );
+    }
+
+    fn natural_key(columns: &[CircuitColumn]) -> NaturalKey {
+        NaturalKey {
+            columns: columns.to_vec(),
+        }
+    }
+
+    fn circuit(raw: RawCircuit) -> Circuit {
+        Circuit::from(CircuitDTO::try_from(raw).unwrap())
+    }
+
+    #[test]
+    fn natural_keys_take_distinct_text_and_address_columns() {
+        natural_key(&[CircuitColumn::Provider, CircuitColumn::CktId])
+            .validate()
+            .unwrap();
+        natural_key(&[CircuitColumn::RouterIp]).validate().unwrap();
+
+        assert!(natural_key(&[]).validate().is_err());
+        assert!(natural_key(&[CircuitColumn::BwMbps]).validate().is_err());
+        assert!(natural_key(&[CircuitColumn::CktId, CircuitColumn::CktId])
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn natural_keys_of_rows_are_normalized() {
+        let key = natural_key(&[CircuitColumn::Provider, CircuitColumn::RouterIp]);
+        let row = RawCircuit {
+            provider: Some(" ACME ".to_string()),
+            router_ip: Some("2001:DB8::0:1".to_string()),
+            ..RawCircuit::default()
+        };
+
+        assert_eq!(
+            key.of_row(&row),
+            Some(vec!["acme".to_string(), "2001:db8::1".to_string()])
+        );
+        assert_eq!(
+            key.of_row(&RawCircuit {
+                router_ip: Some(" ".to_string()),
+                ..row
+            }),
+            None
+        );
+    }
+
+    #[test]
+    fn key_matchers_fill_in_ids_and_refuse_ambiguous_rows() {
+        let key = natural_key(&[CircuitColumn::CktId]);
+        let row = |ckt_id: &str| RawCircuit {
+            ckt_id: Some(ckt_id.to_string()),
+            ..RawCircuit::default()
+        };
+        let circuits = [
+            circuit(row("CR-01")),
+            circuit(row("CR-02")),
+            circuit(row("cr-02")),
+        ];
+
+        assert_eq!(key.conflicts(&circuits).len(), 1);
+
+        let mut matcher = KeyMatcher::new(key, &circuits);
+        let matched = matcher.resolve(row("cr-01"), 2).unwrap();
+        assert_eq!(matched.id.as_deref(), Some(circuits[0].id.as_str()));
+        assert_eq!(matcher.resolve(row("CR-09"), 3).unwrap().id, None);
+
+        let ambiguous = matcher.resolve(row("CR-02"), 4).unwrap_err();
+        assert!(ambiguous.to_string().starts_with("Matches 2 circuits"));
+
+        let repeated = matcher.resolve(row("CR-01"), 5).unwrap_err();
+        assert_eq!(repeated.to_string(), "Has the same ckt_id as line 2");
+    }
+
+    #[test]
+    fn diffs_list_changed_fields_only() {
+        let old = circuit(RawCircuit {
+            ckt_id: Some("CR-01".to_string()),
+            provider: Some("Acme".to_string()),
+            bw_mbps: Some("100".to_string()),
+            ..RawCircuit::default()
+        });
+        let new = Circuit {
+            provider: "Globex".to_string(),
+            bw_mbps: None,
+            ..old.clone()
+        };
+
+        assert_eq!(
+            FieldChange::diff(Some(&old), Some(&new)),
+            [
+                FieldChange {
+                    field: "provider".to_string(),
+                    old: Some("Acme".to_string()),
+                    new: Some("Globex".to_string()),
+                },
+                FieldChange {
+                    field: "bw_mbps".to_string(),
+                    old: Some("100".to_string()),
+                    new: None,
+                },
+            ]
+        );
+        assert!(FieldChange::diff(Some(&old), Some(&old)).is_empty());
+    }
+
+    #[test]
+    fn diffs_of_created_circuits_skip_the_id_and_empty_fields() {
+        let created = circuit(RawCircuit {
+            ckt_id: Some("CR-01".to_string()),
+            ..RawCircuit::default()
+        });
+
+        let fields: Vec<_> = FieldChange::diff(None, Some(&created))
+            .into_iter()
+            .map(|change| (change.field, change.old, change.new))
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                ("ckt_id".to_string(), None, Some("CR-01".to_string())),
+                ("single_isp".to_string(), None, Some("false".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn value_transforms() {
+        let apply = |transform: ValueTransform, value: &str| transform.apply(value);
+
+        assert_eq!(apply(ValueTransform::Trim, "  R1 "), "R1");
+        assert_eq!(apply(ValueTransform::Uppercase, "r1s2"), "R1S2");
+        assert_eq!(apply(ValueTransform::StripUnit, "100 Mbps"), "100");
+        assert_eq!(apply(ValueTransform::StripUnit, "1.5Gbit/s"), "1.5");
+        assert_eq!(apply(ValueTransform::StripUnit, "unknown"), "unknown");
+        assert_eq!(
+            apply(
+                ValueTransform::Replace {
+                    from: "Yes".to_string(),
+                    to: "true".to_string(),
+                },
+                "Yes"
+            ),
+            "true"
+        );
+        assert_eq!(
+            apply(
+                ValueTransform::Default {
+                    value: "Active".to_string(),
+                },
+                " "
+            ),
+            "Active"
+        );
+    }
+
+    fn mapped(source: &str, field: &str, transforms: Vec<ValueTransform>) -> MappedColumn {
+        MappedColumn {
+            source: source.to_string(),
+            field: field.to_string(),
+            transforms,
+        }
+    }
+
+    #[test]
+    fn mappers_rename_transform_and_pass_through_columns() {
+        let mapping = ColumnMapping {
+            id: String::new(),
+            name: "carrier".to_string(),
+            columns: vec![
+                mapped(" Circuit ", "ckt_id", vec![ValueTransform::Trim]),
+                mapped("Speed", "bw_mbps", vec![ValueTransform::StripUnit]),
+            ],
+        };
+        mapping.validate().unwrap();
+
+        let headers = csv::StringRecord::from(vec!["speed", "circuit", "provider", "ckt_id"]);
+        let mapper = mapping.mapper(&headers).unwrap();
+        assert_eq!(
+            mapper.headers(),
+            csv::StringRecord::from(vec!["ckt_id", "bw_mbps", "provider"])
+        );
+
+        let record = csv::StringRecord::from(vec!["100 Mbps", " CR-01 ", "Acme", "ignored"]);
+        assert_eq!(
+            mapper.map(&record),
+            csv::StringRecord::from(vec!["CR-01", "100", "Acme"])
+        );
+
+        let missing = csv::StringRecord::from(vec!["circuit"]);
+        assert!(mapping.mapper(&missing).is_err());
+    }
+
+    #[test]
+    fn mappings_need_known_distinct_fields() {
+        let mapping = ColumnMapping {
+            id: String::new(),
+            name: " ".to_string(),
+            columns: vec![
+                mapped("a", "ckt_id", vec![]),
+                mapped("b", "ckt_id", vec![]),
+                mapped("c", "decommissioned_at", vec![]),
+            ],
+        };
+
+        let message = mapping.validate().unwrap_err().to_string();
+        assert!(message.contains("the name can't be empty"), "{message}");
+        assert!(
+            message.contains("`ckt_id` is mapped more than once"),
+            "{message}"
+        );
+        assert!(
+            message.contains("`decommissioned_at` is not a circuit field"),
+            "{message}"
+        );
+    }
+
+    fn role(name: &str, permissions: &[Permission]) -> Role {
+        Role {
+            name: name.to_string(),
+            description: String::new(),
+            permissions: permissions.to_vec(),
+        }
+    }
+
+    #[test]
+    fn role_names_are_short_single_words() {
+        role("noc-viewer", &[Permission::CircuitsRead])
+            .validate()
+            .unwrap();
+        role(&"r".repeat(64), &[]).validate().unwrap();
+
+        assert!(role("", &[]).validate().is_err());
+        assert!(role(&"r".repeat(65), &[]).validate().is_err());
+        assert!(role("noc viewer", &[]).validate().is_err());
+    }
+
+    #[test]
+    fn the_admin_role_keeps_every_permission() {
+        role(Role::ADMIN, &Permission::ALL).validate().unwrap();
+
+        let message = role(Role::ADMIN, &[Permission::UsersAdmin])
+            .validate()
+            .unwrap_err()
+            .to_string();
+        assert_eq!(message, "The admin role keeps every permission");
+    }
+
+    fn access(kind: ScopeKind, value: &str) -> AccessScope {
+        AccessScope {
+            kind,
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn scopes_resolve_regions_to_their_sites() {
+        let regions = [Region {
+            name: "North".to_string(),
+            description: String::new(),
+            sites: vec!["Ann Arbor".to_string(), "Flint".to_string()],
+        }];
+
+        assert_eq!(CircuitScope::resolve(&[], &regions), None);
+
+        let scope = CircuitScope::resolve(
+            &[
+                access(ScopeKind::Region, "North"),
+                access(ScopeKind::Site, "FLINT"),
+                access(ScopeKind::Provider, "Acme"),
+                access(ScopeKind::Region, "Nowhere"),
+            ],
+            &regions,
+        )
+        .unwrap();
+        assert_eq!(scope.sites, ["ann arbor", "flint"]);
+        assert_eq!(scope.providers, ["acme"]);
+        assert!(scope.states.is_empty());
+
+        // A region that doesn't exist reaches nothing instead of everything
+        let nothing =
+            CircuitScope::resolve(&[access(ScopeKind::Region, "Nowhere")], &regions).unwrap();
+        assert_eq!(nothing, CircuitScope::default());
+    }
+
+    #[test]
+    fn scopes_contain_circuits_by_site_provider_or_state() {
+        let scope = CircuitScope {
+            sites: vec!["flint".to_string()],
+            states: vec!["active".to_string()],
+            providers: vec!["acme".to_string()],
+        };
+        let at = |site: &str, provider: &str, state: Option<&str>| {
+            circuit(RawCircuit {
+                site_name: Some(site.to_string()),
+                provider: Some(provider.to_string()),
+                state: state.map(str::to_string),
+                ..RawCircuit::default()
+            })
+        };
+
+        assert!(scope.contains(&at("Flint", "Other", None)));
+        assert!(scope.contains(&at("Detroit", "ACME", None)));
+        assert!(scope.contains(&at("Detroit", "Other", Some("active"))));
+        assert!(!scope.contains(&at("Detroit", "Other", None)));
+
+        let outside = at("Detroit", "Other", None);
+        assert!(CircuitScope::check(None, &outside).is_ok());
+        assert!(CircuitScope::check(Some(&scope), &outside)
+            .unwrap_err()
+            .downcast_ref::<OutOfScope>()
+            .is_some());
+    }
 }
@@ -0,0 +1,189 @@
+//! Password hashing with argon2id. Users that predate hashed passwords have a
+//! plaintext one until they next log in, see `Credentials::verify`.
+
+use std::{fmt::Write, sync::OnceLock};
+
+use argon2::{
+    password_hash::{
+        rand_core::{OsRng, RngCore},
+        PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+    },
+    Argon2,
+};
+use subtle::ConstantTimeEq;
+
+/// Checked against when there's no hash to check, so the response takes as
+/// long as for a wrong password
+fn dummy_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+
+    DUMMY_HASH.get_or_init(|| hash("").expect("Hashing with the default parameters works"))
+}
+
+/// A PHC string with the argon2id hash of `password` under a fresh salt
+pub fn hash(password: &str) -> eyre::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| eyre::Report::msg(format!("Failed hashing the password : {e}")))
+}
+
+/// `hash` on a blocking thread. Argon2 is slow on purpose, run on the async
+/// workers it would hold up every other request.
+pub async fn hash_blocking(password: String) -> eyre::Result<String> {
+    tokio::task::spawn_blocking(move || hash(&password))
+        .await
+        .map_err(eyre::Report::from)
+        .and_then(|hash| hash)
+}
+
+/// Random bytes in a generated secret
+const SECRET_BYTES: usize = 32;
+
+/// A random hex string for tokens and keys, too long to be guessed
+pub fn random_secret() -> String {
+    let mut bytes = [0u8; SECRET_BYTES];
+    OsRng.fill_bytes(&mut bytes);
+
+    bytes.iter().fold(String::new(), |mut secret, byte| {
+        let _ = write!(secret, "{byte:02x}");
+        secret
+    })
+}
+
+/// A user's password as stored, plaintext only for users that haven't logged
+/// in since passwords were hashed
+#[derive(Debug, Clone, Default, sqlx::FromRow)]
+pub struct StoredPassword {
+    pub plaintext: Option<String>,
+    pub hash: Option<String>,
+}
+
+impl StoredPassword {
+    pub fn credentials(&self) -> Credentials<'_> {
+        match (&self.hash, &self.plaintext) {
+            (Some(hash), _) => Credentials::Hashed(hash),
+            (None, Some(plaintext)) => Credentials::Plaintext(plaintext),
+            (None, None) => Credentials::Missing,
+        }
+    }
+
+    /// `Credentials::verify` on a blocking thread, like `hash_blocking`
+    pub async fn verify_blocking(self, password: String) -> bool {
+        tokio::task::spawn_blocking(move || self.credentials().verify(&password))
+            .await
+            .unwrap_or_else(|e| {
+                tracing::error!("Failed checking a password : {}", e);
+                false
+            })
+    }
+}
+
+/// Shortest password accepted for new passwords
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// Fails for passwords too weak to be set
+pub fn validate(password: &str) -> eyre::Result<()> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(eyre::Report::msg(format!(
+            "Passwords need at least {MIN_PASSWORD_LENGTH} characters"
+        )));
+    }
+
+    Ok(())
+}
+
+/// What is stored for a user's password
+pub enum Credentials<'a> {
+    Hashed(&'a str),
+    /// Left over from before passwords were hashed
+    Plaintext(&'a str),
+    /// There is no such user
+    Missing,
+}
+
+impl Credentials<'_> {
+    /// Whether `password` matches, taking the same time whatever it is compared to
+    pub fn verify(&self, password: &str) -> bool {
+        match self {
+            Credentials::Hashed(hash) => verify_hash(password, hash),
+            Credentials::Plaintext(stored) => {
+                // Hashed like a real check would be so the time doesn't tell
+                // plaintext users apart
+                verify_hash(password, dummy_hash());
+                bool::from(password.as_bytes().ct_eq(stored.as_bytes()))
+            }
+            Credentials::Missing => {
+                verify_hash(password, dummy_hash());
+                false
+            }
+        }
+    }
+
+    /// Whether the password should be hashed again once it's known to match
+    pub fn needs_rehash(&self) -> bool {
+        match self {
+            Credentials::Hashed(hash) => PasswordHash::new(hash)
+                .map(|hash| hash.algorithm != argon2::Algorithm::Argon2id.ident())
+                .unwrap_or(true),
+            Credentials::Plaintext(_) => true,
+            Credentials::Missing => false,
+        }
+    }
+}
+
+fn verify_hash(password: &str, hash: &str) -> bool {
+    let Ok(hash) = PasswordHash::new(hash) else {
+        tracing::error!("A stored password hash can't be parsed");
+        return false;
+    };
+
+    Argon2::default()
+        .verify_password(password.as_bytes(), &hash)
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use argon2::{Algorithm, Params, Version};
+
+    use super::*;
+
+    #[test]
+    fn credentials_match_only_their_password() {
+        let hashed = hash("correct horse").unwrap();
+
+        assert!(Credentials::Hashed(&hashed).verify("correct horse"));
+        assert!(!Credentials::Hashed(&hashed).verify("correct horse "));
+        assert!(Credentials::Plaintext("hunter22").verify("hunter22"));
+        assert!(!Credentials::Plaintext("hunter22").verify("hunter2"));
+        assert!(!Credentials::Missing.verify(""));
+        assert!(!Credentials::Hashed("not a hash").verify("not a hash"));
+    }
+
+    #[test]
+    fn only_argon2id_hashes_are_kept() {
+        let salt = SaltString::generate(&mut OsRng);
+        let argon2i = Argon2::new(Algorithm::Argon2i, Version::V0x13, Params::default())
+            .hash_password(b"correct horse", &salt)
+            .unwrap()
+            .to_string();
+
+        assert!(!Credentials::Hashed(&hash("correct horse").unwrap()).needs_rehash());
+        assert!(Credentials::Hashed(&argon2i).needs_rehash());
+        assert!(Credentials::Hashed("not a hash").needs_rehash());
+        assert!(Credentials::Plaintext("hunter22").needs_rehash());
+        assert!(!Credentials::Missing.needs_rehash());
+    }
+
+    #[test]
+    fn secrets_are_long_random_hex() {
+        let secret = random_secret();
+
+        assert_eq!(secret.len(), SECRET_BYTES * 2);
+        assert!(secret.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(secret, random_secret());
+    }
+}
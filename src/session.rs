@@ -0,0 +1,128 @@
+//! Logins hand out a short lived access token and a refresh token. The refresh
+//! token is swapped for a new one every time it's used, a session that sees the
+//! one it last swapped out again was copied by someone and is revoked.
+
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::password;
+
+const DEFAULT_ACCESS_MINUTES: u64 = 15;
+const DEFAULT_REFRESH_DAYS: u64 = 7;
+
+/// How long tokens are good for, set with `ACCESS_TOKEN_MINUTES` and
+/// `REFRESH_TOKEN_DAYS`
+#[derive(Debug, Clone, Copy)]
+pub struct TokenLifetimes {
+    pub access: Duration,
+    /// How long a session can go without being refreshed
+    pub refresh: Duration,
+}
+
+impl TokenLifetimes {
+    pub fn from_env() -> TokenLifetimes {
+        let read = |name: &str, default: u64| match std::env::var(name) {
+            Ok(value) => value
+                .parse()
+                .unwrap_or_else(|_| panic!("{name} MUST BE A WHOLE NUMBER")),
+            Err(_) => default,
+        };
+
+        TokenLifetimes {
+            access: Duration::from_secs(read("ACCESS_TOKEN_MINUTES", DEFAULT_ACCESS_MINUTES) * 60),
+            refresh: Duration::from_secs(
+                read("REFRESH_TOKEN_DAYS", DEFAULT_REFRESH_DAYS) * 24 * 60 * 60,
+            ),
+        }
+    }
+
+    /// When a session refreshed now expires
+    pub fn refresh_expiry(&self) -> chrono::DateTime<chrono::Utc> {
+        chrono::Utc::now()
+            + chrono::Duration::from_std(self.refresh).expect("Refresh lifetime fits a duration")
+    }
+}
+
+/// The id of a session and a secret only its holder knows, sent as `id.secret`
+pub struct RefreshToken {
+    pub session_id: String,
+    secret: String,
+}
+
+impl RefreshToken {
+    pub fn generate(session_id: String) -> RefreshToken {
+        RefreshToken {
+            session_id,
+            secret: password::random_secret(),
+        }
+    }
+
+    pub fn parse(token: &str) -> eyre::Result<RefreshToken> {
+        match token.split_once('.') {
+            Some((session_id, secret)) if !session_id.is_empty() && !secret.is_empty() => {
+                Ok(RefreshToken {
+                    session_id: session_id.to_string(),
+                    secret: secret.to_string(),
+                })
+            }
+            _ => Err(eyre::Report::msg("Invalid refresh token")),
+        }
+    }
+
+    /// What's stored for the session. The secret is random like an API key's
+    /// and checked on every refresh, so it's hashed the same way.
+    pub fn hash(&self) -> String {
+        Sha256::digest(self.secret.as_bytes())
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    pub fn verify(&self, hash: &str) -> bool {
+        bool::from(self.hash().as_bytes().ct_eq(hash.as_bytes()))
+    }
+}
+
+impl std::fmt::Display for RefreshToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.session_id, self.secret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refresh_tokens_parse_back_from_their_text() {
+        let token = RefreshToken::generate("01HSESSION".to_string());
+        let parsed = RefreshToken::parse(&token.to_string()).unwrap();
+
+        assert_eq!(parsed.session_id, "01HSESSION");
+        assert_eq!(parsed.secret, token.secret);
+    }
+
+    #[test]
+    fn refresh_tokens_need_a_session_and_a_secret() {
+        for token in ["", "01HSESSION", "01HSESSION.", ".secret", "."] {
+            assert!(RefreshToken::parse(token).is_err(), "{token}");
+        }
+
+        // Only the first dot splits, the secret is taken as it is
+        let parsed = RefreshToken::parse("01HSESSION.a.b").unwrap();
+        assert_eq!(parsed.session_id, "01HSESSION");
+        assert_eq!(parsed.secret, "a.b");
+    }
+
+    #[test]
+    fn refresh_tokens_verify_against_their_own_hash() {
+        let token = RefreshToken::generate("01HSESSION".to_string());
+        let other = RefreshToken::generate("01HSESSION".to_string());
+        let hash = token.hash();
+
+        assert!(token.verify(&hash));
+        assert!(!other.verify(&hash));
+    }
+}
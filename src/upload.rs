@@ -0,0 +1,369 @@
+//! Import uploads read a chunk at a time as they arrive, so a file over the
+//! limits is turned away as soon as that is known instead of after all of it
+//! was held in memory. Gzip compressed uploads are unpacked on the way in.
+
+use std::io::{self, Write};
+
+use csv_core::ReadRecordResult;
+use flate2::write::GzDecoder;
+
+/// Every gzip file starts with these
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+const DEFAULT_MAX_BYTES: usize = 50 * 1024 * 1024;
+const DEFAULT_MAX_ROWS: usize = 100_000;
+
+/// How large an import upload can be, set with `IMPORT_MAX_BYTES` and
+/// `IMPORT_MAX_ROWS`
+#[derive(Debug, Clone, Copy)]
+pub struct UploadLimits {
+    /// Largest file accepted, both as uploaded and once unpacked
+    pub max_bytes: usize,
+    /// Most rows a file can have, not counting its headers
+    pub max_rows: usize,
+}
+
+impl UploadLimits {
+    pub fn from_env() -> UploadLimits {
+        let read = |name: &str, default: usize| match std::env::var(name) {
+            Ok(value) => value
+                .parse()
+                .unwrap_or_else(|_| panic!("{name} MUST BE A WHOLE NUMBER")),
+            Err(_) => default,
+        };
+
+        UploadLimits {
+            max_bytes: read("IMPORT_MAX_BYTES", DEFAULT_MAX_BYTES),
+            max_rows: read("IMPORT_MAX_ROWS", DEFAULT_MAX_ROWS),
+        }
+    }
+
+    /// Fails when the CSV in `csv_data` has more rows than allowed, for
+    /// uploads whose rows can only be counted once they were converted
+    pub fn check_rows(&self, csv_data: &str) -> eyre::Result<()> {
+        let rows = csv::Reader::from_reader(csv_data.as_bytes())
+            .records()
+            .count();
+
+        if rows > self.max_rows {
+            return Err(self.too_many_rows().into());
+        }
+
+        Ok(())
+    }
+
+    /// Fails when the workbook in `data` unpacks to more than the allowed
+    /// bytes. Its entries are unpacked and thrown away to find out, the sizes
+    /// the archive declares for them could be made up.
+    pub fn check_workbook(&self, data: &[u8]) -> eyre::Result<()> {
+        let mut archive = zip::ZipArchive::new(io::Cursor::new(data))
+            .map_err(|e| eyre::Report::msg(format!("Not a valid workbook : {e}")))?;
+
+        let max_bytes = self.max_bytes as u64;
+        let mut unpacked = 0;
+        for index in 0..archive.len() {
+            let entry = archive.by_index(index)?;
+            // One byte over the limit is enough to know
+            let allowed = max_bytes - unpacked + 1;
+            unpacked += io::copy(&mut io::Read::take(entry, allowed), &mut io::sink())?;
+
+            if unpacked > max_bytes {
+                return Err(self.too_many_bytes().into());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn too_many_bytes(&self) -> TooLarge {
+        TooLarge(format!(
+            "The file is larger than the {} allowed for imports",
+            human_size(self.max_bytes)
+        ))
+    }
+
+    fn too_many_rows(&self) -> TooLarge {
+        TooLarge(format!(
+            "The file has more than the {} rows allowed for imports, split it into smaller files",
+            self.max_rows
+        ))
+    }
+}
+
+fn human_size(bytes: usize) -> String {
+    const MB: usize = 1024 * 1024;
+
+    if bytes >= MB && bytes.is_multiple_of(MB) {
+        format!("{} MB", bytes / MB)
+    } else {
+        format!("{bytes} bytes")
+    }
+}
+
+/// An upload went over one of its `UploadLimits`
+#[derive(Debug)]
+pub struct TooLarge(pub String);
+
+impl std::fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+/// An upload being read, fed its chunks as they arrive
+pub struct UploadReader {
+    limits: UploadLimits,
+    count_rows: bool,
+    received: usize,
+    /// First bytes of the upload, kept until there are enough to tell whether
+    /// it's compressed
+    head: Vec<u8>,
+    decoding: Option<Decoding>,
+}
+
+enum Decoding {
+    Plain(Contents),
+    Gzip(GzDecoder<Contents>),
+}
+
+impl UploadReader {
+    /// Reader for a file whose rows can't be counted as it's read, like a workbook
+    pub fn new(limits: UploadLimits) -> UploadReader {
+        UploadReader {
+            limits,
+            count_rows: false,
+            received: 0,
+            head: vec![],
+            decoding: None,
+        }
+    }
+
+    /// Reader for a CSV file, its rows are counted against the limit as they come in
+    pub fn csv(limits: UploadLimits) -> UploadReader {
+        UploadReader {
+            count_rows: true,
+            ..UploadReader::new(limits)
+        }
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) -> eyre::Result<()> {
+        self.received += chunk.len();
+        if self.received > self.limits.max_bytes {
+            return Err(self.limits.too_many_bytes().into());
+        }
+
+        match &mut self.decoding {
+            Some(Decoding::Plain(contents)) => contents.write_all(chunk),
+            Some(Decoding::Gzip(decoder)) => decoder.write_all(chunk),
+            None => {
+                self.head.extend_from_slice(chunk);
+                if self.head.len() < GZIP_MAGIC.len() {
+                    return Ok(());
+                }
+                self.decode_head()
+                    .map(|decoding| self.decoding = Some(decoding))
+            }
+        }
+        .map_err(into_report)
+    }
+
+    /// The whole upload, unpacked
+    pub fn finish(mut self) -> eyre::Result<Vec<u8>> {
+        let decoding = match self.decoding.take() {
+            Some(decoding) => decoding,
+            None => self.decode_head().map_err(into_report)?,
+        };
+
+        let mut contents = match decoding {
+            Decoding::Plain(contents) => contents,
+            Decoding::Gzip(decoder) => decoder.finish().map_err(into_report)?,
+        };
+
+        // The last row is only counted once it's known nothing follows it
+        contents.count_rows(&[]).map_err(into_report)?;
+
+        Ok(contents.data)
+    }
+
+    /// Starts decoding the upload from the bytes in `head`
+    fn decode_head(&mut self) -> io::Result<Decoding> {
+        let head = std::mem::take(&mut self.head);
+        let mut contents = Contents {
+            data: vec![],
+            limits: self.limits,
+            rows: self.count_rows.then(|| (csv_core::Reader::new(), 0)),
+        };
+
+        if head.starts_with(&GZIP_MAGIC) {
+            let mut decoder = GzDecoder::new(contents);
+            decoder.write_all(&head)?;
+            Ok(Decoding::Gzip(decoder))
+        } else {
+            contents.write_all(&head)?;
+            Ok(Decoding::Plain(contents))
+        }
+    }
+}
+
+/// The unpacked upload, with the CSV records in it counted when asked to
+struct Contents {
+    data: Vec<u8>,
+    limits: UploadLimits,
+    rows: Option<(csv_core::Reader, usize)>,
+}
+
+impl Contents {
+    /// Counts the records that end in `input`, an empty `input` tells the
+    /// reader the file is over
+    fn count_rows(&mut self, mut input: &[u8]) -> io::Result<()> {
+        let Some((reader, records)) = &mut self.rows else {
+            return Ok(());
+        };
+        let at_end = input.is_empty();
+
+        // Only the record boundaries matter, the fields are thrown away
+        let mut output = [0; 1024];
+        let mut ends = [0; 64];
+
+        loop {
+            let (result, read, _, _) = reader.read_record(input, &mut output, &mut ends);
+            input = &input[read..];
+
+            match result {
+                ReadRecordResult::InputEmpty | ReadRecordResult::End => return Ok(()),
+                ReadRecordResult::OutputFull | ReadRecordResult::OutputEndsFull => {}
+                ReadRecordResult::Record => {
+                    *records += 1;
+                    // The first record holds the headers
+                    if *records - 1 > self.limits.max_rows {
+                        return Err(io::Error::other(self.limits.too_many_rows()));
+                    }
+                }
+            }
+
+            // Asking for more with nothing left would end the file early
+            if input.is_empty() && !at_end {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Write for Contents {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.data.len() + buf.len() > self.limits.max_bytes {
+            return Err(io::Error::other(self.limits.too_many_bytes()));
+        }
+
+        self.count_rows(buf)?;
+        self.data.extend_from_slice(buf);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Limits are passed through the decoder as IO errors, they come out as `TooLarge` again
+fn into_report(error: io::Error) -> eyre::Report {
+    if error.get_ref().is_some_and(|inner| inner.is::<TooLarge>()) {
+        let inner = error.into_inner().expect("Checked above");
+        let too_large = inner.downcast::<TooLarge>().expect("Checked above");
+        return eyre::Report::new(*too_large);
+    }
+
+    eyre::Report::msg(format!("Failed reading the upload : {error}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    fn limits(max_bytes: usize, max_rows: usize) -> UploadLimits {
+        UploadLimits {
+            max_bytes,
+            max_rows,
+        }
+    }
+
+    /// Feeds `data` to `reader` a few bytes at a time, like chunks arriving
+    fn read(mut reader: UploadReader, data: &[u8]) -> eyre::Result<Vec<u8>> {
+        for chunk in data.chunks(3) {
+            reader.push(chunk)?;
+        }
+        reader.finish()
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn is_too_large(result: eyre::Result<Vec<u8>>) -> bool {
+        result.is_err_and(|e| e.downcast_ref::<TooLarge>().is_some())
+    }
+
+    const CSV: &[u8] = b"ckt_id,provider\nCR-01,Acme\n\"CR-02\nsplit\",Acme\nCR-03,Acme";
+
+    #[test]
+    fn plain_and_gzip_uploads_come_out_the_same() {
+        let plain = read(UploadReader::csv(limits(1024, 10)), CSV).unwrap();
+        let unpacked = read(UploadReader::csv(limits(1024, 10)), &gzip(CSV)).unwrap();
+
+        assert_eq!(plain, CSV);
+        assert_eq!(unpacked, CSV);
+        assert_eq!(
+            read(UploadReader::new(limits(1024, 10)), b"x").unwrap(),
+            b"x"
+        );
+    }
+
+    #[test]
+    fn rows_after_the_headers_are_counted() {
+        assert!(read(UploadReader::csv(limits(1024, 3)), CSV).is_ok());
+        assert!(is_too_large(read(UploadReader::csv(limits(1024, 2)), CSV)));
+        assert!(is_too_large(read(
+            UploadReader::csv(limits(1024, 2)),
+            &gzip(CSV)
+        )));
+        // Workbooks are counted once they are converted
+        assert!(read(UploadReader::new(limits(1024, 0)), CSV).is_ok());
+    }
+
+    #[test]
+    fn bytes_are_counted_as_uploaded_and_unpacked() {
+        assert!(is_too_large(read(
+            UploadReader::csv(limits(CSV.len() - 1, 10)),
+            CSV
+        )));
+
+        let bomb = gzip(&vec![b'a'; 64 * 1024]);
+        assert!(bomb.len() < 1024);
+        assert!(is_too_large(read(
+            UploadReader::new(limits(1024, 10)),
+            &bomb
+        )));
+    }
+
+    #[test]
+    fn broken_gzip_is_reported() {
+        let mut broken = gzip(CSV);
+        broken.truncate(broken.len() / 2);
+
+        let error = read(UploadReader::csv(limits(1024, 10)), &broken).unwrap_err();
+        assert!(error.downcast_ref::<TooLarge>().is_none());
+        assert_eq!(human_size(50 * 1024 * 1024), "50 MB");
+        assert_eq!(human_size(1000), "1000 bytes");
+    }
+}
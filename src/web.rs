@@ -1,5 +1,11 @@
+use axum::{
+    extract::FromRequestParts,
+    http::{request::Parts, StatusCode},
+};
 use serde::{Deserialize, Serialize};
 
+use crate::model::{ChangeContext, ChangeSource, CircuitScope, Permission};
+
 pub mod responses {
     use axum::{
         http::{header::CONTENT_TYPE, Response, StatusCode},
@@ -8,12 +14,17 @@ pub mod responses {
     use eyre::Report;
     use serde::Serialize;
 
+    use crate::model::NotFound;
+
     #[derive(Serialize, Clone)]
     #[serde(tag = "status")]
     #[serde(rename_all = "lowercase")]
     pub enum RequestResponse<T: Serialize> {
         Success {
             data: T,
+            /// Problems that didn't stop the request
+            #[serde(skip_serializing_if = "Vec::is_empty")]
+            warnings: Vec<String>,
             #[serde(skip)]
             code: StatusCode,
         },
@@ -35,6 +46,7 @@ pub mod responses {
             match result {
                 Ok(data) => RequestResponse::Success {
                     data,
+                    warnings: vec![],
                     code: success_code,
                 },
                 Err(report) => RequestResponse::Error {
@@ -43,6 +55,25 @@ pub mod responses {
                 },
             }
         }
+
+        pub fn with_warnings(self, warnings: Vec<String>) -> RequestResponse<T> {
+            match self {
+                RequestResponse::Success { data, code, .. } => RequestResponse::Success {
+                    data,
+                    warnings,
+                    code,
+                },
+                error => error,
+            }
+        }
+    }
+
+    /// Status of a failed lookup, ids that don't exist are the client's mistake
+    pub fn lookup_error_code<T>(result: &Result<T, Report>) -> StatusCode {
+        match result {
+            Err(e) if e.downcast_ref::<NotFound>().is_some() => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
     }
 
     impl<T> IntoResponse for RequestResponse<T>
@@ -68,6 +99,16 @@ pub mod responses {
     #[derive(Serialize)]
     pub struct LoginResponse {
         pub token: String,
+        pub refresh_token: String,
+        pub roles: Vec<String>,
+        pub permissions: Vec<crate::model::Permission>,
+    }
+
+    /// A new API key, the only time `key` is shown
+    #[derive(Serialize)]
+    pub struct CreatedApiKey {
+        pub api_key: crate::model::ApiKey,
+        pub key: String,
     }
 }
 
@@ -75,59 +116,500 @@ pub mod requests {
     use serde::{Deserialize, Serialize};
     use sqlx::prelude::FromRow;
 
+    use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+
+    use crate::{
+        model::{
+            AccessScope, AuditFilter, CircuitColumn, CircuitQuery, ImportMode, NaturalKey,
+            Permission, SearchQuery, SortKey,
+        },
+        xlsx::{SheetSelection, SheetSplit},
+    };
+
     #[derive(Deserialize, Serialize, FromRow)]
     pub struct LoginRequest {
         pub username: String,
         pub password: String,
-        pub requested_role: super::Role,
+        /// Logs an admin in with only the `user` role, for a read-only session
+        #[serde(default)]
+        pub reduced_privileges: bool,
+    }
+
+    #[derive(Deserialize)]
+    pub struct RefreshRequest {
+        pub refresh_token: String,
     }
 
-    #[derive(FromRow)]
-    pub struct UserResponse {
+    #[derive(Deserialize)]
+    pub struct NewUserRequest {
         pub username: String,
-        #[allow(dead_code)]
         pub password: String,
-        pub role: String,
+        pub roles: Vec<String>,
+    }
+
+    /// Changes to a user, what's missing stays as it is
+    #[derive(Deserialize)]
+    pub struct UserUpdateRequest {
+        pub roles: Option<Vec<String>>,
+        pub disabled: Option<bool>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct PasswordResetRequest {
+        pub password: String,
+    }
+
+    #[derive(Deserialize)]
+    pub struct PasswordChangeRequest {
+        pub current_password: String,
+        pub new_password: String,
+    }
+
+    #[derive(Deserialize)]
+    pub struct NewApiKeyRequest {
+        pub name: String,
+        pub roles: Vec<String>,
+        /// Permissions of its roles the key is held to, unscoped keys have
+        /// all of them
+        #[serde(default)]
+        pub scopes: Vec<Permission>,
+        pub expires_at: Option<DateTime<Utc>>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct NewRoleRequest {
+        pub name: String,
+        #[serde(default)]
+        pub description: String,
+        pub permissions: Vec<Permission>,
+    }
+
+    /// Changes to a role, what's missing stays as it is
+    #[derive(Deserialize)]
+    pub struct RoleUpdateRequest {
+        pub description: Option<String>,
+        pub permissions: Option<Vec<Permission>>,
+    }
+
+    /// Every scope of a user, none lets them reach every circuit
+    #[derive(Deserialize)]
+    pub struct ScopesRequest {
+        pub scopes: Vec<AccessScope>,
+    }
+
+    /// Scopes as stored, trimmed and every one of them once
+    pub fn scope_values(scopes: Vec<AccessScope>) -> eyre::Result<Vec<AccessScope>> {
+        let mut scopes: Vec<AccessScope> = scopes
+            .into_iter()
+            .map(|scope| AccessScope {
+                value: scope.value.trim().to_string(),
+                ..scope
+            })
+            .collect();
+
+        if scopes.iter().any(|scope| scope.value.is_empty()) {
+            return Err(eyre::Report::msg("Scopes need a value"));
+        }
+
+        scopes.sort();
+        scopes.dedup();
+
+        Ok(scopes)
+    }
+
+    /// A region as saved, its name comes from the path
+    #[derive(Deserialize)]
+    pub struct RegionRequest {
+        #[serde(default)]
+        pub description: String,
+        pub sites: Vec<String>,
+    }
+
+    /// Roles as stored, every one of them once
+    pub fn role_names(roles: Vec<String>) -> eyre::Result<Vec<String>> {
+        let mut names: Vec<String> = roles
+            .into_iter()
+            .map(|role| role.trim().to_string())
+            .collect();
+        names.sort();
+        names.dedup();
+
+        if names.is_empty() {
+            return Err(eyre::Report::msg("A user needs at least one role"));
+        }
+
+        Ok(names)
     }
 
     #[derive(Deserialize)]
     pub struct ReportAcknowledgement {
         pub id: String,
     }
+
+    /// Query string of `/api/circuits/all`, `sort` is a comma separated list of
+    /// columns where a leading `-` sorts that column descending
+    #[derive(Deserialize)]
+    pub struct ListCircuitsQuery {
+        #[serde(default)]
+        pub include_decommissioned: bool,
+        pub state: Option<String>,
+        pub provider: Option<String>,
+        pub link_type: Option<String>,
+        pub site_name: Option<String>,
+        pub q: Option<String>,
+        pub sort: Option<String>,
+        pub cursor: Option<String>,
+        pub limit: Option<i64>,
+        pub as_of: Option<String>,
+    }
+
+    const MAX_PAGE_SIZE: i64 = 1000;
+
+    impl TryFrom<ListCircuitsQuery> for CircuitQuery {
+        type Error = eyre::Report;
+
+        fn try_from(value: ListCircuitsQuery) -> Result<Self, Self::Error> {
+            let non_blank = |value: Option<String>| value.filter(|v| !v.trim().is_empty());
+
+            let sort = value
+                .sort
+                .as_deref()
+                .unwrap_or_default()
+                .split(',')
+                .filter(|key| !key.trim().is_empty())
+                .map(str::parse)
+                .collect::<Result<Vec<SortKey>, _>>()?;
+
+            let cursor = non_blank(value.cursor)
+                .map(|cursor| cursor.parse::<ulid::Ulid>())
+                .transpose()
+                .map_err(|_| eyre::Report::msg("Invalid cursor"))?;
+
+            Ok(CircuitQuery {
+                include_decommissioned: value.include_decommissioned,
+                state: non_blank(value.state),
+                provider: non_blank(value.provider),
+                link_type: non_blank(value.link_type),
+                site_name: non_blank(value.site_name),
+                search: non_blank(value.q),
+                sort,
+                cursor,
+                limit: value.limit.map(|limit| limit.clamp(1, MAX_PAGE_SIZE)),
+                as_of: non_blank(value.as_of)
+                    .map(|as_of| parse_timestamp(&as_of, true))
+                    .transpose()?,
+                scope: None,
+            })
+        }
+    }
+
+    #[derive(Deserialize)]
+    pub struct SearchCircuitsQuery {
+        pub q: String,
+        pub limit: Option<i64>,
+        #[serde(default)]
+        pub include_decommissioned: bool,
+    }
+
+    const DEFAULT_SEARCH_RESULTS: i64 = 20;
+    const MAX_SEARCH_RESULTS: i64 = 100;
+
+    impl From<SearchCircuitsQuery> for SearchQuery {
+        fn from(value: SearchCircuitsQuery) -> Self {
+            SearchQuery {
+                text: value.q,
+                limit: value
+                    .limit
+                    .unwrap_or(DEFAULT_SEARCH_RESULTS)
+                    .clamp(1, MAX_SEARCH_RESULTS),
+                include_decommissioned: value.include_decommissioned,
+                scope: None,
+            }
+        }
+    }
+
+    /// Query string of the endpoints that preview or start an import
+    #[derive(Deserialize)]
+    pub struct ImportQuery {
+        #[serde(default)]
+        pub mode: ImportMode,
+        /// Id of the column mapping to read the file with
+        pub mapping: Option<String>,
+        /// Sheet of an uploaded workbook to import, the first one by default
+        pub sheet: Option<String>,
+        /// Row of the workbook sheet with the headers, detected by default
+        pub header_row: Option<u32>,
+    }
+
+    impl ImportQuery {
+        pub fn sheet_selection(&self) -> SheetSelection {
+            SheetSelection {
+                sheet: self.sheet.clone(),
+                header_row: self.header_row,
+            }
+        }
+    }
+
+    #[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum ExportFormat {
+        #[default]
+        Csv,
+        Xlsx,
+        Json,
+        Ndjson,
+    }
+
+    /// Query string of `/api/circuits/export`
+    #[derive(Deserialize)]
+    pub struct ExportQuery {
+        #[serde(default)]
+        pub format: ExportFormat,
+        /// Workbooks only, puts the circuits of each state or provider on their own sheet
+        pub split_by: Option<SheetSplit>,
+        /// Comma separated columns to export in that order, all of them by default
+        pub columns: Option<String>,
+    }
+
+    impl ExportQuery {
+        pub fn columns(&self) -> eyre::Result<Vec<CircuitColumn>> {
+            let mut columns: Vec<CircuitColumn> = vec![];
+
+            for column in self
+                .columns
+                .as_deref()
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|column| !column.is_empty())
+            {
+                let column = column.parse()?;
+                if columns.contains(&column) {
+                    return Err(eyre::Report::msg(format!(
+                        "Column `{}` is listed more than once",
+                        column.as_str()
+                    )));
+                }
+                columns.push(column);
+            }
+
+            if columns.is_empty() {
+                return Ok(CircuitColumn::ALL.to_vec());
+            }
+
+            Ok(columns)
+        }
+    }
+
+    #[derive(Deserialize)]
+    pub struct KeyConflictsQuery {
+        /// Comma separated columns of the key to check, the configured key by default
+        pub columns: Option<String>,
+    }
+
+    impl KeyConflictsQuery {
+        pub fn key(&self) -> eyre::Result<Option<NaturalKey>> {
+            let columns = self
+                .columns
+                .as_deref()
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|column| !column.is_empty())
+                .map(str::parse)
+                .collect::<eyre::Result<Vec<CircuitColumn>>>()?;
+
+            if columns.is_empty() {
+                return Ok(None);
+            }
+
+            let key = NaturalKey { columns };
+            key.validate()?;
+
+            Ok(Some(key))
+        }
+    }
+
+    #[derive(Deserialize, Default, PartialEq, Eq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum ReportFormat {
+        #[default]
+        Json,
+        Csv,
+    }
+
+    /// Query string of `/api/circuits/imports/:id/errors`
+    #[derive(Deserialize)]
+    pub struct ImportErrorsQuery {
+        #[serde(default)]
+        pub format: ReportFormat,
+    }
+
+    #[derive(Deserialize)]
+    pub struct DecommissionRequest {
+        pub reason: String,
+    }
+
+    /// Reads either an RFC 3339 timestamp or a plain date. With `end_of_day` a
+    /// date means the end of that day instead of its start.
+    fn parse_timestamp(value: &str, end_of_day: bool) -> eyre::Result<DateTime<Utc>> {
+        if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+            return Ok(time.with_timezone(&Utc));
+        }
+
+        let date = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+            .map_err(|_| eyre::Report::msg(format!("Invalid date `{value}`")))?;
+        let date = if end_of_day {
+            date.succ_opt().unwrap_or(date)
+        } else {
+            date
+        };
+
+        Ok(date.and_time(NaiveTime::MIN).and_utc())
+    }
+
+    /// Query string of `/api/circuits/imports/:id/undo`, `force` overwrites
+    /// circuits that were changed again after the import
+    #[derive(Deserialize)]
+    pub struct UndoImportQuery {
+        #[serde(default)]
+        pub force: bool,
+    }
+
+    /// Query string of `/api/circuits/:id`
+    #[derive(Deserialize)]
+    pub struct AsOfQuery {
+        pub as_of: Option<String>,
+    }
+
+    impl AsOfQuery {
+        pub fn as_of(&self) -> eyre::Result<Option<DateTime<Utc>>> {
+            self.as_of
+                .as_deref()
+                .filter(|as_of| !as_of.trim().is_empty())
+                .map(|as_of| parse_timestamp(as_of, true))
+                .transpose()
+        }
+    }
+
+    /// Query string of `/api/audit`, `from` and `to` take either an RFC 3339
+    /// timestamp or a plain date, a date in `to` includes that whole day
+    #[derive(Deserialize)]
+    pub struct AuditQuery {
+        pub user: Option<String>,
+        pub from: Option<String>,
+        pub to: Option<String>,
+        pub before: Option<i64>,
+        pub limit: Option<i64>,
+    }
+
+    const DEFAULT_AUDIT_ENTRIES: i64 = 100;
+    const MAX_AUDIT_ENTRIES: i64 = 1000;
+
+    impl TryFrom<AuditQuery> for AuditFilter {
+        type Error = eyre::Report;
+
+        fn try_from(value: AuditQuery) -> Result<Self, Self::Error> {
+            let non_blank = |value: Option<String>| value.filter(|v| !v.trim().is_empty());
+
+            Ok(AuditFilter {
+                actor: non_blank(value.user),
+                from: non_blank(value.from)
+                    .map(|from| parse_timestamp(&from, false))
+                    .transpose()?,
+                to: non_blank(value.to)
+                    .map(|to| parse_timestamp(&to, true))
+                    .transpose()?,
+                before: value.before,
+                limit: value
+                    .limit
+                    .unwrap_or(DEFAULT_AUDIT_ENTRIES)
+                    .clamp(1, MAX_AUDIT_ENTRIES),
+            })
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 struct Claims {
     sub: String,
     exp: usize,
-    role: String,
+    roles: Vec<String>,
+    /// What the roles allowed when the token was issued
+    permissions: Vec<Permission>,
+    /// The session the token was issued for, checked on every request
+    sid: String,
+    /// The circuits a user with scopes reaches, worked out when the token was issued
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scope: Option<CircuitScope>,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-#[serde(try_from = "&str")]
-pub enum Role {
-    Admin,
-    User,
+/// Put next to the claims of requests made with an API key rather than a session
+#[derive(Clone, Copy)]
+struct ApiKeyCaller;
+
+#[axum::async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ChangeContext {
+    type Rejection = responses::RequestResponse<()>;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let claims = parts.extensions.get::<Claims>().ok_or_else(|| {
+            responses::RequestResponse::<()>::Error {
+                message: "Invalid auth".to_string(),
+                code: StatusCode::UNAUTHORIZED,
+            }
+        })?;
+
+        // Told by how the request was authenticated, never by what the client says
+        let source = if parts.extensions.get::<ApiKeyCaller>().is_some() {
+            ChangeSource::Api
+        } else {
+            ChangeSource::Ui
+        };
+
+        Ok(ChangeContext {
+            actor: claims.sub.clone(),
+            source,
+            scope: claims.scope.clone(),
+        })
+    }
 }
 
-impl TryFrom<&str> for Role {
-    type Error = eyre::Report;
+/// The session the caller's token was issued for, the key for API key callers
+pub struct CallerSession(pub String);
 
-    fn try_from(value: &str) -> Result<Self, Self::Error> {
-        match value {
-            "admin" => Ok(Self::Admin),
-            "user" => Ok(Self::User),
-            _ => Err(eyre::Report::msg("Not a valid application role")),
-        }
+#[axum::async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for CallerSession {
+    type Rejection = responses::RequestResponse<()>;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let claims = parts.extensions.get::<Claims>().ok_or_else(|| {
+            responses::RequestResponse::<()>::Error {
+                message: "Invalid auth".to_string(),
+                code: StatusCode::UNAUTHORIZED,
+            }
+        })?;
+
+        Ok(CallerSession(claims.sid.clone()))
     }
 }
 
-impl From<Role> for String {
-    fn from(value: Role) -> Self {
-        match value {
-            Role::Admin => "admin".to_owned(),
-            Role::User => "user".to_owned(),
-        }
+/// The circuits the caller reaches, all of them when it holds `None`
+pub struct CallerScope(pub Option<CircuitScope>);
+
+#[axum::async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for CallerScope {
+    type Rejection = responses::RequestResponse<()>;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let claims = parts.extensions.get::<Claims>().ok_or_else(|| {
+            responses::RequestResponse::<()>::Error {
+                message: "Invalid auth".to_string(),
+                code: StatusCode::UNAUTHORIZED,
+            }
+        })?;
+
+        Ok(CallerScope(claims.scope.clone()))
     }
 }
 
@@ -136,569 +618,4398 @@ pub mod handlers {
     use ulid::Ulid;
 
     use crate::model::{
-        AppState, Circuit, CircuitImportReport, DataSource, NotificationRepository, Reporter,
+        AccessScope, AccessScopes, ApiKey, ApiKeys, AppState, ChangeHistory, Circuit,
+        CircuitImportReport, CircuitQuery, ColumnMapping, ConflictChecks, DataSource, ImportJob,
+        ImportJobs, LoginAudit, LoginFailure, LoginLock, LoginLocks, MappingProfiles, NaturalKey,
+        NaturalKeys, NotificationRepository, Region, Regions, Reporter, Role, Roles, Session,
+        Sessions, StagedImport, Staging, User, Users,
     };
 
     pub mod circuits {
         use axum::{
-            extract::{Multipart, Path, State},
+            extract::{multipart::Field, DefaultBodyLimit, Multipart, Path, Query, State},
             http::{Response, StatusCode},
             middleware::from_fn,
             response::IntoResponse,
-            routing::{get, post, put},
+            routing::{delete, get, post, put},
             Json, Router,
         };
 
+        use std::collections::HashSet;
+
+        use chrono::{SecondsFormat, Utc};
         use ulid::Ulid;
 
         use crate::{
-            model::{AppState, Circuit, CircuitDTO, CircuitImportReport, DataSource, Reporter},
-            web::{middleware::validate_role_mw, responses::RequestResponse},
+            conflicts::{
+                out_of_scope, Conflict, ConflictIndex, ConflictPolicy, ConflictReport, Conflicting,
+            },
+            json::{self, JsonLayout},
+            model::{
+                AppState, Change, ChangeContext, ChangeHistory, ChangeSource, ChangedSince,
+                Circuit, CircuitColumn, CircuitDTO, CircuitImportReport, CircuitQuery,
+                CircuitScope, ColumnMapper, ColumnMapping, ConflictChecks, DataSource,
+                DuplicateKey, FieldChange, ImportAction, ImportErrorCategory, ImportJob,
+                ImportJobs, ImportMode, ImportPreview, ImportProgress, ImportStatus, KeyMatcher,
+                MappingProfiles, NaturalKey, NaturalKeys, NotFound, NotificationRepository,
+                OutOfScope, Page, Permission, PlannedAction, PreviewRow, RawCircuit, Reporter,
+                Revision, SearchQuery, SearchResult, StagedImport, Staging, UnknownCursor,
+                ValidationErrors,
+            },
+            upload::{TooLarge, UploadLimits, UploadReader},
+            web::{
+                middleware::validate_permission_mw,
+                requests::{
+                    AsOfQuery, DecommissionRequest, ExportFormat, ExportQuery, ImportErrorsQuery,
+                    ImportQuery, ListCircuitsQuery, ReportFormat, SearchCircuitsQuery,
+                    UndoImportQuery,
+                },
+                responses::{lookup_error_code, RequestResponse},
+                CallerScope,
+            },
+            xlsx::{self, SheetSelection},
         };
+        use futures_util::{stream, Stream, StreamExt, TryStreamExt};
+
+        const XLSX_CONTENT_TYPE: &str =
+            "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet";
+
+        /// Most circuits written to an export body in one chunk
+        const EXPORT_BATCH: usize = 256;
 
         pub fn get_router<S>() -> Router<AppState<Circuit, S>>
         where
-            S: DataSource<Circuit> + Clone + Send + Sync + 'static + Reporter<CircuitImportReport>,
+            S: DataSource<Circuit>
+                + ConflictChecks<Circuit>
+                + NaturalKeys<NaturalKey>
+                + Clone
+                + Send
+                + Sync
+                + 'static
+                + Reporter<CircuitImportReport>
+                + NotificationRepository<CircuitImportReport>
+                + ChangeHistory<Circuit>
+                + ImportJobs<ImportJob>
+                + MappingProfiles<ColumnMapping>
+                + Staging<StagedImport>,
             <S as DataSource<Circuit>>::Id: From<Ulid> + Send + Sync,
+            <S as ChangeHistory<Circuit>>::Id: From<Ulid>,
+            <S as ImportJobs<ImportJob>>::Id: From<std::string::String> + Send,
+            <S as MappingProfiles<ColumnMapping>>::Id: From<std::string::String>,
+            <S as Staging<StagedImport>>::Id: From<std::string::String>,
+            <S as DataSource<Circuit>>::Query: From<CircuitQuery>,
             <S as Reporter<CircuitImportReport>>::Id: From<std::string::String>,
         {
+            // Uploads are held to `AppState::upload_limits` instead of the default body limit
+            let uploads = Router::new()
+                .route(
+                    "/import",
+                    post(import_circuits).layer(from_fn(|req, next| {
+                        validate_permission_mw(req, next, Permission::CircuitsImport)
+                    })),
+                )
+                .route(
+                    "/import/json",
+                    post(import_json).layer(from_fn(|req, next| {
+                        validate_permission_mw(req, next, Permission::CircuitsImport)
+                    })),
+                )
+                .route(
+                    "/import/preview",
+                    post(preview_import).layer(from_fn(|req, next| {
+                        validate_permission_mw(req, next, Permission::CircuitsImport)
+                    })),
+                )
+                .layer(DefaultBodyLimit::disable());
+
             Router::new()
                 .route(
                     "/create",
-                    post(create)
-                        .layer(from_fn(|req, next| validate_role_mw(req, next, &["admin"]))),
+                    post(create).layer(from_fn(|req, next| {
+                        validate_permission_mw(req, next, Permission::CircuitsWrite)
+                    })),
                 )
                 .route(
                     "/update",
-                    put(update).layer(from_fn(|req, next| validate_role_mw(req, next, &["admin"]))),
+                    put(update).layer(from_fn(|req, next| {
+                        validate_permission_mw(req, next, Permission::CircuitsWrite)
+                    })),
+                )
+                .route(
+                    "/conflicts",
+                    get(get_conflicts).layer(from_fn(|req, next| {
+                        validate_permission_mw(req, next, Permission::CircuitsRead)
+                    })),
                 )
                 .route(
                     "/export",
                     get(export_circuits).layer(from_fn(|req, next| {
-                        validate_role_mw(req, next, &["admin", "user"])
+                        validate_permission_mw(req, next, Permission::CircuitsRead)
                     })),
                 )
+                .merge(uploads)
                 .route(
-                    "/import",
-                    post(import_circuits)
-                        .layer(from_fn(|req, next| validate_role_mw(req, next, &["admin"]))),
+                    "/import/preview/:preview_id/commit",
+                    post(commit_import).layer(from_fn(|req, next| {
+                        validate_permission_mw(req, next, Permission::CircuitsImport)
+                    })),
                 )
                 .route(
                     "/:circuit_id",
-                    get(get_circuit).layer(from_fn(|req, next| {
-                        validate_role_mw(req, next, &["admin", "user"])
+                    get(get_circuit)
+                        .layer(from_fn(|req, next| {
+                            validate_permission_mw(req, next, Permission::CircuitsRead)
+                        }))
+                        .merge(delete(delete_circuit).layer(from_fn(|req, next| {
+                            validate_permission_mw(req, next, Permission::CircuitsDelete)
+                        }))),
+                )
+                .route(
+                    "/:circuit_id/history",
+                    get(get_circuit_history).layer(from_fn(|req, next| {
+                        validate_permission_mw(req, next, Permission::CircuitsRead)
+                    })),
+                )
+                .route(
+                    "/:circuit_id/revert/:revision",
+                    post(revert_circuit).layer(from_fn(|req, next| {
+                        validate_permission_mw(req, next, Permission::CircuitsWrite)
+                    })),
+                )
+                .route(
+                    "/imports/:import_id",
+                    get(get_import_job).layer(from_fn(|req, next| {
+                        validate_permission_mw(req, next, Permission::CircuitsImport)
+                    })),
+                )
+                .route(
+                    "/imports/:import_id/cancel",
+                    post(cancel_import).layer(from_fn(|req, next| {
+                        validate_permission_mw(req, next, Permission::CircuitsImport)
+                    })),
+                )
+                .route(
+                    "/imports/:import_id/errors",
+                    get(get_import_errors).layer(from_fn(|req, next| {
+                        validate_permission_mw(req, next, Permission::CircuitsImport)
+                    })),
+                )
+                .route(
+                    "/imports/:import_id/undo",
+                    post(undo_import).layer(from_fn(|req, next| {
+                        validate_permission_mw(req, next, Permission::CircuitsImport)
+                    })),
+                )
+                .route(
+                    "/:circuit_id/decommission",
+                    post(decommission_circuit).layer(from_fn(|req, next| {
+                        validate_permission_mw(req, next, Permission::CircuitsWrite)
+                    })),
+                )
+                .route(
+                    "/search",
+                    get(search_circuits).layer(from_fn(|req, next| {
+                        validate_permission_mw(req, next, Permission::CircuitsRead)
                     })),
                 )
                 .route(
                     "/all",
                     get(get_all).layer(from_fn(|req, next| {
-                        validate_role_mw(req, next, &["admin", "user"])
+                        validate_permission_mw(req, next, Permission::CircuitsRead)
                     })),
                 )
         }
 
         async fn create<S>(
             State(state): State<AppState<Circuit, S>>,
-            Json(circuit_dto): Json<CircuitDTO>,
+            context: ChangeContext,
+            Json(raw_circuit): Json<RawCircuit>,
         ) -> impl IntoResponse
         where
-            S: DataSource<Circuit> + Clone + Send + Sync + 'static,
+            S: DataSource<Circuit> + ConflictChecks<Circuit> + Clone + Send + Sync + 'static,
         {
-            RequestResponse::<Circuit>::from_result(
-                state.data_source.create(circuit_dto.into()).await,
-                (StatusCode::CREATED, StatusCode::INTERNAL_SERVER_ERROR),
-            )
+            let circuit: Circuit = match CircuitDTO::try_from(raw_circuit) {
+                Ok(circuit_dto) => circuit_dto.into(),
+                Err(e) => {
+                    return RequestResponse::<Circuit>::Error {
+                        message: e.to_string(),
+                        code: StatusCode::BAD_REQUEST,
+                    }
+                }
+            };
+
+            if let Err(e) = CircuitScope::check(context.scope.as_ref(), &circuit) {
+                return RequestResponse::<Circuit>::Error {
+                    message: e.to_string(),
+                    code: StatusCode::FORBIDDEN,
+                };
+            }
+
+            let (result, warnings) = with_warnings(
+                state
+                    .data_source
+                    .create_checked(circuit, context, state.conflict_policy)
+                    .await,
+            );
+            let error_code = change_error_code(&result);
+
+            RequestResponse::<Circuit>::from_result(result, (StatusCode::CREATED, error_code))
+                .with_warnings(warnings)
         }
 
         async fn get_circuit<S>(
             State(state): State<AppState<Circuit, S>>,
             Path(circuit_id): Path<Ulid>,
+            Query(as_of_query): Query<AsOfQuery>,
+            CallerScope(scope): CallerScope,
         ) -> impl IntoResponse
         where
-            S: DataSource<Circuit> + Clone + Send + Sync + 'static,
-            S::Id: From<Ulid>,
+            S: DataSource<Circuit> + ChangeHistory<Circuit> + Clone + Send + Sync + 'static,
+            <S as DataSource<Circuit>>::Id: From<Ulid>,
+            <S as ChangeHistory<Circuit>>::Id: From<Ulid>,
         {
-            RequestResponse::<Circuit>::from_result(
-                state.data_source.get(circuit_id.into()).await,
-                (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
-            )
+            let result = match as_of_query.as_of() {
+                Ok(Some(as_of)) => state.data_source.get_as_of(circuit_id.into(), as_of).await,
+                Ok(None) => state.data_source.get(circuit_id.into()).await,
+                Err(e) => {
+                    return RequestResponse::<Circuit>::Error {
+                        message: e.to_string(),
+                        code: StatusCode::BAD_REQUEST,
+                    }
+                }
+            };
+
+            // Circuits out of scope don't exist as far as the caller is concerned
+            if let Ok(circuit) = &result {
+                if CircuitScope::check(scope.as_ref(), circuit).is_err() {
+                    return RequestResponse::<Circuit>::Error {
+                        message: NotFound::circuit(circuit_id).to_string(),
+                        code: StatusCode::NOT_FOUND,
+                    };
+                }
+            }
+
+            let error_code = lookup_error_code(&result);
+
+            RequestResponse::<Circuit>::from_result(result, (StatusCode::OK, error_code))
         }
 
         async fn update<S>(
             State(state): State<AppState<Circuit, S>>,
-            Json(circuit): Json<Circuit>,
+            context: ChangeContext,
+            Json(raw_circuit): Json<RawCircuit>,
         ) -> impl IntoResponse
         where
-            S: DataSource<Circuit> + Clone + Send + Sync + 'static,
+            S: DataSource<Circuit> + ConflictChecks<Circuit> + Clone + Send + Sync + 'static,
         {
-            RequestResponse::<Circuit>::from_result(
-                state.data_source.update(circuit).await,
-                (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
-            )
-        }
+            let circuit = match Circuit::try_from(raw_circuit) {
+                Ok(circuit) => circuit,
+                Err(e) => {
+                    return RequestResponse::<Circuit>::Error {
+                        message: e.to_string(),
+                        code: StatusCode::BAD_REQUEST,
+                    }
+                }
+            };
 
-        async fn get_all<S>(State(state): State<AppState<Circuit, S>>) -> impl IntoResponse
-        where
-            S: DataSource<Circuit> + Clone + Send + Sync + 'static,
-        {
-            RequestResponse::<Vec<Circuit>>::from_result(
-                state.data_source.get_all().await,
-                (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
-            )
-        }
+            if let Err(e) = CircuitScope::check(context.scope.as_ref(), &circuit) {
+                return RequestResponse::<Circuit>::Error {
+                    message: e.to_string(),
+                    code: StatusCode::FORBIDDEN,
+                };
+            }
 
-        async fn export_circuits<S>(State(state): State<AppState<Circuit, S>>) -> impl IntoResponse
-        where
-            S: DataSource<Circuit> + Clone + Send + Sync + 'static,
-        {
-            let all_circuits_result = state.data_source.get_all().await;
+            let (result, warnings) = with_warnings(
+                state
+                    .data_source
+                    .update_checked(circuit, context, state.conflict_policy)
+                    .await,
+            );
+            let error_code = change_error_code(&result);
 
-            match all_circuits_result {
-                Ok(circuits) => {
-                    let mut writer = csv::Writer::from_writer(vec![]);
+            RequestResponse::<Circuit>::from_result(result, (StatusCode::OK, error_code))
+                .with_warnings(warnings)
+        }
 
-                    for circuit in circuits {
-                        let _ = writer.serialize(circuit);
-                    }
+        /// Changes to circuits that don't exist aren't found, the ones refused
+        /// for touching circuits outside the caller's scope are forbidden, the
+        /// ones refused for their conflicts conflict, any other failure is the
+        /// server's
+        fn change_error_code<T>(result: &eyre::Result<T>) -> StatusCode {
+            match result {
+                Err(e) if e.downcast_ref::<NotFound>().is_some() => StatusCode::NOT_FOUND,
+                Err(e) if e.downcast_ref::<OutOfScope>().is_some() => StatusCode::FORBIDDEN,
+                Err(e) if e.downcast_ref::<Conflicting>().is_some() => StatusCode::CONFLICT,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            }
+        }
 
-                    let csv_data = writer.into_inner();
+        /// A checked write split into its result and the warnings about the
+        /// conflicts it let through
+        fn with_warnings(
+            result: eyre::Result<(Circuit, Vec<Conflict>)>,
+        ) -> (eyre::Result<Circuit>, Vec<String>) {
+            match result {
+                Ok((circuit, conflicts)) => (
+                    Ok(circuit),
+                    conflicts.iter().map(ToString::to_string).collect(),
+                ),
+                Err(e) => (Err(e), vec![]),
+            }
+        }
 
-                    match csv_data {
-                        Ok(data) => {
-                            let headers = [
-                                (axum::http::header::CONTENT_TYPE, "text/csv"),
-                                (
-                                    axum::http::header::CONTENT_DISPOSITION,
-                                    "attachment; filename=\"circuits.csv\"",
-                                ),
-                            ];
+        /// Circuit IDs, interface addresses and router ports claimed by more
+        /// than one circuit that isn't decommissioned. Callers with a scope
+        /// only get the conflicts involving their circuits.
+        async fn get_conflicts<S>(
+            State(state): State<AppState<Circuit, S>>,
+            CallerScope(scope): CallerScope,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + Clone + Send + Sync + 'static,
+            <S as DataSource<Circuit>>::Query: From<CircuitQuery>,
+        {
+            RequestResponse::<ConflictReport>::from_result(
+                state
+                    .data_source
+                    .get_all(CircuitQuery::default().into())
+                    .await
+                    .map(|circuits| {
+                        ConflictIndex::new(&circuits.items)
+                            .report()
+                            .scoped(&out_of_scope(scope.as_ref(), &circuits.items))
+                    }),
+                (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
+            )
+        }
 
-                            (headers, axum::body::Body::from(data)).into_response()
-                        }
+        async fn decommission_circuit<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Path(circuit_id): Path<Ulid>,
+            context: ChangeContext,
+            Json(decommission_request): Json<DecommissionRequest>,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + Clone + Send + Sync + 'static,
+            S::Id: From<Ulid>,
+        {
+            let result = state
+                .data_source
+                .decommission(circuit_id.into(), decommission_request.reason, context)
+                .await;
+            let error_code = change_error_code(&result);
+
+            RequestResponse::<Circuit>::from_result(result, (StatusCode::OK, error_code))
+        }
+
+        async fn delete_circuit<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Path(circuit_id): Path<Ulid>,
+            context: ChangeContext,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + Clone + Send + Sync + 'static,
+            S::Id: From<Ulid>,
+        {
+            let result = state.data_source.delete(circuit_id.into(), context).await;
+            let error_code = change_error_code(&result);
+
+            RequestResponse::<()>::from_result(result, (StatusCode::OK, error_code))
+        }
+
+        async fn get_circuit_history<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Path(circuit_id): Path<Ulid>,
+            CallerScope(scope): CallerScope,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + ChangeHistory<Circuit> + Clone + Send + Sync + 'static,
+            <S as ChangeHistory<Circuit>>::Id: From<Ulid>,
+        {
+            let result = state.data_source.get_history(circuit_id.into()).await;
+
+            // The history goes with the circuit as it was last seen
+            if let Ok(revisions) = &result {
+                let latest = revisions
+                    .iter()
+                    .rev()
+                    .find_map(|revision| revision.snapshot.as_ref());
+                if latest
+                    .is_some_and(|circuit| CircuitScope::check(scope.as_ref(), circuit).is_err())
+                {
+                    return RequestResponse::<Vec<Revision<Circuit>>>::Error {
+                        message: NotFound::circuit(circuit_id).to_string(),
+                        code: StatusCode::NOT_FOUND,
+                    };
+                }
+            }
+
+            let error_code = lookup_error_code(&result);
+
+            RequestResponse::<Vec<Revision<Circuit>>>::from_result(
+                result,
+                (StatusCode::OK, error_code),
+            )
+        }
+
+        async fn revert_circuit<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Path((circuit_id, revision)): Path<(Ulid, i64)>,
+            context: ChangeContext,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + ChangeHistory<Circuit> + Clone + Send + Sync + 'static,
+            <S as ChangeHistory<Circuit>>::Id: From<Ulid>,
+        {
+            let result = state
+                .data_source
+                .revert(circuit_id.into(), revision, context)
+                .await;
+            let error_code = change_error_code(&result);
+
+            RequestResponse::<Circuit>::from_result(result, (StatusCode::OK, error_code))
+        }
+
+        async fn undo_import<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Path(import_id): Path<String>,
+            Query(undo_query): Query<UndoImportQuery>,
+            context: ChangeContext,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + ChangeHistory<Circuit> + Clone + Send + Sync + 'static,
+        {
+            let result = state
+                .data_source
+                .undo_import(import_id, undo_query.force, context)
+                .await;
+            let error_code = match &result {
+                Err(e) if e.downcast_ref::<ChangedSince>().is_some() => StatusCode::CONFLICT,
+                _ => change_error_code(&result),
+            };
+
+            RequestResponse::<Vec<String>>::from_result(result, (StatusCode::OK, error_code))
+        }
+
+        async fn get_all<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Query(list_query): Query<ListCircuitsQuery>,
+            CallerScope(scope): CallerScope,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + Clone + Send + Sync + 'static,
+            S::Query: From<CircuitQuery>,
+        {
+            let circuit_query = match CircuitQuery::try_from(list_query) {
+                Ok(circuit_query) => CircuitQuery {
+                    scope,
+                    ..circuit_query
+                },
+                Err(e) => {
+                    return RequestResponse::<Page<Circuit>>::Error {
+                        message: e.to_string(),
+                        code: StatusCode::BAD_REQUEST,
+                    }
+                }
+            };
+
+            let result = state.data_source.get_all(circuit_query.into()).await;
+            let error_code = match &result {
+                Err(e) if e.downcast_ref::<UnknownCursor>().is_some() => StatusCode::BAD_REQUEST,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+
+            RequestResponse::<Page<Circuit>>::from_result(result, (StatusCode::OK, error_code))
+        }
+
+        async fn search_circuits<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Query(search_query): Query<SearchCircuitsQuery>,
+            CallerScope(scope): CallerScope,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + Clone + Send + Sync + 'static,
+        {
+            let search_query = SearchQuery {
+                scope,
+                ..search_query.into()
+            };
+
+            RequestResponse::<Vec<SearchResult<Circuit>>>::from_result(
+                state.data_source.search(search_query).await,
+                (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
+            )
+        }
+
+        /// The circuits `/all` would list for the same filters and sort order, in
+        /// the chosen format and with the chosen columns. CSV and JSON are
+        /// streamed as the rows are read from the database, so a failure part way
+        /// through can only cut the download short, it is logged.
+        async fn export_circuits<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Query(list_query): Query<ListCircuitsQuery>,
+            Query(export_query): Query<ExportQuery>,
+            CallerScope(scope): CallerScope,
+        ) -> Response<axum::body::Body>
+        where
+            S: DataSource<Circuit> + Clone + Send + Sync + 'static,
+            S::Query: From<CircuitQuery>,
+        {
+            let bad_request = |e: eyre::Report| {
+                RequestResponse::<()>::Error {
+                    message: e.to_string(),
+                    code: StatusCode::BAD_REQUEST,
+                }
+                .into_response()
+            };
+
+            let circuit_query = match CircuitQuery::try_from(list_query) {
+                Ok(circuit_query) => CircuitQuery {
+                    scope,
+                    ..circuit_query
+                },
+                Err(e) => return bad_request(e),
+            };
+
+            let columns = match export_query.columns() {
+                Ok(columns) => columns,
+                Err(e) => return bad_request(e),
+            };
+
+            let (body, content_type, file_name) = match export_query.format {
+                ExportFormat::Csv => (
+                    csv_export(state.data_source.stream_all(circuit_query.into()), columns),
+                    "text/csv",
+                    "circuits.csv",
+                ),
+                ExportFormat::Json | ExportFormat::Ndjson if export_query.columns.is_some() => {
+                    return bad_request(eyre::Report::msg(
+                        "Columns can only be picked for CSV and XLSX exports",
+                    ))
+                }
+                ExportFormat::Json => (
+                    json_export(
+                        state.data_source.stream_all(circuit_query.into()),
+                        JsonLayout::Array,
+                    ),
+                    "application/json",
+                    "circuits.json",
+                ),
+                ExportFormat::Ndjson => (
+                    json_export(
+                        state.data_source.stream_all(circuit_query.into()),
+                        JsonLayout::Lines,
+                    ),
+                    "application/x-ndjson",
+                    "circuits.ndjson",
+                ),
+                // Workbooks are built in memory either way, on a thread of their own
+                ExportFormat::Xlsx => {
+                    let split = export_query.split_by;
+                    let workbook = match state.data_source.get_all(circuit_query.into()).await {
+                        Ok(page) => tokio::task::spawn_blocking(move || {
+                            xlsx::circuits_workbook(&page.items, &columns, split)
+                        })
+                        .await
+                        .map_err(eyre::Report::from)
+                        .and_then(|workbook| workbook),
+                        Err(e) => Err(e),
+                    };
+
+                    match workbook {
+                        Ok(data) => (
+                            axum::body::Body::from(data),
+                            XLSX_CONTENT_TYPE,
+                            "circuits.xlsx",
+                        ),
                         Err(e) => {
-                            tracing::error!("Error ocurred exporting csv {}", e);
+                            tracing::error!("Error ocurred exporting circuits {}", e);
 
-                            Response::builder()
-                                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                                .body(axum::body::Body::empty())
-                                .expect("Is valid response")
+                            return RequestResponse::<()>::Error {
+                                message: e.to_string(),
+                                code: StatusCode::INTERNAL_SERVER_ERROR,
+                            }
+                            .into_response();
                         }
                     }
                 }
-                Err(e) => {
-                    tracing::error!("Error ocurred exporting csv {}", e);
+            };
+
+            let headers = [
+                (axum::http::header::CONTENT_TYPE, content_type.to_string()),
+                (
+                    axum::http::header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"{file_name}\""),
+                ),
+            ];
+
+            (headers, body).into_response()
+        }
+
+        /// `circuits` as CSV with a header row of `columns`
+        fn csv_export(
+            circuits: impl Stream<Item = eyre::Result<Circuit>> + Send + 'static,
+            columns: Vec<CircuitColumn>,
+        ) -> axum::body::Body {
+            let mut header = vec![];
+            let header =
+                csv_row(&mut header, columns.iter().map(CircuitColumn::as_str)).map(|_| header);
+
+            let rows = circuits.ready_chunks(EXPORT_BATCH).map(move |batch| {
+                let mut data = vec![];
+                for circuit in batch {
+                    let circuit = circuit?;
+                    csv_row(
+                        &mut data,
+                        columns.iter().map(|column| export_field(&circuit, *column)),
+                    )?;
+                }
+                Ok(data)
+            });
+
+            let body = stream::once(async move { header })
+                .chain(rows)
+                .inspect_err(|e| tracing::error!("Error ocurred exporting circuits {}", e));
+
+            axum::body::Body::from_stream(body)
+        }
+
+        /// `circuits` as a JSON array or one per line
+        fn json_export(
+            circuits: impl Stream<Item = eyre::Result<Circuit>> + Send + 'static,
+            layout: JsonLayout,
+        ) -> axum::body::Body {
+            let mut first = true;
+            let items = circuits.ready_chunks(EXPORT_BATCH).map(move |batch| {
+                let circuits = batch.into_iter().collect::<eyre::Result<Vec<_>>>()?;
+                let chunk = json::encode(&circuits, layout, first);
+                first &= circuits.is_empty();
+                chunk
+            });
 
-                    Response::builder()
-                        .status(StatusCode::INTERNAL_SERVER_ERROR)
-                        .body(axum::body::Body::empty())
-                        .expect("Is valid response")
+            let body = match layout {
+                JsonLayout::Array => stream::once(async { Ok(b"[".to_vec()) })
+                    .chain(items)
+                    .chain(stream::once(async { Ok(b"]".to_vec()) }))
+                    .boxed(),
+                JsonLayout::Lines => items.boxed(),
+            }
+            .inspect_err(|e| tracing::error!("Error ocurred exporting circuits {}", e));
+
+            axum::body::Body::from_stream(body)
+        }
+
+        /// Appends a CSV record of `values` to `data`
+        fn csv_row<T: AsRef<[u8]>>(
+            data: &mut Vec<u8>,
+            values: impl IntoIterator<Item = T>,
+        ) -> eyre::Result<()> {
+            let mut writer = csv::Writer::from_writer(data);
+            writer.write_record(values)?;
+            writer.flush()?;
+            Ok(())
+        }
+
+        /// A field as exported to CSV, timestamps are written the way they are
+        /// in JSON rather than as `Circuit::field` shows them
+        fn export_field(circuit: &Circuit, column: CircuitColumn) -> String {
+            match (column, circuit.decommissioned_at) {
+                (CircuitColumn::DecommissionedAt, Some(decommissioned_at)) => {
+                    decommissioned_at.to_rfc3339_opts(SecondsFormat::AutoSi, true)
                 }
+                _ => circuit.field(column),
             }
         }
 
+        /// Queues the uploaded CSV for import, returns the id of the import
         async fn import_circuits<S>(
             State(state): State<AppState<Circuit, S>>,
-            mut multipart: Multipart,
+            Query(import_query): Query<ImportQuery>,
+            context: ChangeContext,
+            multipart: Multipart,
         ) -> impl IntoResponse
         where
-            S: DataSource<Circuit> + Reporter<CircuitImportReport> + Clone + Send + Sync + 'static,
+            S: DataSource<Circuit>
+                + NaturalKeys<NaturalKey>
+                + Reporter<CircuitImportReport>
+                + ImportJobs<ImportJob>
+                + MappingProfiles<ColumnMapping>
+                + Clone
+                + Send
+                + Sync
+                + 'static,
+            <S as DataSource<Circuit>>::Id: From<Ulid> + Send,
+            <S as Reporter<CircuitImportReport>>::Id: From<std::string::String>,
+            <S as ImportJobs<ImportJob>>::Id: From<std::string::String> + Send,
+            <S as MappingProfiles<ColumnMapping>>::Id: From<std::string::String>,
+            <S as DataSource<Circuit>>::Query: From<CircuitQuery>,
+        {
+            let (file_name, csv_data) = match read_upload(
+                multipart,
+                &import_query.sheet_selection(),
+                state.upload_limits,
+            )
+            .await
+            {
+                Ok(upload) => upload,
+                Err(response) => return response.into_response(),
+            };
+
+            let mapping =
+                match load_mapping(&state.data_source, import_query.mapping, &csv_data).await {
+                    Ok(mapping) => mapping,
+                    Err(response) => return response.into_response(),
+                };
+
+            let upload = ImportUpload {
+                file_name,
+                csv_data,
+                mode: import_query.mode,
+                mapping,
+                added_header: false,
+            };
+
+            RequestResponse::<String>::from_result(
+                start_import(&state, upload, context).await,
+                (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
+            )
+            .into_response()
+        }
 
+        /// Queues a JSON array or NDJSON body of circuits for import, returns
+        /// the id of the import. The records are imported like the rows of a
+        /// CSV file with their keys as columns, lines in the error reports are
+        /// their position in the array or their line in the NDJSON body. The
+        /// body may be gzip compressed.
+        async fn import_json<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Query(import_query): Query<ImportQuery>,
+            context: ChangeContext,
+            body: axum::body::Body,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit>
+                + NaturalKeys<NaturalKey>
+                + Reporter<CircuitImportReport>
+                + ImportJobs<ImportJob>
+                + MappingProfiles<ColumnMapping>
+                + Clone
+                + Send
+                + Sync
+                + 'static,
+            <S as DataSource<Circuit>>::Id: From<Ulid> + Send,
             <S as Reporter<CircuitImportReport>>::Id: From<std::string::String>,
+            <S as ImportJobs<ImportJob>>::Id: From<std::string::String> + Send,
+            <S as MappingProfiles<ColumnMapping>>::Id: From<std::string::String>,
+            <S as DataSource<Circuit>>::Query: From<CircuitQuery>,
+        {
+            let mut reader = UploadReader::new(state.upload_limits);
+            let mut chunks = body.into_data_stream();
+
+            let converted = async {
+                while let Some(chunk) = chunks.next().await {
+                    reader.push(&chunk?)?;
+                }
+
+                let csv_data = json::records_to_csv(&upload_text(reader.finish()?))?;
+                state.upload_limits.check_rows(&csv_data)?;
+                Ok::<_, eyre::Report>(csv_data)
+            };
+
+            let csv_data = match converted.await {
+                Ok(csv_data) => csv_data,
+                Err(e) => return upload_error(e).into_response(),
+            };
+
+            let mapping =
+                match load_mapping(&state.data_source, import_query.mapping, &csv_data).await {
+                    Ok(mapping) => mapping,
+                    Err(response) => return response.into_response(),
+                };
+
+            let upload = ImportUpload {
+                file_name: None,
+                csv_data,
+                mode: import_query.mode,
+                mapping,
+                added_header: true,
+            };
+
+            RequestResponse::<String>::from_result(
+                start_import(&state, upload, context).await,
+                (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
+            )
+            .into_response()
+        }
+
+        /// Status and progress of an import
+        async fn get_import_job<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Path(import_id): Path<String>,
+            context: ChangeContext,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + ImportJobs<ImportJob> + Clone + Send + Sync + 'static,
+            <S as ImportJobs<ImportJob>>::Id: From<std::string::String>,
+        {
+            let result = visible_job(&state.data_source, import_id, &context).await;
+            let error_code = lookup_error_code(&result);
+
+            RequestResponse::<ImportJob>::from_result(result, (StatusCode::OK, error_code))
+        }
+
+        /// The import unless the caller has a scope and someone else started
+        /// it, callers with a scope only reach their own imports
+        async fn visible_job<S>(
+            data_source: &S,
+            import_id: String,
+            context: &ChangeContext,
+        ) -> eyre::Result<ImportJob>
+        where
+            S: ImportJobs<ImportJob>,
+            <S as ImportJobs<ImportJob>>::Id: From<std::string::String>,
+        {
+            let job = data_source.get_job(import_id.clone().into()).await?;
+
+            if context.scope.is_some() && job.started_by != context.actor {
+                return Err(NotFound::new("import", import_id).into());
+            }
+
+            Ok(job)
+        }
+
+        /// Stops a queued or running import. Rows are only checked for
+        /// cancellation between each other, so the job may take a moment to
+        /// show as cancelled.
+        async fn cancel_import<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Path(import_id): Path<String>,
+            context: ChangeContext,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + ImportJobs<ImportJob> + Clone + Send + Sync + 'static,
+            <S as ImportJobs<ImportJob>>::Id: From<std::string::String>,
         {
-            if let Ok(Some(field)) = multipart.next_field().await {
-                if !field.content_type().is_some_and(|ct| ct == "text/csv") {
+            let job = visible_job(&state.data_source, import_id.clone(), &context).await;
+            let job = match job {
+                Ok(job) => job,
+                Err(ref e) => {
+                    return RequestResponse::<&str>::Error {
+                        message: e.to_string(),
+                        code: lookup_error_code(&job),
+                    }
+                }
+            };
+
+            if job.status.is_finished() {
+                return RequestResponse::<&str>::Error {
+                    message: format!("Import {} already {}", job.id, job.status.as_str()),
+                    code: StatusCode::CONFLICT,
+                };
+            }
+
+            if !state.imports.cancel(&import_id) {
+                // Nothing in this process is working on it, so there is nothing to stop
+                let result = state
+                    .data_source
+                    .finish_job(
+                        import_id.into(),
+                        ImportStatus::Cancelled,
+                        job.progress,
+                        "Import cancelled".to_string(),
+                    )
+                    .await;
+
+                if let Err(e) = result {
                     return RequestResponse::<&str>::Error {
-                        message: "No data field".to_string(),
+                        message: e.to_string(),
+                        code: StatusCode::INTERNAL_SERVER_ERROR,
+                    };
+                }
+            }
+
+            RequestResponse::<&str>::Success {
+                data: "Cancelling import",
+                warnings: vec![],
+                code: StatusCode::OK,
+            }
+        }
+
+        /// Works out what importing the uploaded CSV would do without writing
+        /// anything, the upload is kept so it can be committed later by the preview id
+        async fn preview_import<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Query(import_query): Query<ImportQuery>,
+            context: ChangeContext,
+            multipart: Multipart,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit>
+                + NaturalKeys<NaturalKey>
+                + Staging<StagedImport>
+                + MappingProfiles<ColumnMapping>
+                + Clone
+                + Send
+                + Sync
+                + 'static,
+            <S as DataSource<Circuit>>::Id: From<Ulid>,
+            <S as MappingProfiles<ColumnMapping>>::Id: From<std::string::String>,
+            <S as DataSource<Circuit>>::Query: From<CircuitQuery>,
+        {
+            let (file_name, csv_data) = match read_upload(
+                multipart,
+                &import_query.sheet_selection(),
+                state.upload_limits,
+            )
+            .await
+            {
+                Ok(upload) => upload,
+                Err(response) => return response.into_response(),
+            };
+
+            let mapping =
+                match load_mapping(&state.data_source, import_query.mapping, &csv_data).await {
+                    Ok(mapping) => mapping,
+                    Err(response) => return response.into_response(),
+                };
+
+            let mut preview = ImportPreview {
+                id: Ulid::new().to_string(),
+                file_name: file_name.clone(),
+                mapping_id: mapping.as_ref().map(|mapping| mapping.id.clone()),
+                ..Default::default()
+            };
+
+            let (mut reader, headers, mapper) = match csv_reader(&csv_data, mapping.as_ref()) {
+                Ok(reader) => reader,
+                Err(e) => {
+                    return RequestResponse::<ImportPreview>::Error {
+                        message: format!("Failed reading csv headers : {e}"),
                         code: StatusCode::BAD_REQUEST,
                     }
-                    .into_response();
+                    .into_response()
                 }
+            };
 
-                let file_name = field.file_name().map(|s| s.to_string());
-                let csv_data = field.text().await;
+            let circuits = match state
+                .data_source
+                .get_all(CircuitQuery::default().into())
+                .await
+            {
+                Ok(circuits) => circuits.items,
+                Err(e) => {
+                    return RequestResponse::<ImportPreview>::Error {
+                        message: e.to_string(),
+                        code: StatusCode::INTERNAL_SERVER_ERROR,
+                    }
+                    .into_response()
+                }
+            };
 
-                match csv_data {
-                    Ok(raw) => {
-                        let report_begin_id = ulid::Ulid::new().to_string();
-                        let report_begin_result = state
-                            .data_source
-                            .report(CircuitImportReport {
-                                r#type: "finished".to_string(),
-                                id: report_begin_id.clone(),
-                                message: "In progress".to_string(),
-                                file_name: file_name.clone(),
-                            })
-                            .await;
+            let mut matcher = match key_matcher(&state.data_source, &circuits).await {
+                Ok(matcher) => matcher,
+                Err(e) => {
+                    return RequestResponse::<ImportPreview>::Error {
+                        message: e.to_string(),
+                        code: StatusCode::INTERNAL_SERVER_ERROR,
+                    }
+                    .into_response()
+                }
+            };
+            let mut conflicts = ConflictIndex::new(&circuits);
+            let hidden = out_of_scope(context.scope.as_ref(), &circuits);
 
-                        match report_begin_result {
-                            Ok(_) => {
-                                tracing::info!("Beginning report");
-                            }
-                            Err(e) => {
-                                tracing::error!("Failed to begin report : {}", e);
-                                return RequestResponse::<&str>::Error {
-                                    message: e.to_string(),
-                                    code: StatusCode::INTERNAL_SERVER_ERROR,
-                                }
-                                .into_response();
+            for record in reader.records() {
+                let row = match record {
+                    Ok(row) => {
+                        let line = csv_line(&csv_data, row.position());
+                        let row = match &mapper {
+                            Some(mapper) => mapper.map(&row),
+                            None => row,
+                        };
+                        let raw_circuit = row
+                            .deserialize::<RawCircuit>(Some(&headers))
+                            .map_err(eyre::Report::from)
+                            .and_then(|raw_circuit| match_key(&mut matcher, raw_circuit, line));
+                        match raw_circuit {
+                            Ok(raw_circuit) => {
+                                preview_row(
+                                    &state.data_source,
+                                    &mut conflicts,
+                                    &hidden,
+                                    &state.conflict_policy,
+                                    context.scope.as_ref(),
+                                    raw_circuit,
+                                    line,
+                                )
+                                .await
                             }
+                            Err(e) => PreviewRow::error(line, None, e),
                         }
+                    }
+                    Err(e) => {
+                        let line = csv_line(&csv_data, e.position());
+                        PreviewRow::error(line, None, e.into())
+                    }
+                };
 
-                        tokio::spawn(async move {
-                            let mut num_errors: i32 = 0;
-                            let mut reader = csv::ReaderBuilder::new()
-                                .has_headers(true)
-                                .from_reader(raw.as_bytes());
-
-                            for record in reader.records() {
-                                match record {
-                                    Ok(row) => {
-                                        let circuit_deserialize_res: Result<Circuit, csv::Error> =
-                                            row.deserialize(None);
-
-                                        if let Ok(mut circuit) = circuit_deserialize_res {
-                                            if circuit.id.is_empty() {
-                                                circuit.id = ulid::Ulid::new().to_string();
-                                                match state.data_source.create(circuit).await {
-                                                    Ok(circuit) => {
-                                                        tracing::info!(
-                                                            "Successfully created circuit {:?}",
-                                                            circuit
-                                                        )
-                                                    }
-                                                    Err(e) => {
-                                                        let report_result = state
-                                                            .data_source
-                                                            .report(CircuitImportReport {
-                                                                id: ulid::Ulid::new().to_string(),
-                                                                file_name: file_name.clone(),
-                                                                r#type: "error".to_string(),
-                                                                message: e.to_string(),
-                                                            })
-                                                            .await;
-
-                                                        match report_result {
-                                                            Err(e) => {
-                                                                tracing::error!(
-                                                                "Failed to report error to db : {}",
-                                                                e
-                                                            );
-                                                            }
-                                                            _ => {}
-                                                        }
-
-                                                        tracing::error!(
-                                                            "Failed to create circuit {:?}",
-                                                            e
-                                                        );
-
-                                                        num_errors += 1;
-                                                    }
-                                                }
-                                                continue;
-                                            }
-
-                                            match state.data_source.update(circuit).await {
-                                                Ok(circuit) => {
-                                                    tracing::info!(
-                                                        "Succesfully updated circuit {:?}",
-                                                        circuit
-                                                    )
-                                                }
-                                                Err(e) => {
-                                                    tracing::error!("Failed to update imported circuit with error {}", e);
-                                                    num_errors += 1;
-
-                                                    let report_result = state
-                                                        .data_source
-                                                        .report(CircuitImportReport {
-                                                            id: ulid::Ulid::new().to_string(),
-                                                            file_name: file_name.clone(),
-                                                            r#type: "error".to_string(),
-                                                            message: e.to_string(),
-                                                        })
-                                                        .await;
-
-                                                    match report_result {
-                                                        Err(e) => {
-                                                            tracing::error!(
-                                                                "Failed to report error to db : {}",
-                                                                e
-                                                            );
-                                                        }
-                                                        _ => {}
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-                                    Err(e) => {
-                                        tracing::error!(
-                                            "Failed reading record from imported csv : {}",
-                                            e
-                                        );
-                                        let report_result = state
-                                            .data_source
-                                            .report(CircuitImportReport {
-                                                id: ulid::Ulid::new().to_string(),
-                                                file_name: file_name.clone(),
-                                                r#type: "error".to_string(),
-                                                message: e.to_string(),
-                                            })
-                                            .await;
-
-                                        match report_result {
-                                            Err(e) => {
-                                                tracing::error!(
-                                                    "Failed to report error to db : {}",
-                                                    e
-                                                );
-                                            }
-                                            _ => {}
-                                        }
-                                        num_errors += 1
-                                    }
-                                }
-                            }
+                preview.push(row);
+            }
 
-                            let finish_report_status = state
-                                .data_source
-                                .finish(
-                                    report_begin_id.into(),
-                                    format!("Finished import with {} errors", num_errors),
-                                )
-                                .await;
+            let stage_result = state
+                .data_source
+                .stage(StagedImport {
+                    id: preview.id.clone(),
+                    file_name,
+                    data: csv_data,
+                    staged_by: context.actor,
+                    mapping_id: preview.mapping_id.clone(),
+                })
+                .await;
 
-                            match finish_report_status {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    tracing::error!("Failed to finish reporting import : {}", e)
-                                }
-                            };
-                        });
-                    }
+            RequestResponse::<ImportPreview>::from_result(
+                stage_result.map(|_| preview),
+                (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
+            )
+            .into_response()
+        }
+
+        /// Runs a previewed import for real, returns the id of the started import.
+        /// Rows are applied against the circuits as they are now, which may
+        /// differ from what the preview saw.
+        async fn commit_import<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Path(preview_id): Path<String>,
+            Query(import_query): Query<ImportQuery>,
+            context: ChangeContext,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit>
+                + NaturalKeys<NaturalKey>
+                + Reporter<CircuitImportReport>
+                + ImportJobs<ImportJob>
+                + MappingProfiles<ColumnMapping>
+                + Staging<StagedImport>
+                + Clone
+                + Send
+                + Sync
+                + 'static,
+            <S as DataSource<Circuit>>::Id: From<Ulid> + Send,
+            <S as Reporter<CircuitImportReport>>::Id: From<std::string::String>,
+            <S as ImportJobs<ImportJob>>::Id: From<std::string::String> + Send,
+            <S as MappingProfiles<ColumnMapping>>::Id: From<std::string::String>,
+            <S as Staging<StagedImport>>::Id: From<std::string::String>,
+            <S as DataSource<Circuit>>::Query: From<CircuitQuery>,
+        {
+            let staged = match state
+                .data_source
+                .take(preview_id.into(), context.actor.clone())
+                .await
+            {
+                Ok(staged) => staged,
+                Err(e) => {
+                    let code = if e.downcast_ref::<NotFound>().is_some() {
+                        StatusCode::NOT_FOUND
+                    } else {
+                        StatusCode::INTERNAL_SERVER_ERROR
+                    };
+
+                    return RequestResponse::<String>::Error {
+                        message: e.to_string(),
+                        code,
+                    };
+                }
+            };
+
+            // The file goes through the same mapping the preview used
+            let mapping = match staged.mapping_id {
+                Some(mapping_id) => match state.data_source.get_profile(mapping_id.into()).await {
+                    Ok(mapping) => Some(mapping),
                     Err(e) => {
-                        tracing::error!("Failed to read csv file");
                         return RequestResponse::<String>::Error {
                             message: e.to_string(),
-                            code: StatusCode::BAD_REQUEST,
+                            code: StatusCode::INTERNAL_SERVER_ERROR,
                         }
-                        .into_response();
                     }
-                }
+                },
+                None => None,
+            };
 
-                RequestResponse::<&str>::Success {
-                    data: "Successfully started report",
-                    code: StatusCode::OK,
-                }
-                .into_response()
-            } else {
-                RequestResponse::<&str>::Error {
+            let upload = ImportUpload {
+                file_name: staged.file_name,
+                csv_data: staged.data,
+                mode: import_query.mode,
+                mapping,
+                added_header: false,
+            };
+
+            RequestResponse::<String>::from_result(
+                start_import(&state, upload, context).await,
+                (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
+            )
+        }
+
+        /// Line of the file a record starts on. The csv reader's own line count
+        /// lags behind by one on files with `\r\n` line endings, so it's worked
+        /// out from the byte offset instead.
+        fn csv_line(csv_data: &str, position: Option<&csv::Position>) -> u64 {
+            let Some(byte) = position.map(|position| position.byte() as usize) else {
+                return 0;
+            };
+
+            let before = csv_data.as_bytes().get(..byte).unwrap_or_default();
+            let lines = before.iter().filter(|b| **b == b'\n').count();
+            // After a `\r` the record starts on the `\n` still left over from it
+            let leftover = csv_data.as_bytes().get(byte) == Some(&b'\n');
+
+            (lines + 1 + leftover as usize) as u64
+        }
+
+        /// An uploaded CSV and how to import it
+        struct ImportUpload {
+            file_name: Option<String>,
+            csv_data: String,
+            mode: ImportMode,
+            mapping: Option<ColumnMapping>,
+            /// The header line came from converting the upload and isn't
+            /// counted in the lines of the error reports
+            added_header: bool,
+        }
+
+        /// The column mapping an upload asked for, or the response to send back
+        /// when it doesn't exist or doesn't fit the file
+        async fn load_mapping<S>(
+            data_source: &S,
+            mapping_id: Option<String>,
+            csv_data: &str,
+        ) -> Result<Option<ColumnMapping>, RequestResponse<()>>
+        where
+            S: MappingProfiles<ColumnMapping>,
+            <S as MappingProfiles<ColumnMapping>>::Id: From<std::string::String>,
+        {
+            let Some(mapping_id) = mapping_id else {
+                return Ok(None);
+            };
+
+            let bad_request = |e: eyre::Report| RequestResponse::<()>::Error {
+                message: e.to_string(),
+                code: StatusCode::BAD_REQUEST,
+            };
+
+            let mapping = data_source
+                .get_profile(mapping_id.into())
+                .await
+                .map_err(bad_request)?;
+            csv_reader(csv_data, Some(&mapping)).map_err(bad_request)?;
+
+            Ok(Some(mapping))
+        }
+
+        /// Rows of an uploaded CSV, the headers to read them with and the
+        /// mapper every row goes through first when the upload has a mapping
+        type MappedReader<'a> = (
+            csv::Reader<&'a [u8]>,
+            csv::StringRecord,
+            Option<ColumnMapper>,
+        );
+
+        /// Reader over the rows of an uploaded CSV. With a mapping the headers
+        /// are the mapped ones.
+        fn csv_reader<'a>(
+            csv_data: &'a str,
+            mapping: Option<&ColumnMapping>,
+        ) -> eyre::Result<MappedReader<'a>> {
+            let mut reader = csv::ReaderBuilder::new()
+                .has_headers(true)
+                .from_reader(csv_data.as_bytes());
+            let headers = reader.headers()?.clone();
+
+            match mapping {
+                Some(mapping) => {
+                    let mapper = mapping.mapper(&headers)?;
+                    Ok((reader, mapper.headers(), Some(mapper)))
+                }
+                None => Ok((reader, headers, None)),
+            }
+        }
+
+        /// The file name and contents of the file in an import upload as CSV,
+        /// or the response to send back when there isn't one or it's over the
+        /// `limits`. Workbooks are converted from the sheet in `selection`, gzip
+        /// compressed files are unpacked.
+        async fn read_upload(
+            mut multipart: Multipart,
+            selection: &SheetSelection,
+            limits: UploadLimits,
+        ) -> Result<(Option<String>, String), RequestResponse<()>> {
+            let Ok(Some(mut field)) = multipart.next_field().await else {
+                return Err(RequestResponse::<()>::Error {
                     message: "Malformed request".to_string(),
                     code: StatusCode::BAD_REQUEST,
+                });
+            };
+
+            let file_name = field.file_name().map(|s| s.to_string());
+            let has_extension = |extension: &str| {
+                file_name
+                    .as_deref()
+                    .is_some_and(|name| name.to_lowercase().ends_with(extension))
+            };
+
+            // Browsers don't always know the workbook content type, the extension does
+            let is_workbook =
+                field.content_type() == Some(XLSX_CONTENT_TYPE) || has_extension(".xlsx");
+            let is_csv = matches!(
+                field.content_type(),
+                Some("text/csv" | "application/gzip" | "application/x-gzip")
+            ) || has_extension(".csv.gz");
+
+            if !is_workbook && !is_csv {
+                return Err(RequestResponse::<()>::Error {
+                    message: "No data field".to_string(),
+                    code: StatusCode::BAD_REQUEST,
+                });
+            }
+
+            let reader = if is_workbook {
+                UploadReader::new(limits)
+            } else {
+                UploadReader::csv(limits)
+            };
+
+            let converted = match read_field(&mut field, reader).await {
+                Ok(data) if is_workbook => {
+                    let selection = selection.clone();
+                    // Unpacking and parsing a workbook keeps a thread busy for a while
+                    tokio::task::spawn_blocking(move || {
+                        limits.check_workbook(&data)?;
+                        let csv_data = xlsx::sheet_to_csv(data, &selection)?;
+                        limits.check_rows(&csv_data)?;
+                        Ok(csv_data)
+                    })
+                    .await
+                    .map_err(eyre::Report::from)
+                    .and_then(|converted| converted)
+                }
+                Ok(data) => Ok(upload_text(data)),
+                Err(e) => Err(e),
+            };
+
+            match converted {
+                Ok(csv_data) => Ok((file_name, csv_data)),
+                Err(e) => {
+                    tracing::error!("Failed to read upload : {}", e);
+                    Err(upload_error(e))
                 }
-                .into_response()
             }
         }
 
-        pub mod reporting {
-            use axum::extract::State;
-            use axum::middleware::from_fn;
+        /// The contents of a multipart field, read through `reader` as they arrive
+        async fn read_field(
+            field: &mut Field<'_>,
+            mut reader: UploadReader,
+        ) -> eyre::Result<Vec<u8>> {
+            while let Some(chunk) = field.chunk().await? {
+                reader.push(&chunk)?;
+            }
 
-            use axum::Json;
-            use axum::{
-                http::StatusCode,
-                response::IntoResponse,
-                routing::{get, post},
-                Router,
+            reader.finish()
+        }
+
+        /// An uploaded text file as a string. Like browsers do, a leading byte
+        /// order mark is dropped and bytes that aren't UTF-8 are replaced.
+        fn upload_text(data: Vec<u8>) -> String {
+            let data = data.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(&data);
+            String::from_utf8_lossy(data).into_owned()
+        }
+
+        /// The response for an upload that couldn't be read
+        fn upload_error(error: eyre::Report) -> RequestResponse<()> {
+            let code = if error.downcast_ref::<TooLarge>().is_some() {
+                StatusCode::PAYLOAD_TOO_LARGE
+            } else {
+                StatusCode::BAD_REQUEST
+            };
+
+            RequestResponse::<()>::Error {
+                message: error.to_string(),
+                code,
+            }
+        }
+
+        /// Queues the import and applies its rows in the background once no
+        /// other import is running. Returns the id of the import's job, which
+        /// its revisions and reports are kept under as well.
+        async fn start_import<S>(
+            state: &AppState<Circuit, S>,
+            upload: ImportUpload,
+            context: ChangeContext,
+        ) -> eyre::Result<String>
+        where
+            S: DataSource<Circuit>
+                + NaturalKeys<NaturalKey>
+                + Reporter<CircuitImportReport>
+                + ImportJobs<ImportJob>
+                + Clone
+                + Send
+                + Sync
+                + 'static,
+            <S as DataSource<Circuit>>::Id: From<Ulid> + Send,
+            <S as Reporter<CircuitImportReport>>::Id: From<std::string::String>,
+            <S as ImportJobs<ImportJob>>::Id: From<std::string::String> + Send,
+            <S as DataSource<Circuit>>::Query: From<CircuitQuery>,
+        {
+            let import_id = ulid::Ulid::new().to_string();
+
+            state
+                .data_source
+                .queue_job(ImportJob {
+                    id: import_id.clone(),
+                    file_name: upload.file_name.clone(),
+                    mode: upload.mode,
+                    status: ImportStatus::Queued,
+                    started_by: context.actor.clone(),
+                    message: None,
+                    total_rows: None,
+                    progress: ImportProgress::default(),
+                    queued_at: Utc::now(),
+                    started_at: None,
+                    finished_at: None,
+                    mapping_id: upload.mapping.as_ref().map(|mapping| mapping.id.clone()),
+                })
+                .await?;
+
+            let report_begin_result = state
+                .data_source
+                .report(CircuitImportReport {
+                    r#type: "finished".to_string(),
+                    id: import_id.clone(),
+                    message: "In progress".to_string(),
+                    file_name: upload.file_name.clone(),
+                    ..Default::default()
+                })
+                .await;
+
+            match report_begin_result {
+                Ok(_) => {
+                    tracing::info!("Beginning report");
+                }
+                Err(e) => {
+                    tracing::error!("Failed to begin report : {}", e);
+                    return Err(e);
+                }
+            }
+
+            let context = ChangeContext {
+                source: ChangeSource::Import {
+                    file_name: upload.file_name.clone(),
+                    import_id: import_id.clone(),
+                },
+                ..context
+            };
+
+            let mut cancelled = state.imports.register(import_id.clone());
+            let state = state.clone();
+            let job_id = import_id.clone();
+
+            tokio::spawn(async move {
+                // Cancelling an import that is still waiting takes it out of the line
+                let turn = tokio::select! {
+                    turn = state.imports.wait_turn() => Some(turn),
+                    _ = cancelled.wait_for(|cancelled| *cancelled) => None,
+                };
+
+                let (status, progress, message) = match turn {
+                    Some(_turn) => run_import(&state, &job_id, &upload, context, cancelled).await,
+                    None => (
+                        ImportStatus::Cancelled,
+                        ImportProgress::default(),
+                        "Import cancelled before it started, no changes were made".to_string(),
+                    ),
+                };
+
+                if let Err(e) = state
+                    .data_source
+                    .finish_job(job_id.clone().into(), status, progress, message.clone())
+                    .await
+                {
+                    tracing::error!("Failed to finish import job : {}", e);
+                }
+
+                if let Err(e) = state
+                    .data_source
+                    .finish(job_id.clone().into(), message)
+                    .await
+                {
+                    tracing::error!("Failed to finish reporting import : {}", e)
+                }
+
+                state.imports.remove(&job_id);
+            });
+
+            Ok(import_id)
+        }
+
+        /// Writes out how far along an import is, failing to only gets logged
+        async fn record_progress<S>(data_source: &S, job_id: &str, progress: ImportProgress)
+        where
+            S: ImportJobs<ImportJob>,
+            <S as ImportJobs<ImportJob>>::Id: From<std::string::String> + Send,
+        {
+            if let Err(e) = data_source
+                .record_progress(job_id.to_string().into(), progress)
+                .await
+            {
+                tracing::error!("Failed to record import progress : {}", e);
+            }
+        }
+
+        /// Applies the rows of an import whose turn has come and reports the
+        /// ones that failed. Returns how the job ended.
+        async fn run_import<S>(
+            state: &AppState<Circuit, S>,
+            job_id: &str,
+            upload: &ImportUpload,
+            context: ChangeContext,
+            mut cancelled: tokio::sync::watch::Receiver<bool>,
+        ) -> (ImportStatus, ImportProgress, String)
+        where
+            S: DataSource<Circuit>
+                + NaturalKeys<NaturalKey>
+                + Reporter<CircuitImportReport>
+                + ImportJobs<ImportJob>
+                + Clone
+                + Send
+                + Sync
+                + 'static,
+            <S as DataSource<Circuit>>::Id: From<Ulid> + Send,
+            <S as ImportJobs<ImportJob>>::Id: From<std::string::String> + Send,
+            <S as DataSource<Circuit>>::Query: From<CircuitQuery>,
+        {
+            // Progress is written out every this many rows
+            const PROGRESS_INTERVAL: u64 = 100;
+
+            let ImportUpload {
+                file_name,
+                csv_data,
+                mode,
+                mapping,
+                added_header,
+            } = upload;
+            let mode = *mode;
+            let line = |position: Option<&csv::Position>| {
+                csv_line(csv_data, position).saturating_sub(*added_header as u64)
+            };
+
+            let (mut reader, headers, mapper) = match csv_reader(csv_data, mapping.as_ref()) {
+                Ok(reader) => reader,
+                Err(e) => {
+                    tracing::error!("Failed reading csv headers : {}", e);
+                    return (
+                        ImportStatus::Failed,
+                        ImportProgress::default(),
+                        format!("Failed reading csv headers : {e}"),
+                    );
+                }
+            };
+
+            let circuits = match state
+                .data_source
+                .get_all(CircuitQuery::default().into())
+                .await
+            {
+                Ok(circuits) => circuits.items,
+                Err(e) => {
+                    tracing::error!("Failed loading the circuits : {}", e);
+                    return (
+                        ImportStatus::Failed,
+                        ImportProgress::default(),
+                        format!("Failed loading the circuits : {e}"),
+                    );
+                }
+            };
+
+            let mut matcher = match key_matcher(&state.data_source, &circuits).await {
+                Ok(matcher) => matcher,
+                Err(e) => {
+                    tracing::error!("Failed loading the natural key : {}", e);
+                    return (
+                        ImportStatus::Failed,
+                        ImportProgress::default(),
+                        format!("Failed loading the natural key : {e}"),
+                    );
+                }
+            };
+            // Rows are checked against the circuits and the rows before them
+            let mut conflicts = ConflictIndex::new(&circuits);
+            let hidden = out_of_scope(context.scope.as_ref(), &circuits);
+
+            // Line and raw values of every row are kept around for the error reports
+            let (rows, changes): (Vec<_>, Vec<_>) = reader
+                .records()
+                .map(|record| match record {
+                    Ok(record) => {
+                        let line = line(record.position());
+                        let record = match &mapper {
+                            Some(mapper) => mapper.map(&record),
+                            None => record,
+                        };
+                        let checked = record
+                            .deserialize::<RawCircuit>(Some(&headers))
+                            .map_err(eyre::Report::from)
+                            .and_then(|raw_circuit| match_key(&mut matcher, raw_circuit, line))
+                            .and_then(|raw_circuit| {
+                                let change = import_change::<<S as DataSource<Circuit>>::Id>(
+                                    raw_circuit.clone(),
+                                )?;
+                                let warnings = check_conflicts(
+                                    &mut conflicts,
+                                    &hidden,
+                                    &state.conflict_policy,
+                                    &raw_circuit,
+                                    line,
+                                )?;
+                                Ok((change, warnings))
+                            });
+                        match checked {
+                            Ok((change, warnings)) => ((line, Some(record), warnings), Ok(change)),
+                            Err(e) => ((line, Some(record), vec![]), Err(e)),
+                        }
+                    }
+                    Err(e) => ((line(e.position()), None, vec![]), Err(e.into())),
+                })
+                .unzip();
+
+            let total_rows = rows.len() as u64;
+            if let Err(e) = state
+                .data_source
+                .start_job(job_id.to_string().into(), total_rows)
+                .await
+            {
+                tracing::error!("Failed to start import job : {}", e);
+            }
+
+            let mut progress = ImportProgress::default();
+            let mut was_cancelled = false;
+
+            // Failures of the import as a whole don't belong to any row
+            let errors: Vec<(Option<usize>, eyre::Report)> = match mode {
+                ImportMode::BestEffort => {
+                    let mut errors = vec![];
+                    for (position, change) in changes.into_iter().enumerate() {
+                        if *cancelled.borrow() {
+                            was_cancelled = true;
+                            break;
+                        }
+
+                        let result = match change {
+                            Ok(change) => {
+                                let mut counted = progress;
+                                counted.applied(&change);
+                                apply_change(&state.data_source, change, context.clone())
+                                    .await
+                                    .map(|_| counted)
+                            }
+                            Err(e) => Err(e),
+                        };
+
+                        match result {
+                            Ok(counted) => progress = counted,
+                            Err(e) => {
+                                progress.failed();
+                                errors.push((Some(position), e));
+                            }
+                        }
+
+                        if progress.processed.is_multiple_of(PROGRESS_INTERVAL) {
+                            record_progress(&state.data_source, job_id, progress).await;
+                        }
+                    }
+                    errors
+                }
+                ImportMode::Atomic => {
+                    let mut valid = vec![];
+                    let mut invalid = vec![];
+                    for (position, change) in changes.into_iter().enumerate() {
+                        match change {
+                            Ok(change) => valid.push((position, change)),
+                            Err(e) => invalid.push((Some(position), e)),
+                        }
+                    }
+
+                    // Rows that don't even parse already fail the import, so
+                    // there's no point in touching the database
+                    if invalid.is_empty() {
+                        let (positions, valid): (Vec<_>, Vec<_>) = valid.into_iter().unzip();
+                        // Progress after each change, as long as all of them succeed
+                        let steps: Vec<ImportProgress> = valid
+                            .iter()
+                            .scan(ImportProgress::default(), |applied, change| {
+                                applied.applied(change);
+                                Some(*applied)
+                            })
+                            .collect();
+
+                        let (processed, mut processed_changes) = tokio::sync::watch::channel(0);
+                        let apply_all = state.data_source.apply_all(valid, context, processed);
+                        tokio::pin!(apply_all);
+
+                        // Dropping `apply_all` part way rolls its transaction back
+                        let result = loop {
+                            tokio::select! {
+                                result = &mut apply_all => break Some(result),
+                                // The guard `wait_for` returns can't be held
+                                // while the progress is recorded
+                                _ = async { cancelled.wait_for(|c| *c).await.is_ok() } => break None,
+                                Ok(()) = processed_changes.changed() => {
+                                    let applied = steps[*processed_changes.borrow_and_update() - 1];
+                                    if applied.processed.is_multiple_of(PROGRESS_INTERVAL) {
+                                        record_progress(&state.data_source, job_id, applied).await;
+                                    }
+                                }
+                            }
+                        };
+
+                        match result {
+                            Some(Ok(failures)) if failures.is_empty() => {
+                                progress = steps.last().copied().unwrap_or_default();
+                                vec![]
+                            }
+                            Some(Ok(failures)) => failures
+                                .into_iter()
+                                .map(|(i, e)| (Some(positions[i]), e))
+                                .collect(),
+                            Some(Err(e)) => vec![(None, e)],
+                            None => {
+                                was_cancelled = true;
+                                vec![]
+                            }
+                        }
+                    } else {
+                        invalid
+                    }
+                }
+            };
+
+            if mode == ImportMode::Atomic && !errors.is_empty() {
+                // Nothing was written, every row counts as processed
+                progress = ImportProgress {
+                    processed: total_rows,
+                    failed: errors.iter().filter(|(row, _)| row.is_some()).count() as u64,
+                    ..Default::default()
+                };
+            }
+
+            let header_names: Vec<String> = headers.iter().map(str::to_string).collect();
+
+            for (position, e) in &errors {
+                tracing::error!("Failed to import circuit : {}", e);
+
+                let row = position.map(|position| &rows[position]);
+                let template = CircuitImportReport {
+                    r#type: "error".to_string(),
+                    file_name: file_name.clone(),
+                    import_id: Some(job_id.to_string()),
+                    line: row.map(|(line, _, _)| *line as i64),
+                    headers: Some(header_names.clone()),
+                    record: row.and_then(|(_, record, _)| {
+                        record
+                            .as_ref()
+                            .map(|record| record.iter().map(str::to_string).collect())
+                    }),
+                    ..Default::default()
+                };
+
+                for report in error_reports(e, template) {
+                    if let Err(e) = state.data_source.report(report).await {
+                        tracing::error!("Failed to report error to db : {}", e);
+                    }
+                }
+            }
+
+            for (position, (line, record, warnings)) in rows.iter().enumerate() {
+                // A row that failed is reported by its error alone
+                if errors.iter().any(|(row, _)| *row == Some(position)) {
+                    continue;
+                }
+
+                for warning in warnings {
+                    let report = CircuitImportReport {
+                        r#type: "warning".to_string(),
+                        id: Ulid::new().to_string(),
+                        message: warning.to_string(),
+                        file_name: file_name.clone(),
+                        import_id: Some(job_id.to_string()),
+                        line: Some(*line as i64),
+                        category: Some(ImportErrorCategory::Conflict.as_str().to_string()),
+                        headers: Some(header_names.clone()),
+                        record: record
+                            .as_ref()
+                            .map(|record| record.iter().map(str::to_string).collect()),
+                        ..Default::default()
+                    };
+
+                    if let Err(e) = state.data_source.report(report).await {
+                        tracing::error!("Failed to report warning to db : {}", e);
+                    }
+                }
+            }
+
+            if was_cancelled {
+                let message = match mode {
+                    ImportMode::Atomic => "Import cancelled, no changes were made".to_string(),
+                    ImportMode::BestEffort => format!(
+                        "Import cancelled after {} of {} rows, the changes made until then were kept",
+                        progress.processed, total_rows
+                    ),
+                };
+                (ImportStatus::Cancelled, progress, message)
+            } else if mode == ImportMode::Atomic && !errors.is_empty() {
+                let message = format!(
+                    "Import rolled back with {} errors, no changes were made",
+                    errors.len()
+                );
+                (ImportStatus::Failed, progress, message)
+            } else {
+                let message = format!("Finished import with {} errors", errors.len());
+                (ImportStatus::Succeeded, progress, message)
+            }
+        }
+
+        /// Matches import rows to circuits by the natural key, when one is set
+        async fn key_matcher<S>(
+            data_source: &S,
+            circuits: &[Circuit],
+        ) -> eyre::Result<Option<KeyMatcher>>
+        where
+            S: NaturalKeys<NaturalKey>,
+        {
+            let Some(key) = data_source.get_natural_key().await? else {
+                return Ok(None);
+            };
+
+            Ok(Some(KeyMatcher::new(key, circuits)))
+        }
+
+        /// Checks the circuit a row leaves behind against `index` and records
+        /// it there. Conflicts the policy refuses fail the row, the warnings
+        /// are returned. New circuits go by their line until they have an id.
+        fn check_conflicts(
+            index: &mut ConflictIndex,
+            hidden: &HashSet<String>,
+            policy: &ConflictPolicy,
+            raw_circuit: &RawCircuit,
+            line: u64,
+        ) -> eyre::Result<Vec<Conflict>> {
+            let circuit = match raw_circuit.action()? {
+                ImportAction::Upsert if raw_circuit.id().is_none() => Circuit {
+                    id: format!("line {line}"),
+                    ..CircuitDTO::try_from(raw_circuit.clone())?.into()
+                },
+                ImportAction::Upsert => Circuit::try_from(raw_circuit.clone())?,
+                ImportAction::Decommission | ImportAction::Delete => {
+                    index.remove(&raw_circuit.required_id()?.to_string());
+                    return Ok(vec![]);
+                }
+            };
+
+            let conflicts = index
+                .check(&circuit)
+                .into_iter()
+                .map(|conflict| conflict.scoped(hidden))
+                .collect();
+            let (errors, warnings) = policy.split(conflicts);
+            if !errors.is_empty() {
+                return Err(Conflicting(errors).into());
+            }
+
+            index.insert(&circuit);
+
+            Ok(warnings)
+        }
+
+        fn match_key(
+            matcher: &mut Option<KeyMatcher>,
+            raw_circuit: RawCircuit,
+            line: u64,
+        ) -> eyre::Result<RawCircuit> {
+            match matcher {
+                Some(matcher) => matcher.resolve(raw_circuit, line),
+                None => Ok(raw_circuit),
+            }
+        }
+
+        async fn preview_row<S>(
+            data_source: &S,
+            conflicts: &mut ConflictIndex,
+            hidden: &HashSet<String>,
+            policy: &ConflictPolicy,
+            scope: Option<&CircuitScope>,
+            raw_circuit: RawCircuit,
+            line: u64,
+        ) -> PreviewRow
+        where
+            S: DataSource<Circuit>,
+            S::Id: From<Ulid>,
+        {
+            let circuit_id = raw_circuit.id().map(str::to_string);
+
+            let planned = match plan_import_row(data_source, scope, raw_circuit.clone()).await {
+                Ok(planned) => check_conflicts(conflicts, hidden, policy, &raw_circuit, line)
+                    .map(|warnings| (planned, warnings)),
+                Err(e) => Err(e),
+            };
+
+            match planned {
+                Ok(((action, changes), warnings)) => PreviewRow {
+                    line,
+                    action,
+                    circuit_id,
+                    changes,
+                    error: None,
+                    warnings: warnings.iter().map(ToString::to_string).collect(),
+                },
+                Err(e) => PreviewRow::error(line, circuit_id, e),
+            }
+        }
+
+        /// What `import_circuit` would do with a row, worked out against the
+        /// circuits as they are now. Rows touching circuits outside `scope` fail.
+        async fn plan_import_row<S>(
+            data_source: &S,
+            scope: Option<&CircuitScope>,
+            raw_circuit: RawCircuit,
+        ) -> eyre::Result<(PlannedAction, Vec<FieldChange>)>
+        where
+            S: DataSource<Circuit>,
+            S::Id: From<Ulid>,
+        {
+            let action = raw_circuit.action()?;
+            let circuit_id = raw_circuit.id().map(str::to_string);
+
+            if action == ImportAction::Upsert && circuit_id.is_none() {
+                let circuit: Circuit = CircuitDTO::try_from(raw_circuit)?.into();
+                CircuitScope::check(scope, &circuit)?;
+                return Ok((
+                    PlannedAction::Create,
+                    FieldChange::diff(None, Some(&circuit)),
+                ));
+            }
+
+            let id = raw_circuit.required_id()?;
+            let current = data_source.get(id.into()).await?;
+            CircuitScope::check(scope, &current)?;
+
+            match action {
+                ImportAction::Delete => Ok((
+                    PlannedAction::Delete,
+                    FieldChange::diff(Some(&current), None),
+                )),
+                ImportAction::Decommission => {
+                    let decommissioned = Circuit {
+                        decommissioned_at: current.decommissioned_at.or(Some(Utc::now())),
+                        decommission_reason: Some(
+                            raw_circuit.decommission_reason.unwrap_or_default(),
+                        ),
+                        ..current.clone()
+                    };
+                    Ok((
+                        PlannedAction::Decommission,
+                        FieldChange::diff(Some(&current), Some(&decommissioned)),
+                    ))
+                }
+                ImportAction::Upsert => {
+                    // Updates leave the decommission details alone
+                    let updated = Circuit {
+                        decommissioned_at: current.decommissioned_at,
+                        decommission_reason: current.decommission_reason.clone(),
+                        ..Circuit::try_from(raw_circuit)?
+                    };
+                    CircuitScope::check(scope, &updated)?;
+                    let changes = FieldChange::diff(Some(&current), Some(&updated));
+                    let action = if changes.is_empty() {
+                        PlannedAction::Unchanged
+                    } else {
+                        PlannedAction::Update
+                    };
+                    Ok((action, changes))
+                }
+            }
+        }
+
+        /// Reports for a failed row, one per offending field when the fields are
+        /// known. `template` carries what all of them share.
+        fn error_reports(
+            error: &eyre::Report,
+            template: CircuitImportReport,
+        ) -> Vec<CircuitImportReport> {
+            if let Some(ValidationErrors(field_errors)) = error.downcast_ref::<ValidationErrors>() {
+                return field_errors
+                    .iter()
+                    .map(|field_error| CircuitImportReport {
+                        id: Ulid::new().to_string(),
+                        message: format!(
+                            "{}: {} (got `{}`)",
+                            field_error.field, field_error.message, field_error.value
+                        ),
+                        column: Some(field_error.field.to_string()),
+                        raw_value: Some(field_error.value.clone()),
+                        category: Some(ImportErrorCategory::Validation.as_str().to_string()),
+                        ..template.clone()
+                    })
+                    .collect();
+            }
+
+            if let Some(Conflicting(conflicts)) = error.downcast_ref::<Conflicting>() {
+                return conflicts
+                    .iter()
+                    .map(|conflict| CircuitImportReport {
+                        id: Ulid::new().to_string(),
+                        message: conflict.to_string(),
+                        category: Some(ImportErrorCategory::Conflict.as_str().to_string()),
+                        ..template.clone()
+                    })
+                    .collect();
+            }
+
+            let category = if error.downcast_ref::<csv::Error>().is_some() {
+                ImportErrorCategory::Csv
+            } else if error.downcast_ref::<NotFound>().is_some() {
+                ImportErrorCategory::NotFound
+            } else if error.downcast_ref::<DuplicateKey>().is_some() {
+                ImportErrorCategory::DuplicateKey
+            } else if error.downcast_ref::<OutOfScope>().is_some() {
+                ImportErrorCategory::OutOfScope
+            } else {
+                ImportErrorCategory::Database
+            };
+
+            vec![CircuitImportReport {
+                id: Ulid::new().to_string(),
+                message: error.to_string(),
+                category: Some(category.as_str().to_string()),
+                ..template
+            }]
+        }
+
+        /// Errors of a single import, or with `format=csv` the rows that failed
+        /// in their original form with the line and errors added, ready to be
+        /// fixed and uploaded again
+        async fn get_import_errors<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Path(import_id): Path<String>,
+            Query(errors_query): Query<ImportErrorsQuery>,
+            context: ChangeContext,
+        ) -> Response<axum::body::Body>
+        where
+            S: DataSource<Circuit>
+                + NotificationRepository<CircuitImportReport>
+                + ImportJobs<ImportJob>
+                + Clone
+                + Send
+                + Sync
+                + 'static,
+            <S as ImportJobs<ImportJob>>::Id: From<std::string::String>,
+        {
+            let job = visible_job(&state.data_source, import_id.clone(), &context).await;
+            if let Err(e) = &job {
+                return RequestResponse::<()>::Error {
+                    message: e.to_string(),
+                    code: lookup_error_code(&job),
+                }
+                .into_response();
+            }
+
+            let reports = match state.data_source.get_for_import(import_id.clone()).await {
+                Ok(reports) => reports
+                    .into_iter()
+                    .filter(|report| report.r#type == "error")
+                    .collect::<Vec<_>>(),
+                Err(e) => {
+                    return RequestResponse::<()>::Error {
+                        message: e.to_string(),
+                        code: StatusCode::INTERNAL_SERVER_ERROR,
+                    }
+                    .into_response()
+                }
+            };
+
+            if errors_query.format != ReportFormat::Csv {
+                return RequestResponse::<Vec<CircuitImportReport>>::Success {
+                    data: reports,
+                    warnings: vec![],
+                    code: StatusCode::OK,
+                }
+                .into_response();
+            }
+
+            match annotated_csv(&reports) {
+                Ok(data) => {
+                    let headers = [
+                        (axum::http::header::CONTENT_TYPE, "text/csv".to_string()),
+                        (
+                            axum::http::header::CONTENT_DISPOSITION,
+                            format!("attachment; filename=\"import-{import_id}-errors.csv\""),
+                        ),
+                    ];
+
+                    (headers, axum::body::Body::from(data)).into_response()
+                }
+                Err(e) => {
+                    tracing::error!("Error ocurred exporting import errors {}", e);
+
+                    RequestResponse::<()>::Error {
+                        message: e.to_string(),
+                        code: StatusCode::INTERNAL_SERVER_ERROR,
+                    }
+                    .into_response()
+                }
+            }
+        }
+
+        /// The failed rows with the file's own columns followed by `import_line`
+        /// and `import_errors`. Imports ignore unknown columns, so the result can
+        /// be uploaded again as is.
+        fn annotated_csv(reports: &[CircuitImportReport]) -> eyre::Result<Vec<u8>> {
+            let headers = reports
+                .iter()
+                .find_map(|report| report.headers.clone())
+                .unwrap_or_default();
+
+            let mut writer = csv::Writer::from_writer(vec![]);
+            writer.write_record(
+                headers
+                    .iter()
+                    .map(String::as_str)
+                    .chain(["import_line", "import_errors"]),
+            )?;
+
+            // Reports come ordered by line, a row with several bad fields has one each
+            for row_reports in reports.chunk_by(|a, b| a.line == b.line) {
+                let mut values = row_reports[0].record.clone().unwrap_or_default();
+                values.resize(headers.len(), String::new());
+
+                let line = row_reports[0]
+                    .line
+                    .map(|line| line.to_string())
+                    .unwrap_or_default();
+                let messages = row_reports
+                    .iter()
+                    .map(|report| report.message.as_str())
+                    .collect::<Vec<_>>()
+                    .join("; ");
+
+                writer.write_record(
+                    values
+                        .iter()
+                        .map(String::as_str)
+                        .chain([line.as_str(), messages.as_str()]),
+                )?;
+            }
+
+            Ok(writer.into_inner()?)
+        }
+
+        /// What an imported row asks for according to its `action` column
+        fn import_change<Id>(raw_circuit: RawCircuit) -> eyre::Result<Change<Circuit, Id>>
+        where
+            Id: From<Ulid>,
+        {
+            let action = raw_circuit.action()?;
+
+            if action != ImportAction::Upsert {
+                let circuit_id = raw_circuit.required_id()?;
+
+                if action == ImportAction::Delete {
+                    return Ok(Change::Delete(circuit_id.into()));
+                }
+
+                return Ok(Change::Decommission {
+                    id: circuit_id.into(),
+                    reason: raw_circuit.decommission_reason.unwrap_or_default(),
+                });
+            }
+
+            if raw_circuit.id().is_none() {
+                let circuit_dto = CircuitDTO::try_from(raw_circuit)?;
+                return Ok(Change::Create(circuit_dto.into()));
+            }
+
+            Ok(Change::Update(Circuit::try_from(raw_circuit)?))
+        }
+
+        /// Applies a single imported row on its own
+        async fn apply_change<S>(
+            data_source: &S,
+            change: Change<Circuit, S::Id>,
+            context: ChangeContext,
+        ) -> eyre::Result<()>
+        where
+            S: DataSource<Circuit>,
+        {
+            match change {
+                Change::Create(circuit) => {
+                    let circuit = data_source.create(circuit, context).await?;
+                    tracing::info!("Successfully created circuit {:?}", circuit);
+                }
+                Change::Update(circuit) => {
+                    let circuit = data_source.update(circuit, context).await?;
+                    tracing::info!("Succesfully updated circuit {:?}", circuit);
+                }
+                Change::Decommission { id, reason } => {
+                    let circuit = data_source.decommission(id, reason, context).await?;
+                    tracing::info!("Successfully decommissioned circuit {}", circuit.id);
+                }
+                Change::Delete(id) => {
+                    data_source.delete(id, context).await?;
+                    tracing::info!("Successfully deleted circuit");
+                }
+            }
+
+            Ok(())
+        }
+
+        pub mod reporting {
+            use axum::extract::State;
+            use axum::middleware::from_fn;
+
+            use axum::Json;
+            use axum::{
+                http::StatusCode,
+                response::IntoResponse,
+                routing::{get, post},
+                Router,
+            };
+            use ulid::Ulid;
+
+            use crate::model::{AppState, Circuit, DataSource, NotificationRepository, Reporter};
+            use crate::web::requests::ReportAcknowledgement;
+            use crate::{
+                model::{CircuitImportReport, Permission},
+                web::{middleware::validate_permission_mw, responses::RequestResponse},
+            };
+
+            pub fn get_router<S>() -> Router<AppState<Circuit, S>>
+            where
+                S: DataSource<Circuit>
+                    + Clone
+                    + Send
+                    + Sync
+                    + 'static
+                    + NotificationRepository<CircuitImportReport>
+                    + Reporter<CircuitImportReport>,
+
+                <S as DataSource<Circuit>>::Id: From<Ulid> + Send + Sync,
+                <S as Reporter<CircuitImportReport>>::Id: From<std::string::String>,
+            {
+                Router::new()
+                    .route(
+                        "/get/unseen",
+                        get(get_all_unseen_reports).layer(from_fn(|req, next| {
+                            validate_permission_mw(req, next, Permission::ReportsRead)
+                        })),
+                    )
+                    .route(
+                        "/get/all",
+                        get(get_all_reports).layer(from_fn(|req, next| {
+                            validate_permission_mw(req, next, Permission::ReportsRead)
+                        })),
+                    )
+                    .route(
+                        "/acknowledge",
+                        post(acknowledge_report).layer(from_fn(|req, next| {
+                            validate_permission_mw(req, next, Permission::ReportsRead)
+                        })),
+                    )
+            }
+
+            async fn get_all_unseen_reports<S>(
+                State(state): State<AppState<Circuit, S>>,
+            ) -> impl IntoResponse
+            where
+                S: DataSource<Circuit>
+                    + Clone
+                    + Send
+                    + Sync
+                    + 'static
+                    + NotificationRepository<CircuitImportReport>,
+            {
+                RequestResponse::<Vec<CircuitImportReport>>::from_result(
+                    NotificationRepository::get_new(&state.data_source).await,
+                    (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
+                )
+            }
+
+            async fn get_all_reports<S>(
+                State(state): State<AppState<Circuit, S>>,
+            ) -> impl IntoResponse
+            where
+                S: DataSource<Circuit>
+                    + NotificationRepository<CircuitImportReport>
+                    + Clone
+                    + Send
+                    + Sync
+                    + 'static,
+            {
+                RequestResponse::<Vec<CircuitImportReport>>::from_result(
+                    NotificationRepository::get_all(&state.data_source).await,
+                    (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
+                )
+            }
+
+            async fn acknowledge_report<S>(
+                State(state): State<AppState<Circuit, S>>,
+                Json(acknowledgement): Json<ReportAcknowledgement>,
+            ) -> impl IntoResponse
+            where
+                S: DataSource<Circuit>
+                    + Reporter<CircuitImportReport>
+                    + Clone
+                    + Send
+                    + Sync
+                    + 'static,
+                <S as Reporter<CircuitImportReport>>::Id: From<std::string::String>,
+            {
+                RequestResponse::<()>::from_result(
+                    state
+                        .data_source
+                        .acknowledge(acknowledgement.id.into())
+                        .await,
+                    (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
+                )
+            }
+        }
+
+        pub mod mappings {
+            use axum::{
+                extract::{Path, State},
+                http::StatusCode,
+                middleware::from_fn,
+                response::IntoResponse,
+                routing::get,
+                Json, Router,
+            };
+            use ulid::Ulid;
+
+            use crate::{
+                model::{
+                    AppState, Circuit, ColumnMapping, DataSource, MappingProfiles, Permission,
+                },
+                web::{
+                    middleware::validate_permission_mw,
+                    responses::{lookup_error_code, RequestResponse},
+                },
+            };
+
+            pub fn get_router<S>() -> Router<AppState<Circuit, S>>
+            where
+                S: DataSource<Circuit>
+                    + MappingProfiles<ColumnMapping>
+                    + Clone
+                    + Send
+                    + Sync
+                    + 'static,
+                <S as MappingProfiles<ColumnMapping>>::Id: From<std::string::String>,
+            {
+                Router::new()
+                    .route(
+                        "/",
+                        get(get_mappings)
+                            .post(create_mapping)
+                            .layer(from_fn(|req, next| {
+                                validate_permission_mw(req, next, Permission::CircuitsImport)
+                            })),
+                    )
+                    .route(
+                        "/:mapping_id",
+                        get(get_mapping)
+                            .put(update_mapping)
+                            .delete(delete_mapping)
+                            .layer(from_fn(|req, next| {
+                                validate_permission_mw(req, next, Permission::CircuitsImport)
+                            })),
+                    )
+            }
+
+            async fn get_mappings<S>(State(state): State<AppState<Circuit, S>>) -> impl IntoResponse
+            where
+                S: DataSource<Circuit>
+                    + MappingProfiles<ColumnMapping>
+                    + Clone
+                    + Send
+                    + Sync
+                    + 'static,
+            {
+                RequestResponse::<Vec<ColumnMapping>>::from_result(
+                    state.data_source.get_profiles().await,
+                    (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
+                )
+            }
+
+            async fn get_mapping<S>(
+                State(state): State<AppState<Circuit, S>>,
+                Path(mapping_id): Path<String>,
+            ) -> impl IntoResponse
+            where
+                S: DataSource<Circuit>
+                    + MappingProfiles<ColumnMapping>
+                    + Clone
+                    + Send
+                    + Sync
+                    + 'static,
+                <S as MappingProfiles<ColumnMapping>>::Id: From<std::string::String>,
+            {
+                let result = state.data_source.get_profile(mapping_id.into()).await;
+                let error_code = lookup_error_code(&result);
+
+                RequestResponse::<ColumnMapping>::from_result(result, (StatusCode::OK, error_code))
+            }
+
+            async fn create_mapping<S>(
+                State(state): State<AppState<Circuit, S>>,
+                Json(mapping): Json<ColumnMapping>,
+            ) -> impl IntoResponse
+            where
+                S: DataSource<Circuit>
+                    + MappingProfiles<ColumnMapping>
+                    + Clone
+                    + Send
+                    + Sync
+                    + 'static,
+            {
+                if let Err(e) = mapping.validate() {
+                    return RequestResponse::<ColumnMapping>::Error {
+                        message: e.to_string(),
+                        code: StatusCode::BAD_REQUEST,
+                    };
+                }
+
+                let mapping = ColumnMapping {
+                    id: Ulid::new().to_string(),
+                    ..mapping
+                };
+
+                RequestResponse::<ColumnMapping>::from_result(
+                    state.data_source.save_profile(mapping).await,
+                    (StatusCode::CREATED, StatusCode::INTERNAL_SERVER_ERROR),
+                )
+            }
+
+            async fn update_mapping<S>(
+                State(state): State<AppState<Circuit, S>>,
+                Path(mapping_id): Path<String>,
+                Json(mapping): Json<ColumnMapping>,
+            ) -> impl IntoResponse
+            where
+                S: DataSource<Circuit>
+                    + MappingProfiles<ColumnMapping>
+                    + Clone
+                    + Send
+                    + Sync
+                    + 'static,
+                <S as MappingProfiles<ColumnMapping>>::Id: From<std::string::String>,
+            {
+                if let Err(e) = mapping.validate() {
+                    return RequestResponse::<ColumnMapping>::Error {
+                        message: e.to_string(),
+                        code: StatusCode::BAD_REQUEST,
+                    };
+                }
+
+                // Saving creates missing mappings, updates only go to existing ones
+                let existing = state
+                    .data_source
+                    .get_profile(mapping_id.clone().into())
+                    .await;
+                if let Err(e) = &existing {
+                    return RequestResponse::<ColumnMapping>::Error {
+                        message: e.to_string(),
+                        code: lookup_error_code(&existing),
+                    };
+                }
+
+                let mapping = ColumnMapping {
+                    id: mapping_id,
+                    ..mapping
+                };
+
+                RequestResponse::<ColumnMapping>::from_result(
+                    state.data_source.save_profile(mapping).await,
+                    (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
+                )
+            }
+
+            async fn delete_mapping<S>(
+                State(state): State<AppState<Circuit, S>>,
+                Path(mapping_id): Path<String>,
+            ) -> impl IntoResponse
+            where
+                S: DataSource<Circuit>
+                    + MappingProfiles<ColumnMapping>
+                    + Clone
+                    + Send
+                    + Sync
+                    + 'static,
+                <S as MappingProfiles<ColumnMapping>>::Id: From<std::string::String>,
+            {
+                let result = state.data_source.delete_profile(mapping_id.into()).await;
+                let error_code = lookup_error_code(&result);
+
+                RequestResponse::<()>::from_result(result, (StatusCode::OK, error_code))
+            }
+        }
+
+        /// The natural key import rows without an id are matched to circuits by
+        pub mod natural_key {
+            use axum::{
+                extract::{Query, State},
+                http::StatusCode,
+                middleware::from_fn,
+                response::IntoResponse,
+                routing::get,
+                Json, Router,
+            };
+
+            use crate::{
+                model::{
+                    AppState, Circuit, CircuitQuery, DataSource, KeyConflict, NaturalKey,
+                    NaturalKeys, Permission,
+                },
+                web::{
+                    middleware::validate_permission_mw, requests::KeyConflictsQuery,
+                    responses::RequestResponse,
+                },
+            };
+
+            pub fn get_router<S>() -> Router<AppState<Circuit, S>>
+            where
+                S: DataSource<Circuit> + NaturalKeys<NaturalKey> + Clone + Send + Sync + 'static,
+                <S as DataSource<Circuit>>::Query: From<CircuitQuery>,
+            {
+                Router::new()
+                    .route(
+                        "/",
+                        get(get_natural_key)
+                            .put(set_natural_key)
+                            .delete(remove_natural_key)
+                            .layer(from_fn(|req, next| {
+                                validate_permission_mw(req, next, Permission::CircuitsWrite)
+                            })),
+                    )
+                    .route(
+                        "/conflicts",
+                        get(get_conflicts).layer(from_fn(|req, next| {
+                            validate_permission_mw(req, next, Permission::CircuitsWrite)
+                        })),
+                    )
+            }
+
+            async fn get_natural_key<S>(
+                State(state): State<AppState<Circuit, S>>,
+            ) -> impl IntoResponse
+            where
+                S: DataSource<Circuit> + NaturalKeys<NaturalKey> + Clone + Send + Sync + 'static,
+            {
+                RequestResponse::<Option<NaturalKey>>::from_result(
+                    state.data_source.get_natural_key().await,
+                    (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
+                )
+            }
+
+            /// Refuses keys the circuits already break, those have to be cleaned
+            /// up first, see `get_conflicts`
+            async fn set_natural_key<S>(
+                State(state): State<AppState<Circuit, S>>,
+                Json(key): Json<NaturalKey>,
+            ) -> impl IntoResponse
+            where
+                S: DataSource<Circuit> + NaturalKeys<NaturalKey> + Clone + Send + Sync + 'static,
+                <S as DataSource<Circuit>>::Query: From<CircuitQuery>,
+            {
+                if let Err(e) = key.validate() {
+                    return RequestResponse::<NaturalKey>::Error {
+                        message: e.to_string(),
+                        code: StatusCode::BAD_REQUEST,
+                    };
+                }
+
+                let conflicts = match conflicts(&state.data_source, &key).await {
+                    Ok(conflicts) => conflicts,
+                    Err(e) => {
+                        return RequestResponse::<NaturalKey>::Error {
+                            message: e.to_string(),
+                            code: StatusCode::INTERNAL_SERVER_ERROR,
+                        }
+                    }
+                };
+
+                if !conflicts.is_empty() {
+                    return RequestResponse::<NaturalKey>::Error {
+                        message: format!(
+                            "{} values of {} are shared by more than one circuit, \
+                             list them with /circuits/natural-key/conflicts?columns={}",
+                            conflicts.len(),
+                            key.describe(),
+                            key.describe().replace(' ', "")
+                        ),
+                        code: StatusCode::CONFLICT,
+                    };
+                }
+
+                RequestResponse::<NaturalKey>::from_result(
+                    state
+                        .data_source
+                        .set_natural_key(Some(key.clone()))
+                        .await
+                        .map(|_| key),
+                    (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
+                )
+            }
+
+            async fn remove_natural_key<S>(
+                State(state): State<AppState<Circuit, S>>,
+            ) -> impl IntoResponse
+            where
+                S: DataSource<Circuit> + NaturalKeys<NaturalKey> + Clone + Send + Sync + 'static,
+            {
+                RequestResponse::<()>::from_result(
+                    state.data_source.set_natural_key(None).await,
+                    (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
+                )
+            }
+
+            /// Circuits that share a value of the key in `columns`, or of the
+            /// configured key
+            async fn get_conflicts<S>(
+                State(state): State<AppState<Circuit, S>>,
+                Query(conflicts_query): Query<KeyConflictsQuery>,
+            ) -> impl IntoResponse
+            where
+                S: DataSource<Circuit> + NaturalKeys<NaturalKey> + Clone + Send + Sync + 'static,
+                <S as DataSource<Circuit>>::Query: From<CircuitQuery>,
+            {
+                let key = match conflicts_query.key() {
+                    Ok(Some(key)) => key,
+                    Ok(None) => match state.data_source.get_natural_key().await {
+                        Ok(Some(key)) => key,
+                        Ok(None) => {
+                            return RequestResponse::<Vec<KeyConflict>>::Error {
+                                message: "No natural key is set, pick the columns to check"
+                                    .to_string(),
+                                code: StatusCode::BAD_REQUEST,
+                            }
+                        }
+                        Err(e) => {
+                            return RequestResponse::<Vec<KeyConflict>>::Error {
+                                message: e.to_string(),
+                                code: StatusCode::INTERNAL_SERVER_ERROR,
+                            }
+                        }
+                    },
+                    Err(e) => {
+                        return RequestResponse::<Vec<KeyConflict>>::Error {
+                            message: e.to_string(),
+                            code: StatusCode::BAD_REQUEST,
+                        }
+                    }
+                };
+
+                RequestResponse::<Vec<KeyConflict>>::from_result(
+                    conflicts(&state.data_source, &key).await,
+                    (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
+                )
+            }
+
+            /// Conflicts among the circuits the key's constraint covers, the ones
+            /// that aren't decommissioned
+            async fn conflicts<S>(
+                data_source: &S,
+                key: &NaturalKey,
+            ) -> eyre::Result<Vec<KeyConflict>>
+            where
+                S: DataSource<Circuit>,
+                <S as DataSource<Circuit>>::Query: From<CircuitQuery>,
+            {
+                let circuits = data_source.get_all(CircuitQuery::default().into()).await?;
+
+                Ok(key.conflicts(&circuits.items))
+            }
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+
+            #[test]
+            fn csv_lines_count_from_one_with_either_line_ending() {
+                fn record_lines(csv_data: &str) -> Vec<u64> {
+                    let mut reader = csv::Reader::from_reader(csv_data.as_bytes());
+                    reader
+                        .records()
+                        .map(|record| csv_line(csv_data, record.unwrap().position()))
+                        .collect()
+                }
+
+                assert_eq!(record_lines("id,state\n1,Active\n2,New\n"), [2, 3]);
+                assert_eq!(record_lines("id,state\r\n1,Active\r\n2,New\r\n"), [2, 3]);
+                assert_eq!(record_lines("id,state\n1,\"two\nlines\"\n2,New\n"), [2, 4]);
+                assert_eq!(csv_line("", None), 0);
+            }
+        }
+    }
+
+    pub mod auth {
+        use std::{net::SocketAddr, time::SystemTime};
+
+        use axum::{
+            extract::{ConnectInfo, State},
+            http::{HeaderMap, StatusCode},
+            response::IntoResponse,
+            routing::post,
+            Json, Router,
+        };
+        use chrono::{DateTime, SecondsFormat, Utc};
+        use ulid::Ulid;
+
+        use crate::{
+            model::{
+                AccessScope, AccessScopes, AppState, Circuit, CircuitScope, DataSource, LockKind,
+                LoginAudit, LoginFailure, LoginFailureReason, LoginLock, LoginLocks, Permission,
+                Region, Regions, Role, Roles, Session, Sessions, User, Users,
+            },
+            password,
+            session::RefreshToken,
+            web::{
+                requests::{LoginRequest, RefreshRequest},
+                responses::{LoginResponse, RequestResponse},
+                Claims,
+            },
+        };
+
+        use jsonwebtoken::{encode, EncodingKey, Header};
+        use std::env;
+
+        pub fn create_jwt(
+            username: &str,
+            roles: &[String],
+            permissions: &[Permission],
+            session_id: &str,
+            scope: Option<CircuitScope>,
+            lifetime: std::time::Duration,
+        ) -> String {
+            // `exp` is in seconds since the epoch, as `jsonwebtoken` checks it
+            let expiration = (SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .expect("Time went backwards")
+                + lifetime)
+                .as_secs();
+
+            let claims = Claims {
+                sub: username.to_owned(),
+                exp: expiration as usize,
+                roles: roles.to_vec(),
+                permissions: permissions.to_vec(),
+                sid: session_id.to_owned(),
+                scope,
+            };
+
+            let secret = env::var("JWT_SECRET").expect("JWT_SECRET must be set");
+
+            encode(
+                &Header::default(),
+                &claims,
+                &EncodingKey::from_secret(secret.as_ref()),
+            )
+            .unwrap()
+        }
+
+        pub fn get_router<S>() -> Router<AppState<Circuit, S>>
+        where
+            S: DataSource<Circuit>
+                + Users<User>
+                + Sessions<Session>
+                + Roles<Role>
+                + AccessScopes<AccessScope>
+                + Regions<Region>
+                + LoginLocks<LoginLock>
+                + LoginAudit<LoginFailure>
+                + Clone
+                + Send
+                + Sync
+                + 'static,
+            <S as Users<User>>::Id: From<std::string::String> + Send,
+            <S as Sessions<Session>>::Id: From<std::string::String> + Send,
+        {
+            Router::new()
+                .route("/login", post(login))
+                .route("/refresh", post(refresh))
+                .route("/logout", post(logout))
+        }
+
+        /// Logs in with a username and password. Failures count against the
+        /// username and the client's address, either one having too many of
+        /// them recently is refused before the password is looked at.
+        async fn login<S>(
+            State(state): State<AppState<Circuit, S>>,
+            ConnectInfo(peer): ConnectInfo<SocketAddr>,
+            headers: HeaderMap,
+            Json(login_request): Json<LoginRequest>,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit>
+                + Users<User>
+                + Sessions<Session>
+                + Roles<Role>
+                + AccessScopes<AccessScope>
+                + Regions<Region>
+                + LoginLocks<LoginLock>
+                + LoginAudit<LoginFailure>
+                + Clone
+                + Send
+                + Sync
+                + 'static,
+            <S as Users<User>>::Id: From<std::string::String> + Send,
+        {
+            let ip = state.lockout.client_ip(peer, &headers).to_string();
+            let keys = [
+                (LockKind::Username, login_request.username.clone()),
+                (LockKind::Ip, ip.clone()),
+            ];
+
+            match locked_until(&state.data_source, &keys).await {
+                Ok(None) => {}
+                Ok(Some(locked_until)) => {
+                    audit_failure(
+                        &state.data_source,
+                        &login_request.username,
+                        &ip,
+                        LoginFailureReason::Locked,
+                    )
+                    .await;
+
+                    return RequestResponse::<LoginResponse>::Error {
+                        message: format!(
+                            "Too many failed logins, try again after {}",
+                            locked_until.to_rfc3339_opts(SecondsFormat::Secs, true)
+                        ),
+                        code: StatusCode::TOO_MANY_REQUESTS,
+                    };
+                }
+                Err(e) => {
+                    return RequestResponse::<LoginResponse>::Error {
+                        message: e.to_string(),
+                        code: StatusCode::INTERNAL_SERVER_ERROR,
+                    }
+                }
+            }
+
+            let user = match state
+                .data_source
+                .get_user(login_request.username.clone().into())
+                .await
+            {
+                Ok(user) if !user.disabled => Some(user),
+                Ok(_) => None,
+                Err(e) => {
+                    tracing::debug!("Login as {} refused : {}", login_request.username, e);
+                    None
+                }
+            };
+
+            let stored = match &user {
+                Some(user) => state
+                    .data_source
+                    .get_password(user.username.clone().into())
+                    .await
+                    .ok(),
+                None => None,
+            };
+
+            // Unknown users are checked against a dummy hash, so how long this
+            // takes doesn't tell whether the username exists
+            let stored = stored.unwrap_or_default();
+            let needs_rehash = stored.credentials().needs_rehash();
+            let verified = stored.verify_blocking(login_request.password.clone()).await;
+            let user = user.filter(|_| verified);
+
+            let Some(user) = user else {
+                record_failure(&state, &keys).await;
+                audit_failure(
+                    &state.data_source,
+                    &login_request.username,
+                    &ip,
+                    LoginFailureReason::InvalidCredentials,
+                )
+                .await;
+
+                return RequestResponse::<LoginResponse>::Error {
+                    message: "Invalid user".to_string(),
+                    code: StatusCode::BAD_REQUEST,
+                };
+            };
+
+            // The address keeps its failures, one good login doesn't vouch for
+            // everyone else behind it
+            if let Err(e) = state
+                .data_source
+                .clear_lock(LockKind::Username, user.username.clone())
+                .await
+            {
+                tracing::error!(
+                    "Failed clearing the failed logins of {} : {}",
+                    user.username,
+                    e
+                );
+            }
+
+            if needs_rehash {
+                rehash_password(&state.data_source, &user.username, &login_request.password).await;
+            }
+
+            let session = Session {
+                id: Ulid::new().to_string(),
+                username: user.username.clone(),
+                reduced_privileges: login_request.reduced_privileges,
+                created_at: Utc::now(),
+                expires_at: state.token_lifetimes.refresh_expiry(),
+                revoked_at: None,
+            };
+            let refresh_token = RefreshToken::generate(session.id.clone());
+
+            let result = state
+                .data_source
+                .create_session(session, refresh_token.hash())
+                .await;
+
+            let result = match result {
+                Ok(session) => tokens(&state, &session, &user, &refresh_token).await,
+                Err(e) => Err(e),
+            };
+
+            RequestResponse::<LoginResponse>::from_result(
+                result,
+                (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
+            )
+        }
+
+        /// The latest time any of `keys` has to wait until, if one of them is locked
+        async fn locked_until<S>(
+            data_source: &S,
+            keys: &[(LockKind, String)],
+        ) -> eyre::Result<Option<DateTime<Utc>>>
+        where
+            S: LoginLocks<LoginLock>,
+        {
+            let mut locked_until = None;
+            for (kind, key) in keys {
+                if let Some(lock) = data_source.get_lock(*kind, key.clone()).await? {
+                    if lock.is_locked() {
+                        locked_until = locked_until.max(lock.locked_until);
+                    }
+                }
+            }
+
+            Ok(locked_until)
+        }
+
+        /// Counts a failed login against `keys`, locking the ones that went
+        /// over their limits. The login is refused either way, so failing
+        /// here is only logged.
+        async fn record_failure<S>(state: &AppState<Circuit, S>, keys: &[(LockKind, String)])
+        where
+            S: DataSource<Circuit> + LoginLocks<LoginLock>,
+        {
+            for (kind, key) in keys {
+                let recorded = async {
+                    let lock = state
+                        .data_source
+                        .record_failure(*kind, key.clone(), state.lockout.forget_before())
+                        .await?;
+
+                    match state.lockout.locked_until(*kind, lock.failures as u32) {
+                        Some(until) => state.data_source.lock(*kind, key.clone(), until).await,
+                        None => Ok(()),
+                    }
+                };
+
+                if let Err(e) = recorded.await {
+                    tracing::error!(
+                        "Failed recording a failed login for {} {} : {}",
+                        kind.as_str(),
+                        key,
+                        e
+                    );
+                }
+            }
+        }
+
+        async fn audit_failure<S>(
+            data_source: &S,
+            username: &str,
+            ip: &str,
+            reason: LoginFailureReason,
+        ) where
+            S: LoginAudit<LoginFailure>,
+        {
+            let failure = LoginFailure {
+                id: 0,
+                username: username.to_string(),
+                ip: ip.to_string(),
+                reason,
+                failed_at: Utc::now(),
+            };
+
+            if let Err(e) = data_source.record_login_failure(failure).await {
+                tracing::error!("Failed writing a failed login to the audit trail : {}", e);
+            }
+        }
+
+        /// Swaps a refresh token for a new one and a fresh access token. The
+        /// token the last refresh used up revokes its session, someone kept a
+        /// copy of it.
+        async fn refresh<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Json(refresh_request): Json<RefreshRequest>,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit>
+                + Users<User>
+                + Sessions<Session>
+                + Roles<Role>
+                + AccessScopes<AccessScope>
+                + Regions<Region>
+                + Clone
+                + Send
+                + Sync
+                + 'static,
+            <S as Users<User>>::Id: From<std::string::String> + Send,
+            <S as Sessions<Session>>::Id: From<std::string::String> + Send,
+        {
+            let invalid = || RequestResponse::<LoginResponse>::Error {
+                message: "Invalid refresh token".to_string(),
+                code: StatusCode::UNAUTHORIZED,
+            };
+
+            let Ok(presented) = RefreshToken::parse(&refresh_request.refresh_token) else {
+                return invalid();
+            };
+
+            let id = presented.session_id.clone();
+            let (session, hashes) = match tokio::try_join!(
+                state.data_source.get_session(id.clone().into()),
+                state.data_source.get_refresh_hashes(id.clone().into())
+            ) {
+                Ok(found) => found,
+                Err(_) => return invalid(),
+            };
+
+            if !session.is_active() {
+                return invalid();
+            }
+
+            if !presented.verify(&hashes.current) {
+                // Secrets that were never handed out for the session are just wrong
+                let reused = hashes
+                    .previous
+                    .as_deref()
+                    .is_some_and(|previous| presented.verify(previous));
+                if !reused {
+                    return invalid();
+                }
+
+                tracing::warn!(
+                    "An old refresh token of {} was used, revoking session {}",
+                    session.username,
+                    session.id
+                );
+                if let Err(e) = state.data_source.revoke_session(id.into()).await {
+                    tracing::error!("Failed revoking session {} : {}", session.id, e);
+                }
+                return invalid();
+            }
+
+            let user = match state
+                .data_source
+                .get_user(session.username.clone().into())
+                .await
+            {
+                Ok(user) if !user.disabled => user,
+                _ => return invalid(),
+            };
+
+            let refresh_token = RefreshToken::generate(session.id.clone());
+            let hash = refresh_token.hash();
+
+            let expires_at = state.token_lifetimes.refresh_expiry();
+            match state
+                .data_source
+                .rotate_session(id.into(), hashes.current, hash, expires_at)
+                .await
+            {
+                Ok(true) => RequestResponse::<LoginResponse>::from_result(
+                    tokens(&state, &session, &user, &refresh_token).await,
+                    (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
+                ),
+                // Someone else refreshed with the same token in the meantime
+                Ok(false) => invalid(),
+                Err(e) => RequestResponse::<LoginResponse>::Error {
+                    message: e.to_string(),
+                    code: StatusCode::INTERNAL_SERVER_ERROR,
+                },
+            }
+        }
+
+        /// Revokes the session of a refresh token, its access tokens stop
+        /// working with it
+        async fn logout<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Json(refresh_request): Json<RefreshRequest>,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + Sessions<Session> + Clone + Send + Sync + 'static,
+            <S as Sessions<Session>>::Id: From<std::string::String> + Send,
+        {
+            let invalid = || RequestResponse::<()>::Error {
+                message: "Invalid refresh token".to_string(),
+                code: StatusCode::UNAUTHORIZED,
+            };
+
+            let Ok(presented) = RefreshToken::parse(&refresh_request.refresh_token) else {
+                return invalid();
+            };
+
+            let id = presented.session_id.clone();
+            match state
+                .data_source
+                .get_refresh_hashes(id.clone().into())
+                .await
+            {
+                Ok(hashes) if presented.verify(&hashes.current) => {
+                    RequestResponse::<()>::from_result(
+                        state.data_source.revoke_session(id.into()).await,
+                        (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
+                    )
+                }
+                _ => invalid(),
+            }
+        }
+
+        async fn tokens<S>(
+            state: &AppState<Circuit, S>,
+            session: &Session,
+            user: &User,
+            refresh_token: &RefreshToken,
+        ) -> eyre::Result<LoginResponse>
+        where
+            S: DataSource<Circuit> + Roles<Role> + AccessScopes<AccessScope> + Regions<Region>,
+        {
+            let (granted, scopes, regions) = tokio::try_join!(
+                state.data_source.get_permissions(user.roles.clone()),
+                state.data_source.get_scopes(user.username.clone()),
+                state.data_source.get_regions(),
+            )?;
+            let permissions = session.permissions(granted);
+
+            Ok(LoginResponse {
+                token: create_jwt(
+                    &user.username,
+                    &user.roles,
+                    &permissions,
+                    &session.id,
+                    CircuitScope::resolve(&scopes, &regions),
+                    state.token_lifetimes.access,
+                ),
+                refresh_token: refresh_token.to_string(),
+                roles: user.roles.clone(),
+                permissions,
+            })
+        }
+
+        /// Replaces a plaintext or outdated password with a fresh hash. The
+        /// login went through either way, failing here only means trying
+        /// again next time.
+        async fn rehash_password<S>(data_source: &S, username: &str, password: &str)
+        where
+            S: Users<User>,
+            <S as Users<User>>::Id: From<std::string::String> + Send,
+        {
+            let hash = match password::hash_blocking(password.to_string()).await {
+                Ok(hash) => hash,
+                Err(e) => {
+                    tracing::error!("Failed rehashing the password of {} : {}", username, e);
+                    return;
+                }
+            };
+
+            match data_source
+                .set_password(username.to_string().into(), hash)
+                .await
+            {
+                Ok(_) => tracing::info!("Rehashed the password of {}", username),
+                Err(e) => tracing::error!(
+                    "Failed storing the rehashed password of {} : {}",
+                    username,
+                    e
+                ),
+            }
+        }
+    }
+
+    /// Accounts, managed by admins
+    pub mod users {
+        use axum::{
+            extract::{Path, State},
+            http::StatusCode,
+            middleware::from_fn,
+            response::IntoResponse,
+            routing::{get, put},
+            Json, Router,
+        };
+        use chrono::Utc;
+        use serde::Serialize;
+
+        use crate::{
+            model::{
+                AccessScope, AccessScopes, AlreadyExists, AppState, ChangeContext, Circuit,
+                DataSource, LockKind, LoginLock, LoginLocks, Permission, Region, Regions, Role,
+                Roles, Session, Sessions, User, Users,
+            },
+            password,
+            web::{
+                handlers::{regions::check_regions, roles::check_roles},
+                middleware::validate_permission_mw,
+                requests::{
+                    role_names, scope_values, NewUserRequest, PasswordResetRequest, ScopesRequest,
+                    UserUpdateRequest,
+                },
+                responses::{lookup_error_code, RequestResponse},
+            },
+        };
+
+        pub fn get_router<S>() -> Router<AppState<Circuit, S>>
+        where
+            S: DataSource<Circuit>
+                + Users<User>
+                + Sessions<Session>
+                + Roles<Role>
+                + AccessScopes<AccessScope>
+                + Regions<Region>
+                + LoginLocks<LoginLock>
+                + Clone
+                + Send
+                + Sync
+                + 'static,
+            <S as Users<User>>::Id: From<std::string::String> + Send,
+        {
+            Router::new()
+                .route(
+                    "/",
+                    get(get_users).post(create_user).layer(from_fn(|req, next| {
+                        validate_permission_mw(req, next, Permission::UsersAdmin)
+                    })),
+                )
+                .route(
+                    "/:username",
+                    get(get_user)
+                        .put(update_user)
+                        .delete(delete_user)
+                        .layer(from_fn(|req, next| {
+                            validate_permission_mw(req, next, Permission::UsersAdmin)
+                        })),
+                )
+                .route(
+                    "/:username/password",
+                    put(reset_password).layer(from_fn(|req, next| {
+                        validate_permission_mw(req, next, Permission::UsersAdmin)
+                    })),
+                )
+                .route(
+                    "/:username/sessions",
+                    get(get_sessions)
+                        .delete(revoke_sessions)
+                        .layer(from_fn(|req, next| {
+                            validate_permission_mw(req, next, Permission::UsersAdmin)
+                        })),
+                )
+                .route(
+                    "/:username/scopes",
+                    get(get_scopes).put(set_scopes).layer(from_fn(|req, next| {
+                        validate_permission_mw(req, next, Permission::UsersAdmin)
+                    })),
+                )
+        }
+
+        async fn get_users<S>(State(state): State<AppState<Circuit, S>>) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + Users<User> + Clone + Send + Sync + 'static,
+        {
+            RequestResponse::<Vec<User>>::from_result(
+                state.data_source.get_users().await,
+                (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
+            )
+        }
+
+        async fn get_user<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Path(username): Path<String>,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + Users<User> + Clone + Send + Sync + 'static,
+            <S as Users<User>>::Id: From<std::string::String>,
+        {
+            let result = state.data_source.get_user(username.into()).await;
+            let error_code = lookup_error_code(&result);
+
+            RequestResponse::<User>::from_result(result, (StatusCode::OK, error_code))
+        }
+
+        async fn create_user<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Json(new_user): Json<NewUserRequest>,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + Users<User> + Roles<Role> + Clone + Send + Sync + 'static,
+        {
+            let checked = User::validate_username(&new_user.username)
+                .and_then(|_| password::validate(&new_user.password))
+                .and_then(|_| role_names(new_user.roles));
+            let checked = match checked {
+                Ok(roles) => check_roles(&state.data_source, &roles).await.map(|_| roles),
+                Err(e) => Err(e),
+            };
+            let roles = match checked {
+                Ok(roles) => roles,
+                Err(e) => {
+                    return RequestResponse::<User>::Error {
+                        message: e.to_string(),
+                        code: StatusCode::BAD_REQUEST,
+                    }
+                }
+            };
+
+            let user = User {
+                username: new_user.username,
+                roles,
+                disabled: false,
+                created_at: Utc::now(),
+            };
+
+            let result = match password::hash_blocking(new_user.password).await {
+                Ok(hash) => state.data_source.create_user(user, hash).await,
+                Err(e) => Err(e),
+            };
+            let error_code = match &result {
+                Err(e) if e.downcast_ref::<AlreadyExists>().is_some() => StatusCode::CONFLICT,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+
+            RequestResponse::<User>::from_result(result, (StatusCode::CREATED, error_code))
+        }
+
+        /// Changes the roles of a user or disables them. Admins can't lock
+        /// themselves out, and there is always an admin left. Disabling a user
+        /// ends their sessions.
+        async fn update_user<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Path(username): Path<String>,
+            context: ChangeContext,
+            Json(update): Json<UserUpdateRequest>,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit>
+                + Users<User>
+                + Sessions<Session>
+                + Roles<Role>
+                + Clone
+                + Send
+                + Sync
+                + 'static,
+            <S as Users<User>>::Id: From<std::string::String>,
+        {
+            let current = state.data_source.get_user(username.into()).await;
+            let current = match current {
+                Ok(current) => current,
+                Err(ref e) => {
+                    return RequestResponse::<User>::Error {
+                        message: e.to_string(),
+                        code: lookup_error_code(&current),
+                    }
+                }
+            };
+
+            let checked = match update.roles.map(role_names) {
+                Some(Ok(roles)) => check_roles(&state.data_source, &roles).await.map(|_| roles),
+                Some(Err(e)) => Err(e),
+                None => Ok(current.roles.clone()),
+            };
+            let roles = match checked {
+                Ok(roles) => roles,
+                Err(e) => {
+                    return RequestResponse::<User>::Error {
+                        message: e.to_string(),
+                        code: StatusCode::BAD_REQUEST,
+                    }
+                }
+            };
+
+            let updated = User {
+                roles,
+                disabled: update.disabled.unwrap_or(current.disabled),
+                ..current.clone()
+            };
+
+            if current.is_admin() && !updated.is_admin() {
+                if let Err(response) = keep_an_admin(&state.data_source, &current, &context).await {
+                    return response;
+                }
+            }
+
+            let disabling = updated.disabled && !current.disabled;
+            let mut result = state.data_source.update_user(updated).await;
+
+            if disabling && result.is_ok() {
+                if let Err(e) = state
+                    .data_source
+                    .revoke_user_sessions(current.username)
+                    .await
+                {
+                    result = Err(e);
+                }
+            }
+
+            RequestResponse::<User>::from_result(
+                result,
+                (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
+            )
+        }
+
+        async fn delete_user<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Path(username): Path<String>,
+            context: ChangeContext,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + Users<User> + Clone + Send + Sync + 'static,
+            <S as Users<User>>::Id: From<std::string::String>,
+        {
+            let current = state.data_source.get_user(username.clone().into()).await;
+            let current = match current {
+                Ok(current) => current,
+                Err(ref e) => {
+                    return RequestResponse::<()>::Error {
+                        message: e.to_string(),
+                        code: lookup_error_code(&current),
+                    }
+                }
+            };
+
+            if current.is_admin() {
+                if let Err(response) = keep_an_admin(&state.data_source, &current, &context).await {
+                    return response;
+                }
+            }
+
+            let result = state.data_source.delete_user(username.into()).await;
+            let error_code = lookup_error_code(&result);
+
+            RequestResponse::<()>::from_result(result, (StatusCode::OK, error_code))
+        }
+
+        /// Refuses taking `admin` away from the acting admin or the last one
+        async fn keep_an_admin<S, T>(
+            data_source: &S,
+            admin: &User,
+            context: &ChangeContext,
+        ) -> Result<(), RequestResponse<T>>
+        where
+            S: Users<User>,
+            T: Serialize,
+        {
+            if admin.username == context.actor {
+                return Err(RequestResponse::Error {
+                    message: "Admins can't take away their own access, ask another admin"
+                        .to_string(),
+                    code: StatusCode::CONFLICT,
+                });
+            }
+
+            let users = data_source
+                .get_users()
+                .await
+                .map_err(|e| RequestResponse::Error {
+                    message: e.to_string(),
+                    code: StatusCode::INTERNAL_SERVER_ERROR,
+                })?;
+
+            if !users
+                .iter()
+                .any(|user| user.username != admin.username && user.is_admin())
+            {
+                return Err(RequestResponse::Error {
+                    message: format!(
+                        "{} is the last admin, make someone else an admin first",
+                        admin.username
+                    ),
+                    code: StatusCode::CONFLICT,
+                });
+            }
+
+            Ok(())
+        }
+
+        /// Sets a new password for the user and logs them out everywhere.
+        /// Failed logins against the username are forgotten so the new
+        /// password works right away.
+        async fn reset_password<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Path(username): Path<String>,
+            Json(reset): Json<PasswordResetRequest>,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit>
+                + Users<User>
+                + Sessions<Session>
+                + LoginLocks<LoginLock>
+                + Clone
+                + Send
+                + Sync
+                + 'static,
+            <S as Users<User>>::Id: From<std::string::String>,
+        {
+            if let Err(e) = password::validate(&reset.password) {
+                return RequestResponse::<()>::Error {
+                    message: e.to_string(),
+                    code: StatusCode::BAD_REQUEST,
+                };
+            }
+
+            let result = match password::hash_blocking(reset.password).await {
+                Ok(hash) => {
+                    state
+                        .data_source
+                        .set_password(username.clone().into(), hash)
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            // Whoever knew the old password doesn't stay logged in with it
+            let result = match result {
+                Ok(()) => state
+                    .data_source
+                    .revoke_user_sessions(username.clone())
+                    .await
+                    .map(|_| ()),
+                Err(e) => Err(e),
+            };
+            let result = match result {
+                Ok(()) => state
+                    .data_source
+                    .clear_lock(LockKind::Username, username)
+                    .await
+                    .map(|_| ()),
+                Err(e) => Err(e),
+            };
+            let error_code = lookup_error_code(&result);
+
+            RequestResponse::<()>::from_result(result, (StatusCode::OK, error_code))
+        }
+
+        /// Sessions of the user that can still get new access tokens
+        async fn get_sessions<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Path(username): Path<String>,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + Sessions<Session> + Clone + Send + Sync + 'static,
+        {
+            RequestResponse::<Vec<Session>>::from_result(
+                state.data_source.get_active_sessions(username).await,
+                (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
+            )
+        }
+
+        /// Logs the user out everywhere, returning how many sessions ended
+        async fn revoke_sessions<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Path(username): Path<String>,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + Sessions<Session> + Clone + Send + Sync + 'static,
+        {
+            RequestResponse::<u64>::from_result(
+                state.data_source.revoke_user_sessions(username).await,
+                (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
+            )
+        }
+
+        async fn get_scopes<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Path(username): Path<String>,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit>
+                + Users<User>
+                + AccessScopes<AccessScope>
+                + Clone
+                + Send
+                + Sync
+                + 'static,
+            <S as Users<User>>::Id: From<std::string::String>,
+        {
+            let scopes = async {
+                state.data_source.get_user(username.clone().into()).await?;
+                state.data_source.get_scopes(username).await
+            };
+
+            let result = scopes.await;
+            let error_code = lookup_error_code(&result);
+
+            RequestResponse::<Vec<AccessScope>>::from_result(result, (StatusCode::OK, error_code))
+        }
+
+        /// Replaces what part of the inventory the user is limited to. Tokens
+        /// already handed out keep their scope until they're refreshed.
+        async fn set_scopes<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Path(username): Path<String>,
+            Json(request): Json<ScopesRequest>,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit>
+                + Users<User>
+                + AccessScopes<AccessScope>
+                + Regions<Region>
+                + Clone
+                + Send
+                + Sync
+                + 'static,
+            <S as Users<User>>::Id: From<std::string::String>,
+        {
+            let user = state.data_source.get_user(username.clone().into()).await;
+            if let Err(ref e) = user {
+                return RequestResponse::<Vec<AccessScope>>::Error {
+                    message: e.to_string(),
+                    code: lookup_error_code(&user),
+                };
+            }
+
+            let checked = async {
+                let scopes = scope_values(request.scopes)?;
+                check_regions(&state.data_source, &scopes).await?;
+                Ok::<_, eyre::Report>(scopes)
+            };
+
+            let scopes = match checked.await {
+                Ok(scopes) => scopes,
+                Err(e) => {
+                    return RequestResponse::<Vec<AccessScope>>::Error {
+                        message: e.to_string(),
+                        code: StatusCode::BAD_REQUEST,
+                    }
+                }
+            };
+
+            RequestResponse::<Vec<AccessScope>>::from_result(
+                state.data_source.set_scopes(username, scopes).await,
+                (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
+            )
+        }
+    }
+
+    /// Roles and the permissions they grant, managed by admins
+    pub mod roles {
+        use axum::{
+            extract::{Path, State},
+            http::StatusCode,
+            middleware::from_fn,
+            response::IntoResponse,
+            routing::get,
+            Json, Router,
+        };
+
+        use crate::{
+            model::{
+                AlreadyExists, AppState, Circuit, DataSource, Permission, Role, Roles, StillInUse,
+            },
+            web::{
+                middleware::validate_permission_mw,
+                requests::{NewRoleRequest, RoleUpdateRequest},
+                responses::{lookup_error_code, RequestResponse},
+            },
+        };
+
+        pub fn get_router<S>() -> Router<AppState<Circuit, S>>
+        where
+            S: DataSource<Circuit> + Roles<Role> + Clone + Send + Sync + 'static,
+            <S as Roles<Role>>::Id: From<std::string::String> + Send,
+        {
+            Router::new()
+                .route(
+                    "/",
+                    get(get_roles).post(create_role).layer(from_fn(|req, next| {
+                        validate_permission_mw(req, next, Permission::UsersAdmin)
+                    })),
+                )
+                .route(
+                    "/:role",
+                    get(get_role)
+                        .put(update_role)
+                        .delete(delete_role)
+                        .layer(from_fn(|req, next| {
+                            validate_permission_mw(req, next, Permission::UsersAdmin)
+                        })),
+                )
+        }
+
+        /// Fails for role names that aren't in the database, they would grant nothing
+        pub async fn check_roles<S>(data_source: &S, names: &[String]) -> eyre::Result<()>
+        where
+            S: Roles<Role>,
+        {
+            let roles = data_source.get_roles().await?;
+
+            match names
+                .iter()
+                .find(|name| !roles.iter().any(|role| role.name == **name))
+            {
+                Some(name) => Err(eyre::Report::msg(format!("There is no role named {name}"))),
+                None => Ok(()),
+            }
+        }
+
+        /// Permissions as stored, every one of them once
+        fn normalize(mut permissions: Vec<Permission>) -> Vec<Permission> {
+            permissions.sort();
+            permissions.dedup();
+            permissions
+        }
+
+        async fn get_roles<S>(State(state): State<AppState<Circuit, S>>) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + Roles<Role> + Clone + Send + Sync + 'static,
+        {
+            RequestResponse::<Vec<Role>>::from_result(
+                state.data_source.get_roles().await,
+                (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
+            )
+        }
+
+        async fn get_role<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Path(name): Path<String>,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + Roles<Role> + Clone + Send + Sync + 'static,
+            <S as Roles<Role>>::Id: From<std::string::String>,
+        {
+            let result = state.data_source.get_role(name.into()).await;
+            let error_code = lookup_error_code(&result);
+
+            RequestResponse::<Role>::from_result(result, (StatusCode::OK, error_code))
+        }
+
+        async fn create_role<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Json(new_role): Json<NewRoleRequest>,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + Roles<Role> + Clone + Send + Sync + 'static,
+        {
+            let role = Role {
+                name: new_role.name.trim().to_string(),
+                description: new_role.description,
+                permissions: normalize(new_role.permissions),
+            };
+
+            if let Err(e) = role.validate() {
+                return RequestResponse::<Role>::Error {
+                    message: e.to_string(),
+                    code: StatusCode::BAD_REQUEST,
+                };
+            }
+
+            let result = state.data_source.create_role(role).await;
+            let error_code = match &result {
+                Err(e) if e.downcast_ref::<AlreadyExists>().is_some() => StatusCode::CONFLICT,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+
+            RequestResponse::<Role>::from_result(result, (StatusCode::CREATED, error_code))
+        }
+
+        /// Changes what a role grants. Tokens already handed out keep their
+        /// permissions until they're refreshed.
+        async fn update_role<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Path(name): Path<String>,
+            Json(update): Json<RoleUpdateRequest>,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + Roles<Role> + Clone + Send + Sync + 'static,
+            <S as Roles<Role>>::Id: From<std::string::String>,
+        {
+            let current = state.data_source.get_role(name.into()).await;
+            let current = match current {
+                Ok(current) => current,
+                Err(ref e) => {
+                    return RequestResponse::<Role>::Error {
+                        message: e.to_string(),
+                        code: lookup_error_code(&current),
+                    }
+                }
+            };
+
+            let role = Role {
+                description: update.description.unwrap_or(current.description),
+                permissions: normalize(update.permissions.unwrap_or(current.permissions)),
+                ..current
+            };
+
+            if let Err(e) = role.validate() {
+                return RequestResponse::<Role>::Error {
+                    message: e.to_string(),
+                    code: StatusCode::BAD_REQUEST,
+                };
+            }
+
+            let result = state.data_source.update_role(role).await;
+            let error_code = lookup_error_code(&result);
+
+            RequestResponse::<Role>::from_result(result, (StatusCode::OK, error_code))
+        }
+
+        async fn delete_role<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Path(name): Path<String>,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + Roles<Role> + Clone + Send + Sync + 'static,
+            <S as Roles<Role>>::Id: From<std::string::String>,
+        {
+            if name == Role::ADMIN {
+                return RequestResponse::<()>::Error {
+                    message: "The admin role can't be deleted".to_string(),
+                    code: StatusCode::CONFLICT,
+                };
+            }
+
+            let result = state.data_source.delete_role(name.into()).await;
+            let error_code = match &result {
+                Err(e) if e.downcast_ref::<StillInUse>().is_some() => StatusCode::CONFLICT,
+                _ => lookup_error_code(&result),
+            };
+
+            RequestResponse::<()>::from_result(result, (StatusCode::OK, error_code))
+        }
+    }
+
+    /// Groups of sites that user scopes can name, managed by admins
+    pub mod regions {
+        use axum::{
+            extract::{Path, State},
+            http::StatusCode,
+            middleware::from_fn,
+            response::IntoResponse,
+            routing::get,
+            Json, Router,
+        };
+
+        use crate::{
+            model::{
+                AccessScope, AppState, Circuit, DataSource, Permission, Region, Regions, ScopeKind,
+                StillInUse,
+            },
+            web::{
+                middleware::validate_permission_mw,
+                requests::RegionRequest,
+                responses::{lookup_error_code, RequestResponse},
+            },
+        };
+
+        pub fn get_router<S>() -> Router<AppState<Circuit, S>>
+        where
+            S: DataSource<Circuit> + Regions<Region> + Clone + Send + Sync + 'static,
+            <S as Regions<Region>>::Id: From<std::string::String> + Send,
+        {
+            Router::new()
+                .route(
+                    "/",
+                    get(get_regions).layer(from_fn(|req, next| {
+                        validate_permission_mw(req, next, Permission::UsersAdmin)
+                    })),
+                )
+                .route(
+                    "/:region",
+                    get(get_region)
+                        .put(save_region)
+                        .delete(delete_region)
+                        .layer(from_fn(|req, next| {
+                            validate_permission_mw(req, next, Permission::UsersAdmin)
+                        })),
+                )
+        }
+
+        /// Fails for region scopes naming a region that doesn't exist, they
+        /// would reach nothing
+        pub async fn check_regions<S>(data_source: &S, scopes: &[AccessScope]) -> eyre::Result<()>
+        where
+            S: Regions<Region>,
+        {
+            let regions = data_source.get_regions().await?;
+
+            match scopes.iter().find(|scope| {
+                scope.kind == ScopeKind::Region
+                    && !regions.iter().any(|region| region.name == scope.value)
+            }) {
+                Some(scope) => Err(eyre::Report::msg(format!(
+                    "There is no region named {}",
+                    scope.value
+                ))),
+                None => Ok(()),
+            }
+        }
+
+        async fn get_regions<S>(State(state): State<AppState<Circuit, S>>) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + Regions<Region> + Clone + Send + Sync + 'static,
+        {
+            RequestResponse::<Vec<Region>>::from_result(
+                state.data_source.get_regions().await,
+                (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
+            )
+        }
+
+        async fn get_region<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Path(name): Path<String>,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + Regions<Region> + Clone + Send + Sync + 'static,
+            <S as Regions<Region>>::Id: From<std::string::String>,
+        {
+            let result = state.data_source.get_region(name.into()).await;
+            let error_code = lookup_error_code(&result);
+
+            RequestResponse::<Region>::from_result(result, (StatusCode::OK, error_code))
+        }
+
+        /// Creates the region or replaces its sites. Tokens already handed out
+        /// keep the sites they were issued with until they're refreshed.
+        async fn save_region<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Path(name): Path<String>,
+            Json(request): Json<RegionRequest>,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + Regions<Region> + Clone + Send + Sync + 'static,
+        {
+            let mut sites: Vec<String> = request
+                .sites
+                .into_iter()
+                .map(|site| site.trim().to_string())
+                .collect();
+            sites.sort();
+            sites.dedup();
+
+            let region = Region {
+                name: name.trim().to_string(),
+                description: request.description,
+                sites,
+            };
+
+            if let Err(e) = region.validate() {
+                return RequestResponse::<Region>::Error {
+                    message: e.to_string(),
+                    code: StatusCode::BAD_REQUEST,
+                };
+            }
+
+            RequestResponse::<Region>::from_result(
+                state.data_source.save_region(region).await,
+                (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
+            )
+        }
+
+        async fn delete_region<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Path(name): Path<String>,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + Regions<Region> + Clone + Send + Sync + 'static,
+            <S as Regions<Region>>::Id: From<std::string::String>,
+        {
+            let result = state.data_source.delete_region(name.into()).await;
+            let error_code = match &result {
+                Err(e) if e.downcast_ref::<StillInUse>().is_some() => StatusCode::CONFLICT,
+                _ => lookup_error_code(&result),
+            };
+
+            RequestResponse::<()>::from_result(result, (StatusCode::OK, error_code))
+        }
+    }
+
+    /// Usernames and addresses held back for failing to log in, admins can
+    /// see them and let them try again straight away
+    pub mod locks {
+        use axum::{
+            extract::{Path, State},
+            http::StatusCode,
+            middleware::from_fn,
+            response::IntoResponse,
+            routing::{delete, get},
+            Router,
+        };
+
+        use crate::{
+            model::{AppState, Circuit, DataSource, LockKind, LoginLock, LoginLocks, Permission},
+            web::{middleware::validate_permission_mw, responses::RequestResponse},
+        };
+
+        pub fn get_router<S>() -> Router<AppState<Circuit, S>>
+        where
+            S: DataSource<Circuit> + LoginLocks<LoginLock> + Clone + Send + Sync + 'static,
+        {
+            Router::new()
+                .route(
+                    "/",
+                    get(get_locks).layer(from_fn(|req, next| {
+                        validate_permission_mw(req, next, Permission::UsersAdmin)
+                    })),
+                )
+                .route(
+                    "/:kind/:key",
+                    delete(clear_lock).layer(from_fn(|req, next| {
+                        validate_permission_mw(req, next, Permission::UsersAdmin)
+                    })),
+                )
+        }
+
+        async fn get_locks<S>(State(state): State<AppState<Circuit, S>>) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + LoginLocks<LoginLock> + Clone + Send + Sync + 'static,
+        {
+            RequestResponse::<Vec<LoginLock>>::from_result(
+                state.data_source.get_locks().await,
+                (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
+            )
+        }
+
+        /// Forgets the failed logins of a username or address, which unlocks it
+        async fn clear_lock<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Path((kind, key)): Path<(String, String)>,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + LoginLocks<LoginLock> + Clone + Send + Sync + 'static,
+        {
+            let kind = match LockKind::try_from(kind) {
+                Ok(kind) => kind,
+                Err(e) => {
+                    return RequestResponse::<()>::Error {
+                        message: e.to_string(),
+                        code: StatusCode::BAD_REQUEST,
+                    }
+                }
+            };
+
+            match state.data_source.clear_lock(kind, key.clone()).await {
+                Ok(true) => RequestResponse::<()>::Success {
+                    data: (),
+                    warnings: vec![],
+                    code: StatusCode::OK,
+                },
+                Ok(false) => RequestResponse::<()>::Error {
+                    message: format!("No failed logins for {} {key}", kind.as_str()),
+                    code: StatusCode::NOT_FOUND,
+                },
+                Err(e) => RequestResponse::<()>::Error {
+                    message: e.to_string(),
+                    code: StatusCode::INTERNAL_SERVER_ERROR,
+                },
+            }
+        }
+    }
+
+    /// Keys for service accounts, managed by admins
+    pub mod api_keys {
+        use axum::{
+            extract::{Path, State},
+            http::StatusCode,
+            middleware::from_fn,
+            response::IntoResponse,
+            routing::get,
+            Json, Router,
+        };
+        use chrono::Utc;
+        use ulid::Ulid;
+
+        use crate::{
+            api_key::Key,
+            model::{
+                AlreadyExists, ApiKey, ApiKeys, AppState, ChangeContext, Circuit, DataSource,
+                Permission, Role, Roles,
+            },
+            web::{
+                handlers::roles::check_roles,
+                middleware::validate_permission_mw,
+                requests::{role_names, NewApiKeyRequest},
+                responses::{lookup_error_code, CreatedApiKey, RequestResponse},
+            },
+        };
+
+        pub fn get_router<S>() -> Router<AppState<Circuit, S>>
+        where
+            S: DataSource<Circuit> + ApiKeys<ApiKey> + Roles<Role> + Clone + Send + Sync + 'static,
+            <S as ApiKeys<ApiKey>>::Id: From<std::string::String> + Send,
+        {
+            Router::new()
+                .route(
+                    "/",
+                    get(get_api_keys)
+                        .post(create_api_key)
+                        .layer(from_fn(|req, next| {
+                            validate_permission_mw(req, next, Permission::UsersAdmin)
+                        })),
+                )
+                .route(
+                    "/:key_id",
+                    get(get_api_key)
+                        .delete(delete_api_key)
+                        .layer(from_fn(|req, next| {
+                            validate_permission_mw(req, next, Permission::UsersAdmin)
+                        })),
+                )
+        }
+
+        async fn get_api_keys<S>(State(state): State<AppState<Circuit, S>>) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + ApiKeys<ApiKey> + Clone + Send + Sync + 'static,
+        {
+            RequestResponse::<Vec<ApiKey>>::from_result(
+                state.data_source.get_api_keys().await,
+                (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
+            )
+        }
+
+        async fn get_api_key<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Path(key_id): Path<String>,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + ApiKeys<ApiKey> + Clone + Send + Sync + 'static,
+            <S as ApiKeys<ApiKey>>::Id: From<std::string::String>,
+        {
+            let result = state.data_source.get_api_key(key_id.into()).await;
+            let error_code = lookup_error_code(&result);
+
+            RequestResponse::<ApiKey>::from_result(result, (StatusCode::OK, error_code))
+        }
+
+        async fn create_api_key<S>(
+            State(state): State<AppState<Circuit, S>>,
+            context: ChangeContext,
+            Json(new_key): Json<NewApiKeyRequest>,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + ApiKeys<ApiKey> + Roles<Role> + Clone + Send + Sync + 'static,
+        {
+            let name = new_key.name.trim().to_string();
+            let checked = if name.is_empty() || name.len() > 255 {
+                Err(eyre::Report::msg(
+                    "API key names need between 1 and 255 characters",
+                ))
+            } else if new_key.roles.is_empty() {
+                Err(eyre::Report::msg("An API key needs at least one role"))
+            } else if new_key
+                .expires_at
+                .is_some_and(|expires_at| expires_at <= Utc::now())
+            {
+                Err(eyre::Report::msg(
+                    "The expiry of an API key has to be in the future",
+                ))
+            } else {
+                role_names(new_key.roles)
+            };
+            let checked = match checked {
+                Ok(roles) => check_roles(&state.data_source, &roles).await.map(|_| roles),
+                Err(e) => Err(e),
+            };
+
+            let roles = match checked {
+                Ok(roles) => roles,
+                Err(e) => {
+                    return RequestResponse::<CreatedApiKey>::Error {
+                        message: e.to_string(),
+                        code: StatusCode::BAD_REQUEST,
+                    }
+                }
             };
-            use ulid::Ulid;
 
-            use crate::model::{AppState, Circuit, DataSource, NotificationRepository, Reporter};
-            use crate::web::requests::ReportAcknowledgement;
-            use crate::{
-                model::CircuitImportReport,
-                web::{middleware::validate_role_mw, responses::RequestResponse},
+            let mut scopes: Vec<String> = new_key
+                .scopes
+                .into_iter()
+                .map(|scope| scope.as_str().to_string())
+                .collect();
+            scopes.sort();
+            scopes.dedup();
+
+            let api_key = ApiKey {
+                id: Ulid::new().to_string(),
+                name,
+                roles,
+                scopes,
+                created_by: context.actor,
+                created_at: Utc::now(),
+                expires_at: new_key.expires_at,
+                last_used_at: None,
             };
+            let key = Key::generate(api_key.id.clone());
 
-            pub fn get_router<S>() -> Router<AppState<Circuit, S>>
-            where
-                S: DataSource<Circuit>
-                    + Clone
-                    + Send
-                    + Sync
-                    + 'static
-                    + NotificationRepository<CircuitImportReport>
-                    + Reporter<CircuitImportReport>,
+            let result = state
+                .data_source
+                .create_api_key(api_key, key.hash())
+                .await
+                .map(|api_key| CreatedApiKey {
+                    api_key,
+                    key: key.to_string(),
+                });
 
-                <S as DataSource<Circuit>>::Id: From<Ulid> + Send + Sync,
-                <S as Reporter<CircuitImportReport>>::Id: From<std::string::String>,
-            {
-                Router::new()
-                    .route(
-                        "/get/unseen",
-                        get(get_all_unseen_reports)
-                            .layer(from_fn(|req, next| validate_role_mw(req, next, &["admin"]))),
-                    )
-                    .route(
-                        "/get/all",
-                        get(get_all_reports)
-                            .layer(from_fn(|req, next| validate_role_mw(req, next, &["admin"]))),
-                    )
-                    .route(
-                        "/acknowledge",
-                        post(acknowledge_report)
-                            .layer(from_fn(|req, next| validate_role_mw(req, next, &["admin"]))),
-                    )
-            }
+            let error_code = match &result {
+                Err(e) if e.downcast_ref::<AlreadyExists>().is_some() => StatusCode::CONFLICT,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
 
-            async fn get_all_unseen_reports<S>(
-                State(state): State<AppState<Circuit, S>>,
-            ) -> impl IntoResponse
-            where
-                S: DataSource<Circuit>
-                    + Clone
-                    + Send
-                    + Sync
-                    + 'static
-                    + NotificationRepository<CircuitImportReport>,
-            {
-                RequestResponse::<Vec<CircuitImportReport>>::from_result(
-                    NotificationRepository::get_new(&state.data_source).await,
-                    (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
-                )
-            }
+            RequestResponse::<CreatedApiKey>::from_result(result, (StatusCode::CREATED, error_code))
+        }
 
-            async fn get_all_reports<S>(
-                State(state): State<AppState<Circuit, S>>,
-            ) -> impl IntoResponse
-            where
-                S: DataSource<Circuit>
-                    + NotificationRepository<CircuitImportReport>
-                    + Clone
-                    + Send
-                    + Sync
-                    + 'static,
-            {
-                RequestResponse::<Vec<CircuitImportReport>>::from_result(
-                    NotificationRepository::get_all(&state.data_source).await,
-                    (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
-                )
-            }
+        /// Revokes the key, scripts using it are turned away from then on
+        async fn delete_api_key<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Path(key_id): Path<String>,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + ApiKeys<ApiKey> + Clone + Send + Sync + 'static,
+            <S as ApiKeys<ApiKey>>::Id: From<std::string::String>,
+        {
+            let result = state.data_source.delete_api_key(key_id.into()).await;
+            let error_code = lookup_error_code(&result);
 
-            async fn acknowledge_report<S>(
-                State(state): State<AppState<Circuit, S>>,
-                Json(acknowledgement): Json<ReportAcknowledgement>,
-            ) -> impl IntoResponse
-            where
-                S: DataSource<Circuit>
-                    + Reporter<CircuitImportReport>
-                    + Clone
-                    + Send
-                    + Sync
-                    + 'static,
-                <S as Reporter<CircuitImportReport>>::Id: From<std::string::String>,
-            {
-                RequestResponse::<()>::from_result(
-                    state
-                        .data_source
-                        .acknowledge(acknowledgement.id.into())
-                        .await,
-                    (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
-                )
-            }
+            RequestResponse::<()>::from_result(result, (StatusCode::OK, error_code))
         }
     }
 
-    pub mod auth {
-        use std::time::SystemTime;
-
-        use axum::{http::StatusCode, response::IntoResponse, routing::post, Json, Router};
+    /// The logged in user's own account
+    pub mod me {
+        use axum::{
+            extract::State,
+            http::StatusCode,
+            response::IntoResponse,
+            routing::{get, put},
+            Json, Router,
+        };
 
-        use crate::web::{
-            requests::{LoginRequest, UserResponse},
-            responses::{LoginResponse, RequestResponse},
-            Claims,
+        use crate::{
+            model::{AppState, ChangeContext, Circuit, DataSource, Session, Sessions, User, Users},
+            password,
+            web::{requests::PasswordChangeRequest, responses::RequestResponse, CallerSession},
         };
 
-        use jsonwebtoken::{encode, EncodingKey, Header};
-        use std::env;
+        pub fn get_router<S>() -> Router<AppState<Circuit, S>>
+        where
+            S: DataSource<Circuit>
+                + Users<User>
+                + Sessions<Session>
+                + Clone
+                + Send
+                + Sync
+                + 'static,
+            <S as Users<User>>::Id: From<std::string::String> + Send,
+            <S as Sessions<Session>>::Id: From<std::string::String> + Send,
+        {
+            // Anyone logged in has an account of their own, no permission needed
+            Router::new()
+                .route("/", get(get_me))
+                .route("/password", put(change_password))
+        }
 
-        pub fn create_jwt(username: &str, role: &str) -> String {
-            let expiration = (SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .expect("Time went backwards")
-                + std::time::Duration::from_secs(86400))
-            .as_millis();
+        async fn get_me<S>(
+            State(state): State<AppState<Circuit, S>>,
+            context: ChangeContext,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + Users<User> + Clone + Send + Sync + 'static,
+            <S as Users<User>>::Id: From<std::string::String>,
+        {
+            RequestResponse::<User>::from_result(
+                state.data_source.get_user(context.actor.into()).await,
+                (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
+            )
+        }
 
-            let claims = Claims {
-                sub: username.to_owned(),
-                exp: expiration as usize,
-                role: role.to_owned(),
+        /// Needs the current password, a stolen session alone can't take over
+        /// the account. Every other session of the user is logged out.
+        async fn change_password<S>(
+            State(state): State<AppState<Circuit, S>>,
+            context: ChangeContext,
+            CallerSession(session_id): CallerSession,
+            Json(change): Json<PasswordChangeRequest>,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit>
+                + Users<User>
+                + Sessions<Session>
+                + Clone
+                + Send
+                + Sync
+                + 'static,
+            <S as Users<User>>::Id: From<std::string::String>,
+            <S as Sessions<Session>>::Id: From<std::string::String>,
+        {
+            let stored = match state
+                .data_source
+                .get_password(context.actor.clone().into())
+                .await
+            {
+                Ok(stored) => stored,
+                Err(e) => {
+                    return RequestResponse::<()>::Error {
+                        message: e.to_string(),
+                        code: StatusCode::INTERNAL_SERVER_ERROR,
+                    }
+                }
             };
 
-            let secret = env::var("JWT_SECRET").expect("JWT_SECRET must be set");
+            if !stored.verify_blocking(change.current_password).await {
+                return RequestResponse::<()>::Error {
+                    message: "The current password is wrong".to_string(),
+                    code: StatusCode::BAD_REQUEST,
+                };
+            }
 
-            encode(
-                &Header::default(),
-                &claims,
-                &EncodingKey::from_secret(secret.as_ref()),
+            if let Err(e) = password::validate(&change.new_password) {
+                return RequestResponse::<()>::Error {
+                    message: e.to_string(),
+                    code: StatusCode::BAD_REQUEST,
+                };
+            }
+
+            let result = match password::hash_blocking(change.new_password).await {
+                Ok(hash) => {
+                    state
+                        .data_source
+                        .set_password(context.actor.clone().into(), hash)
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            let result = match result {
+                Ok(()) => state
+                    .data_source
+                    .revoke_other_sessions(context.actor, session_id.into())
+                    .await
+                    .map(|_| ()),
+                Err(e) => Err(e),
+            };
+
+            RequestResponse::<()>::from_result(
+                result,
+                (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
             )
-            .unwrap()
         }
+    }
 
-        pub fn get_router() -> Router {
-            Router::new().route("/login", post(login))
-        }
+    pub mod audit {
+        use axum::{
+            extract::{Query, State},
+            http::StatusCode,
+            middleware::from_fn,
+            response::IntoResponse,
+            routing::get,
+            Router,
+        };
 
-        async fn login(Json(login_request): Json<LoginRequest>) -> impl IntoResponse {
-            let pool = sqlx::PgPool::connect(
-                &std::env::var("DATABASE_URL").expect("DATABASE_URL MUST BE SET"),
-            )
-            .await
-            .map_err(Into::<eyre::Report>::into);
+        use crate::{
+            model::{
+                AppState, AuditFilter, ChangeHistory, Circuit, DataSource, LoginAudit,
+                LoginFailure, Permission, Revision,
+            },
+            web::{
+                middleware::validate_permission_mw, requests::AuditQuery,
+                responses::RequestResponse,
+            },
+        };
 
-            let pool = if let Err(e) = pool {
-                return RequestResponse::<LoginResponse>::Error {
-                    message: e.to_string(),
-                    code: StatusCode::INTERNAL_SERVER_ERROR,
-                };
-            } else {
-                pool.unwrap()
+        pub fn get_router<S>() -> Router<AppState<Circuit, S>>
+        where
+            S: DataSource<Circuit>
+                + ChangeHistory<Circuit>
+                + LoginAudit<LoginFailure>
+                + Clone
+                + Send
+                + Sync
+                + 'static,
+        {
+            Router::new()
+                .route(
+                    "/",
+                    get(get_audit_log).layer(from_fn(|req, next| {
+                        validate_permission_mw(req, next, Permission::ReportsRead)
+                    })),
+                )
+                .route(
+                    "/logins",
+                    get(get_login_failures).layer(from_fn(|req, next| {
+                        validate_permission_mw(req, next, Permission::UsersAdmin)
+                    })),
+                )
+        }
+
+        async fn get_audit_log<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Query(audit_query): Query<AuditQuery>,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + ChangeHistory<Circuit> + Clone + Send + Sync + 'static,
+        {
+            let filter = match AuditFilter::try_from(audit_query) {
+                Ok(filter) => filter,
+                Err(e) => {
+                    return RequestResponse::<Vec<Revision<Circuit>>>::Error {
+                        message: e.to_string(),
+                        code: StatusCode::BAD_REQUEST,
+                    }
+                }
             };
 
-            let result: Result<Option<UserResponse>, _> = sqlx::query_as(
-                "SELECT username, password, role FROM users WHERE username = $1 AND password = $2 AND role = $3 ",
+            RequestResponse::<Vec<Revision<Circuit>>>::from_result(
+                state.data_source.get_audit_log(filter).await,
+                (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
             )
-            .bind(login_request.username)
-            .bind(login_request.password)
-            .bind(Into::<String>::into(login_request.requested_role))
-            .fetch_optional(&pool)
-            .await
-            .map_err(Into::<eyre::Report>::into);
+        }
 
-            let query = if let Err(e) = result {
-                return RequestResponse::<LoginResponse>::Error {
-                    message: e.to_string(),
-                    code: StatusCode::INTERNAL_SERVER_ERROR,
-                };
-            } else {
-                result.unwrap()
+        /// Failed logins, newest first. `user` is the username that was tried
+        /// and `before` an id of this list rather than a revision.
+        async fn get_login_failures<S>(
+            State(state): State<AppState<Circuit, S>>,
+            Query(audit_query): Query<AuditQuery>,
+        ) -> impl IntoResponse
+        where
+            S: DataSource<Circuit> + LoginAudit<LoginFailure> + Clone + Send + Sync + 'static,
+        {
+            let filter = match AuditFilter::try_from(audit_query) {
+                Ok(filter) => filter,
+                Err(e) => {
+                    return RequestResponse::<Vec<LoginFailure>>::Error {
+                        message: e.to_string(),
+                        code: StatusCode::BAD_REQUEST,
+                    }
+                }
             };
 
-            if let Some(user) = query {
-                RequestResponse::<LoginResponse>::Success {
-                    data: LoginResponse {
-                        token: create_jwt(&user.username, &Into::<String>::into(user.role)),
-                    },
-                    code: StatusCode::OK,
-                }
-            } else {
-                RequestResponse::<LoginResponse>::Error {
-                    message: "Invalid user".to_string(),
-                    code: StatusCode::BAD_REQUEST,
-                }
-            }
+            RequestResponse::<Vec<LoginFailure>>::from_result(
+                state.data_source.get_login_failures(filter).await,
+                (StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR),
+            )
         }
     }
 
@@ -710,17 +5021,68 @@ pub mod handlers {
             + Sync
             + 'static
             + Reporter<CircuitImportReport>
-            + NotificationRepository<CircuitImportReport>,
+            + NotificationRepository<CircuitImportReport>
+            + ChangeHistory<Circuit>
+            + ConflictChecks<Circuit>
+            + ImportJobs<ImportJob>
+            + MappingProfiles<ColumnMapping>
+            + NaturalKeys<NaturalKey>
+            + Staging<StagedImport>
+            + Users<User>
+            + Sessions<Session>
+            + ApiKeys<ApiKey>
+            + Roles<Role>
+            + AccessScopes<AccessScope>
+            + Regions<Region>
+            + LoginLocks<LoginLock>
+            + LoginAudit<LoginFailure>,
         <S as DataSource<Circuit>>::Id: From<Ulid> + Send + Sync,
+        <S as DataSource<Circuit>>::Query: From<CircuitQuery>,
         <S as Reporter<CircuitImportReport>>::Id: From<std::string::String>,
+        <S as ChangeHistory<Circuit>>::Id: From<Ulid>,
+        <S as ImportJobs<ImportJob>>::Id: From<std::string::String> + Send,
+        <S as MappingProfiles<ColumnMapping>>::Id: From<std::string::String>,
+        <S as Staging<StagedImport>>::Id: From<std::string::String>,
+        <S as Users<User>>::Id: From<std::string::String> + Send,
+        <S as Sessions<Session>>::Id: From<std::string::String> + Send,
+        <S as ApiKeys<ApiKey>>::Id: From<std::string::String> + Send,
+        <S as Roles<Role>>::Id: From<std::string::String> + Send,
+        <S as Regions<Region>>::Id: From<std::string::String> + Send,
     {
-        Router::new().nest(
-            "/circuits",
-            circuits::get_router().nest("/reports", circuits::reporting::get_router()),
-        )
+        Router::new()
+            .nest(
+                "/circuits",
+                circuits::get_router()
+                    .nest("/reports", circuits::reporting::get_router())
+                    .nest("/mappings", circuits::mappings::get_router())
+                    .nest("/natural-key", circuits::natural_key::get_router()),
+            )
+            .nest("/audit", audit::get_router())
+            .nest("/users", users::get_router())
+            .nest("/roles", roles::get_router())
+            .nest("/regions", regions::get_router())
+            .nest("/locks", locks::get_router())
+            .nest("/keys", api_keys::get_router())
+            .nest("/me", me::get_router())
     }
 
-    pub fn get_auth_router() -> Router {
+    pub fn get_auth_router<S>() -> Router<AppState<Circuit, S>>
+    where
+        S: DataSource<Circuit>
+            + Users<User>
+            + Sessions<Session>
+            + Roles<Role>
+            + AccessScopes<AccessScope>
+            + Regions<Region>
+            + LoginLocks<LoginLock>
+            + LoginAudit<LoginFailure>
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+        <S as Users<User>>::Id: From<std::string::String> + Send,
+        <S as Sessions<Session>>::Id: From<std::string::String> + Send,
+    {
         Router::new().merge(auth::get_router())
     }
 }
@@ -729,16 +5091,37 @@ pub mod middleware {
 
     use axum::{
         body::{to_bytes, Body},
-        extract::Request,
-        http::{header::AUTHORIZATION, StatusCode},
+        extract::{Request, State},
+        http::{
+            header::{AUTHORIZATION, CONTENT_DISPOSITION, CONTENT_TYPE},
+            StatusCode,
+        },
         middleware::Next,
         response::{IntoResponse, Response},
     };
     use jsonwebtoken::{decode, DecodingKey, Validation};
 
-    use super::{responses::RequestResponse, Claims};
+    use super::{responses::RequestResponse, ApiKeyCaller, Claims};
+    use crate::{
+        api_key::Key,
+        model::{
+            ApiKey, ApiKeys, AppState, Circuit, DataSource, Permission, Role, Roles, Session,
+            Sessions,
+        },
+    };
 
     pub async fn response_mapper(res: Response) -> Response {
+        // Errors we built ourselves already carry a useful message, only the plain
+        // text rejections from axum's extractors get replaced
+        let is_json = res
+            .headers()
+            .get(CONTENT_TYPE)
+            .is_some_and(|content_type| content_type == "application/json");
+
+        if is_json {
+            return res;
+        }
+
         match res.status() {
             StatusCode::UNPROCESSABLE_ENTITY
             | StatusCode::BAD_REQUEST
@@ -769,6 +5152,19 @@ pub mod middleware {
         let path = req.uri().clone();
 
         let res = next.run(req).await;
+
+        // Downloads can be large and some are streamed, they're passed on as they are
+        let is_download = res
+            .headers()
+            .get(CONTENT_DISPOSITION)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("attachment"));
+
+        if is_download {
+            tracing::info!("Sending download from {path}");
+            return res;
+        }
+
         let (mut res_parts, res_body) = res.into_parts();
         res_parts.headers.remove("transfer-encoding");
 
@@ -805,10 +5201,25 @@ pub mod middleware {
         }
     }
 
-    pub async fn validate_jwt_mw(
+    /// Lets through requests with an unexpired access token whose session
+    /// wasn't revoked, or with an API key that covers the request
+    pub async fn validate_jwt_mw<S>(
+        State(state): State<AppState<Circuit, S>>,
         mut req: Request<Body>,
         next: Next,
-    ) -> Result<Response, RequestResponse<()>> {
+    ) -> Result<Response, RequestResponse<()>>
+    where
+        S: DataSource<Circuit>
+            + Sessions<Session>
+            + ApiKeys<ApiKey>
+            + Roles<Role>
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+        <S as Sessions<Session>>::Id: From<std::string::String> + Send,
+        <S as ApiKeys<ApiKey>>::Id: From<std::string::String> + Send,
+    {
         tracing::debug!("Validating jwt for request...");
 
         let ret_error = RequestResponse::<()>::Error {
@@ -816,46 +5227,122 @@ pub mod middleware {
             code: StatusCode::UNAUTHORIZED,
         };
 
-        let auth_token = get_auth_token_from_req(&req);
+        if let Some(key) = get_auth_token_from_req(&req).and_then(Key::parse) {
+            let claims = validate_api_key(&state.data_source, &key)
+                .await
+                .ok_or(ret_error)?;
+            req.extensions_mut().insert(claims);
+            req.extensions_mut().insert(ApiKeyCaller);
+
+            return Ok(next.run(req).await);
+        }
 
-        match auth_token {
-            Some(token) => {
-                if let Some(token_data) = get_valid_token(
-                    token,
-                    &std::env::var("JWT_SECRET").expect("JWT_SECRET MUST BE SET"),
-                ) {
-                    req.extensions_mut().insert(token_data);
+        let token_data = match get_auth_token_from_req(&req) {
+            Some(token) => get_valid_token(
+                token,
+                &std::env::var("JWT_SECRET").expect("JWT_SECRET MUST BE SET"),
+            ),
+            None => None,
+        };
 
-                    Ok(next.run(req).await)
-                } else {
-                    Err(ret_error)
-                }
+        let Some(token_data) = token_data else {
+            return Err(ret_error);
+        };
+
+        match state
+            .data_source
+            .get_session(token_data.sid.clone().into())
+            .await
+        {
+            Ok(session) if session.is_active() && session.username == token_data.sub => {}
+            Ok(_) => return Err(ret_error),
+            Err(e) => {
+                tracing::debug!("Session of token not found : {}", e);
+                return Err(ret_error);
             }
+        }
+
+        req.extensions_mut().insert(token_data);
+
+        Ok(next.run(req).await)
+    }
+    /// Claims for a request made with `key`, `None` when the key is unknown,
+    /// expired or not scoped for the request
+    async fn validate_api_key<S>(data_source: &S, key: &Key) -> Option<Claims>
+    where
+        S: ApiKeys<ApiKey> + Roles<Role>,
+        <S as ApiKeys<ApiKey>>::Id: From<std::string::String> + Send,
+    {
+        let (api_key, hash) = tokio::try_join!(
+            data_source.get_api_key(key.id.clone().into()),
+            data_source.get_key_hash(key.id.clone().into())
+        )
+        .ok()?;
+
+        if !key.verify(&hash) || api_key.is_expired() {
+            return None;
+        }
+
+        let permissions: Vec<Permission> = data_source
+            .get_permissions(api_key.roles.clone())
+            .await
+            .ok()?
+            .into_iter()
+            .filter(|permission| {
+                api_key.scopes.is_empty()
+                    || api_key
+                        .scopes
+                        .iter()
+                        .any(|scope| scope == permission.as_str())
+            })
+            .collect();
 
-            None => Err(ret_error),
+        if let Err(e) = data_source.touch_api_key(key.id.clone().into()).await {
+            tracing::error!(
+                "Failed recording the use of API key {} : {}",
+                api_key.name,
+                e
+            );
         }
+
+        Some(Claims {
+            sub: api_key.actor(),
+            exp: api_key
+                .expires_at
+                .map(|expires_at| expires_at.timestamp() as usize)
+                .unwrap_or(usize::MAX),
+            roles: api_key.roles,
+            permissions,
+            sid: api_key.id,
+            // Scopes are for people, service accounts reach every circuit
+            scope: None,
+        })
     }
-    pub async fn validate_role_mw(
+
+    /// Lets through requests whose token carries `permission`
+    pub async fn validate_permission_mw(
         req: Request<Body>,
         next: Next,
-        allowed_roles: &[&str],
+        permission: Permission,
     ) -> Result<Response<Body>, RequestResponse<()>> {
-        let ret_error = RequestResponse::<()>::Error {
-            message: "Invalid auth".to_string(),
-            code: StatusCode::UNAUTHORIZED,
-        };
-
-        let role = match req.extensions().get::<Claims>() {
-            Some(claims) => &claims.role,
-            None => return Err(ret_error),
+        let permissions = match req.extensions().get::<Claims>() {
+            Some(claims) => &claims.permissions,
+            None => {
+                return Err(RequestResponse::<()>::Error {
+                    message: "Invalid auth".to_string(),
+                    code: StatusCode::UNAUTHORIZED,
+                })
+            }
         };
 
-        for required_role in allowed_roles {
-            if role == required_role {
-                return Ok(next.run(req).await);
-            }
+        if permissions.contains(&permission) {
+            return Ok(next.run(req).await);
         }
 
-        Err(ret_error)
+        // Who the caller is is known, they just aren't allowed to do this
+        Err(RequestResponse::<()>::Error {
+            message: format!("Missing the `{}` permission", permission.as_str()),
+            code: StatusCode::FORBIDDEN,
+        })
     }
 }
@@ -0,0 +1,345 @@
+//! Excel workbooks in and out. An uploaded workbook is turned into CSV text so
+//! it goes through the same import pipeline as an uploaded CSV file.
+
+use std::{collections::BTreeMap, io::Cursor};
+
+use calamine::{Data, Reader, Xlsx};
+use rust_xlsxwriter::{ExcelDateTime, Format, Workbook, Worksheet, XlsxError};
+use serde::Deserialize;
+
+use crate::model::{Circuit, CircuitColumn};
+
+/// Rows looked at when working out which one holds the headers
+const HEADER_SEARCH_ROWS: usize = 20;
+
+/// Longest sheet name Excel accepts
+const MAX_SHEET_NAME: usize = 31;
+
+/// Which sheet of an uploaded workbook is imported and where its headers are
+#[derive(Debug, Default, Clone)]
+pub struct SheetSelection {
+    /// The first sheet when missing
+    pub sheet: Option<String>,
+    /// Row number as shown by Excel, detected when missing
+    pub header_row: Option<u32>,
+}
+
+/// The selected sheet as CSV, from its header row on. Rows above the headers
+/// and empty rows become blank lines, which CSV readers skip, so line numbers
+/// in the CSV are the row numbers of the sheet until a cell with line breaks
+/// in it pushes the rows under it down.
+pub fn sheet_to_csv(data: Vec<u8>, selection: &SheetSelection) -> eyre::Result<String> {
+    let mut workbook: Xlsx<_> = calamine::open_workbook_from_rs(Cursor::new(data))?;
+
+    let sheet = match &selection.sheet {
+        Some(sheet) => sheet.clone(),
+        None => workbook
+            .sheet_names()
+            .first()
+            .cloned()
+            .ok_or_else(|| eyre::Report::msg("The workbook has no sheets"))?,
+    };
+
+    let range = workbook
+        .worksheet_range(&sheet)
+        .map_err(|e| eyre::Report::msg(format!("Failed reading sheet `{sheet}` : {e}")))?;
+
+    // Sheets that don't use the first rows start further down
+    let first_row = range.start().map(|(row, _)| row as usize + 1).unwrap_or(1);
+    let rows: Vec<&[Data]> = range.rows().collect();
+
+    let header_index = match selection.header_row {
+        Some(header_row) => (header_row as usize)
+            .checked_sub(first_row)
+            .filter(|index| {
+                rows.get(*index)
+                    .is_some_and(|row| row.iter().any(|cell| !matches!(cell, Data::Empty)))
+            })
+            .ok_or_else(|| {
+                eyre::Report::msg(format!("Row {header_row} of sheet `{sheet}` is empty"))
+            })?,
+        None => detect_header_row(&rows)
+            .ok_or_else(|| eyre::Report::msg(format!("Sheet `{sheet}` is empty")))?,
+    };
+
+    let mut csv_data = "\n".repeat(first_row - 1 + header_index).into_bytes();
+
+    for row in &rows[header_index..] {
+        if row.iter().all(|cell| matches!(cell, Data::Empty)) {
+            csv_data.push(b'\n');
+            continue;
+        }
+
+        let mut writer = csv::Writer::from_writer(&mut csv_data);
+        writer.write_record(row.iter().map(cell_text))?;
+        writer.flush()?;
+    }
+
+    Ok(String::from_utf8(csv_data)?)
+}
+
+/// The first row with the most cells among the top rows, not counting rows
+/// with anything but text in them. Titles above a table fill fewer cells than
+/// its headers, and the rows under them usually hold numbers somewhere.
+fn detect_header_row(rows: &[&[Data]]) -> Option<usize> {
+    let labels = |row: &[Data]| {
+        if row
+            .iter()
+            .all(|cell| matches!(cell, Data::String(_) | Data::Empty))
+        {
+            row.iter()
+                .filter(|cell| !matches!(cell, Data::Empty))
+                .count()
+        } else {
+            0
+        }
+    };
+
+    let candidates = &rows[..rows.len().min(HEADER_SEARCH_ROWS)];
+    let most = candidates
+        .iter()
+        .map(|row| labels(row))
+        .max()
+        .filter(|most| *most > 0)?;
+
+    candidates.iter().position(|row| labels(row) == most)
+}
+
+fn cell_text(cell: &Data) -> String {
+    match cell {
+        Data::Empty => String::new(),
+        Data::String(text) => text.clone(),
+        // Excel keeps every number as a float, whole ones read better without the `.0`
+        Data::Float(number) if number.fract() == 0.0 && number.abs() < 1e15 => {
+            (*number as i64).to_string()
+        }
+        other => other.to_string(),
+    }
+}
+
+/// What export workbooks get a sheet for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SheetSplit {
+    State,
+    Provider,
+}
+
+/// A workbook with `columns` of the circuits in typed cells under a frozen,
+/// filterable header row, all on one sheet or one sheet per value of `split`
+pub fn circuits_workbook(
+    circuits: &[Circuit],
+    columns: &[CircuitColumn],
+    split: Option<SheetSplit>,
+) -> eyre::Result<Vec<u8>> {
+    let mut groups: BTreeMap<String, Vec<&Circuit>> = BTreeMap::new();
+    for circuit in circuits {
+        let group = match split {
+            None => "Circuits".to_string(),
+            Some(SheetSplit::State) => circuit
+                .state
+                .as_ref()
+                .map(ToString::to_string)
+                .unwrap_or_else(|| "No state".to_string()),
+            Some(SheetSplit::Provider) if circuit.provider.trim().is_empty() => {
+                "No provider".to_string()
+            }
+            Some(SheetSplit::Provider) => circuit.provider.trim().to_string(),
+        };
+        groups.entry(group).or_default().push(circuit);
+    }
+
+    // An export without circuits still has the headers
+    if groups.is_empty() {
+        groups.insert("Circuits".to_string(), vec![]);
+    }
+
+    let mut workbook = Workbook::new();
+    let mut names: Vec<String> = vec![];
+
+    for (group, circuits) in groups {
+        let name = sheet_name(&group, &names);
+        let sheet = workbook.add_worksheet();
+        sheet.set_name(&name)?;
+        write_circuits(sheet, &circuits, columns)?;
+        names.push(name);
+    }
+
+    Ok(workbook.save_to_buffer()?)
+}
+
+fn write_circuits(
+    sheet: &mut Worksheet,
+    circuits: &[&Circuit],
+    columns: &[CircuitColumn],
+) -> Result<(), XlsxError> {
+    let header = Format::new().set_bold();
+    let timestamp = Format::new().set_num_format("yyyy-mm-dd hh:mm:ss");
+
+    for (col, column) in columns.iter().enumerate() {
+        sheet.write_string_with_format(0, col as u16, column.as_str(), &header)?;
+    }
+
+    for (index, circuit) in circuits.iter().enumerate() {
+        let row = index as u32 + 1;
+
+        for (col, column) in columns.iter().enumerate() {
+            let col = col as u16;
+
+            match column {
+                CircuitColumn::BwMbps => {
+                    if let Some(bandwidth) = circuit.bw_mbps {
+                        sheet.write_number(row, col, bandwidth.mbps() as f64)?;
+                    }
+                }
+                CircuitColumn::SingleIsp => {
+                    sheet.write_boolean(row, col, circuit.single_isp)?;
+                }
+                CircuitColumn::DecommissionedAt => {
+                    if let Some(decommissioned_at) = circuit.decommissioned_at {
+                        let excel_time =
+                            ExcelDateTime::from_timestamp(decommissioned_at.timestamp())?;
+                        sheet.write_datetime_with_format(row, col, &excel_time, &timestamp)?;
+                    }
+                }
+                _ => {
+                    let value = circuit.field(*column);
+                    if !value.is_empty() {
+                        sheet.write_string(row, col, value)?;
+                    }
+                }
+            }
+        }
+    }
+
+    sheet.set_freeze_panes(1, 0)?;
+    sheet.autofilter(0, 0, circuits.len() as u32, columns.len() as u16 - 1)?;
+    sheet.autofit();
+
+    Ok(())
+}
+
+/// `value` cut down to what Excel accepts as a sheet name and told apart from `taken`
+fn sheet_name(value: &str, taken: &[String]) -> String {
+    let cleaned: String = value
+        .chars()
+        .map(|c| match c {
+            '[' | ']' | ':' | '*' | '?' | '/' | '\\' => '-',
+            c => c,
+        })
+        .collect();
+    let cleaned = cleaned.trim().trim_matches('\'');
+    let cleaned = if cleaned.is_empty() { "Sheet" } else { cleaned };
+
+    let is_taken = |name: &str| taken.iter().any(|other| other.eq_ignore_ascii_case(name));
+
+    let base: String = cleaned.chars().take(MAX_SHEET_NAME).collect();
+    if !is_taken(&base) {
+        return base;
+    }
+
+    (2..)
+        .map(|n| {
+            let suffix = format!(" ({n})");
+            let base: String = cleaned
+                .chars()
+                .take(MAX_SHEET_NAME - suffix.len())
+                .collect();
+            format!("{base}{suffix}")
+        })
+        .find(|name| !is_taken(name))
+        .expect("There is always a free sheet name")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        model::{CircuitDTO, RawCircuit},
+        upload::{TooLarge, UploadLimits},
+    };
+
+    fn text(value: &str) -> Data {
+        Data::String(value.to_string())
+    }
+
+    #[test]
+    fn headers_are_found_under_titles() {
+        let title = [text("Circuit inventory"), Data::Empty, Data::Empty];
+        let headers = [text("ckt_id"), text("provider"), text("bw_mbps")];
+        let row = [text("CR-01"), text("Acme"), Data::Float(100.0)];
+        let rows: Vec<&[Data]> = vec![&title, &[], &headers, &row];
+
+        assert_eq!(detect_header_row(&rows), Some(2));
+        assert_eq!(detect_header_row(&[&row]), None);
+        assert_eq!(cell_text(&Data::Float(100.0)), "100");
+        assert_eq!(cell_text(&Data::Float(1.5)), "1.5");
+    }
+
+    #[test]
+    fn sheet_names_are_cleaned_and_kept_apart() {
+        assert_eq!(sheet_name("AT&T: East/West", &[]), "AT&T- East-West");
+        assert_eq!(sheet_name(" '' ", &[]), "Sheet");
+
+        let long = "A provider with a very long name indeed";
+        let first = sheet_name(long, &[]);
+        assert_eq!(first.chars().count(), MAX_SHEET_NAME);
+
+        let second = sheet_name(long, &[first.to_uppercase()]);
+        assert!(second.ends_with(" (2)"), "{second}");
+        assert_eq!(second.chars().count(), MAX_SHEET_NAME);
+    }
+
+    fn workbook() -> Vec<u8> {
+        let circuit = Circuit::from(
+            CircuitDTO::try_from(RawCircuit {
+                ckt_id: Some("CR-01".to_string()),
+                provider: Some("Acme".to_string()),
+                bw_mbps: Some("1G".to_string()),
+                ..RawCircuit::default()
+            })
+            .unwrap(),
+        );
+        let columns = [
+            CircuitColumn::CktId,
+            CircuitColumn::Provider,
+            CircuitColumn::BwMbps,
+        ];
+
+        circuits_workbook(&[circuit], &columns, Some(SheetSplit::Provider)).unwrap()
+    }
+
+    #[test]
+    fn exported_workbooks_import_as_csv() {
+        let selection = SheetSelection {
+            sheet: Some("Acme".to_string()),
+            header_row: None,
+        };
+
+        assert_eq!(
+            sheet_to_csv(workbook(), &selection).unwrap(),
+            "ckt_id,provider,bw_mbps\nCR-01,Acme,1000\n"
+        );
+
+        let missing = SheetSelection {
+            sheet: Some("Globex".to_string()),
+            header_row: None,
+        };
+        assert!(sheet_to_csv(workbook(), &missing).is_err());
+    }
+
+    #[test]
+    fn workbooks_are_held_to_their_unpacked_size() {
+        let limits = |max_bytes| UploadLimits {
+            max_bytes,
+            max_rows: 10,
+        };
+        let data = workbook();
+
+        limits(1024 * 1024).check_workbook(&data).unwrap();
+
+        let error = limits(data.len()).check_workbook(&data).unwrap_err();
+        assert!(error.downcast_ref::<TooLarge>().is_some(), "{error}");
+        assert!(limits(1024).check_workbook(b"not a zip").is_err());
+    }
+}